@@ -1,7 +1,14 @@
 use clap::{command, Parser};
+use riscv_emu::elf::Elf;
 use riscv_emu::emulator::{Emulator, EmulatorOpts};
-use riscv_emu::mcu::DeviceDef;
-use riscv_emu::peripherals::{clint::CLINT, flash::Flash, plic::PLIC, uart::UART};
+use riscv_emu::mcu::{DeviceDef, MisalignedAccess};
+use riscv_emu::peripherals::{
+    clint::{Timebase, CLINT},
+    flash::Flash,
+    plic::PLIC,
+    ram::RAM,
+    uart::UART,
+};
 use riscv_emu::terminal::TermEmulator;
 use std::fs;
 use std::io::{BufReader, Read};
@@ -10,14 +17,64 @@ use std::io::{BufReader, Read};
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
-    /// the binary to flash
+    /// the binary to flash, ELF files are loaded at the address of their segments
     flash: String,
     #[arg(short, long)]
     dump_folder: Option<String>,
+    /// also write memory dumps as annotated hexdumps
+    #[arg(long)]
+    dump_hex: bool,
     #[arg(short, long)]
     speed: Option<u32>,
     #[arg(short, long)]
     log: Option<String>,
+    /// wait for a GDB connection on this localhost port before running
+    #[arg(long)]
+    gdb: Option<u16>,
+    /// wait for an OpenOCD remote_bitbang connection on this localhost port, giving it JTAG
+    /// access to an emulated Debug Module
+    #[arg(long, conflicts_with = "gdb")]
+    jtag: Option<u16>,
+    /// riscv-arch-test mode: run the ELF to halt and write the memory between
+    /// `begin_signature` and `end_signature` to this file
+    #[arg(long)]
+    signature: Option<String>,
+    /// enable semihosting, giving the program access to the files in this directory
+    #[arg(long)]
+    semihosting: Option<String>,
+    /// number of hardware performance counters (mhpmcounter3 onwards) that count events
+    #[arg(long, default_value_t = 29)]
+    hpm_counters: u32,
+    /// fill the RAM with this 32-bit pattern instead of zeros, e.g. 0xdeadbeef
+    #[arg(long, value_parser = parse_u32)]
+    ram_pattern: Option<u32>,
+    /// raise address misaligned exceptions on misaligned loads and stores instead of
+    /// splitting them in byte accesses
+    #[arg(long)]
+    trap_misaligned: bool,
+    /// frequency of the CLINT mtime counter in Hz, the hart speed by default
+    #[arg(long)]
+    timebase: Option<u64>,
+    /// advance mtime following the host wall clock instead of the emulated cycles
+    #[arg(long)]
+    wall_clock: bool,
+    /// arguments passed to the program through semihosting
+    #[arg(last = true)]
+    program_args: Vec<String>,
+}
+
+/// Parses a decimal or 0x prefixed hexadecimal number
+fn parse_u32(s: &str) -> Result<u32, std::num::ParseIntError> {
+    match s.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => s.parse(),
+    }
+}
+
+/// The process exit status for the program's exit code. Only the low byte of a status reaches
+/// the shell, so nonzero codes are clamped to 1..=255 to never be reported as a success.
+fn exit_status(code: u32) -> i32 {
+    code.min(255) as i32
 }
 
 pub fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -25,6 +82,11 @@ pub fn main() -> Result<(), Box<dyn std::error::Error>> {
     let mut builder = env_logger::Builder::new();
     builder.parse_filters(&args.log.unwrap_or("info".into()));
     builder.init();
+    let cmdline = std::iter::once(&args.flash)
+        .chain(args.program_args.iter())
+        .cloned()
+        .collect::<Vec<_>>()
+        .join(" ");
     let file = fs::File::open(args.flash).map_err(|err| {
         log::error!("Error geting flash image: {}", err);
         err
@@ -33,12 +95,21 @@ pub fn main() -> Result<(), Box<dyn std::error::Error>> {
     let mut mem: Vec<u8> = Vec::new();
     br.read_to_end(&mut mem)?;
 
-    log::info!("Flash memory loaded");
     let mut term = TermEmulator::new();
     term.lock();
+    let speed = args.speed.unwrap_or(1);
     let opts = EmulatorOpts {
-        speed: args.speed.unwrap_or(1),
+        speed,
         terminal: None,
+        dump_hex: args.dump_hex,
+        semihosting: args.semihosting.map(std::path::PathBuf::from),
+        cmdline,
+        hpm_counters: args.hpm_counters,
+        misaligned: if args.trap_misaligned {
+            MisalignedAccess::Trap
+        } else {
+            MisalignedAccess::Emulate
+        },
         dump_path: std::path::PathBuf::from(
             args.dump_folder.unwrap_or(
                 std::env::current_dir()
@@ -53,20 +124,37 @@ pub fn main() -> Result<(), Box<dyn std::error::Error>> {
     };
 
     let mut uart_device = UART::new(Some(Box::new(term)));
-    uart_device.set_interrupt_id(0b10000);
+    uart_device.set_interrupt_id(4);
+
+    // execute-in-place flash, only the loader writes it
+    let mut flash = Flash::new(0x3_2000);
+    flash.set_read_only(true);
+    let hz = args.timebase.unwrap_or(speed as u64);
+    let timebase = if args.wall_clock {
+        Timebase::WallClock { hz }
+    } else {
+        Timebase::Cycles {
+            hz,
+            cpu_hz: speed as u64,
+        }
+    };
+    let ram = match args.ram_pattern {
+        Some(pattern) => RAM::with_pattern(0x4000, pattern),
+        None => RAM::new(0x4000),
+    };
 
     let devices = vec![
         DeviceDef {
             identifier: "FLASH".to_string(),
             memory_start: 0,
             memory_end: 0x3_2000,
-            device: Box::new(Flash::new(0x3_2000)),
+            device: Box::new(flash),
         },
         DeviceDef {
             identifier: "CLINT".to_string(),
             memory_start: 0x200_0000,
             memory_end: 0x200_FFFF,
-            device: Box::new(CLINT::new()),
+            device: Box::new(CLINT::with_timebase(timebase)),
         },
         DeviceDef {
             identifier: "PLIC".to_string(),
@@ -80,12 +168,35 @@ pub fn main() -> Result<(), Box<dyn std::error::Error>> {
             memory_end: 0x1001_3FFF,
             device: Box::new(uart_device),
         },
+        DeviceDef {
+            identifier: "RAM".to_string(),
+            memory_start: 0x8000_0000,
+            memory_end: 0x8000_3FFF,
+            device: Box::new(ram),
+        },
     ];
 
     let mut emu = Emulator::new(opts);
     emu.setup_devices(devices).unwrap();
-    emu.flash(mem);
-    emu.run_program()?;
-    log::info!("Program ended execution");
-    Ok(())
+    if Elf::is_elf(&mem) {
+        emu.load_elf(&mem).map_err(|err| {
+            log::error!("Error loading ELF image: {}", err);
+            err
+        })?;
+    } else {
+        emu.flash(mem);
+    }
+    log::info!("Flash memory loaded");
+    let code = if let Some(port) = args.gdb {
+        emu.debug(port)?
+    } else if let Some(port) = args.jtag {
+        emu.serve_jtag(port)?
+    } else {
+        emu.run_program()?
+    };
+    log::info!("Program ended execution with exit code {code}");
+    if let Some(path) = args.signature {
+        emu.write_signature(std::path::Path::new(&path))?;
+    }
+    std::process::exit(exit_status(code))
 }
@@ -1,14 +1,205 @@
 use crate::instructions::{Exception, Interrupt};
+use crate::pmp::Pmp;
+use crate::trigger::Triggers;
+
+/// Privilege levels, ordered from the least to the most privileged
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u32)]
+pub enum Privilege {
+    User = 0,
+    Supervisor = 1,
+    Machine = 3,
+}
+
+impl Privilege {
+    /// Decodes the value of a MPP-like field, unsupported levels map to User
+    pub fn from_bits(bits: u32) -> Self {
+        match bits & 0b11 {
+            3 => Privilege::Machine,
+            1 => Privilege::Supervisor,
+            _ => Privilege::User,
+        }
+    }
+}
+
+// mstatus fields
+pub const MSTATUS_SIE: u32 = 1 << 1;
+pub const MSTATUS_MIE: u32 = 1 << 3;
+pub const MSTATUS_SPIE: u32 = 1 << 5;
+pub const MSTATUS_MPIE: u32 = 1 << 7;
+pub const MSTATUS_SPP: u32 = 1 << 8;
+pub const MSTATUS_MPP_SHIFT: u32 = 11;
+pub const MSTATUS_MPP: u32 = 0b11 << MSTATUS_MPP_SHIFT;
+pub const MSTATUS_MPRV: u32 = 1 << 17;
+pub const MSTATUS_SUM: u32 = 1 << 18;
+pub const MSTATUS_MXR: u32 = 1 << 19;
+pub const MSTATUS_TVM: u32 = 1 << 20;
+pub const MSTATUS_TW: u32 = 1 << 21;
+pub const MSTATUS_TSR: u32 = 1 << 22;
+
+// mtvec and stvec fields
+pub const TVEC_MODE: u32 = 0b11;
+pub const TVEC_VECTORED: u32 = 1;
+
+/// The mstatus fields visible through sstatus
+const SSTATUS_MASK: u32 = MSTATUS_SIE | MSTATUS_SPIE | MSTATUS_SPP | MSTATUS_SUM | MSTATUS_MXR;
+/// The mstatus fields that are implemented
+const MSTATUS_MASK: u32 = SSTATUS_MASK
+    | MSTATUS_MIE
+    | MSTATUS_MPIE
+    | MSTATUS_MPP
+    | MSTATUS_MPRV
+    | MSTATUS_TVM
+    | MSTATUS_TW
+    | MSTATUS_TSR;
+/// Interrupts that can be delegated to S-mode
+const SUPERVISOR_INTERRUPTS: u32 = (1 << Interrupt::SSoftInterrupt as u32)
+    | (1 << Interrupt::STimerInterrupt as u32)
+    | (1 << Interrupt::SExternalInterrupt as u32);
+const ALL_INTERRUPTS: u32 = SUPERVISOR_INTERRUPTS
+    | (1 << Interrupt::MSoftInterrupt as u32)
+    | (1 << Interrupt::MTimerInterrupt as u32)
+    | (1 << Interrupt::MExternalInterrupt as u32);
+/// Environment calls from M-mode can't be delegated
+const MEDELEG_MASK: u32 = 0xffff & !(1 << Exception::MEnvironmentCall as u32);
+/// RV32 with the A, C, I, M, S and U extensions
+const MISA: u32 = 1 << 30 | 1 << 0 | MISA_C | 1 << 8 | 1 << 12 | 1 << 18 | 1 << 20;
+/// The C extension can be disabled, making 32 bits the instruction alignment
+const MISA_C: u32 = 1 << 2;
+// menvcfg and senvcfg fields
+const ENVCFG_FIOM: u32 = 1 << 0;
+// counter enable and inhibit bits
+const COUNTER_CY: u32 = 1 << 0;
+const COUNTER_IR: u32 = 1 << 2;
+
+// hardware performance monitor counters and their event selectors
+pub const MHPMCOUNTER3: u32 = 0xb03;
+pub const MHPMEVENT3: u32 = 0x323;
+pub const HPM_COUNTERS: u32 = 29;
+// the high halves of the counters are 0x80 above the low halves
+const COUNTER_HIGH: u32 = 0x80;
+// the unprivileged counters are read-only shadows 0x100 above the machine counters
+const COUNTER_SHADOW: u32 = 0x100;
+
+/// Events mhpmcounter3-31 can count, selected through mhpmevent3-31. Zero counts nothing.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[repr(u32)]
+pub enum HpmEvent {
+    Load = 1,
+    Store = 2,
+    TakenBranch = 3,
+    Trap = 4,
+    WfiCycle = 5,
+}
+const HPM_EVENTS: u32 = HpmEvent::WfiCycle as u32;
+
+// dcsr fields
+const DCSR_XDEBUGVER: u32 = 4 << 28;
+pub const DCSR_EBREAKM: u32 = 1 << 15;
+pub const DCSR_EBREAKS: u32 = 1 << 13;
+pub const DCSR_EBREAKU: u32 = 1 << 12;
+pub const DCSR_STEPIE: u32 = 1 << 11;
+const DCSR_STOPCOUNT: u32 = 1 << 10;
+pub const DCSR_CAUSE_SHIFT: u32 = 6;
+pub const DCSR_CAUSE: u32 = 0b111 << DCSR_CAUSE_SHIFT;
+pub const DCSR_STEP: u32 = 1 << 2;
+pub const DCSR_PRV: u32 = 0b11;
+
+/// Why the hart entered Debug mode, as reported in dcsr.cause
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[repr(u32)]
+pub enum DebugCause {
+    Ebreak = 1,
+    Trigger = 2,
+    HaltRequest = 3,
+    Step = 4,
+}
+
+/// The implemented CSRs, with their reset value and the bits CSR instructions can write. The
+/// bits outside of the mask keep their value. sstatus, sie and sip are views of mstatus, mie
+/// and mip; the PMP CSRs are kept by [`Pmp`] and the trigger CSRs by [`Triggers`]; the
+/// unprivileged counters are shadows of the machine counters, except time, which is kept up to
+/// date with the mtime of the timer device.
+const CSR_FILE: [(CSRs, u32, u32); 39] = [
+    (CSRs::stvec, 0, u32::MAX),
+    (CSRs::scounteren, 0, u32::MAX),
+    (CSRs::senvcfg, 0, ENVCFG_FIOM),
+    (CSRs::sscratch, 0, u32::MAX),
+    (CSRs::sepc, 0, !1),
+    (CSRs::scause, 0, u32::MAX),
+    (CSRs::stval, 0, u32::MAX),
+    (CSRs::satp, 0, u32::MAX),
+    (CSRs::mvendorid, 0, 0),
+    (CSRs::marchid, 0, 0),
+    (CSRs::mimpid, 0, 0),
+    (CSRs::mhartid, 0, 0),
+    (CSRs::mconfigptr, 0, 0),
+    (CSRs::mstatus, 0, MSTATUS_MASK),
+    (CSRs::misa, MISA, MISA_C),
+    (CSRs::medeleg, 0, MEDELEG_MASK),
+    (CSRs::mideleg, 0, SUPERVISOR_INTERRUPTS),
+    (CSRs::mie, 0, ALL_INTERRUPTS),
+    (CSRs::mtvec, 0, u32::MAX),
+    (CSRs::mcounteren, 0, u32::MAX),
+    (CSRs::menvcfg, 0, ENVCFG_FIOM),
+    (CSRs::mstatush, 0, 0),
+    (CSRs::menvcfgh, 0, 0),
+    (CSRs::mcountinhibit, 0, COUNTER_CY | COUNTER_IR),
+    (CSRs::mscratch, 0, u32::MAX),
+    (CSRs::mepc, 0, !1),
+    (CSRs::mcause, 0, u32::MAX),
+    (CSRs::mtval, 0, u32::MAX),
+    (CSRs::mip, 0, SUPERVISOR_INTERRUPTS),
+    (CSRs::mcycle, 0, u32::MAX),
+    (CSRs::minstret, 0, u32::MAX),
+    (CSRs::mcycleh, 0, u32::MAX),
+    (CSRs::minstreth, 0, u32::MAX),
+    (CSRs::time, 0, 0),
+    (CSRs::timeh, 0, 0),
+    // counters don't count while halted, time keeps running
+    (
+        CSRs::dcsr,
+        DCSR_XDEBUGVER | DCSR_STOPCOUNT | Privilege::Machine as u32,
+        DCSR_EBREAKM | DCSR_EBREAKS | DCSR_EBREAKU | DCSR_STEPIE | DCSR_STEP | DCSR_PRV,
+    ),
+    (CSRs::dpc, 0, !1),
+    (CSRs::dscratch0, 0, u32::MAX),
+    (CSRs::dscratch1, 0, u32::MAX),
+];
+
+/// A CSR slot of the file, indexed by the 12-bit address
+#[derive(Clone, Copy, Default)]
+struct Csr {
+    value: u32,
+    write_mask: u32,
+    implemented: bool,
+}
 
 pub struct CPU {
     // program counter
     pub pc: u32,
+    // current privilege level
+    pub privilege: Privilege,
     // x regisers, ignoring x0
     x: [u32; 32],
     // waiting for interrupt
     pub wfi: bool,
+    // address reserved by a load-reserved instruction
+    pub reservation: Option<u32>,
     // csr registers
-    csr: [u32; 8], // TODO: Implement only the CSRs I want.
+    csr: Box<[Csr; 4096]>,
+    // physical memory protection, pmpcfg and pmpaddr CSRs
+    pub pmp: Pmp,
+    // hardware breakpoints and watchpoints, tselect and tdata CSRs
+    pub triggers: Triggers,
+    // counters written by the running instruction, they don't count it
+    counters_written: u32,
+    // halted in Debug mode, waiting for the debugger to resume
+    pub debug_mode: bool,
+    // the debugger asks the hart to halt
+    pub halt_request: bool,
+    // interrupt pending bits driven by the devices
+    interrupt_lines: u32,
 }
 
 #[derive(Copy, Clone)]
@@ -16,60 +207,356 @@ pub struct CPU {
 #[repr(u32)]
 pub enum CSRs {
     mstatus = 0x300,
-    mip = 0x344,
+    misa = 0x301,
+    medeleg = 0x302,
+    mideleg = 0x303,
     mie = 0x304,
-    mcause = 0x342,
     mtvec = 0x305,
-    mtval = 0x343,
-    mepc = 0x341,
+    mcounteren = 0x306,
+    menvcfg = 0x30a,
+    mstatush = 0x310,
+    menvcfgh = 0x31a,
+    mcountinhibit = 0x320,
     mscratch = 0x340,
-    // TODO: Supervisor mode
-    // sepc = 0
-    // sstatus = 0,
+    mepc = 0x341,
+    mcause = 0x342,
+    mtval = 0x343,
+    mip = 0x344,
+    mvendorid = 0xf11,
+    marchid = 0xf12,
+    mimpid = 0xf13,
+    mhartid = 0xf14,
+    mconfigptr = 0xf15,
+    // only accessible in Debug mode
+    dcsr = 0x7b0,
+    dpc = 0x7b1,
+    dscratch0 = 0x7b2,
+    dscratch1 = 0x7b3,
+    mcycle = 0xb00,
+    minstret = 0xb02,
+    mcycleh = 0xb80,
+    minstreth = 0xb82,
+    // cycle and instret are shadows of mcycle and minstret
+    cycle = 0xc00,
+    time = 0xc01,
+    instret = 0xc02,
+    cycleh = 0xc80,
+    timeh = 0xc81,
+    instreth = 0xc82,
+    // sstatus, sie and sip are views of mstatus, mie and mip
+    sstatus = 0x100,
+    sie = 0x104,
+    stvec = 0x105,
+    scounteren = 0x106,
+    senvcfg = 0x10a,
+    sscratch = 0x140,
+    sepc = 0x141,
+    scause = 0x142,
+    stval = 0x143,
+    sip = 0x144,
+    satp = 0x180,
 }
 
 impl CPU {
     pub fn new() -> Self {
-        CPU {
+        let mut csr = Box::new([Csr::default(); 4096]);
+        for (addr, value, write_mask) in CSR_FILE {
+            csr[addr as usize] = Csr {
+                value,
+                write_mask,
+                implemented: true,
+            };
+        }
+        for i in 0..HPM_COUNTERS {
+            for addr in [
+                MHPMCOUNTER3 + i,
+                MHPMCOUNTER3 + COUNTER_HIGH + i,
+                MHPMEVENT3 + i,
+            ] {
+                csr[addr as usize].implemented = true;
+            }
+        }
+        let mut cpu = CPU {
             pc: 0,
+            privilege: Privilege::Machine,
             x: [0; 32],
-            csr: [0; 8],
+            csr,
+            pmp: Pmp::new(),
+            triggers: Triggers::new(),
             wfi: false,
+            reservation: None,
+            counters_written: 0,
+            debug_mode: false,
+            halt_request: false,
+            interrupt_lines: 0,
+        };
+        cpu.set_hpm_counters(HPM_COUNTERS);
+        cpu
+    }
+
+    /// Resets the hart, registers and CSRs take their reset values and execution restarts at
+    /// `pc`. The number of implemented hpm counters is kept.
+    pub fn reset(&mut self, pc: u32) {
+        let hpm_counters = (0..HPM_COUNTERS)
+            .filter(|i| self.csr[(MHPMEVENT3 + i) as usize].write_mask != 0)
+            .count() as u32;
+        *self = CPU::new();
+        self.set_hpm_counters(hpm_counters);
+        self.pc = pc;
+    }
+
+    /// Sets how many of mhpmcounter3-31 count events, the rest are hardwired to zero
+    pub fn set_hpm_counters(&mut self, n: u32) {
+        let n = n.min(HPM_COUNTERS);
+        let mut inhibit = COUNTER_CY | COUNTER_IR;
+        for i in 0..HPM_COUNTERS {
+            let mask = if i < n { u32::MAX } else { 0 };
+            for addr in [
+                MHPMCOUNTER3 + i,
+                MHPMCOUNTER3 + COUNTER_HIGH + i,
+                MHPMEVENT3 + i,
+            ] {
+                let csr = &mut self.csr[addr as usize];
+                csr.write_mask = mask;
+                csr.value &= mask;
+            }
+            inhibit |= (1 << (i + 3)) & mask;
         }
+        let csr = &mut self.csr[CSRs::mcountinhibit as usize];
+        csr.write_mask = inhibit;
+        csr.value &= inhibit;
+    }
+
+    /// Updates the time CSR with the value of the mtime register of the CLINT or ACLINT MTIMER
+    pub fn set_time(&mut self, mtime: u64) {
+        self.csr[CSRs::time as usize].value = mtime as u32;
+        self.csr[CSRs::timeh as usize].value = (mtime >> 32) as u32;
+    }
+
+    /// Sets the interrupt pending bits driven by the devices. mip reads as these ORed with the
+    /// bits written by software, so it follows the interrupt lines without being cleared.
+    pub fn set_interrupt_lines(&mut self, lines: u32) {
+        self.interrupt_lines = lines;
+    }
+
+    /// Sets software writable pending bits of mip, for devices that raise interrupts software
+    /// clears, like the ACLINT SSWI
+    pub fn set_pending(&mut self, bits: u32) {
+        let mip = &mut self.csr[CSRs::mip as usize];
+        mip.value |= bits & mip.write_mask;
+    }
+
+    /// Adds `n` to the 64-bit counter with its low half at `addr`, unless it's inhibited or it
+    /// was written by the running instruction
+    fn increment_counter(&mut self, addr: u32, n: u64) {
+        let bit = 1 << (addr & 0x1f);
+        let inhibit = self.csr[CSRs::mcountinhibit as usize].value;
+        if (inhibit | self.counters_written) & bit != 0 {
+            return;
+        }
+        let (low, high) = (addr as usize, (addr + COUNTER_HIGH) as usize);
+        let value = (self.csr[high].value as u64) << 32 | self.csr[low].value as u64;
+        let value = value.wrapping_add(n);
+        self.csr[low].value = value as u32;
+        self.csr[high].value = (value >> 32) as u32;
+    }
+
+    /// Counts an event on the mhpmcounters selecting it
+    pub fn count_event(&mut self, event: HpmEvent) {
+        for i in 0..HPM_COUNTERS {
+            if self.csr[(MHPMEVENT3 + i) as usize].value == event as u32 {
+                self.increment_counter(MHPMCOUNTER3 + i, 1);
+            }
+        }
+    }
+
+    /// Advances mcycle by `cycles` and minstret when an instruction retired. Called once per
+    /// tick, counters written by the instruction keep the written value.
+    pub fn retire(&mut self, cycles: u32, retired: bool) {
+        self.increment_counter(CSRs::mcycle as u32, cycles as u64);
+        if retired {
+            self.increment_counter(CSRs::minstret as u32, 1);
+        }
+        self.counters_written = 0;
     }
 
     pub fn log_registers(&self) {
         if log::log_enabled!(log::Level::Trace) {
-            let csrs = self.csr.map(|c| format!("{c:b}"));
+            let csrs: Vec<String> = self
+                .csr
+                .iter()
+                .enumerate()
+                .filter(|(_, c)| c.value != 0)
+                .map(|(addr, c)| format!("{addr:x}: {:b}", c.value))
+                .collect();
             let x = self.x.map(|x| format!("{x:x}"));
             let pc = self.pc;
             log::trace!("executing - : csr: {csrs:?}, pc: {pc:x}, x: {x:?}");
         }
     }
 
-    fn csr_idx_map(v: u32) -> Result<usize, Exception> {
-        let m = match v {
-            _ if CSRs::mstatus as u32 == v => 0,
-            _ if CSRs::mip as u32 == v => 1,
-            _ if CSRs::mie as u32 == v => 2,
-            _ if CSRs::mcause as u32 == v => 3,
-            _ if CSRs::mtvec as u32 == v => 4,
-            _ if CSRs::mtval as u32 == v => 5,
-            _ if CSRs::mepc as u32 == v => 6,
-            _ if CSRs::mscratch as u32 == v => 7,
-            _ => return Err(Exception::IllegalInstruction),
+    /// Resolves the views of other CSRs, returns the address of the CSR holding the value and
+    /// the bits of it that are visible
+    fn resolve_csr(&self, addr: u32) -> Result<(usize, u32), Exception> {
+        let mideleg = self.csr[CSRs::mideleg as usize].value;
+        let (addr, mask) = match addr {
+            _ if CSRs::sstatus as u32 == addr => (CSRs::mstatus as u32, SSTATUS_MASK),
+            _ if CSRs::sie as u32 == addr => (CSRs::mie as u32, mideleg),
+            _ if CSRs::sip as u32 == addr => (CSRs::mip as u32, mideleg),
+            // time is kept up to date on its own, as there's no mtime CSR
+            _ if is_counter_shadow(addr) && addr & 0x1f != 1 => (addr - COUNTER_SHADOW, u32::MAX),
+            _ => (addr, u32::MAX),
         };
-        Ok(m)
+        match self.csr.get(addr as usize) {
+            Some(csr) if csr.implemented => Ok((addr as usize, mask)),
+            _ => Err(Exception::IllegalInstruction),
+        }
     }
 
     pub fn get_csr(&self, addr: u32) -> Result<u32, Exception> {
-        let idx = Self::csr_idx_map(addr)?;
-        Ok(self.csr[idx])
+        if Pmp::is_pmp_csr(addr) {
+            return Ok(self.pmp.get_csr(addr));
+        }
+        if Triggers::is_trigger_csr(addr) {
+            return Ok(self.triggers.get_csr(addr));
+        }
+        let (idx, mask) = self.resolve_csr(addr)?;
+        let mut value = self.csr[idx].value;
+        if idx as u32 == CSRs::mip as u32 {
+            value |= self.interrupt_lines;
+        }
+        let value = value & mask;
+        // Bit 1 of the exception pcs reads as zero when the instructions are 32-bit aligned
+        if (idx as u32 == CSRs::mepc as u32 || idx as u32 == CSRs::sepc as u32)
+            && self.ialign() == 4
+        {
+            return Ok(value & !0b10);
+        }
+        Ok(value)
+    }
+
+    /// The alignment of instructions in bytes, 2 while the C extension is enabled and 4 otherwise
+    pub fn ialign(&self) -> u32 {
+        if self.csr[CSRs::misa as usize].value & MISA_C != 0 {
+            2
+        } else {
+            4
+        }
     }
 
+    /// Sets the value of a CSR on behalf of the emulator, only views are masked
     pub fn set_csr(&mut self, addr: u32, v: u32) -> Result<(), Exception> {
-        let idx = Self::csr_idx_map(addr)?;
-        self.csr[idx] = v;
+        if Pmp::is_pmp_csr(addr) {
+            self.pmp.set_csr(addr, v);
+            return Ok(());
+        }
+        if Triggers::is_trigger_csr(addr) {
+            self.triggers.set_csr(addr, v, true);
+            return Ok(());
+        }
+        let (idx, mask) = self.resolve_csr(addr)?;
+        let csr = &mut self.csr[idx];
+        csr.value = (csr.value & !mask) | (v & mask);
+        Ok(())
+    }
+
+    /// The CSR address encodes the lowest privilege level allowed to access it in bits 9:8
+    fn check_csr_privilege(&self, addr: u32) -> Result<(), Exception> {
+        if (self.privilege as u32) < (addr >> 8) & 0b11 {
+            return Err(Exception::IllegalInstruction);
+        }
+        // 0x7b0-0x7bf are Debug mode CSRs
+        if addr & !0xf == 0x7b0 && !self.debug_mode {
+            return Err(Exception::IllegalInstruction);
+        }
+        // mstatus.TVM traps S-mode accesses to satp
+        let mstatus = self.get_csr(CSRs::mstatus as u32)?;
+        if addr == CSRs::satp as u32
+            && self.privilege == Privilege::Supervisor
+            && mstatus & MSTATUS_TVM != 0
+        {
+            return Err(Exception::IllegalInstruction);
+        }
+        // the unprivileged counters need to be enabled by mcounteren, and by scounteren in U-mode
+        if is_counter_shadow(addr) {
+            let bit = 1 << (addr & 0x1f);
+            let mcounteren = self.get_csr(CSRs::mcounteren as u32)?;
+            let scounteren = self.get_csr(CSRs::scounteren as u32)?;
+            if (self.privilege < Privilege::Machine && mcounteren & bit == 0)
+                || (self.privilege == Privilege::User && scounteren & bit == 0)
+            {
+                return Err(Exception::IllegalInstruction);
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads a CSR on behalf of a CSR instruction, checking the current privilege level
+    pub fn read_csr(&self, addr: u32) -> Result<u32, Exception> {
+        self.check_csr_privilege(addr)?;
+        self.get_csr(addr)
+    }
+
+    /// Writes a CSR on behalf of a CSR instruction, checking the current privilege level.
+    /// Read-only CSRs trap and WARL fields only take legal values.
+    pub fn write_csr(&mut self, addr: u32, v: u32) -> Result<(), Exception> {
+        self.check_csr_privilege(addr)?;
+        self.debugger_write_csr(addr, v)
+    }
+
+    /// Writes a CSR on behalf of a debugger, like [`CPU::write_csr`] at any privilege level
+    pub fn debugger_write_csr(&mut self, addr: u32, v: u32) -> Result<(), Exception> {
+        // CSRs with the address bits 11:10 set are read-only
+        if addr >> 10 == 0b11 {
+            return Err(Exception::IllegalInstruction);
+        }
+        if Pmp::is_pmp_csr(addr) {
+            self.pmp.set_csr(addr, v);
+            return Ok(());
+        }
+        if Triggers::is_trigger_csr(addr) {
+            self.triggers.set_csr(addr, v, self.debug_mode);
+            return Ok(());
+        }
+        let (idx, mut mask) = self.resolve_csr(addr)?;
+        // Only the software interrupt is writable through sip, the others are set by devices
+        if addr == CSRs::sip as u32 {
+            mask &= 1 << Interrupt::SSoftInterrupt as u32;
+        }
+        let csr = &mut self.csr[idx];
+        let mask = mask & csr.write_mask;
+        let mut v = (csr.value & !mask) | (v & mask);
+
+        match idx as u32 {
+            // Only the direct and vectored modes are supported, reserved modes are ignored
+            a if (a == CSRs::mtvec as u32 || a == CSRs::stvec as u32)
+                && v & TVEC_MODE > TVEC_VECTORED =>
+            {
+                v = (v & !TVEC_MODE) | (csr.value & TVEC_MODE);
+            }
+            // MPP can't hold the reserved privilege level 2
+            a if a == CSRs::mstatus as u32 && (v & MSTATUS_MPP) >> MSTATUS_MPP_SHIFT == 2 => {
+                v = (v & !MSTATUS_MPP) | (csr.value & MSTATUS_MPP);
+            }
+            // dcsr.prv can't hold the reserved privilege level 2
+            a if a == CSRs::dcsr as u32 && v & DCSR_PRV == 2 => {
+                v = (v & !DCSR_PRV) | (csr.value & DCSR_PRV);
+            }
+            // Disabling C is ignored when the next instruction isn't 32-bit aligned
+            a if a == CSRs::misa as u32
+                && v & MISA_C == 0
+                && self.pc.wrapping_add(4) & 0b10 != 0 =>
+            {
+                v |= MISA_C;
+            }
+            // Unsupported events select no event
+            a if (MHPMEVENT3..MHPMEVENT3 + HPM_COUNTERS).contains(&a) && v > HPM_EVENTS => v = 0,
+            _ => {}
+        }
+        csr.value = v;
+        if is_counter(idx as u32) {
+            self.counters_written |= 1 << (idx & 0x1f);
+        }
         Ok(())
     }
 
@@ -83,30 +570,262 @@ impl CPU {
         }
     }
 
-    /// Checks the mie and mip CSRs, if there are pending interrupts. Map them to a value of the
-    /// [`Interrupt`] enum
-    pub fn get_interrupt(&mut self) -> Option<Interrupt> {
+    /// Halts the hart in Debug mode, saving the pc in dpc and the privilege level in dcsr
+    pub fn enter_debug_mode(&mut self, cause: DebugCause) {
+        let dcsr = self.csr[CSRs::dcsr as usize].value & !(DCSR_CAUSE | DCSR_PRV);
+        self.csr[CSRs::dcsr as usize].value =
+            dcsr | (cause as u32) << DCSR_CAUSE_SHIFT | self.privilege as u32;
+        self.csr[CSRs::dpc as usize].value = self.pc;
+        self.privilege = Privilege::Machine;
+        self.debug_mode = true;
+        self.wfi = false;
+    }
+
+    /// Resumes execution at dpc with the privilege level in dcsr, as done by DRET
+    pub fn leave_debug_mode(&mut self) {
+        let dcsr = self.csr[CSRs::dcsr as usize].value;
+        self.pc = self.csr[CSRs::dpc as usize].value;
+        self.privilege = Privilege::from_bits(dcsr & DCSR_PRV);
+        if self.privilege < Privilege::Machine {
+            self.csr[CSRs::mstatus as usize].value &= !MSTATUS_MPRV;
+        }
+        self.debug_mode = false;
+    }
+
+    /// True while single stepping, the hart halts again after the next instruction
+    pub fn stepping(&self) -> bool {
+        !self.debug_mode && self.csr[CSRs::dcsr as usize].value & DCSR_STEP != 0
+    }
+
+    /// Returns true if EBREAK enters Debug mode at the current privilege level instead of
+    /// raising a breakpoint exception
+    pub fn ebreak_enters_debug_mode(&self) -> bool {
+        let dcsr = self.csr[CSRs::dcsr as usize].value;
+        let bit = match self.privilege {
+            Privilege::Machine => DCSR_EBREAKM,
+            Privilege::Supervisor => DCSR_EBREAKS,
+            Privilege::User => DCSR_EBREAKU,
+        };
+        dcsr & bit != 0
+    }
+
+    /// Checks the mie and mip CSRs, if there are pending interrupts that can be taken at the
+    /// current privilege level, map the one with the highest priority to a value of the
+    /// [`Interrupt`] enum.
+    ///
+    /// Interrupts delegated through mideleg are handled in S-mode, so they are never taken while
+    /// running in M-mode. Interrupts handled in a more privileged mode than the current one are
+    /// always enabled. Interrupts are disabled in Debug mode and while single stepping, unless
+    /// dcsr.stepie is set.
+    pub fn get_interrupt(&self) -> Option<Interrupt> {
         use Interrupt::*;
+        let dcsr = self.csr[CSRs::dcsr as usize].value;
+        if self.debug_mode || (self.stepping() && dcsr & DCSR_STEPIE == 0) {
+            return None;
+        }
+        let mstatus = self.get_csr(CSRs::mstatus as u32).unwrap();
+        let mideleg = self.get_csr(CSRs::mideleg as u32).unwrap();
         let mie = self.get_csr(CSRs::mie as u32).unwrap();
-        let mie_msi = (mie & (1 << MSoftInterrupt as u32)) != 0;
-        let mie_mti = mie & (1 << MTimerInterrupt as u32) != 0;
-        let mie_mei = mie & (1 << MExternalInterrupt as u32) != 0;
-
         let mip = self.get_csr(CSRs::mip as u32).unwrap();
-        let mip_msi = mip & (1 << MSoftInterrupt as u32) != 0;
-        let mip_mti = mip & (1 << MTimerInterrupt as u32) != 0;
-        let mip_mei = mip & (1 << MExternalInterrupt as u32) != 0;
-
-        match mip {
-            // TODO: Handle interrupts for other privilege modes
-            _v if mie_msi && mip_msi => {
-                self.set_csr(CSRs::mip as u32, mip & !(1 << MSoftInterrupt as u32))
-                    .unwrap();
-                Some(MSoftInterrupt)
-            }
-            _v if mie_mti && mip_mti => Some(MTimerInterrupt),
-            _v if mie_mei && mip_mei => Some(MExternalInterrupt),
-            _ => None,
-        }
+
+        let m_enabled = self.privilege < Privilege::Machine || mstatus & MSTATUS_MIE != 0;
+        let s_enabled = self.privilege < Privilege::Supervisor
+            || (self.privilege == Privilege::Supervisor && mstatus & MSTATUS_SIE != 0);
+
+        [
+            MExternalInterrupt,
+            MSoftInterrupt,
+            MTimerInterrupt,
+            SExternalInterrupt,
+            SSoftInterrupt,
+            STimerInterrupt,
+        ]
+        .into_iter()
+        .find(|i| {
+            let bit = 1 << *i as u32;
+            let enabled = if mideleg & bit != 0 {
+                s_enabled
+            } else {
+                m_enabled
+            };
+            mie & mip & bit != 0 && enabled
+        })
+    }
+}
+
+/// mcycle, minstret, mhpmcounter3-31 and their high halves
+fn is_counter(addr: u32) -> bool {
+    (0xb00..0xb20).contains(&addr) || (0xb80..0xba0).contains(&addr)
+}
+
+/// cycle, time, instret, hpmcounter3-31 and their high halves
+fn is_counter_shadow(addr: u32) -> bool {
+    is_counter(addr.wrapping_sub(COUNTER_SHADOW))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn machine_information() {
+        let cpu = CPU::new();
+        assert_eq!(cpu.get_csr(CSRs::misa as u32), Ok(MISA));
+        assert_eq!(cpu.read_csr(CSRs::mhartid as u32), Ok(0));
+        assert_eq!(cpu.read_csr(CSRs::mconfigptr as u32), Ok(0));
+        assert_eq!(cpu.read_csr(0x7c0), Err(Exception::IllegalInstruction));
+    }
+
+    #[test]
+    fn warl_fields() {
+        let mut cpu = CPU::new();
+        cpu.pc = 0x102;
+        cpu.write_csr(CSRs::misa as u32, 0).unwrap();
+        assert_eq!(
+            cpu.get_csr(CSRs::misa as u32),
+            Ok(MISA),
+            "C can't be disabled with the next instruction misaligned"
+        );
+        cpu.pc = 0x100;
+        cpu.write_csr(CSRs::misa as u32, 0).unwrap();
+        assert_eq!(cpu.get_csr(CSRs::misa as u32), Ok(MISA & !MISA_C));
+        cpu.set_csr(CSRs::mepc as u32, 0x106).unwrap();
+        assert_eq!(cpu.get_csr(CSRs::mepc as u32), Ok(0x104));
+        cpu.write_csr(CSRs::misa as u32, MISA).unwrap();
+        assert_eq!(cpu.get_csr(CSRs::mepc as u32), Ok(0x106));
+        cpu.write_csr(CSRs::mstatush as u32, u32::MAX).unwrap();
+        assert_eq!(cpu.get_csr(CSRs::mstatush as u32), Ok(0));
+        cpu.write_csr(CSRs::mcountinhibit as u32, u32::MAX).unwrap();
+        assert_eq!(cpu.get_csr(CSRs::mcountinhibit as u32), Ok(!0b10));
+        cpu.write_csr(CSRs::mepc as u32, 0x103).unwrap();
+        assert_eq!(cpu.get_csr(CSRs::mepc as u32), Ok(0x102));
+
+        cpu.write_csr(CSRs::mstatus as u32, MSTATUS_MPP).unwrap();
+        cpu.write_csr(CSRs::mstatus as u32, 2 << MSTATUS_MPP_SHIFT)
+            .unwrap();
+        assert_eq!(
+            cpu.get_csr(CSRs::mstatus as u32),
+            Ok(MSTATUS_MPP),
+            "mpp keeps its value on writes of the reserved level"
+        );
+        cpu.write_csr(CSRs::mstatus as u32, u32::MAX).unwrap();
+        assert_eq!(cpu.get_csr(CSRs::mstatus as u32), Ok(MSTATUS_MASK));
+
+        // M-mode can write the S-mode interrupt pending bits, devices set the others
+        cpu.write_csr(CSRs::mip as u32, u32::MAX).unwrap();
+        assert_eq!(cpu.get_csr(CSRs::mip as u32), Ok(SUPERVISOR_INTERRUPTS));
+    }
+
+    #[test]
+    fn read_only_csrs() {
+        let mut cpu = CPU::new();
+        assert_eq!(
+            cpu.write_csr(CSRs::mhartid as u32, 0),
+            Err(Exception::IllegalInstruction)
+        );
+        cpu.privilege = Privilege::Supervisor;
+        assert_eq!(
+            cpu.read_csr(CSRs::mstatus as u32),
+            Err(Exception::IllegalInstruction)
+        );
+        assert_eq!(cpu.read_csr(CSRs::sstatus as u32), Ok(0));
+    }
+
+    #[test]
+    fn counters() {
+        let mut cpu = CPU::new();
+        cpu.write_csr(CSRs::mcycle as u32, u32::MAX).unwrap();
+        cpu.retire(2, true);
+        assert_eq!(
+            cpu.get_csr(CSRs::mcycle as u32),
+            Ok(u32::MAX),
+            "written this tick"
+        );
+        cpu.retire(2, true);
+        assert_eq!(cpu.read_csr(CSRs::cycle as u32), Ok(1));
+        assert_eq!(cpu.read_csr(CSRs::cycleh as u32), Ok(1));
+        assert_eq!(cpu.read_csr(CSRs::instret as u32), Ok(2));
+        cpu.set_time(5 << 32 | 7);
+        assert_eq!(cpu.read_csr(CSRs::time as u32), Ok(7));
+        assert_eq!(cpu.read_csr(CSRs::timeh as u32), Ok(5));
+
+        cpu.write_csr(CSRs::mcountinhibit as u32, COUNTER_IR)
+            .unwrap();
+        cpu.retire(1, true);
+        assert_eq!(cpu.get_csr(CSRs::minstret as u32), Ok(2));
+        assert_eq!(cpu.get_csr(CSRs::mcycle as u32), Ok(2));
+
+        // hpmcounters count the selected event, unsupported events count nothing
+        cpu.write_csr(MHPMEVENT3, HpmEvent::Load as u32).unwrap();
+        cpu.write_csr(MHPMEVENT3 + 1, 0xff).unwrap();
+        assert_eq!(cpu.get_csr(MHPMEVENT3 + 1), Ok(0));
+        cpu.count_event(HpmEvent::Load);
+        cpu.count_event(HpmEvent::Store);
+        assert_eq!(cpu.get_csr(MHPMCOUNTER3), Ok(1));
+
+        cpu.set_hpm_counters(1);
+        cpu.write_csr(MHPMCOUNTER3 + 1, 1).unwrap();
+        assert_eq!(cpu.get_csr(MHPMCOUNTER3 + 1), Ok(0), "not implemented");
+        assert_eq!(cpu.get_csr(MHPMCOUNTER3), Ok(1));
+    }
+
+    #[test]
+    fn counter_enable() {
+        let mut cpu = CPU::new();
+        cpu.privilege = Privilege::Supervisor;
+        assert_eq!(
+            cpu.read_csr(CSRs::cycle as u32),
+            Err(Exception::IllegalInstruction)
+        );
+        cpu.set_csr(CSRs::mcounteren as u32, COUNTER_CY).unwrap();
+        assert_eq!(cpu.read_csr(CSRs::cycle as u32), Ok(0));
+        assert_eq!(
+            cpu.write_csr(CSRs::cycle as u32, 0),
+            Err(Exception::IllegalInstruction)
+        );
+
+        cpu.privilege = Privilege::User;
+        assert_eq!(
+            cpu.read_csr(CSRs::cycle as u32),
+            Err(Exception::IllegalInstruction)
+        );
+        cpu.set_csr(CSRs::scounteren as u32, COUNTER_CY).unwrap();
+        assert_eq!(cpu.read_csr(CSRs::cycle as u32), Ok(0));
+        assert_eq!(
+            cpu.read_csr(CSRs::instret as u32),
+            Err(Exception::IllegalInstruction)
+        );
+    }
+
+    #[test]
+    fn debug_mode() {
+        let mut cpu = CPU::new();
+        assert_eq!(
+            cpu.read_csr(CSRs::dpc as u32),
+            Err(Exception::IllegalInstruction),
+            "debug CSRs are only accessible in Debug mode"
+        );
+
+        cpu.pc = 0x40;
+        cpu.privilege = Privilege::User;
+        cpu.enter_debug_mode(DebugCause::HaltRequest);
+        assert_eq!(cpu.privilege, Privilege::Machine);
+        assert_eq!(cpu.read_csr(CSRs::dpc as u32), Ok(0x40));
+        let dcsr = cpu.read_csr(CSRs::dcsr as u32).unwrap();
+        assert_eq!((dcsr & DCSR_CAUSE) >> DCSR_CAUSE_SHIFT, 3);
+        assert_eq!(dcsr & DCSR_PRV, Privilege::User as u32);
+
+        // the debugger resumes in S-mode at another address, the reserved level is ignored
+        cpu.write_csr(CSRs::dpc as u32, 0x81).unwrap();
+        cpu.write_csr(CSRs::dcsr as u32, dcsr & !DCSR_PRV | 2)
+            .unwrap();
+        cpu.write_csr(CSRs::dcsr as u32, dcsr & !DCSR_PRV | 1)
+            .unwrap();
+        cpu.write_csr(CSRs::dscratch0 as u32, 0x1234).unwrap();
+        assert_eq!(cpu.read_csr(CSRs::dscratch0 as u32), Ok(0x1234));
+        cpu.leave_debug_mode();
+        assert_eq!(cpu.pc, 0x80);
+        assert_eq!(cpu.privilege, Privilege::Supervisor);
+        assert!(!cpu.debug_mode);
     }
 }
@@ -0,0 +1,489 @@
+//! The Debug Module of the RISC-V External Debug Support spec (version 0.13), accessed through
+//! its DMI registers.
+//!
+//! It controls a single hart: halt and resume requests, hart reset through ndmreset, abstract
+//! commands to access the registers of the halted hart and system bus access to the memory.
+//! There is no program buffer, so abstract commands can't execute instructions.
+use crate::mcu::MCU;
+use crate::memory::Memory;
+
+// DMI register addresses
+const DATA0: u32 = 0x04;
+const DMCONTROL: u32 = 0x10;
+const DMSTATUS: u32 = 0x11;
+const ABSTRACTCS: u32 = 0x16;
+const COMMAND: u32 = 0x17;
+const ABSTRACTAUTO: u32 = 0x18;
+const SBCS: u32 = 0x38;
+const SBADDRESS0: u32 = 0x39;
+const SBDATA0: u32 = 0x3c;
+const HALTSUM0: u32 = 0x40;
+
+// dmcontrol fields
+const DMCONTROL_HALTREQ: u32 = 1 << 31;
+const DMCONTROL_RESUMEREQ: u32 = 1 << 30;
+const DMCONTROL_ACKHAVERESET: u32 = 1 << 28;
+const DMCONTROL_NDMRESET: u32 = 1 << 1;
+const DMCONTROL_DMACTIVE: u32 = 1 << 0;
+
+// dmstatus fields
+const DMSTATUS_VERSION_013: u32 = 2;
+const DMSTATUS_AUTHENTICATED: u32 = 1 << 7;
+const DMSTATUS_ALLHALTED: u32 = 0b11 << 8;
+const DMSTATUS_ALLRUNNING: u32 = 0b11 << 10;
+const DMSTATUS_ALLRESUMEACK: u32 = 0b11 << 16;
+const DMSTATUS_ALLHAVERESET: u32 = 0b11 << 18;
+
+// abstractcs fields
+const DATACOUNT: u32 = 1;
+const ABSTRACTCS_CMDERR_SHIFT: u32 = 8;
+const ABSTRACTCS_CMDERR: u32 = 0b111 << ABSTRACTCS_CMDERR_SHIFT;
+
+// abstract command errors
+const CMDERR_NOT_SUPPORTED: u32 = 2;
+const CMDERR_EXCEPTION: u32 = 3;
+const CMDERR_HALT_RESUME: u32 = 4;
+
+// access register command fields
+const CMDTYPE_ACCESS_REGISTER: u32 = 0;
+const AARSIZE_32: u32 = 2;
+const AARPOSTINCREMENT: u32 = 1 << 19;
+const POSTEXEC: u32 = 1 << 18;
+const TRANSFER: u32 = 1 << 17;
+const WRITE: u32 = 1 << 16;
+// register numbers of the GPRs, CSRs take their own address
+const REGNO_GPR0: u32 = 0x1000;
+const GPRS: u32 = 32;
+
+// sbcs fields
+const SBCS_SBVERSION: u32 = 1 << 29;
+const SBCS_SBBUSYERROR: u32 = 1 << 22;
+const SBCS_SBREADONADDR: u32 = 1 << 20;
+const SBCS_SBACCESS_SHIFT: u32 = 17;
+const SBCS_SBACCESS: u32 = 0b111 << SBCS_SBACCESS_SHIFT;
+const SBCS_SBAUTOINCREMENT: u32 = 1 << 16;
+const SBCS_SBREADONDATA: u32 = 1 << 15;
+const SBCS_SBERROR_SHIFT: u32 = 12;
+const SBCS_SBERROR: u32 = 0b111 << SBCS_SBERROR_SHIFT;
+// 32-bit addresses and 8, 16 and 32-bit accesses
+const SBCS_SBASIZE: u32 = 32 << 5;
+const SBCS_SBACCESS_SUPPORTED: u32 = 0b111;
+const SBCS_WRITE_MASK: u32 =
+    SBCS_SBREADONADDR | SBCS_SBACCESS | SBCS_SBAUTOINCREMENT | SBCS_SBREADONDATA;
+
+// system bus errors
+const SBERROR_BAD_ADDRESS: u32 = 2;
+const SBERROR_ALIGNMENT: u32 = 3;
+const SBERROR_SIZE: u32 = 4;
+
+pub struct DebugModule {
+    // pc the hart restarts from when reset through ndmreset
+    reset_pc: u32,
+    dmcontrol: u32,
+    havereset: bool,
+    resumeack: bool,
+    data0: u32,
+    cmderr: u32,
+    // last command written, executed again by abstractauto
+    command: u32,
+    abstractauto: u32,
+    sbcs: u32,
+    sbaddress: u32,
+    sbdata: u32,
+}
+
+impl DebugModule {
+    pub fn new(reset_pc: u32) -> Self {
+        Self {
+            reset_pc,
+            dmcontrol: 0,
+            havereset: true,
+            resumeack: false,
+            data0: 0,
+            cmderr: 0,
+            command: 0,
+            abstractauto: 0,
+            sbcs: 0,
+            sbaddress: 0,
+            sbdata: 0,
+        }
+    }
+
+    /// Reads a DMI register, reads of data0 and sbdata0 may trigger an access
+    pub fn read(&mut self, mcu: &mut MCU, addr: u32) -> u32 {
+        if self.dmcontrol & DMCONTROL_DMACTIVE == 0 && addr != DMCONTROL {
+            return 0;
+        }
+        match addr {
+            DATA0 => {
+                let v = self.data0;
+                if self.abstractauto & 1 != 0 {
+                    self.execute(mcu, self.command);
+                }
+                v
+            }
+            DMCONTROL => self.dmcontrol,
+            DMSTATUS => self.status(mcu),
+            ABSTRACTCS => DATACOUNT | self.cmderr << ABSTRACTCS_CMDERR_SHIFT,
+            COMMAND => 0,
+            ABSTRACTAUTO => self.abstractauto,
+            SBCS => SBCS_SBVERSION | self.sbcs | SBCS_SBASIZE | SBCS_SBACCESS_SUPPORTED,
+            SBADDRESS0 => self.sbaddress,
+            SBDATA0 => {
+                let v = self.sbdata;
+                if self.sbcs & SBCS_SBREADONDATA != 0 {
+                    self.system_bus_read(mcu);
+                }
+                v
+            }
+            HALTSUM0 => mcu.cpu.debug_mode as u32,
+            // hartinfo is zero as well, there are no data registers shared with the hart
+            _ => 0,
+        }
+    }
+
+    /// Writes a DMI register
+    pub fn write(&mut self, mcu: &mut MCU, addr: u32, value: u32) {
+        if self.dmcontrol & DMCONTROL_DMACTIVE == 0 && addr != DMCONTROL {
+            return;
+        }
+        match addr {
+            DATA0 => {
+                self.data0 = value;
+                if self.abstractauto & 1 != 0 {
+                    self.execute(mcu, self.command);
+                }
+            }
+            DMCONTROL => self.write_dmcontrol(mcu, value),
+            ABSTRACTCS => self.cmderr &= !((value & ABSTRACTCS_CMDERR) >> ABSTRACTCS_CMDERR_SHIFT),
+            COMMAND => {
+                self.command = value;
+                self.execute(mcu, value);
+            }
+            // only data0 can trigger a command
+            ABSTRACTAUTO => self.abstractauto = value & 1,
+            SBCS => {
+                // sberror and sbbusyerror are cleared by writing 1
+                let errors = self.sbcs & !(value & (SBCS_SBERROR | SBCS_SBBUSYERROR));
+                self.sbcs =
+                    (errors & (SBCS_SBERROR | SBCS_SBBUSYERROR)) | (value & SBCS_WRITE_MASK);
+            }
+            SBADDRESS0 => {
+                self.sbaddress = value;
+                if self.sbcs & SBCS_SBREADONADDR != 0 {
+                    self.system_bus_read(mcu);
+                }
+            }
+            SBDATA0 => {
+                self.sbdata = value;
+                self.system_bus_write(mcu);
+            }
+            _ => {}
+        }
+    }
+
+    fn write_dmcontrol(&mut self, mcu: &mut MCU, value: u32) {
+        if value & DMCONTROL_DMACTIVE == 0 {
+            // inactive, the module goes back to its reset state
+            *self = Self::new(self.reset_pc);
+            mcu.cpu.halt_request = false;
+            return;
+        }
+        // there's a single hart, hartsel and hasel are hardwired to zero
+        let reset = value & DMCONTROL_NDMRESET != 0 && self.dmcontrol & DMCONTROL_NDMRESET == 0;
+        self.dmcontrol = value & (DMCONTROL_NDMRESET | DMCONTROL_DMACTIVE);
+        if reset {
+            mcu.cpu.reset(self.reset_pc);
+            mcu.tlb.flush(None, None);
+            self.havereset = true;
+        }
+        if value & DMCONTROL_ACKHAVERESET != 0 {
+            self.havereset = false;
+        }
+        mcu.cpu.halt_request = value & DMCONTROL_HALTREQ != 0;
+        // resume requests are ignored while a halt is requested
+        if value & DMCONTROL_RESUMEREQ != 0 && value & DMCONTROL_HALTREQ == 0 {
+            self.resumeack = false;
+            if mcu.cpu.debug_mode {
+                mcu.cpu.leave_debug_mode();
+                self.resumeack = true;
+            }
+        }
+    }
+
+    fn status(&self, mcu: &MCU) -> u32 {
+        let mut status = DMSTATUS_VERSION_013 | DMSTATUS_AUTHENTICATED;
+        status |= if mcu.cpu.debug_mode {
+            DMSTATUS_ALLHALTED
+        } else {
+            DMSTATUS_ALLRUNNING
+        };
+        if self.resumeack {
+            status |= DMSTATUS_ALLRESUMEACK;
+        }
+        if self.havereset {
+            status |= DMSTATUS_ALLHAVERESET;
+        }
+        status
+    }
+
+    /// Executes an abstract command, only register accesses are supported. Commands are
+    /// ignored until the previous error is cleared.
+    fn execute(&mut self, mcu: &mut MCU, command: u32) {
+        if self.cmderr != 0 {
+            return;
+        }
+        if let Err(err) = self.access_register(mcu, command) {
+            self.cmderr = err;
+        }
+    }
+
+    fn access_register(&mut self, mcu: &mut MCU, command: u32) -> Result<(), u32> {
+        if command >> 24 != CMDTYPE_ACCESS_REGISTER || command & POSTEXEC != 0 {
+            return Err(CMDERR_NOT_SUPPORTED);
+        }
+        if !mcu.cpu.debug_mode {
+            return Err(CMDERR_HALT_RESUME);
+        }
+        let regno = command & 0xffff;
+        if command & TRANSFER != 0 {
+            if (command >> 20) & 0b111 != AARSIZE_32 {
+                return Err(CMDERR_NOT_SUPPORTED);
+            }
+            let write = command & WRITE != 0;
+            match regno {
+                0..=0xfff if write => mcu
+                    .cpu
+                    .write_csr(regno, self.data0)
+                    .map_err(|_| CMDERR_EXCEPTION)?,
+                0..=0xfff => self.data0 = mcu.cpu.read_csr(regno).map_err(|_| CMDERR_EXCEPTION)?,
+                _ if (REGNO_GPR0..REGNO_GPR0 + GPRS).contains(&regno) => {
+                    if write {
+                        mcu.cpu.set_x(regno - REGNO_GPR0, self.data0);
+                    } else {
+                        self.data0 = mcu.cpu.get_x(regno - REGNO_GPR0);
+                    }
+                }
+                _ => return Err(CMDERR_EXCEPTION),
+            }
+        }
+        if command & AARPOSTINCREMENT != 0 {
+            self.command = (command & !0xffff) | ((regno + 1) & 0xffff);
+        }
+        Ok(())
+    }
+
+    /// The size in bytes of system bus accesses, if it's supported
+    fn system_bus_size(&self) -> Option<u32> {
+        let sbaccess = (self.sbcs & SBCS_SBACCESS) >> SBCS_SBACCESS_SHIFT;
+        (SBCS_SBACCESS_SUPPORTED & (1 << sbaccess) != 0).then(|| 1 << sbaccess)
+    }
+
+    /// Accesses are ignored while there's an error, and set sberror when they fail
+    fn system_bus_access(&mut self, access: impl FnOnce(&mut Self, u32) -> Result<(), u32>) {
+        if self.sbcs & (SBCS_SBERROR | SBCS_SBBUSYERROR) != 0 {
+            return;
+        }
+        let result = match self.system_bus_size() {
+            None => Err(SBERROR_SIZE),
+            Some(size) if !self.sbaddress.is_multiple_of(size) => Err(SBERROR_ALIGNMENT),
+            Some(size) => access(self, size),
+        };
+        match result {
+            Ok(()) => {
+                if self.sbcs & SBCS_SBAUTOINCREMENT != 0 {
+                    self.sbaddress = self.sbaddress.wrapping_add(self.system_bus_size().unwrap());
+                }
+            }
+            Err(err) => self.sbcs |= err << SBCS_SBERROR_SHIFT,
+        }
+    }
+
+    /// Reads sbdata0 from the physical address in sbaddress0
+    fn system_bus_read(&mut self, mcu: &mut MCU) {
+        self.system_bus_access(|dm, size| {
+            let v = match size {
+                1 => mcu.mmu.rb(dm.sbaddress).map(|v| v as u32),
+                2 => mcu.mmu.rhw(dm.sbaddress).map(|v| v as u32),
+                _ => mcu.mmu.rw(dm.sbaddress),
+            };
+            dm.sbdata = v.map_err(|_| SBERROR_BAD_ADDRESS)?;
+            Ok(())
+        });
+    }
+
+    /// Writes sbdata0 to the physical address in sbaddress0
+    fn system_bus_write(&mut self, mcu: &mut MCU) {
+        self.system_bus_access(|dm, size| {
+            let r = match size {
+                1 => mcu.mmu.wb(dm.sbaddress, dm.sbdata as u8),
+                2 => mcu.mmu.whw(dm.sbaddress, dm.sbdata as u16),
+                _ => mcu.mmu.ww(dm.sbaddress, dm.sbdata),
+            };
+            r.map_err(|_| SBERROR_BAD_ADDRESS)
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::{CSRs, DCSR_CAUSE, DCSR_CAUSE_SHIFT};
+    use crate::mcu::test_mcu;
+
+    fn mcu() -> MCU {
+        let mut mcu = test_mcu(0x100, vec![]);
+        // addi x1, x1, 1
+        for addr in (0..0x100).step_by(4) {
+            mcu.mmu.ww(addr, 0x00108093).unwrap();
+        }
+        mcu
+    }
+
+    fn halt(dm: &mut DebugModule, mcu: &mut MCU) {
+        dm.write(mcu, DMCONTROL, DMCONTROL_HALTREQ | DMCONTROL_DMACTIVE);
+        mcu.tick();
+        dm.write(mcu, DMCONTROL, DMCONTROL_DMACTIVE);
+    }
+
+    #[test]
+    fn halt_and_resume() {
+        let mut mcu = mcu();
+        let mut dm = DebugModule::new(0);
+        assert_eq!(dm.read(&mut mcu, DMSTATUS), 0, "inactive");
+        dm.write(
+            &mut mcu,
+            DMCONTROL,
+            DMCONTROL_DMACTIVE | DMCONTROL_ACKHAVERESET,
+        );
+        assert_eq!(
+            dm.read(&mut mcu, DMSTATUS) & (DMSTATUS_ALLRUNNING | DMSTATUS_ALLHAVERESET),
+            DMSTATUS_ALLRUNNING
+        );
+
+        mcu.tick();
+        halt(&mut dm, &mut mcu);
+        assert!(mcu.cpu.debug_mode);
+        assert_eq!(
+            dm.read(&mut mcu, DMSTATUS) & DMSTATUS_ALLHALTED,
+            DMSTATUS_ALLHALTED
+        );
+        assert_eq!(dm.read(&mut mcu, HALTSUM0), 1);
+        mcu.tick();
+        assert_eq!(mcu.cpu.get_x(1), 1, "halted harts don't execute");
+
+        dm.write(
+            &mut mcu,
+            DMCONTROL,
+            DMCONTROL_RESUMEREQ | DMCONTROL_DMACTIVE,
+        );
+        assert!(!mcu.cpu.debug_mode);
+        assert_eq!(
+            dm.read(&mut mcu, DMSTATUS) & DMSTATUS_ALLRESUMEACK,
+            DMSTATUS_ALLRESUMEACK
+        );
+        mcu.tick();
+        assert_eq!(mcu.cpu.pc, 8);
+    }
+
+    #[test]
+    fn abstract_commands() {
+        let mut mcu = mcu();
+        let mut dm = DebugModule::new(0);
+        dm.write(&mut mcu, DMCONTROL, DMCONTROL_DMACTIVE);
+        let read = |regno: u32| AARSIZE_32 << 20 | TRANSFER | regno;
+
+        dm.write(&mut mcu, COMMAND, read(CSRs::dpc as u32));
+        assert_eq!(
+            dm.read(&mut mcu, ABSTRACTCS) & ABSTRACTCS_CMDERR,
+            CMDERR_HALT_RESUME << ABSTRACTCS_CMDERR_SHIFT
+        );
+        dm.write(&mut mcu, ABSTRACTCS, ABSTRACTCS_CMDERR);
+
+        mcu.tick();
+        halt(&mut dm, &mut mcu);
+        dm.write(&mut mcu, COMMAND, read(CSRs::dpc as u32));
+        assert_eq!(dm.read(&mut mcu, ABSTRACTCS), DATACOUNT);
+        assert_eq!(dm.read(&mut mcu, DATA0), 4);
+        dm.write(&mut mcu, COMMAND, read(CSRs::dcsr as u32));
+        let dcsr = dm.read(&mut mcu, DATA0);
+        assert_eq!((dcsr & DCSR_CAUSE) >> DCSR_CAUSE_SHIFT, 3, "halt request");
+
+        // x1-x2 through abstractauto and aarpostincrement
+        dm.write(&mut mcu, DATA0, 0x55);
+        dm.write(
+            &mut mcu,
+            COMMAND,
+            read(REGNO_GPR0 + 1) | WRITE | AARPOSTINCREMENT,
+        );
+        dm.write(&mut mcu, ABSTRACTAUTO, 1);
+        dm.write(&mut mcu, DATA0, 0x66);
+        dm.write(&mut mcu, ABSTRACTAUTO, 0);
+        assert_eq!(mcu.cpu.get_x(1), 0x55);
+        assert_eq!(mcu.cpu.get_x(2), 0x66);
+
+        dm.write(&mut mcu, COMMAND, read(0x7c0));
+        assert_eq!(
+            dm.read(&mut mcu, ABSTRACTCS) & ABSTRACTCS_CMDERR,
+            CMDERR_EXCEPTION << ABSTRACTCS_CMDERR_SHIFT
+        );
+    }
+
+    #[test]
+    fn system_bus() {
+        let mut mcu = mcu();
+        let mut dm = DebugModule::new(0);
+        dm.write(&mut mcu, DMCONTROL, DMCONTROL_DMACTIVE);
+        dm.write(
+            &mut mcu,
+            SBCS,
+            2 << SBCS_SBACCESS_SHIFT | SBCS_SBAUTOINCREMENT,
+        );
+        dm.write(&mut mcu, SBADDRESS0, 0x10);
+        dm.write(&mut mcu, SBDATA0, 0xdead_beef);
+        dm.write(&mut mcu, SBDATA0, 0x1234_5678);
+        assert_eq!(mcu.mmu.rw(0x14), Ok(0x1234_5678));
+
+        dm.write(
+            &mut mcu,
+            SBCS,
+            2 << SBCS_SBACCESS_SHIFT | SBCS_SBREADONADDR | SBCS_SBREADONDATA,
+        );
+        dm.write(&mut mcu, SBADDRESS0, 0x10);
+        assert_eq!(dm.read(&mut mcu, SBDATA0), 0xdead_beef);
+
+        dm.write(&mut mcu, SBADDRESS0, 0x1000);
+        assert_eq!(
+            dm.read(&mut mcu, SBCS) & SBCS_SBERROR,
+            SBERROR_BAD_ADDRESS << SBCS_SBERROR_SHIFT
+        );
+        dm.write(&mut mcu, SBCS, SBCS_SBERROR);
+        assert_eq!(dm.read(&mut mcu, SBCS) & SBCS_SBERROR, 0);
+    }
+
+    #[test]
+    fn ndmreset() {
+        let mut mcu = mcu();
+        let mut dm = DebugModule::new(0x10);
+        dm.write(
+            &mut mcu,
+            DMCONTROL,
+            DMCONTROL_DMACTIVE | DMCONTROL_ACKHAVERESET,
+        );
+        mcu.tick();
+        dm.write(
+            &mut mcu,
+            DMCONTROL,
+            DMCONTROL_DMACTIVE | DMCONTROL_NDMRESET | DMCONTROL_HALTREQ,
+        );
+        dm.write(&mut mcu, DMCONTROL, DMCONTROL_DMACTIVE | DMCONTROL_HALTREQ);
+        mcu.tick();
+        assert_eq!(mcu.cpu.get_x(1), 0);
+        assert!(mcu.cpu.debug_mode);
+        assert_eq!(mcu.cpu.get_csr(CSRs::dpc as u32), Ok(0x10));
+        assert_eq!(
+            dm.read(&mut mcu, DMSTATUS) & DMSTATUS_ALLHAVERESET,
+            DMSTATUS_ALLHAVERESET
+        );
+    }
+}
@@ -0,0 +1,256 @@
+//! A JTAG TAP with the RISC-V Debug Transport Module registers, giving access to the
+//! [`DebugModule`] through the DMI register.
+//!
+//! The IDCODE is the one of the HiFive1 E31 core, so stock OpenOCD configurations for it work.
+use super::dm::DebugModule;
+use crate::mcu::MCU;
+
+const IR_LENGTH: u32 = 5;
+const IDCODE_VALUE: u32 = 0x10e3_1913;
+
+// instructions
+const IR_IDCODE: u32 = 0x01;
+const IR_DTMCS: u32 = 0x10;
+const IR_DMI: u32 = 0x11;
+
+// dtmcs fields: version 0.13 and 7 DMI address bits
+const ABITS: u32 = 7;
+const DTMCS_VALUE: u32 = 1 | ABITS << 4;
+// dmi fields: op in bits 1:0, data in 33:2 and the address above
+const DMI_LENGTH: u32 = ABITS + 34;
+const DMI_OP_READ: u64 = 1;
+const DMI_OP_WRITE: u64 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum TapState {
+    TestLogicReset,
+    RunTestIdle,
+    SelectDrScan,
+    CaptureDr,
+    ShiftDr,
+    Exit1Dr,
+    PauseDr,
+    Exit2Dr,
+    UpdateDr,
+    SelectIrScan,
+    CaptureIr,
+    ShiftIr,
+    Exit1Ir,
+    PauseIr,
+    Exit2Ir,
+    UpdateIr,
+}
+
+impl TapState {
+    /// The state after a rising edge of TCK with the given TMS
+    fn next(self, tms: bool) -> Self {
+        use TapState::*;
+        let (low, high) = match self {
+            TestLogicReset => (RunTestIdle, TestLogicReset),
+            RunTestIdle => (RunTestIdle, SelectDrScan),
+            SelectDrScan => (CaptureDr, SelectIrScan),
+            CaptureDr => (ShiftDr, Exit1Dr),
+            ShiftDr => (ShiftDr, Exit1Dr),
+            Exit1Dr => (PauseDr, UpdateDr),
+            PauseDr => (PauseDr, Exit2Dr),
+            Exit2Dr => (ShiftDr, UpdateDr),
+            UpdateDr => (RunTestIdle, SelectDrScan),
+            SelectIrScan => (CaptureIr, TestLogicReset),
+            CaptureIr => (ShiftIr, Exit1Ir),
+            ShiftIr => (ShiftIr, Exit1Ir),
+            Exit1Ir => (PauseIr, UpdateIr),
+            PauseIr => (PauseIr, Exit2Ir),
+            Exit2Ir => (ShiftIr, UpdateIr),
+            UpdateIr => (RunTestIdle, SelectDrScan),
+        };
+        if tms {
+            high
+        } else {
+            low
+        }
+    }
+}
+
+pub struct JtagTap {
+    state: TapState,
+    tck: bool,
+    // instruction register and its shift register
+    ir: u32,
+    ir_shift: u32,
+    // data shift register of the selected instruction
+    dr: u64,
+    dr_length: u32,
+    // address, data and status of the last DMI operation, returned by the next capture
+    dmi_addr: u32,
+    dmi_data: u32,
+    pub dm: DebugModule,
+}
+
+impl JtagTap {
+    pub fn new(dm: DebugModule) -> Self {
+        Self {
+            state: TapState::TestLogicReset,
+            tck: false,
+            ir: IR_IDCODE,
+            ir_shift: 0,
+            dr: 0,
+            dr_length: 1,
+            dmi_addr: 0,
+            dmi_data: 0,
+            dm,
+        }
+    }
+
+    /// Asynchronous reset through TRST
+    pub fn reset(&mut self) {
+        self.state = TapState::TestLogicReset;
+        self.ir = IR_IDCODE;
+    }
+
+    /// The value of TDO, the bit at the end of the shift register while shifting
+    pub fn tdo(&self) -> bool {
+        match self.state {
+            TapState::ShiftDr => self.dr & 1 != 0,
+            TapState::ShiftIr => self.ir_shift & 1 != 0,
+            _ => false,
+        }
+    }
+
+    /// Drives the TCK, TMS and TDI inputs. On the rising edge of TCK the shift registers shift
+    /// TDI in and the state machine advances, running the capture and update actions of the
+    /// state it enters.
+    pub fn set_pins(&mut self, mcu: &mut MCU, tck: bool, tms: bool, tdi: bool) {
+        let rising = tck && !self.tck;
+        self.tck = tck;
+        if !rising {
+            return;
+        }
+        match self.state {
+            TapState::ShiftDr => {
+                self.dr = (self.dr >> 1) | (tdi as u64) << (self.dr_length - 1);
+            }
+            TapState::ShiftIr => {
+                self.ir_shift = (self.ir_shift >> 1) | (tdi as u32) << (IR_LENGTH - 1);
+            }
+            _ => {}
+        }
+        self.state = self.state.next(tms);
+        match self.state {
+            TapState::TestLogicReset => self.ir = IR_IDCODE,
+            TapState::CaptureDr => self.capture_dr(),
+            TapState::UpdateDr => self.update_dr(mcu),
+            // the two lowest bits captured in the IR must be 01
+            TapState::CaptureIr => self.ir_shift = 0b00001,
+            TapState::UpdateIr => self.ir = self.ir_shift,
+            _ => {}
+        }
+    }
+
+    fn capture_dr(&mut self) {
+        (self.dr, self.dr_length) = match self.ir {
+            IR_IDCODE => (IDCODE_VALUE as u64, 32),
+            IR_DTMCS => (DTMCS_VALUE as u64, 32),
+            // the status of the last operation is always a success
+            IR_DMI => (
+                (self.dmi_addr as u64) << 34 | (self.dmi_data as u64) << 2,
+                DMI_LENGTH,
+            ),
+            // BYPASS and every unsupported instruction
+            _ => (0, 1),
+        };
+    }
+
+    fn update_dr(&mut self, mcu: &mut MCU) {
+        // dmireset and dmihardreset have nothing to do, operations never fail
+        if self.ir != IR_DMI {
+            return;
+        }
+        let addr = ((self.dr >> 34) as u32) & ((1 << ABITS) - 1);
+        let data = (self.dr >> 2) as u32;
+        match self.dr & 0b11 {
+            DMI_OP_READ => {
+                self.dmi_addr = addr;
+                self.dmi_data = self.dm.read(mcu, addr);
+            }
+            DMI_OP_WRITE => {
+                self.dmi_addr = addr;
+                self.dm.write(mcu, addr, data);
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Clocks TMS and TDI in, returning the TDO values sampled before each rising edge like
+    /// OpenOCD's bitbang driver
+    pub(crate) fn clock(tap: &mut JtagTap, mcu: &mut MCU, bits: &[(bool, bool)]) -> Vec<bool> {
+        let mut tdo = Vec::new();
+        for &(tms, tdi) in bits {
+            tap.set_pins(mcu, false, tms, tdi);
+            tdo.push(tap.tdo());
+            tap.set_pins(mcu, true, tms, tdi);
+        }
+        tdo
+    }
+
+    /// Shifts `len` bits of `value` through IR or DR, starting and ending in Run-Test/Idle
+    pub(crate) fn scan(tap: &mut JtagTap, mcu: &mut MCU, ir: bool, value: u64, len: u32) -> u64 {
+        let mut bits = vec![(true, false)];
+        if ir {
+            bits.push((true, false));
+        }
+        // capture and enter shift
+        bits.extend([(false, false), (false, false)]);
+        bits.extend((0..len).map(|i| (i == len - 1, value >> i & 1 != 0)));
+        // update and back to idle
+        bits.extend([(true, false), (false, false)]);
+        let tdo = clock(tap, mcu, &bits);
+        let start = if ir { 4 } else { 3 };
+        tdo[start..start + len as usize]
+            .iter()
+            .enumerate()
+            .fold(0, |acc, (i, b)| acc | (*b as u64) << i)
+    }
+
+    /// Runs a DMI operation, returning the data captured by the following nop
+    pub(crate) fn dmi(tap: &mut JtagTap, mcu: &mut MCU, addr: u32, data: u32, op: u64) -> u32 {
+        scan(tap, mcu, true, IR_DMI as u64, IR_LENGTH);
+        let request = (addr as u64) << 34 | (data as u64) << 2 | op;
+        scan(tap, mcu, false, request, DMI_LENGTH);
+        (scan(tap, mcu, false, 0, DMI_LENGTH) >> 2) as u32
+    }
+
+    #[test]
+    fn idcode_and_dtmcs() {
+        let mut mcu = MCU::new();
+        let mut tap = JtagTap::new(DebugModule::new(0));
+        // five TMS high reach Test-Logic-Reset from any state, selecting IDCODE
+        clock(&mut tap, &mut mcu, &[(true, false); 5]);
+        clock(&mut tap, &mut mcu, &[(false, false)]);
+        assert_eq!(scan(&mut tap, &mut mcu, false, 0, 32), IDCODE_VALUE as u64);
+        assert_eq!(
+            scan(&mut tap, &mut mcu, true, IR_DTMCS as u64, IR_LENGTH),
+            0b00001,
+            "IR captures 01"
+        );
+        assert_eq!(scan(&mut tap, &mut mcu, false, 0, 32), DTMCS_VALUE as u64);
+        // bypass is a single bit register
+        scan(&mut tap, &mut mcu, true, 0x1f, IR_LENGTH);
+        assert_eq!(scan(&mut tap, &mut mcu, false, 0b10, 2), 0b00);
+    }
+
+    #[test]
+    fn dmi_access() {
+        let mut mcu = MCU::new();
+        let mut tap = JtagTap::new(DebugModule::new(0));
+        clock(&mut tap, &mut mcu, &[(true, false), (false, false)]);
+        // dmactive in dmcontrol, then read dmstatus
+        dmi(&mut tap, &mut mcu, 0x10, 1, DMI_OP_WRITE);
+        assert_eq!(dmi(&mut tap, &mut mcu, 0x10, 0, DMI_OP_READ), 1);
+        assert_eq!(dmi(&mut tap, &mut mcu, 0x11, 0, DMI_OP_READ) & 0xf, 2);
+    }
+}
@@ -0,0 +1,6 @@
+//! Debug support of the RISC-V External Debug Support spec: a Debug Module, the JTAG Debug
+//! Transport Module in front of it and OpenOCD's remote bitbang protocol to drive the JTAG pins
+//! over TCP.
+pub mod dm;
+pub mod jtag;
+pub mod remote_bitbang;
@@ -0,0 +1,110 @@
+//! The remote bitbang protocol of OpenOCD's `remote_bitbang` adapter: every byte sent by the
+//! debugger drives the JTAG pins or asks for the value of TDO.
+use super::jtag::JtagTap;
+use crate::mcu::MCU;
+use std::io::{ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+pub struct RemoteBitbang {
+    stream: TcpStream,
+    pub tap: JtagTap,
+}
+
+impl RemoteBitbang {
+    pub fn new(stream: TcpStream, tap: JtagTap) -> Self {
+        Self { stream, tap }
+    }
+
+    /// Handles the commands received from the debugger, returning the replies to send back and
+    /// false once the debugger quits
+    pub fn process(&mut self, mcu: &mut MCU, input: &[u8]) -> (Vec<u8>, bool) {
+        let mut replies = Vec::new();
+        for &c in input {
+            match c {
+                // blink on/off
+                b'B' | b'b' => {}
+                b'R' => replies.push(if self.tap.tdo() { b'1' } else { b'0' }),
+                b'Q' => return (replies, false),
+                // write tck, tms and tdi
+                b'0'..=b'7' => {
+                    let pins = c - b'0';
+                    self.tap
+                        .set_pins(mcu, pins & 4 != 0, pins & 2 != 0, pins & 1 != 0);
+                }
+                // write trst and srst, only the TAP reset is implemented
+                b'r'..=b'u' => {
+                    if (c - b'r') & 2 != 0 {
+                        self.tap.reset();
+                    }
+                }
+                _ => log::warn!("Unknown remote bitbang command {:?}", c as char),
+            }
+        }
+        (replies, true)
+    }
+
+    /// Waits up to `timeout` for commands from the debugger and handles them. Returns false when
+    /// the debugger disconnects.
+    pub fn poll(&mut self, mcu: &mut MCU, timeout: Duration) -> std::io::Result<bool> {
+        // a zero timeout would block forever
+        self.stream
+            .set_read_timeout(Some(timeout.max(Duration::from_micros(1))))?;
+        let mut buf = [0; 4096];
+        let n = match self.stream.read(&mut buf) {
+            Ok(0) => return Ok(false),
+            Ok(n) => n,
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                return Ok(true)
+            }
+            Err(e) => return Err(e),
+        };
+        let (replies, keep_going) = self.process(mcu, &buf[..n]);
+        self.stream.write_all(&replies)?;
+        Ok(keep_going)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::debug::dm::DebugModule;
+    use std::net::TcpListener;
+
+    /// Encodes a rising edge of TCK with the given TMS and TDI, reading TDO before it
+    fn edge(tms: bool, tdi: bool) -> [u8; 3] {
+        let pins = b'0' + ((tms as u8) << 1 | tdi as u8);
+        [pins, b'R', pins + 4]
+    }
+
+    #[test]
+    fn idcode_over_tcp() {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let mut client = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let mut mcu = MCU::new();
+        let mut server = RemoteBitbang::new(stream, JtagTap::new(DebugModule::new(0)));
+
+        // reset the TAP through TRST, then go to Shift-DR through Run-Test/Idle
+        let mut commands = b"tr".to_vec();
+        for tms in [false, true, false, false] {
+            commands.extend(&edge(tms, false)[..1]);
+            commands.extend(&edge(tms, false)[2..]);
+        }
+        for _ in 0..32 {
+            commands.extend(edge(false, false));
+        }
+        commands.push(b'Q');
+        client.write_all(&commands).unwrap();
+
+        while server.poll(&mut mcu, Duration::from_millis(100)).unwrap() {}
+        let mut replies = [0; 32];
+        client.read_exact(&mut replies).unwrap();
+        let idcode = replies
+            .iter()
+            .enumerate()
+            .fold(0, |acc, (i, b)| acc | ((*b == b'1') as u32) << i);
+        assert_eq!(idcode, 0x10e3_1913);
+    }
+}
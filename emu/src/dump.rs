@@ -0,0 +1,205 @@
+//! Memory dumps requested by the guest through the MEMDUMP environment call
+use crate::elf::SymbolTable;
+use crate::memory::{Memory, MMU};
+use std::io::Write;
+use std::ops::RangeInclusive;
+
+const BYTES_PER_LINE: u32 = 16;
+/// Largest range a dump reads, the whole range is held in memory
+pub const MAX_DUMP_LEN: u32 = 16 << 20;
+
+/// Why a memory range can't be dumped
+#[derive(Debug, PartialEq)]
+pub enum DumpError {
+    /// The range ends before it starts
+    Inverted { start: u32, end: u32 },
+    /// The range is longer than [`MAX_DUMP_LEN`]
+    TooLarge { start: u32, end: u32 },
+}
+
+impl std::fmt::Display for DumpError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> Result<(), std::fmt::Error> {
+        match self {
+            DumpError::Inverted { start, end } => {
+                write!(f, "range {start:#x}..={end:#x} ends before it starts")
+            }
+            DumpError::TooLarge { start, end } => write!(
+                f,
+                "range {start:#x}..={end:#x} is larger than {MAX_DUMP_LEN:#x} bytes"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for DumpError {}
+
+/// A snapshot of a memory range, `None` marks the bytes that couldn't be read
+pub struct MemoryDump {
+    pub start: u32,
+    pub data: Vec<Option<u8>>,
+}
+
+impl MemoryDump {
+    /// Reads every byte of the range through the MMU. Inverted ranges and ranges longer than
+    /// [`MAX_DUMP_LEN`] are refused.
+    pub fn read(mmu: &MMU, range: RangeInclusive<u32>) -> Result<Self, DumpError> {
+        let (start, end) = (*range.start(), *range.end());
+        if end < start {
+            return Err(DumpError::Inverted { start, end });
+        }
+        if end - start >= MAX_DUMP_LEN {
+            return Err(DumpError::TooLarge { start, end });
+        }
+        let data = range.map(|addr| mmu.rb(addr).ok()).collect();
+        Ok(Self { start, data })
+    }
+
+    /// Returns the address ranges that couldn't be read
+    pub fn unreadable(&self) -> Vec<RangeInclusive<u32>> {
+        let mut ranges: Vec<RangeInclusive<u32>> = Vec::new();
+        for (offset, byte) in self.data.iter().enumerate() {
+            if byte.is_some() {
+                continue;
+            }
+            let addr = self.start.wrapping_add(offset as u32);
+            match ranges.last_mut() {
+                Some(r) if r.end().wrapping_add(1) == addr => *r = *r.start()..=addr,
+                _ => ranges.push(addr..=addr),
+            }
+        }
+        ranges
+    }
+
+    /// Writes the raw bytes, unreadable bytes are written as zeros
+    pub fn write_binary(&self, w: &mut dyn Write) -> std::io::Result<()> {
+        let bytes: Vec<u8> = self.data.iter().map(|b| b.unwrap_or(0)).collect();
+        w.write_all(&bytes)
+    }
+
+    /// Writes one little endian word per line as 8 hex digits, the format of riscv-arch-test
+    /// signatures. Unreadable bytes are written as zeros.
+    pub fn write_words(&self, w: &mut dyn Write) -> std::io::Result<()> {
+        for chunk in self.data.chunks(4) {
+            let word = chunk
+                .iter()
+                .enumerate()
+                .fold(0u32, |acc, (i, b)| acc | (b.unwrap_or(0) as u32) << (8 * i));
+            writeln!(w, "{word:08x}")?;
+        }
+        Ok(())
+    }
+
+    /// Writes a hexdump, 16 bytes per line with their ASCII representation. Unreadable bytes
+    /// are shown as `??` and the symbols starting in a line are written as labels before it.
+    pub fn write_hex(&self, w: &mut dyn Write, symbols: &SymbolTable) -> std::io::Result<()> {
+        for (line, chunk) in self.data.chunks(BYTES_PER_LINE as usize).enumerate() {
+            let addr = self.start.wrapping_add(line as u32 * BYTES_PER_LINE);
+            let end = addr.wrapping_add(chunk.len() as u32);
+            for symbol in symbols.iter().filter(|s| s.addr >= addr && s.addr < end) {
+                writeln!(w, "{:08x} <{}>:", symbol.addr, symbol.name)?;
+            }
+
+            let mut hex = String::new();
+            let mut ascii = String::new();
+            for byte in chunk {
+                match byte {
+                    Some(b) => {
+                        hex += &format!(" {b:02x}");
+                        ascii.push(if b.is_ascii_graphic() || *b == b' ' {
+                            *b as char
+                        } else {
+                            '.'
+                        });
+                    }
+                    None => {
+                        hex += " ??";
+                        ascii.push('?');
+                    }
+                }
+            }
+            writeln!(
+                w,
+                "{addr:08x}:{hex:<width$}  |{ascii}|",
+                width = BYTES_PER_LINE as usize * 3
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::elf::Symbol;
+    use crate::mcu::{test_mcu, MCU};
+
+    fn mcu() -> MCU {
+        let mut mcu = test_mcu(0x10, vec![]);
+        mcu.flash(b"Hello, world!\n\0\x01".to_vec());
+        mcu
+    }
+
+    #[test]
+    fn binary_dump() {
+        let mcu = mcu();
+        let dump = MemoryDump::read(&mcu.mmu, 0xc..=0x11).unwrap();
+        assert_eq!(dump.unreadable(), vec![0x10..=0x11]);
+        let mut out = Vec::new();
+        dump.write_binary(&mut out).unwrap();
+        assert_eq!(out, b"!\n\0\x01\0\0");
+    }
+
+    #[test]
+    fn word_dump() {
+        let mcu = mcu();
+        let dump = MemoryDump::read(&mcu.mmu, 0x8..=0x11).unwrap();
+        let mut out = Vec::new();
+        dump.write_words(&mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "646c726f\n01000a21\n00000000\n"
+        );
+    }
+
+    #[test]
+    fn hex_dump() {
+        let mcu = mcu();
+        let mut symbols = SymbolTable::default();
+        symbols.insert(Symbol {
+            name: "greeting".to_string(),
+            addr: 0,
+            size: 14,
+            global: true,
+        });
+        let dump = MemoryDump::read(&mcu.mmu, 0..=0x11).unwrap();
+        let mut out = Vec::new();
+        dump.write_hex(&mut out, &symbols).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            concat!(
+                "00000000 <greeting>:\n",
+                "00000000: 48 65 6c 6c 6f 2c 20 77 6f 72 6c 64 21 0a 00 01  |Hello, world!...|\n",
+                "00000010: ?? ??                                            |??|\n",
+            )
+        );
+    }
+
+    #[test]
+    fn refused_ranges() {
+        let mcu = mcu();
+        assert_eq!(
+            MemoryDump::read(&mcu.mmu, 0..=u32::MAX).err(),
+            Some(DumpError::TooLarge {
+                start: 0,
+                end: u32::MAX
+            })
+        );
+        assert_eq!(
+            MemoryDump::read(&mcu.mmu, RangeInclusive::new(0x10, 0x8)).err(),
+            Some(DumpError::Inverted {
+                start: 0x10,
+                end: 0x8
+            })
+        );
+    }
+}
@@ -0,0 +1,380 @@
+const ELF_MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+const ELFCLASS32: u8 = 1;
+const ELFDATA2LSB: u8 = 1;
+const EM_RISCV: u16 = 243;
+const PT_LOAD: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const PHDR_SIZE: usize = 32;
+const SHDR_SIZE: usize = 40;
+const SYM_SIZE: usize = 16;
+const STB_LOCAL: u8 = 0;
+
+#[derive(Debug, PartialEq)]
+pub enum ElfError {
+    /// The file doesn't start with the ELF magic number
+    NotElf,
+    /// The file is an ELF, but not a 32-bit little endian RISC-V one
+    Unsupported(&'static str),
+    /// A header or table points past the end of the file
+    Truncated,
+    /// A loadable segment isn't fully contained in the memory of a single device
+    UnmappedSegment { start: u32, end: u32 },
+    /// The device backing a segment refused the write
+    WriteFault(u32),
+}
+
+impl std::fmt::Display for ElfError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> Result<(), std::fmt::Error> {
+        match self {
+            ElfError::NotElf => write!(f, "not an ELF file"),
+            ElfError::Unsupported(reason) => write!(f, "unsupported ELF file: {reason}"),
+            ElfError::Truncated => write!(f, "truncated ELF file"),
+            ElfError::UnmappedSegment { start, end } => write!(
+                f,
+                "segment {start:#010x}..={end:#010x} falls outside of every device memory region"
+            ),
+            ElfError::WriteFault(addr) => write!(f, "error writing segment data at {addr:#010x}"),
+        }
+    }
+}
+
+impl std::error::Error for ElfError {}
+
+/// A PT_LOAD segment. `mem_size` may be bigger than the data, the remaining bytes (.bss) are
+/// zero-initialized.
+#[derive(Debug)]
+pub struct Segment {
+    pub paddr: u32,
+    pub mem_size: u32,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Symbol {
+    pub name: String,
+    pub addr: u32,
+    pub size: u32,
+    // global or weak binding, local symbols may share their name with others
+    pub global: bool,
+}
+
+/// The symbols of an ELF sorted by address. Names aren't unique, local symbols of different
+/// translation units often share them.
+#[derive(Debug, Default)]
+pub struct SymbolTable {
+    symbols: Vec<Symbol>,
+}
+
+impl SymbolTable {
+    pub fn insert(&mut self, symbol: Symbol) {
+        let i = self.symbols.partition_point(|s| s.addr <= symbol.addr);
+        self.symbols.insert(i, symbol);
+    }
+
+    /// Looks up a symbol by name, preferring a global one over the locals with that name
+    pub fn get(&self, name: &str) -> Option<&Symbol> {
+        let mut named = self.symbols.iter().filter(|s| s.name == name);
+        named.clone().find(|s| s.global).or_else(|| named.next())
+    }
+
+    /// Returns the symbol whose extent contains `addr`, if any
+    pub fn find(&self, addr: u32) -> Option<&Symbol> {
+        self.symbols
+            .iter()
+            .find(|s| addr >= s.addr && addr < s.addr.wrapping_add(s.size.max(1)))
+    }
+
+    /// Iterates over the symbols in address order
+    pub fn iter(&self) -> impl Iterator<Item = &Symbol> {
+        self.symbols.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.symbols.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.symbols.is_empty()
+    }
+}
+
+#[derive(Debug)]
+pub struct Elf {
+    pub entry: u32,
+    pub segments: Vec<Segment>,
+    pub symbols: SymbolTable,
+}
+
+fn u16_at(bytes: &[u8], offset: usize) -> Result<u16, ElfError> {
+    bytes
+        .get(offset..offset + 2)
+        .map(|b| u16::from_le_bytes([b[0], b[1]]))
+        .ok_or(ElfError::Truncated)
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> Result<u32, ElfError> {
+    bytes
+        .get(offset..offset + 4)
+        .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .ok_or(ElfError::Truncated)
+}
+
+fn slice_at(bytes: &[u8], offset: u32, size: u32) -> Result<&[u8], ElfError> {
+    let start = offset as usize;
+    let end = start
+        .checked_add(size as usize)
+        .ok_or(ElfError::Truncated)?;
+    bytes.get(start..end).ok_or(ElfError::Truncated)
+}
+
+fn str_at(strtab: &[u8], offset: u32) -> String {
+    let bytes = strtab.get(offset as usize..).unwrap_or_default();
+    let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..end]).to_string()
+}
+
+impl Elf {
+    pub fn is_elf(bytes: &[u8]) -> bool {
+        bytes.starts_with(&ELF_MAGIC)
+    }
+
+    pub fn parse(bytes: &[u8]) -> Result<Self, ElfError> {
+        if !Self::is_elf(bytes) {
+            return Err(ElfError::NotElf);
+        }
+        if bytes.get(4) != Some(&ELFCLASS32) {
+            return Err(ElfError::Unsupported("not a 32-bit ELF"));
+        }
+        if bytes.get(5) != Some(&ELFDATA2LSB) {
+            return Err(ElfError::Unsupported("not little endian"));
+        }
+        if u16_at(bytes, 18)? != EM_RISCV {
+            return Err(ElfError::Unsupported("not a RISC-V ELF"));
+        }
+
+        let entry = u32_at(bytes, 24)?;
+        let phoff = u32_at(bytes, 28)? as usize;
+        let shoff = u32_at(bytes, 32)? as usize;
+        let phnum = u16_at(bytes, 44)? as usize;
+        let shnum = u16_at(bytes, 48)? as usize;
+
+        let mut segments = Vec::new();
+        for i in 0..phnum {
+            let ph = phoff + i * PHDR_SIZE;
+            if u32_at(bytes, ph)? != PT_LOAD {
+                continue;
+            }
+            let offset = u32_at(bytes, ph + 4)?;
+            let paddr = u32_at(bytes, ph + 12)?;
+            let file_size = u32_at(bytes, ph + 16)?;
+            let mem_size = u32_at(bytes, ph + 20)?;
+            if mem_size == 0 {
+                continue;
+            }
+            segments.push(Segment {
+                paddr,
+                mem_size,
+                data: slice_at(bytes, offset, file_size)?.to_vec(),
+            });
+        }
+
+        let mut symbols = SymbolTable::default();
+        for i in 0..shnum {
+            let sh = shoff + i * SHDR_SIZE;
+            if u32_at(bytes, sh + 4)? != SHT_SYMTAB {
+                continue;
+            }
+            let symtab = slice_at(bytes, u32_at(bytes, sh + 16)?, u32_at(bytes, sh + 20)?)?;
+            let strtab_sh = shoff + u32_at(bytes, sh + 24)? as usize * SHDR_SIZE;
+            let strtab = slice_at(
+                bytes,
+                u32_at(bytes, strtab_sh + 16)?,
+                u32_at(bytes, strtab_sh + 20)?,
+            )?;
+            for sym in symtab.chunks_exact(SYM_SIZE) {
+                let name = str_at(strtab, u32_at(sym, 0)?);
+                if name.is_empty() {
+                    continue;
+                }
+                let info = sym[12];
+                symbols.insert(Symbol {
+                    name,
+                    addr: u32_at(sym, 4)?,
+                    size: u32_at(sym, 8)?,
+                    global: info >> 4 != STB_LOCAL,
+                });
+            }
+        }
+
+        Ok(Elf {
+            entry,
+            segments,
+            symbols,
+        })
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Builds an ELF with a single PT_LOAD segment at `paddr` and a symbol table
+    pub(crate) fn build_elf(
+        entry: u32,
+        paddr: u32,
+        data: &[u8],
+        mem_size: u32,
+        symbols: &[(&str, u32, u32)],
+    ) -> Vec<u8> {
+        let mut strtab = vec![0u8];
+        let mut symtab = vec![0u8; SYM_SIZE];
+        for (name, addr, size) in symbols {
+            symtab.extend((strtab.len() as u32).to_le_bytes());
+            symtab.extend(addr.to_le_bytes());
+            symtab.extend(size.to_le_bytes());
+            symtab.extend([0u8; 4]);
+            strtab.extend(name.as_bytes());
+            strtab.push(0);
+        }
+
+        let phoff = 52u32;
+        let data_off = phoff + PHDR_SIZE as u32;
+        let symtab_off = data_off + data.len() as u32;
+        let strtab_off = symtab_off + symtab.len() as u32;
+        let shoff = strtab_off + strtab.len() as u32;
+
+        let mut elf = vec![0x7f, b'E', b'L', b'F', ELFCLASS32, ELFDATA2LSB, 1];
+        elf.resize(16, 0);
+        elf.extend(2u16.to_le_bytes()); // e_type
+        elf.extend(EM_RISCV.to_le_bytes());
+        elf.extend(1u32.to_le_bytes()); // e_version
+        elf.extend(entry.to_le_bytes());
+        elf.extend(phoff.to_le_bytes());
+        elf.extend(shoff.to_le_bytes());
+        elf.extend(0u32.to_le_bytes()); // e_flags
+        elf.extend(52u16.to_le_bytes()); // e_ehsize
+        elf.extend((PHDR_SIZE as u16).to_le_bytes());
+        elf.extend(1u16.to_le_bytes()); // e_phnum
+        elf.extend((SHDR_SIZE as u16).to_le_bytes());
+        elf.extend(3u16.to_le_bytes()); // e_shnum
+        elf.extend(0u16.to_le_bytes()); // e_shstrndx
+
+        for v in [
+            PT_LOAD,
+            data_off,
+            paddr,
+            paddr,
+            data.len() as u32,
+            mem_size,
+            0b111,
+            4,
+        ] {
+            elf.extend(v.to_le_bytes());
+        }
+        elf.extend(data);
+        elf.extend(&symtab);
+        elf.extend(&strtab);
+
+        // null, .symtab and .strtab section headers
+        elf.extend([0u8; SHDR_SIZE]);
+        for v in [
+            0,
+            SHT_SYMTAB,
+            0,
+            0,
+            symtab_off,
+            symtab.len() as u32,
+            2,
+            0,
+            4,
+            16,
+        ] {
+            elf.extend(v.to_le_bytes());
+        }
+        for v in [0, 3, 0, 0, strtab_off, strtab.len() as u32, 0, 0, 1, 0] {
+            elf.extend(v.to_le_bytes());
+        }
+        elf
+    }
+
+    #[test]
+    fn parse_elf() {
+        let bytes = build_elf(0x10, 0x8000_0000, &[1, 2, 3, 4], 8, &[("_start", 0x10, 4)]);
+        let elf = Elf::parse(&bytes).unwrap();
+
+        assert_eq!(elf.entry, 0x10);
+        assert_eq!(elf.segments.len(), 1);
+        assert_eq!(elf.segments[0].paddr, 0x8000_0000);
+        assert_eq!(elf.segments[0].mem_size, 8);
+        assert_eq!(elf.segments[0].data, vec![1, 2, 3, 4]);
+        assert_eq!(elf.symbols.get("_start").map(|s| s.addr), Some(0x10));
+        assert_eq!(
+            elf.symbols.find(0x12).map(|s| s.name.as_str()),
+            Some("_start")
+        );
+    }
+
+    #[test]
+    fn duplicate_symbols() {
+        let symbol = |name: &str, addr, global| Symbol {
+            name: name.to_string(),
+            addr,
+            size: 4,
+            global,
+        };
+        let mut symbols = SymbolTable::default();
+        symbols.insert(symbol("helper", 0x20, false));
+        symbols.insert(symbol("main", 0x30, true));
+        symbols.insert(symbol("helper", 0x10, false));
+        symbols.insert(symbol("helper", 0x40, true));
+
+        assert_eq!(symbols.len(), 4, "duplicate names are kept");
+        assert_eq!(
+            symbols.iter().map(|s| s.addr).collect::<Vec<_>>(),
+            vec![0x10, 0x20, 0x30, 0x40]
+        );
+        assert_eq!(symbols.get("helper").map(|s| s.addr), Some(0x40));
+        assert_eq!(symbols.find(0x22).map(|s| s.addr), Some(0x20));
+    }
+
+    #[test]
+    fn load_elf() {
+        use crate::mcu::{DeviceDef, MCU};
+        use crate::memory::Memory;
+        use crate::peripherals::flash::Flash;
+
+        let mut mcu = MCU::new();
+        mcu.add_device(DeviceDef {
+            identifier: "FLASH".to_string(),
+            memory_start: 0x100,
+            memory_end: 0x1ff,
+            device: Box::new(Flash::new(0x100)),
+        })
+        .unwrap();
+        mcu.mmu.ww(0x104, 0xffff_ffff).unwrap();
+
+        let bytes = build_elf(0x100, 0x100, &[1, 2, 3, 4], 8, &[("_start", 0x100, 0)]);
+        mcu.load_elf(Elf::parse(&bytes).unwrap()).unwrap();
+        assert_eq!(mcu.cpu.pc, 0x100);
+        assert_eq!(mcu.mmu.rw(0x100), Ok(0x0403_0201));
+        assert_eq!(mcu.mmu.rw(0x104), Ok(0), "bss should be zeroed");
+        assert!(mcu.symbols.get("_start").is_some());
+
+        let bytes = build_elf(0x1fc, 0x1fc, &[1, 2, 3, 4], 8, &[]);
+        assert_eq!(
+            mcu.load_elf(Elf::parse(&bytes).unwrap()).unwrap_err(),
+            ElfError::UnmappedSegment {
+                start: 0x1fc,
+                end: 0x203
+            }
+        );
+    }
+
+    #[test]
+    fn reject_non_elf() {
+        assert_eq!(Elf::parse(&[0x13, 0, 0, 0]).unwrap_err(), ElfError::NotElf);
+        let mut bytes = build_elf(0, 0, &[], 0, &[]);
+        bytes.truncate(40);
+        assert_eq!(Elf::parse(&bytes).unwrap_err(), ElfError::Truncated);
+    }
+}
@@ -1,29 +1,107 @@
-use crate::mcu::{DeviceDef, TickResult, MCU};
+use crate::debug::dm::DebugModule;
+use crate::debug::jtag::JtagTap;
+use crate::debug::remote_bitbang::RemoteBitbang;
+use crate::dump::{DumpError, MemoryDump};
+use crate::elf::{Elf, ElfError, SymbolTable};
+use crate::gdb::{Action, GdbStub};
+use crate::mcu::{DeviceDef, MisalignedAccess, TickResult, MCU};
 use crate::peripherals::uart::UARTDevice;
+use crate::semihosting::Semihosting;
 
 pub struct Emulator {
     mcu: MCU,
     speed: u32, // speed in hz
+    dump_path: std::path::PathBuf,
+    dump_hex: bool,
+    // number of dumps written so far, used to name the dump files
+    dumps: u32,
+}
+
+fn cycles_duration(hz: u32, cycles: u32) -> std::time::Duration {
+    std::time::Duration::from_nanos((1e9 / hz as f64).round() as u64 * cycles as u64)
 }
 
 fn wait_cycles(hz: u32, cycles: u32) {
-    std::thread::sleep(std::time::Duration::from_nanos(
-        (1e9 / hz as f64).round() as u64 * cycles as u64,
-    ))
+    std::thread::sleep(cycles_duration(hz, cycles))
+}
+
+/// Reads a memory range, reporting the addresses that couldn't be read
+fn read_dump(
+    mcu: &MCU,
+    range: std::ops::RangeInclusive<u32>,
+    name: &str,
+) -> Result<MemoryDump, DumpError> {
+    let dump = MemoryDump::read(&mcu.mmu, range)?;
+    for unreadable in dump.unreadable() {
+        log::warn!(
+            "{name}: memory {:#x}..={:#x} couldn't be read, written as zeros",
+            unreadable.start(),
+            unreadable.end()
+        );
+    }
+    Ok(dump)
+}
+
+/// Writes dump number `n` of the memory range to `dump_path`, see [`Emulator::dump`]
+fn write_dump(
+    mcu: &MCU,
+    dump_path: &std::path::Path,
+    dump_hex: bool,
+    n: u32,
+    range: std::ops::RangeInclusive<u32>,
+) -> std::io::Result<()> {
+    log::info!(
+        "Dumping memory {:#x}..={:#x} as dump {n}",
+        range.start(),
+        range.end()
+    );
+    let dump = match read_dump(mcu, range, &format!("Dump {n}")) {
+        Ok(dump) => dump,
+        Err(err) => {
+            log::error!("Dump {n}: {err}, nothing written");
+            return Ok(());
+        }
+    };
+    std::fs::create_dir_all(dump_path)?;
+    let mut file = std::fs::File::create(dump_path.join(format!("dump-{n:04}.bin")))?;
+    dump.write_binary(&mut file)?;
+    if dump_hex {
+        let mut file = std::fs::File::create(dump_path.join(format!("dump-{n:04}.txt")))?;
+        dump.write_hex(&mut file, &mcu.symbols)?;
+    }
+    Ok(())
 }
 
 pub struct EmulatorOpts {
     pub speed: u32,
     pub terminal: Option<Box<dyn UARTDevice>>,
     pub dump_path: std::path::PathBuf,
+    // write a hexdump next to every binary dump
+    pub dump_hex: bool,
+    // enables semihosting, sandboxed to this directory
+    pub semihosting: Option<std::path::PathBuf>,
+    // command line returned to the guest by semihosting
+    pub cmdline: String,
+    // number of mhpmcounters that count events, starting at mhpmcounter3
+    pub hpm_counters: u32,
+    // trap or emulate misaligned loads and stores
+    pub misaligned: MisalignedAccess,
 }
 
 impl Emulator {
     pub fn new(opts: EmulatorOpts) -> Self {
-        let mcu = MCU::new();
+        let mut mcu = MCU::new();
+        mcu.semihosting = opts
+            .semihosting
+            .map(|root| Semihosting::new(root, opts.cmdline));
+        mcu.cpu.set_hpm_counters(opts.hpm_counters);
+        mcu.misaligned = opts.misaligned;
         Emulator {
             mcu,
             speed: opts.speed,
+            dump_path: opts.dump_path,
+            dump_hex: opts.dump_hex,
+            dumps: 0,
         }
     }
 
@@ -35,7 +113,9 @@ impl Emulator {
         Ok(())
     }
 
-    pub fn run_program(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+    /// Runs the program until it halts, returning the exit code it halted with. Fails if the
+    /// hart halts in Debug mode, as there's no debugger to resume it.
+    pub fn run_program(&mut self) -> Result<u32, Box<dyn std::error::Error>> {
         let mut pending_work = 0;
         let mut cycles = 0;
         loop {
@@ -50,16 +130,290 @@ impl Emulator {
                         pending_work += v
                     }
                     TickResult::WFI => pending_work += 1, // TODO: This should actually block on a callback  instead of doing polling
+                    TickResult::Debug => {
+                        return Err("the hart halted in Debug mode with no debugger attached".into())
+                    }
 
                     // TODO: This two results should be done using the AON
-                    TickResult::HALT => return Ok(()),
-                    TickResult::Dump(_range) => {}
+                    TickResult::HALT(code) => return Ok(code),
+                    TickResult::Dump(range) => self.dump(range)?,
                 }
             }
         }
     }
 
+    /// Waits for a GDB connection on localhost and lets the debugger drive the MCU. When the
+    /// debugger detaches the program keeps running normally. Returns the exit code of the
+    /// program, or 0 if it didn't halt.
+    pub fn debug(&mut self, port: u16) -> Result<u32, Box<dyn std::error::Error>> {
+        let listener = std::net::TcpListener::bind(("127.0.0.1", port))?;
+        log::info!("Waiting for a GDB connection on 127.0.0.1:{port}");
+        let (stream, addr) = listener.accept()?;
+        log::info!("GDB connected from {addr}");
+        let mut stub = GdbStub::new();
+        let (dump_path, dump_hex, dumps) = (&self.dump_path, self.dump_hex, &mut self.dumps);
+        let mut dump = |mcu: &MCU, range| {
+            let n = *dumps;
+            *dumps += 1;
+            write_dump(mcu, dump_path, dump_hex, n, range)
+        };
+        match stub.serve(&mut self.mcu, stream, &mut dump)? {
+            Action::Detach => self.run_program(),
+            _ => Ok(stub.exit_code().unwrap_or(0)),
+        }
+    }
+
+    /// Waits for an OpenOCD connection through its remote bitbang adapter on localhost and runs
+    /// the program while serving the JTAG commands, instead of sleeping between cycles. When
+    /// the debugger disconnects the program keeps running normally. Returns the exit code of
+    /// the program.
+    pub fn serve_jtag(&mut self, port: u16) -> Result<u32, Box<dyn std::error::Error>> {
+        let listener = std::net::TcpListener::bind(("127.0.0.1", port))?;
+        log::info!("Waiting for a remote bitbang connection on 127.0.0.1:{port}");
+        let (stream, addr) = listener.accept()?;
+        log::info!("Debugger connected from {addr}");
+        let tap = JtagTap::new(DebugModule::new(self.mcu.cpu.pc));
+        let mut server = RemoteBitbang::new(stream, tap);
+        let mut wait = 0;
+        while server.poll(&mut self.mcu, cycles_duration(self.speed, wait))? {
+            wait = match self.mcu.tick() {
+                TickResult::Cycles(v) => v,
+                TickResult::WFI | TickResult::Debug => 1,
+                TickResult::HALT(code) => return Ok(code),
+                TickResult::Dump(range) => {
+                    self.dump(range)?;
+                    0
+                }
+            };
+        }
+        log::info!("Debugger disconnected");
+        self.run_program()
+    }
+
+    /// The memory between the `begin_signature` and `end_signature` symbols
+    pub fn signature_range(&self) -> Option<std::ops::RangeInclusive<u32>> {
+        let begin = self.mcu.symbols.get("begin_signature")?.addr;
+        let end = self.mcu.symbols.get("end_signature")?.addr;
+        (end > begin).then(|| begin..=end - 1)
+    }
+
+    /// Writes the riscv-arch-test signature, one hex word per line
+    pub fn write_signature(
+        &self,
+        path: &std::path::Path,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let range = self
+            .signature_range()
+            .ok_or("the program has no begin_signature/end_signature symbols")?;
+        let dump = read_dump(&self.mcu, range, "Signature")?;
+        let mut file = std::fs::File::create(path)?;
+        dump.write_words(&mut file)?;
+        Ok(())
+    }
+
+    /// Writes the memory range to `dump-<n>.bin` in the dump folder, and a hexdump to
+    /// `dump-<n>.txt` if enabled.
+    pub fn dump(
+        &mut self,
+        range: std::ops::RangeInclusive<u32>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let n = self.dumps;
+        self.dumps += 1;
+        write_dump(&self.mcu, &self.dump_path, self.dump_hex, n, range)?;
+        Ok(())
+    }
+
     pub fn flash(&mut self, mem: Vec<u8>) {
         self.mcu.flash(mem)
     }
+
+    /// Loads an ELF image through the MMU and sets the pc to its entry point
+    pub fn load_elf(&mut self, bytes: &[u8]) -> Result<(), ElfError> {
+        let elf = Elf::parse(bytes)?;
+        self.mcu.load_elf(elf)
+    }
+
+    pub fn symbols(&self) -> &SymbolTable {
+        &self.mcu.symbols
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::{CSRs, DCSR_EBREAKM};
+    use crate::elf::tests::build_elf;
+    use crate::peripherals::flash::Flash;
+
+    const SIGNATURE: u32 = 0x100;
+    const SIGNATURE_WORDS: u32 = 8;
+
+    /// A directory for the files written by the test `name`, not shared with other tests or
+    /// concurrent test runs
+    fn test_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("riscv-emu-{}-{name}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// An emulator with 4KiB of flash at address 0, dumping to the directory of the test `name`
+    fn emulator(name: &str) -> Emulator {
+        let mut emu = Emulator::new(EmulatorOpts {
+            speed: u32::MAX,
+            terminal: None,
+            dump_path: test_dir(name),
+            dump_hex: false,
+            semihosting: None,
+            cmdline: String::new(),
+            hpm_counters: 0,
+            misaligned: MisalignedAccess::Trap,
+        });
+        emu.setup_devices(vec![DeviceDef {
+            identifier: "FLASH".to_string(),
+            memory_start: 0,
+            memory_end: 0xfff,
+            device: Box::new(Flash::new(0x1000)),
+        }])
+        .unwrap();
+        emu
+    }
+
+    /// Runs a program in the style of riscv-arch-test, with its signature at 0x100 initialized
+    /// to 0xdeadbeef, and compares the signature with the reference in `tests/signatures`.
+    /// The references aren't upstream riscv-arch-test signatures and these tests don't show
+    /// compliance. Each word was worked out from the ISA manual, as noted next to its store,
+    /// rather than taken from the emulator's output.
+    fn check_signature(name: &str, program: &[u32], reference: &str) {
+        let mut data: Vec<u8> = program.iter().flat_map(|w| w.to_le_bytes()).collect();
+        data.resize(SIGNATURE as usize, 0);
+        for _ in 0..SIGNATURE_WORDS {
+            data.extend(0xdead_beefu32.to_le_bytes());
+        }
+        let end = SIGNATURE + 4 * SIGNATURE_WORDS;
+        let elf = build_elf(
+            0,
+            0,
+            &data,
+            data.len() as u32,
+            &[("begin_signature", SIGNATURE, 0), ("end_signature", end, 0)],
+        );
+
+        let mut emu = emulator(name);
+        emu.load_elf(&elf).unwrap();
+        assert_eq!(emu.run_program().unwrap(), 0);
+
+        let path = test_dir(name).join("signature");
+        emu.write_signature(&path).unwrap();
+        assert_eq!(std::fs::read_to_string(path).unwrap(), reference);
+    }
+
+    #[test]
+    fn rv32i_alu_signature() {
+        check_signature(
+            "rv32i_alu",
+            &[
+                0x1000_0093, // li ra, 0x100
+                0x0070_0113, // li sp, 7
+                0xffd0_0193, // li gp, -3
+                0x0031_0233, // add tp, sp, gp
+                0x0040_a023, // sw tp, 0(ra): 7 + -3 = 4
+                0x4031_0233, // sub tp, sp, gp
+                0x0040_a223, // sw tp, 4(ra): 7 - -3 = 0xa
+                0x0021_1233, // sll tp, sp, sp
+                0x0040_a423, // sw tp, 8(ra): 7 << 7 = 0x380
+                0x4021_d233, // sra tp, gp, sp
+                0x0040_a623, // sw tp, 12(ra): -3 >> 7 = -1
+                0x0021_a233, // slt tp, gp, sp
+                0x0040_a823, // sw tp, 16(ra): -3 < 7 = 1
+                0x0021_b233, // sltu tp, gp, sp
+                0x0040_aa23, // sw tp, 20(ra): 0xfffffffd < 7 unsigned = 0
+                0x0031_4233, // xor tp, sp, gp
+                0x0040_ac23, // sw tp, 24(ra): 7 ^ 0xfffffffd = 0xfffffffa
+                0x0000_0593, // li a1, 0
+                0x0ff0_0513, // li a0, 255
+                0x0000_0073, // ecall
+            ],
+            include_str!("../tests/signatures/rv32i_alu.reference_output"),
+        );
+    }
+
+    #[test]
+    fn rv32m_signature() {
+        check_signature(
+            "rv32m",
+            &[
+                0x1000_0093, // li ra, 0x100
+                0xff90_0113, // li sp, -7
+                0x0020_0193, // li gp, 2
+                0x0231_0233, // mul tp, sp, gp
+                0x0040_a023, // sw tp, 0(ra): -7 * 2 = -14
+                0x0231_3233, // mulhu tp, sp, gp
+                0x0040_a223, // sw tp, 4(ra): 0xfffffff9 * 2 >> 32 = 1
+                0x0231_4233, // div tp, sp, gp
+                0x0040_a423, // sw tp, 8(ra): -7 / 2 rounds to zero, -3
+                0x0231_6233, // rem tp, sp, gp
+                0x0040_a623, // sw tp, 12(ra): -7 % 2 = -1
+                0x0201_5233, // divu tp, sp, zero
+                0x0040_a823, // sw tp, 16(ra): division by zero, all ones
+                0x0201_6233, // rem tp, sp, zero
+                0x0040_aa23, // sw tp, 20(ra): remainder by zero is the dividend, -7
+                0x0000_0593, // li a1, 0
+                0x0ff0_0513, // li a0, 255
+                0x0000_0073, // ecall
+            ],
+            include_str!("../tests/signatures/rv32m.reference_output"),
+        );
+    }
+
+    #[test]
+    fn rv32a_signature() {
+        check_signature(
+            "rv32a",
+            &[
+                0x1000_0093, // li ra, 0x100
+                0x0050_0113, // li sp, 5
+                0x0020_a023, // sw sp, 0(ra)
+                0x0020_a22f, // amoadd.w tp, sp, (ra): 0(ra) = 10
+                0x0040_a223, // sw tp, 4(ra): the old value, 5
+                0x1000_a22f, // lr.w tp, (ra)
+                0x1820_a2af, // sc.w t0, sp, (ra): 0(ra) = 5
+                0x0050_a423, // sw t0, 8(ra): the sc succeeded, 0
+                0x1820_a2af, // sc.w t0, sp, (ra)
+                0x0050_a623, // sw t0, 12(ra): no reservation left, 1
+                0xa020_a22f, // amomax.w tp, sp, (ra): 0(ra) = max(5, 5) = 5
+                0x0040_a823, // sw tp, 16(ra): the old value, 5
+                0x0000_0593, // li a1, 0
+                0x0ff0_0513, // li a0, 255
+                0x0000_0073, // ecall
+            ],
+            include_str!("../tests/signatures/rv32a.reference_output"),
+        );
+    }
+
+    #[test]
+    fn debug_halt_without_debugger() {
+        let mut emu = emulator("debug_halt");
+        emu.mcu
+            .cpu
+            .set_csr(CSRs::dcsr as u32, DCSR_EBREAKM)
+            .unwrap();
+        emu.mcu.flash(0x0010_0073u32.to_le_bytes().to_vec()); // ebreak
+        assert!(emu.run_program().is_err());
+        assert!(emu.mcu.cpu.debug_mode);
+    }
+
+    #[test]
+    fn refused_dumps() {
+        let mut emu = emulator("refused_dumps");
+        emu.dump(0..=u32::MAX).unwrap();
+        emu.dump(std::ops::RangeInclusive::new(0x10, 0x8)).unwrap();
+        emu.dump(0..=0xf).unwrap();
+        let dir = test_dir("refused_dumps");
+        assert!(!dir.join("dump-0000.bin").exists());
+        assert!(!dir.join("dump-0001.bin").exists());
+        assert_eq!(
+            std::fs::read(dir.join("dump-0002.bin")).unwrap().len(),
+            0x10
+        );
+    }
 }
@@ -0,0 +1,688 @@
+//! A GDB remote serial protocol stub driving the [`MCU`].
+//!
+//! Only what `riscv32-elf-gdb` needs to debug a single hart is implemented: register and memory
+//! access, continue, single step, breakpoints and watchpoints. Memory is accessed at the
+//! addresses the hart sees, translated like its loads.
+use crate::cpu::CSRs;
+use crate::mcu::{TickResult, WatchKind, Watchpoint, MCU};
+use crate::memory::{Access, Memory};
+use std::collections::BTreeSet;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+
+const PACKET_SIZE: usize = 4096;
+// Instructions executed between checks for a debugger interrupt request
+const POLL_INTERVAL: u32 = 4096;
+// GDB's riscv register numbers
+const PC_REGNUM: u32 = 32;
+const CSR_REGNUM: u32 = 65;
+
+const REGISTER_NAMES: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "fp", "s1", "a0", "a1", "a2", "a3", "a4",
+    "a5", "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4",
+    "t5", "t6",
+];
+
+const CSR_NAMES: [(&str, CSRs); 25] = [
+    ("sstatus", CSRs::sstatus),
+    ("sie", CSRs::sie),
+    ("stvec", CSRs::stvec),
+    ("sscratch", CSRs::sscratch),
+    ("sepc", CSRs::sepc),
+    ("scause", CSRs::scause),
+    ("stval", CSRs::stval),
+    ("sip", CSRs::sip),
+    ("satp", CSRs::satp),
+    ("mstatus", CSRs::mstatus),
+    ("misa", CSRs::misa),
+    ("medeleg", CSRs::medeleg),
+    ("mideleg", CSRs::mideleg),
+    ("mie", CSRs::mie),
+    ("mtvec", CSRs::mtvec),
+    ("mscratch", CSRs::mscratch),
+    ("mepc", CSRs::mepc),
+    ("mcause", CSRs::mcause),
+    ("mtval", CSRs::mtval),
+    ("mip", CSRs::mip),
+    ("mhartid", CSRs::mhartid),
+    ("mcycle", CSRs::mcycle),
+    ("minstret", CSRs::minstret),
+    ("mcycleh", CSRs::mcycleh),
+    ("minstreth", CSRs::minstreth),
+];
+
+/// Why the hart stopped running
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StopReason {
+    Step,
+    Interrupted,
+    SwBreakpoint,
+    HwBreakpoint,
+    Watchpoint(Watchpoint, u32),
+    // The hart entered Debug mode, from an ebreak or a trigger
+    DebugMode,
+    Halted(u32),
+}
+
+impl StopReason {
+    fn reply(&self) -> String {
+        match self {
+            StopReason::Step | StopReason::Interrupted | StopReason::DebugMode => "S05".to_string(),
+            StopReason::SwBreakpoint => "T05swbreak:;".to_string(),
+            StopReason::HwBreakpoint => "T05hwbreak:;".to_string(),
+            StopReason::Watchpoint(w, addr) => {
+                let kind = match w.kind {
+                    WatchKind::Write => "watch",
+                    WatchKind::Read => "rwatch",
+                    WatchKind::Access => "awatch",
+                };
+                format!("T05{kind}:{:x};", addr.max(&w.addr))
+            }
+            StopReason::Halted(code) => format!("W{:02x}", *code as u8),
+        }
+    }
+}
+
+/// Writes a memory dump requested by the program
+pub type DumpHandler<'a> =
+    dyn FnMut(&MCU, std::ops::RangeInclusive<u32>) -> std::io::Result<()> + 'a;
+
+/// What the server has to do after processing a packet
+#[derive(Debug, PartialEq)]
+pub enum Action {
+    Reply(String),
+    Resume { step: bool },
+    Detach,
+    Kill,
+}
+
+pub struct GdbStub {
+    sw_breakpoints: BTreeSet<u32>,
+    hw_breakpoints: BTreeSet<u32>,
+    last_stop: StopReason,
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |acc, b| acc.wrapping_add(*b))
+}
+
+fn parse_hex(s: &str) -> Option<u32> {
+    u32::from_str_radix(s, 16).ok()
+}
+
+/// Registers are transferred in target byte order
+fn encode_reg(v: u32) -> String {
+    format!("{:08x}", v.swap_bytes())
+}
+
+fn decode_reg(s: &str) -> Option<u32> {
+    if s.len() != 8 {
+        return None;
+    }
+    parse_hex(s).map(u32::swap_bytes)
+}
+
+/// Parses the `addr,len` argument of memory and qXfer packets
+fn parse_range(s: &str) -> Option<(u32, u32)> {
+    let (addr, len) = s.split_once(',')?;
+    Some((parse_hex(addr)?, parse_hex(len)?))
+}
+
+fn target_xml() -> String {
+    let mut xml = String::from(concat!(
+        "<?xml version=\"1.0\"?>",
+        "<!DOCTYPE target SYSTEM \"gdb-target.dtd\">",
+        "<target version=\"1.0\">",
+        "<architecture>riscv:rv32</architecture>",
+        "<feature name=\"org.gnu.gdb.riscv.cpu\">",
+    ));
+    for (i, name) in REGISTER_NAMES.iter().enumerate() {
+        xml += &format!("<reg name=\"{name}\" bitsize=\"32\" type=\"int\" regnum=\"{i}\"/>");
+    }
+    xml += &format!("<reg name=\"pc\" bitsize=\"32\" type=\"code_ptr\" regnum=\"{PC_REGNUM}\"/>");
+    xml += "</feature><feature name=\"org.gnu.gdb.riscv.csr\">";
+    for (name, csr) in CSR_NAMES.iter() {
+        let regnum = CSR_REGNUM + *csr as u32;
+        xml += &format!(
+            "<reg name=\"{name}\" bitsize=\"32\" type=\"int\" regnum=\"{regnum}\" group=\"csr\"/>"
+        );
+    }
+    xml += "</feature></target>";
+    xml
+}
+
+impl Default for GdbStub {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl GdbStub {
+    pub fn new() -> Self {
+        Self {
+            sw_breakpoints: BTreeSet::new(),
+            hw_breakpoints: BTreeSet::new(),
+            last_stop: StopReason::Interrupted,
+        }
+    }
+
+    /// The exit code of the program, if it halted
+    pub fn exit_code(&self) -> Option<u32> {
+        match self.last_stop {
+            StopReason::Halted(code) => Some(code),
+            _ => None,
+        }
+    }
+
+    fn read_register(&self, mcu: &MCU, regnum: u32) -> Option<u32> {
+        match regnum {
+            0..=31 => Some(mcu.cpu.get_x(regnum)),
+            PC_REGNUM => Some(mcu.cpu.pc),
+            _ => mcu.cpu.get_csr(regnum.checked_sub(CSR_REGNUM)?).ok(),
+        }
+    }
+
+    fn write_register(&self, mcu: &mut MCU, regnum: u32, v: u32) -> Option<()> {
+        match regnum {
+            0..=31 => mcu.cpu.set_x(regnum, v),
+            PC_REGNUM => mcu.cpu.pc = v,
+            _ => mcu
+                .cpu
+                .debugger_write_csr(regnum.checked_sub(CSR_REGNUM)?, v)
+                .ok()?,
+        };
+        Some(())
+    }
+
+    /// Translates an address of the debugged program through the Sv32 page tables and the PMP,
+    /// with the privilege level of the hart's loads
+    fn physical_address(mcu: &mut MCU, addr: u32) -> Option<u32> {
+        mcu.translate(addr, 1, Access::Load).ok()
+    }
+
+    /// Reads up to the bytes that fit in a reply packet, gdb asks again for the rest
+    fn read_memory(&self, mcu: &mut MCU, addr: u32, len: u32) -> Option<String> {
+        let len = len.min((PACKET_SIZE as u32 - 1) / 2);
+        let mut reply = String::new();
+        for offset in 0..len {
+            let addr = Self::physical_address(mcu, addr.wrapping_add(offset))?;
+            let byte = mcu.mmu.rb(addr).ok()?;
+            reply += &format!("{byte:02x}");
+        }
+        Some(reply)
+    }
+
+    fn write_memory(&self, mcu: &mut MCU, addr: u32, data: &str) -> Option<()> {
+        for (offset, i) in (0..data.len()).step_by(2).enumerate() {
+            let byte = u8::from_str_radix(data.get(i..i + 2)?, 16).ok()?;
+            let addr = Self::physical_address(mcu, addr.wrapping_add(offset as u32))?;
+            mcu.mmu.program(addr, byte).ok()?;
+        }
+        Some(())
+    }
+
+    /// Handles `Z`/`z` packets, `insert` tells them apart
+    fn breakpoint(&mut self, mcu: &mut MCU, args: &str, insert: bool) -> Option<()> {
+        let mut parts = args.split(',');
+        let kind = parts.next()?;
+        let addr = parse_hex(parts.next()?)?;
+        let len = parse_hex(parts.next()?.split(';').next()?)?;
+        let watch_kind = match kind {
+            "0" | "1" => {
+                let set = if kind == "0" {
+                    &mut self.sw_breakpoints
+                } else {
+                    &mut self.hw_breakpoints
+                };
+                if insert {
+                    set.insert(addr);
+                } else {
+                    set.remove(&addr);
+                }
+                return Some(());
+            }
+            "2" => WatchKind::Write,
+            "3" => WatchKind::Read,
+            "4" => WatchKind::Access,
+            _ => return None,
+        };
+        let w = Watchpoint {
+            kind: watch_kind,
+            addr,
+            len,
+        };
+        if insert {
+            mcu.watchpoints.push(w);
+        } else {
+            mcu.watchpoints.retain(|other| *other != w);
+        }
+        Some(())
+    }
+
+    /// Processes the payload of a packet, returning what the server should do with it
+    pub fn process(&mut self, mcu: &mut MCU, packet: &str) -> Action {
+        let ok = |r: Option<()>| Action::Reply(if r.is_some() { "OK" } else { "E01" }.into());
+        let (cmd, args) = packet.split_at(packet.len().min(1));
+        match cmd {
+            "?" => Action::Reply(self.last_stop.reply()),
+            "g" => Action::Reply(
+                (0..=PC_REGNUM)
+                    .map(|r| encode_reg(self.read_register(mcu, r).unwrap_or(0)))
+                    .collect(),
+            ),
+            "G" => ok((0..=PC_REGNUM).try_for_each(|r| {
+                let i = r as usize * 8;
+                let v = decode_reg(args.get(i..i + 8)?)?;
+                self.write_register(mcu, r, v)
+            })),
+            "p" => Action::Reply(
+                parse_hex(args)
+                    .and_then(|r| self.read_register(mcu, r))
+                    .map(encode_reg)
+                    .unwrap_or_else(|| "E01".into()),
+            ),
+            "P" => ok(args
+                .split_once('=')
+                .and_then(|(r, v)| self.write_register(mcu, parse_hex(r)?, decode_reg(v)?))),
+            "m" => Action::Reply(
+                parse_range(args)
+                    .and_then(|(addr, len)| self.read_memory(mcu, addr, len))
+                    .unwrap_or_else(|| "E14".into()),
+            ),
+            "M" => ok(args.split_once(':').and_then(|(range, data)| {
+                let (addr, _len) = parse_range(range)?;
+                self.write_memory(mcu, addr, data)
+            })),
+            "c" | "s" => {
+                if let Some(addr) = parse_hex(args) {
+                    mcu.cpu.pc = addr;
+                }
+                Action::Resume { step: cmd == "s" }
+            }
+            "Z" => ok(self.breakpoint(mcu, args, true)),
+            "z" => ok(self.breakpoint(mcu, args, false)),
+            "H" | "T" => Action::Reply("OK".into()),
+            "D" => Action::Detach,
+            "k" => Action::Kill,
+            _ => Action::Reply(self.query(packet)),
+        }
+    }
+
+    fn query(&self, packet: &str) -> String {
+        if packet.starts_with("qSupported") {
+            format!("PacketSize={PACKET_SIZE:x};qXfer:features:read+;swbreak+;hwbreak+")
+        } else if let Some(range) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            let xml = target_xml();
+            match parse_range(range) {
+                Some((offset, len)) => {
+                    let start = (offset as usize).min(xml.len());
+                    let end = (start + len as usize).min(xml.len());
+                    let prefix = if end == xml.len() { "l" } else { "m" };
+                    format!("{prefix}{}", &xml[start..end])
+                }
+                None => "E01".into(),
+            }
+        } else {
+            match packet {
+                "qAttached" => "1".into(),
+                "qC" => "QC1".into(),
+                "qfThreadInfo" => "m1".into(),
+                "qsThreadInfo" => "l".into(),
+                // Unsupported packets get an empty reply
+                _ => String::new(),
+            }
+        }
+    }
+
+    /// Runs the hart until it hits a breakpoint, a watchpoint, enters Debug mode, halts or
+    /// `interrupted` returns true. When `step` is set only one instruction is executed. Memory
+    /// dumps requested by the program are passed to `dump` and execution continues.
+    pub fn resume(
+        &mut self,
+        mcu: &mut MCU,
+        step: bool,
+        interrupted: &mut dyn FnMut() -> bool,
+        dump: &mut DumpHandler,
+    ) -> std::io::Result<StopReason> {
+        mcu.watchpoint_hit = None;
+        // Leave Debug mode at the pc the debugger sees, which it may have changed
+        if mcu.cpu.debug_mode {
+            mcu.cpu.set_csr(CSRs::dpc as u32, mcu.cpu.pc).unwrap();
+            mcu.cpu.leave_debug_mode();
+        }
+        let mut ticks: u32 = 0;
+        let reason = loop {
+            // The breakpoint at the pc we resume from has already been reported
+            if ticks > 0 {
+                if self.sw_breakpoints.contains(&mcu.cpu.pc) {
+                    break StopReason::SwBreakpoint;
+                }
+                if self.hw_breakpoints.contains(&mcu.cpu.pc) {
+                    break StopReason::HwBreakpoint;
+                }
+            }
+            match mcu.tick() {
+                TickResult::HALT(code) => break StopReason::Halted(code),
+                TickResult::Debug => break StopReason::DebugMode,
+                TickResult::Dump(range) => dump(mcu, range)?,
+                _ => {}
+            }
+            ticks = ticks.wrapping_add(1);
+            if let Some((w, addr)) = mcu.watchpoint_hit.take() {
+                break StopReason::Watchpoint(w, addr);
+            }
+            if step {
+                break StopReason::Step;
+            }
+            if ticks.is_multiple_of(POLL_INTERVAL) && interrupted() {
+                break StopReason::Interrupted;
+            }
+        };
+        self.last_stop = reason;
+        Ok(reason)
+    }
+
+    fn send(stream: &mut TcpStream, data: &str) -> std::io::Result<()> {
+        let packet = format!("${data}#{:02x}", checksum(data.as_bytes()));
+        loop {
+            stream.write_all(packet.as_bytes())?;
+            let mut ack = [0u8];
+            stream.read_exact(&mut ack)?;
+            if ack[0] != b'-' {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Reads the next packet, acknowledging it. Returns `None` when the connection is closed.
+    fn receive(stream: &mut TcpStream) -> std::io::Result<Option<String>> {
+        let mut byte = [0u8];
+        loop {
+            // Skip acks and interrupt requests while halted
+            loop {
+                if stream.read(&mut byte)? == 0 {
+                    return Ok(None);
+                }
+                if byte[0] == b'$' {
+                    break;
+                }
+            }
+            let mut data = Vec::new();
+            loop {
+                stream.read_exact(&mut byte)?;
+                if byte[0] == b'#' {
+                    break;
+                }
+                data.push(byte[0]);
+            }
+            let mut cs = [0u8; 2];
+            stream.read_exact(&mut cs)?;
+            let expected = std::str::from_utf8(&cs)
+                .ok()
+                .and_then(|cs| u8::from_str_radix(cs, 16).ok());
+            if expected == Some(checksum(&data)) {
+                stream.write_all(b"+")?;
+                return Ok(Some(String::from_utf8_lossy(&data).into_owned()));
+            }
+            stream.write_all(b"-")?;
+        }
+    }
+
+    /// Checks, without blocking, if the debugger sent an interrupt request (0x03)
+    fn poll_interrupt(stream: &mut TcpStream) -> bool {
+        let mut byte = [0u8];
+        if stream.set_nonblocking(true).is_err() {
+            return false;
+        }
+        let r = stream.read(&mut byte);
+        let _ = stream.set_nonblocking(false);
+        matches!(r, Ok(1) if byte[0] == 0x03)
+    }
+
+    /// Serves a debugger connection until it detaches, kills the target, the target halts or
+    /// the connection is closed. Returns the last action taken.
+    pub fn serve(
+        &mut self,
+        mcu: &mut MCU,
+        mut stream: TcpStream,
+        dump: &mut DumpHandler,
+    ) -> std::io::Result<Action> {
+        stream.set_nodelay(true)?;
+        while let Some(packet) = Self::receive(&mut stream)? {
+            log::debug!("gdb <- {packet}");
+            match self.process(mcu, &packet) {
+                Action::Reply(reply) => Self::send(&mut stream, &reply)?,
+                Action::Resume { step } => {
+                    let mut poll_stream = stream.try_clone()?;
+                    let reason = self.resume(
+                        mcu,
+                        step,
+                        &mut || Self::poll_interrupt(&mut poll_stream),
+                        dump,
+                    )?;
+                    Self::send(&mut stream, &reason.reply())?;
+                    if let StopReason::Halted(_) = reason {
+                        return Ok(Action::Kill);
+                    }
+                }
+                Action::Detach => {
+                    Self::send(&mut stream, "OK")?;
+                    return Ok(Action::Detach);
+                }
+                Action::Kill => return Ok(Action::Kill),
+            }
+        }
+        Ok(Action::Kill)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mcu::test_mcu;
+
+    // addi x1, x1, 1
+    const ADDI: u32 = 0x0010_8093;
+
+    fn mcu() -> MCU {
+        let mut mcu = test_mcu(0x100, vec![]);
+        for addr in (0..0x40).step_by(4) {
+            mcu.mmu.ww(addr, ADDI).unwrap();
+        }
+        mcu
+    }
+
+    fn reply(stub: &mut GdbStub, mcu: &mut MCU, packet: &str) -> String {
+        match stub.process(mcu, packet) {
+            Action::Reply(r) => r,
+            other => panic!("unexpected action {other:?}"),
+        }
+    }
+
+    fn resume(stub: &mut GdbStub, mcu: &mut MCU, step: bool) -> StopReason {
+        let mut dump = |_: &MCU, range| panic!("unexpected dump of {range:x?}");
+        stub.resume(mcu, step, &mut || false, &mut dump).unwrap()
+    }
+
+    #[test]
+    fn packet_checksum() {
+        assert_eq!(checksum(b"OK"), 0x9a);
+        assert_eq!(checksum(b""), 0);
+    }
+
+    #[test]
+    fn registers() {
+        let mut stub = GdbStub::new();
+        let mut mcu = mcu();
+        mcu.cpu.set_x(1, 0x1234_5678);
+        mcu.cpu.pc = 0x20;
+
+        let g = reply(&mut stub, &mut mcu, "g");
+        assert_eq!(g.len(), 33 * 8);
+        assert_eq!(&g[8..16], "78563412");
+        assert_eq!(&g[32 * 8..], "20000000");
+
+        assert_eq!(reply(&mut stub, &mut mcu, "P2=efbeadde"), "OK");
+        assert_eq!(mcu.cpu.get_x(2), 0xdead_beef);
+        assert_eq!(reply(&mut stub, &mut mcu, "p20"), "20000000");
+
+        // mscratch is register 65 + 0x340
+        assert_eq!(reply(&mut stub, &mut mcu, "P381=01000000"), "OK");
+        assert_eq!(mcu.cpu.get_csr(CSRs::mscratch as u32).unwrap(), 1);
+        assert_eq!(reply(&mut stub, &mut mcu, "p381"), "01000000");
+        assert_eq!(reply(&mut stub, &mut mcu, "p1000"), "E01");
+
+        // CSR writes keep WARL fields legal and fail on read-only CSRs, at any privilege level
+        mcu.cpu.privilege = crate::cpu::Privilege::User;
+        assert_eq!(reply(&mut stub, &mut mcu, "P346=03010000"), "OK");
+        assert_eq!(mcu.cpu.get_csr(CSRs::mtvec as u32).unwrap(), 0x100);
+        assert_eq!(reply(&mut stub, &mut mcu, "Pf55=01000000"), "E01");
+        assert_eq!(mcu.cpu.get_csr(CSRs::mhartid as u32).unwrap(), 0);
+    }
+
+    #[test]
+    fn memory() {
+        let mut stub = GdbStub::new();
+        let mut mcu = mcu();
+        assert_eq!(reply(&mut stub, &mut mcu, "M80,2:abcd"), "OK");
+        assert_eq!(reply(&mut stub, &mut mcu, "m80,3"), "abcd00");
+        assert_eq!(mcu.mmu.rhw(0x80), Ok(0xcdab));
+        assert_eq!(reply(&mut stub, &mut mcu, "m1000,4"), "E14");
+    }
+
+    #[test]
+    fn virtual_memory() {
+        use crate::cpu::Privilege;
+        use crate::memory::sv32::SATP_MODE;
+        use crate::pmp::{PMPADDR0, PMPCFG0};
+
+        let mut stub = GdbStub::new();
+        let mut mcu = test_mcu(0x1000, vec![]);
+        // replies are limited to the packet size
+        let r = reply(&mut stub, &mut mcu, "m0,1000");
+        assert_eq!(r.len(), PACKET_SIZE - 2);
+
+        // a megapage maps 0x4000_0000 to 0 for the S-mode hart
+        mcu.mmu.ww(0x100 * 4, 0xcf).unwrap();
+        mcu.mmu.ww(0x80, 0xdead_beef).unwrap();
+        mcu.cpu.set_csr(CSRs::satp as u32, SATP_MODE).unwrap();
+        mcu.cpu.set_csr(PMPADDR0, u32::MAX).unwrap();
+        mcu.cpu.set_csr(PMPCFG0, 0x1f).unwrap();
+        mcu.cpu.privilege = Privilege::Supervisor;
+        assert_eq!(reply(&mut stub, &mut mcu, "m40000080,4"), "efbeadde");
+        assert_eq!(reply(&mut stub, &mut mcu, "M40000080,1:01"), "OK");
+        assert_eq!(mcu.mmu.rb(0x80), Ok(1));
+        assert_eq!(
+            reply(&mut stub, &mut mcu, "m80,4"),
+            "E14",
+            "0x80 isn't mapped"
+        );
+    }
+
+    #[test]
+    fn breakpoints() {
+        let mut stub = GdbStub::new();
+        let mut mcu = mcu();
+        assert_eq!(reply(&mut stub, &mut mcu, "Z0,8,4"), "OK");
+        assert_eq!(stub.process(&mut mcu, "c"), Action::Resume { step: false });
+        assert_eq!(resume(&mut stub, &mut mcu, false), StopReason::SwBreakpoint);
+        assert_eq!(mcu.cpu.pc, 8);
+        assert_eq!(mcu.cpu.get_x(1), 2);
+        assert_eq!(reply(&mut stub, &mut mcu, "?"), "T05swbreak:;");
+
+        assert_eq!(resume(&mut stub, &mut mcu, true), StopReason::Step);
+        assert_eq!(mcu.cpu.pc, 0xc);
+
+        assert_eq!(reply(&mut stub, &mut mcu, "z0,8,4"), "OK");
+        assert_eq!(reply(&mut stub, &mut mcu, "Z1,14,4"), "OK");
+        assert_eq!(resume(&mut stub, &mut mcu, false), StopReason::HwBreakpoint);
+        assert_eq!(mcu.cpu.pc, 0x14);
+    }
+
+    #[test]
+    fn watchpoints() {
+        let mut stub = GdbStub::new();
+        let mut mcu = mcu();
+        // sw x1, 0x80(x0)
+        mcu.mmu.ww(0x8, 0x0810_2023).unwrap();
+        assert_eq!(reply(&mut stub, &mut mcu, "Z2,80,4"), "OK");
+        let reason = resume(&mut stub, &mut mcu, false);
+        assert_eq!(reply(&mut stub, &mut mcu, "?"), "T05watch:80;");
+        assert!(matches!(reason, StopReason::Watchpoint(_, 0x80)));
+        assert_eq!(mcu.cpu.pc, 0xc);
+        assert_eq!(mcu.mmu.rw(0x80), Ok(2));
+
+        assert_eq!(reply(&mut stub, &mut mcu, "z2,80,4"), "OK");
+        assert!(mcu.watchpoints.is_empty());
+    }
+
+    #[test]
+    fn exit_code() {
+        let mut stub = GdbStub::new();
+        let mut mcu = mcu();
+        // li a1, 3; li a0, 255; ecall
+        mcu.mmu.ww(0x0, 0x0030_0593).unwrap();
+        mcu.mmu.ww(0x4, 0x0ff0_0513).unwrap();
+        mcu.mmu.ww(0x8, 0x0000_0073).unwrap();
+        assert_eq!(resume(&mut stub, &mut mcu, false), StopReason::Halted(3));
+        assert_eq!(reply(&mut stub, &mut mcu, "?"), "W03");
+        assert_eq!(stub.exit_code(), Some(3));
+    }
+
+    #[test]
+    fn target_description() {
+        let mut stub = GdbStub::new();
+        let mut mcu = mcu();
+        assert!(reply(&mut stub, &mut mcu, "qSupported:swbreak+").contains("qXfer:features:read+"));
+        let xml = reply(&mut stub, &mut mcu, "qXfer:features:read:target.xml:0,fff");
+        assert!(xml.starts_with("l<?xml"));
+        assert!(xml.contains("name=\"mepc\" bitsize=\"32\" type=\"int\" regnum=\"898\""));
+        let part = reply(&mut stub, &mut mcu, "qXfer:features:read:target.xml:0,10");
+        assert_eq!(part.len(), 0x11);
+        assert!(part.starts_with('m'));
+        assert_eq!(reply(&mut stub, &mut mcu, "vMustReplyEmpty"), "");
+    }
+
+    #[test]
+    fn debug_mode() {
+        let mut stub = GdbStub::new();
+        let mut mcu = mcu();
+        mcu.cpu
+            .set_csr(CSRs::dcsr as u32, crate::cpu::DCSR_EBREAKM)
+            .unwrap();
+        // ebreak
+        mcu.mmu.ww(0x8, 0x0010_0073).unwrap();
+        assert_eq!(resume(&mut stub, &mut mcu, false), StopReason::DebugMode);
+        assert_eq!(reply(&mut stub, &mut mcu, "?"), "S05");
+        assert_eq!(mcu.cpu.pc, 0x8);
+        assert_eq!(mcu.cpu.get_x(1), 2);
+
+        // the debugger skips the ebreak
+        mcu.cpu.pc = 0xc;
+        assert_eq!(resume(&mut stub, &mut mcu, true), StopReason::Step);
+        assert!(!mcu.cpu.debug_mode);
+        assert_eq!(mcu.cpu.pc, 0x10);
+        assert_eq!(mcu.cpu.get_x(1), 3);
+    }
+
+    #[test]
+    fn dump() {
+        let mut stub = GdbStub::new();
+        let mut mcu = mcu();
+        // li a0, 254; li a1, 0x80; li a2, 0x83; ecall
+        mcu.mmu.ww(0x0, 0x0fe0_0513).unwrap();
+        mcu.mmu.ww(0x4, 0x0800_0593).unwrap();
+        mcu.mmu.ww(0x8, 0x0830_0613).unwrap();
+        mcu.mmu.ww(0xc, 0x0000_0073).unwrap();
+        assert_eq!(reply(&mut stub, &mut mcu, "Z0,14,4"), "OK");
+        let mut dumps = Vec::new();
+        let mut dump = |_: &MCU, range| {
+            dumps.push(range);
+            Ok(())
+        };
+        let reason = stub.resume(&mut mcu, false, &mut || false, &mut dump);
+        assert_eq!(reason.unwrap(), StopReason::SwBreakpoint);
+        assert_eq!(dumps, vec![0x80..=0x83]);
+        assert_eq!(mcu.cpu.get_x(1), 1, "the program continued after the dump");
+    }
+}
@@ -1,6 +1,10 @@
+use crate::cpu::HpmEvent;
 use crate::mcu::MCU;
 pub mod privileged;
+pub mod rv32a;
+pub mod rv32c;
 pub mod rv32i;
+pub mod rv32m;
 
 pub trait Instruction: TryFrom<u32> + Into<u32> {
     fn execute(&self, mcu: &mut MCU) -> Result<u32, ExceptionInterrupt>;
@@ -9,46 +13,54 @@ pub trait Instruction: TryFrom<u32> + Into<u32> {
     }
 }
 
+/// Checks that a jump target is aligned to the instruction alignment, the jump raises an
+/// InstructionAddressMissaligned exception with the target in mtval otherwise
+pub(crate) fn check_target(mcu: &mut MCU, target: u32) -> Result<(), ExceptionInterrupt> {
+    if target & (mcu.cpu.ialign() - 1) != 0 {
+        let e = mcu.fault(Exception::InstructionAddressMissaligned, target);
+        return Err(ExceptionInterrupt::Exception(e));
+    }
+    Ok(())
+}
+
+/// Jumps to the pc plus the sign extended `offset` on a taken conditional branch
+pub(crate) fn take_branch(mcu: &mut MCU, offset: u32) -> Result<(), ExceptionInterrupt> {
+    let target = mcu.cpu.pc.wrapping_add(offset);
+    check_target(mcu, target)?;
+    mcu.cpu.pc = target;
+    mcu.cpu.count_event(HpmEvent::TakenBranch);
+    Ok(())
+}
+
 // external
 // software
 // timer
 
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Interrupt {
-    #[allow(dead_code)]
     SSoftInterrupt = 1,
     MSoftInterrupt = 3,
-    #[allow(dead_code)]
     STimerInterrupt = 5,
     MTimerInterrupt = 7,
-    #[allow(dead_code)]
     SExternalInterrupt = 9,
     MExternalInterrupt = 11,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Exception {
-    #[allow(dead_code)]
     InstructionAddressMissaligned = 0,
     InstructionAccessFault = 1,
     IllegalInstruction = 2,
     Breakpoint = 3,
-    #[allow(dead_code)]
     LoadAddressMisaligned = 4,
     LoadAccessFault = 5,
-    #[allow(dead_code)]
     StoreAddressMisaligned = 6,
     StoreAccessFault = 7,
-    #[allow(dead_code)]
     UEnvironmentCall = 8,
-    #[allow(dead_code)]
     SEnvironmentCall = 9,
     MEnvironmentCall = 11,
-    #[allow(dead_code)]
     InstructionPageFault = 12,
-    #[allow(dead_code)]
     LoadPageFault = 13,
-    #[allow(dead_code)]
     StorePageFault = 14,
 }
 
@@ -1,28 +1,108 @@
-use super::ExceptionInterrupt;
 use super::Instruction;
-use crate::cpu::CSRs;
+use super::{Exception, ExceptionInterrupt};
+use crate::cpu::{
+    CSRs, Privilege, MSTATUS_MIE, MSTATUS_MPIE, MSTATUS_MPP, MSTATUS_MPP_SHIFT, MSTATUS_MPRV,
+    MSTATUS_SIE, MSTATUS_SPIE, MSTATUS_SPP, MSTATUS_TSR, MSTATUS_TVM, MSTATUS_TW,
+};
 use crate::mcu::MCU;
-use riscv_isa_types::privileged::{RVPrivileged, MRET, WFI};
+use riscv_isa_types::privileged::{RVPrivileged, DRET, MRET, SFENCEVMA, SRET, WFI};
 
 impl Instruction for MRET {
     fn execute(&self, mcu: &mut MCU) -> Result<u32, ExceptionInterrupt> {
+        if mcu.cpu.privilege < Privilege::Machine {
+            return Err(ExceptionInterrupt::Exception(Exception::IllegalInstruction));
+        }
         mcu.cpu.pc = mcu.cpu.get_csr(CSRs::mepc as u32).unwrap();
-        // TODO Set MPP privilege mode.
         let mstatus = mcu.cpu.get_csr(CSRs::mstatus as u32).unwrap();
-        let mstatus = mstatus | ((mstatus & (1 << 7)) >> 4); // recover mie from mpie
-        let mstatus = mstatus | 1 << 7; // set mpie to 1
 
-        mcu.int_ctrl.reset(&mut mcu.cpu);
+        // recover mie from mpie and set mpie to 1
+        let mut status = (mstatus & !MSTATUS_MIE) | MSTATUS_MPIE;
+        if mstatus & MSTATUS_MPIE != 0 {
+            status |= MSTATUS_MIE;
+        }
+
+        // return to the privilege level in mpp and set mpp to the least privileged mode
+        let privilege = Privilege::from_bits((mstatus & MSTATUS_MPP) >> MSTATUS_MPP_SHIFT);
+        status &= !MSTATUS_MPP;
+        if privilege != Privilege::Machine {
+            status &= !MSTATUS_MPRV;
+        }
+        mcu.cpu.privilege = privilege;
+
+        mcu.cpu.set_csr(CSRs::mstatus as u32, status).unwrap();
+        Ok(1)
+    }
+
+    fn update_pc(&self, _mcu: &mut MCU) {}
+}
+
+impl Instruction for SRET {
+    fn execute(&self, mcu: &mut MCU) -> Result<u32, ExceptionInterrupt> {
+        let mstatus = mcu.cpu.get_csr(CSRs::mstatus as u32).unwrap();
+        // mstatus.TSR traps sret in S-mode
+        if mcu.cpu.privilege < Privilege::Supervisor
+            || (mcu.cpu.privilege == Privilege::Supervisor && mstatus & MSTATUS_TSR != 0)
+        {
+            return Err(ExceptionInterrupt::Exception(Exception::IllegalInstruction));
+        }
+        mcu.cpu.pc = mcu.cpu.get_csr(CSRs::sepc as u32).unwrap();
+
+        // recover sie from spie, set spie to 1 and return to the privilege level in spp
+        let mut status = (mstatus & !MSTATUS_SIE) | MSTATUS_SPIE;
+        if mstatus & MSTATUS_SPIE != 0 {
+            status |= MSTATUS_SIE;
+        }
+        let privilege = if mstatus & MSTATUS_SPP != 0 {
+            Privilege::Supervisor
+        } else {
+            Privilege::User
+        };
+        // sret always returns below M-mode
+        status &= !(MSTATUS_SPP | MSTATUS_MPRV);
+        mcu.cpu.privilege = privilege;
+
+        mcu.cpu.set_csr(CSRs::mstatus as u32, status).unwrap();
+        Ok(1)
+    }
+
+    fn update_pc(&self, _mcu: &mut MCU) {}
+}
 
-        mcu.cpu.set_csr(CSRs::mstatus as u32, mstatus).unwrap();
+impl Instruction for DRET {
+    fn execute(&self, mcu: &mut MCU) -> Result<u32, ExceptionInterrupt> {
+        if !mcu.cpu.debug_mode {
+            return Err(ExceptionInterrupt::Exception(Exception::IllegalInstruction));
+        }
+        mcu.cpu.leave_debug_mode();
         Ok(1)
     }
 
     fn update_pc(&self, _mcu: &mut MCU) {}
 }
 
+impl Instruction for SFENCEVMA {
+    fn execute(&self, mcu: &mut MCU) -> Result<u32, ExceptionInterrupt> {
+        let mstatus = mcu.cpu.get_csr(CSRs::mstatus as u32).unwrap();
+        // mstatus.TVM traps sfence.vma in S-mode
+        if mcu.cpu.privilege < Privilege::Supervisor
+            || (mcu.cpu.privilege == Privilege::Supervisor && mstatus & MSTATUS_TVM != 0)
+        {
+            return Err(ExceptionInterrupt::Exception(Exception::IllegalInstruction));
+        }
+        // x0 selects every address or address space
+        let vaddr = (self.rs1 != 0).then(|| mcu.cpu.get_x(self.rs1));
+        let asid = (self.rs2 != 0).then(|| mcu.cpu.get_x(self.rs2) & 0x1ff);
+        mcu.tlb.flush(vaddr, asid);
+        Ok(1)
+    }
+}
+
 impl Instruction for WFI {
     fn execute(&self, mcu: &mut MCU) -> Result<u32, ExceptionInterrupt> {
+        let mstatus = mcu.cpu.get_csr(CSRs::mstatus as u32).unwrap();
+        if mcu.cpu.privilege < Privilege::Machine && mstatus & MSTATUS_TW != 0 {
+            return Err(ExceptionInterrupt::Exception(Exception::IllegalInstruction));
+        }
         mcu.cpu.wfi = true;
         Ok(1)
     }
@@ -37,6 +117,9 @@ impl Instruction for RVPrivileged {
         use RVPrivileged::*;
         match self {
             MRET(i) => i.execute(mcu),
+            SRET(i) => i.execute(mcu),
+            DRET(i) => i.execute(mcu),
+            SFENCEVMA(i) => i.execute(mcu),
             WFI(i) => i.execute(mcu),
         }
     }
@@ -45,7 +128,343 @@ impl Instruction for RVPrivileged {
         use RVPrivileged::*;
         match self {
             MRET(i) => i.update_pc(mcu),
+            SRET(i) => i.update_pc(mcu),
+            DRET(i) => i.update_pc(mcu),
+            SFENCEVMA(i) => i.update_pc(mcu),
             WFI(i) => i.update_pc(mcu),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::{DebugCause, DCSR_CAUSE, DCSR_CAUSE_SHIFT, DCSR_EBREAKU, DCSR_STEP};
+    use crate::instructions::Interrupt;
+    use crate::mcu::{test_mcu, TickResult};
+    use crate::memory::Memory;
+    use crate::pmp::{PMPADDR0, PMPCFG0};
+
+    const MRET: u32 = 0x3020_0073;
+    const SRET: u32 = 0x1020_0073;
+    // sfence.vma zero, zero
+    const SFENCE_VMA: u32 = 0x1200_0073;
+    const ECALL: u32 = 0x0000_0073;
+    const EBREAK: u32 = 0x0010_0073;
+    const DRET: u32 = 0x7b20_0073;
+    // addi ra, ra, 1
+    const ADDI: u32 = 0x0010_8093;
+    // csrr t0, mstatus
+    const CSRR_MSTATUS: u32 = 0x3000_22f3;
+    const MTVEC: u32 = 0x80;
+    const STVEC: u32 = 0xc0;
+
+    fn mcu() -> MCU {
+        let mut mcu = test_mcu(0x100, vec![]);
+        mcu.cpu.set_csr(CSRs::mtvec as u32, MTVEC).unwrap();
+        mcu.cpu.set_csr(CSRs::stvec as u32, STVEC).unwrap();
+        // S and U-mode can only access the memory granted by the PMP, allow everything
+        mcu.cpu.set_csr(PMPADDR0, u32::MAX).unwrap();
+        mcu.cpu.set_csr(PMPCFG0, 0x1f).unwrap();
+        mcu
+    }
+
+    fn mstatus(mcu: &MCU) -> u32 {
+        mcu.cpu.get_csr(CSRs::mstatus as u32).unwrap()
+    }
+
+    #[test]
+    fn mret_to_user_mode() {
+        let mut mcu = mcu();
+        mcu.mmu.ww(0x0, MRET).unwrap();
+        mcu.mmu.ww(0x40, CSRR_MSTATUS).unwrap();
+        mcu.cpu.set_csr(CSRs::mepc as u32, 0x40).unwrap();
+        mcu.cpu.set_csr(CSRs::mstatus as u32, MSTATUS_MPRV).unwrap();
+
+        mcu.tick();
+        assert_eq!(mcu.cpu.pc, 0x40);
+        assert_eq!(mcu.cpu.privilege, Privilege::User);
+        assert_eq!(
+            mstatus(&mcu) & MSTATUS_MPRV,
+            0,
+            "mret to U-mode clears mprv"
+        );
+
+        // M-mode CSRs can't be accessed from U-mode
+        mcu.tick();
+        assert_eq!(mcu.cpu.pc, MTVEC);
+        assert_eq!(mcu.cpu.privilege, Privilege::Machine);
+        assert_eq!(mcu.cpu.get_csr(CSRs::mcause as u32).unwrap(), 2);
+        assert_eq!(mstatus(&mcu) & MSTATUS_MPP, 0, "the trap came from U-mode");
+    }
+
+    #[test]
+    fn mret_restores_mie() {
+        let mut mcu = mcu();
+        mcu.mmu.ww(0x0, MRET).unwrap();
+        mcu.mmu.ww(0x40, MRET).unwrap();
+        mcu.cpu.set_csr(CSRs::mepc as u32, 0x40).unwrap();
+        mcu.cpu
+            .set_csr(CSRs::mstatus as u32, MSTATUS_MPP | MSTATUS_MIE)
+            .unwrap();
+
+        // mpie is clear, so mret disables interrupts
+        mcu.tick();
+        assert_eq!(mstatus(&mcu) & (MSTATUS_MIE | MSTATUS_MPIE), MSTATUS_MPIE);
+
+        mcu.cpu
+            .set_csr(CSRs::mstatus as u32, MSTATUS_MPP | MSTATUS_MPIE)
+            .unwrap();
+        mcu.tick();
+        assert_eq!(
+            mstatus(&mcu) & (MSTATUS_MIE | MSTATUS_MPIE),
+            MSTATUS_MIE | MSTATUS_MPIE
+        );
+    }
+
+    #[test]
+    fn user_ecall() {
+        let mut mcu = mcu();
+        mcu.mmu.ww(0x0, MRET).unwrap();
+        mcu.mmu.ww(0x40, ECALL).unwrap();
+        mcu.cpu.set_csr(CSRs::mepc as u32, 0x40).unwrap();
+
+        mcu.tick();
+        mcu.tick();
+        assert_eq!(mcu.cpu.get_csr(CSRs::mcause as u32).unwrap(), 8);
+        assert_eq!(mcu.cpu.get_csr(CSRs::mepc as u32).unwrap(), 0x40);
+
+        // returning with mpp = M stays in M-mode
+        mcu.mmu.ww(MTVEC, MRET).unwrap();
+        mcu.cpu.set_csr(CSRs::mstatus as u32, MSTATUS_MPP).unwrap();
+        mcu.tick();
+        assert_eq!(mcu.cpu.privilege, Privilege::Machine);
+        assert_eq!(mcu.data_privilege(), Privilege::Machine);
+    }
+
+    #[test]
+    fn mprv() {
+        let mut mcu = mcu();
+        mcu.cpu.set_csr(CSRs::mstatus as u32, MSTATUS_MPRV).unwrap();
+        assert_eq!(mcu.data_privilege(), Privilege::User);
+        mcu.cpu.set_csr(CSRs::mstatus as u32, MSTATUS_MPP).unwrap();
+        assert_eq!(mcu.data_privilege(), Privilege::Machine);
+    }
+
+    #[test]
+    fn delegated_ecall() {
+        let mut mcu = mcu();
+        mcu.cpu
+            .set_csr(
+                CSRs::medeleg as u32,
+                1 << Exception::UEnvironmentCall as u32,
+            )
+            .unwrap();
+        mcu.cpu.privilege = Privilege::User;
+        mcu.cpu.pc = 0x40;
+        mcu.mmu.ww(0x40, ECALL).unwrap();
+        mcu.mmu.ww(STVEC, SRET).unwrap();
+
+        mcu.tick();
+        assert_eq!(mcu.cpu.pc, STVEC);
+        assert_eq!(mcu.cpu.privilege, Privilege::Supervisor);
+        assert_eq!(mcu.cpu.get_csr(CSRs::scause as u32).unwrap(), 8);
+        assert_eq!(mcu.cpu.get_csr(CSRs::sepc as u32).unwrap(), 0x40);
+        assert_eq!(mstatus(&mcu) & MSTATUS_SPP, 0, "the trap came from U-mode");
+        assert_eq!(mcu.cpu.get_csr(CSRs::mcause as u32).unwrap(), 0);
+
+        mcu.cpu.set_csr(CSRs::sepc as u32, 0x44).unwrap();
+        mcu.tick();
+        assert_eq!(mcu.cpu.pc, 0x44);
+        assert_eq!(mcu.cpu.privilege, Privilege::User);
+
+        // sret is illegal in U-mode
+        mcu.mmu.ww(0x44, SRET).unwrap();
+        mcu.tick();
+        assert_eq!(mcu.cpu.privilege, Privilege::Machine);
+        assert_eq!(mcu.cpu.get_csr(CSRs::mcause as u32).unwrap(), 2);
+    }
+
+    #[test]
+    fn supervisor_ecall_not_delegated() {
+        let mut mcu = mcu();
+        mcu.cpu.privilege = Privilege::Supervisor;
+        mcu.mmu.ww(0x0, ECALL).unwrap();
+
+        mcu.tick();
+        assert_eq!(mcu.cpu.pc, MTVEC);
+        assert_eq!(mcu.cpu.privilege, Privilege::Machine);
+        assert_eq!(mcu.cpu.get_csr(CSRs::mcause as u32).unwrap(), 9);
+        assert_eq!(
+            Privilege::from_bits((mstatus(&mcu) & MSTATUS_MPP) >> MSTATUS_MPP_SHIFT),
+            Privilege::Supervisor
+        );
+
+        // mstatus.TSR makes sret illegal in S-mode
+        mcu.cpu.privilege = Privilege::Supervisor;
+        mcu.cpu.set_csr(CSRs::mstatus as u32, MSTATUS_TSR).unwrap();
+        mcu.mmu.ww(MTVEC, SRET).unwrap();
+        mcu.tick();
+        assert_eq!(mcu.cpu.get_csr(CSRs::mcause as u32).unwrap(), 2);
+    }
+
+    #[test]
+    fn supervisor_views() {
+        let mut mcu = mcu();
+        mcu.cpu.write_csr(CSRs::sstatus as u32, u32::MAX).unwrap();
+        assert_eq!(mstatus(&mcu) & MSTATUS_MPP, 0, "sstatus can't write mpp");
+        assert_ne!(mstatus(&mcu) & MSTATUS_SIE, 0);
+
+        // sie and sip only show the delegated interrupts
+        let sti = 1 << Interrupt::STimerInterrupt as u32;
+        mcu.cpu.write_csr(CSRs::mie as u32, u32::MAX).unwrap();
+        assert_eq!(mcu.cpu.get_csr(CSRs::sie as u32).unwrap(), 0);
+        mcu.cpu.write_csr(CSRs::mideleg as u32, u32::MAX).unwrap();
+        assert_eq!(
+            mcu.cpu.get_csr(CSRs::mideleg as u32).unwrap(),
+            0x222,
+            "only S interrupts can be delegated"
+        );
+        mcu.cpu.write_csr(CSRs::sie as u32, sti).unwrap();
+        assert_eq!(mcu.cpu.get_csr(CSRs::sie as u32).unwrap(), sti);
+        assert_eq!(
+            mcu.cpu.get_csr(CSRs::mie as u32).unwrap(),
+            0x888 | sti,
+            "sie only writes the delegated bits of mie"
+        );
+    }
+
+    #[test]
+    fn delegated_interrupt() {
+        let mut mcu = mcu();
+        let sti = 1 << Interrupt::STimerInterrupt as u32;
+        mcu.cpu.set_csr(CSRs::mideleg as u32, sti).unwrap();
+        mcu.cpu.set_csr(CSRs::mie as u32, sti).unwrap();
+        mcu.cpu.set_csr(CSRs::mip as u32, sti).unwrap();
+
+        // delegated interrupts are never taken in M-mode
+        mcu.cpu
+            .set_csr(CSRs::mstatus as u32, crate::cpu::MSTATUS_MIE)
+            .unwrap();
+        assert_eq!(mcu.cpu.get_interrupt(), None);
+        // in S-mode they depend on sstatus.SIE
+        mcu.cpu.privilege = Privilege::Supervisor;
+        assert_eq!(mcu.cpu.get_interrupt(), None);
+        mcu.cpu.set_csr(CSRs::sstatus as u32, MSTATUS_SIE).unwrap();
+        assert_eq!(mcu.cpu.get_interrupt(), Some(Interrupt::STimerInterrupt));
+        // and are always enabled in U-mode
+        mcu.cpu.set_csr(CSRs::sstatus as u32, 0).unwrap();
+        mcu.cpu.privilege = Privilege::User;
+        mcu.cpu.pc = 0x40;
+
+        mcu.tick();
+        assert_eq!(mcu.cpu.pc, STVEC);
+        assert_eq!(mcu.cpu.privilege, Privilege::Supervisor);
+        assert_eq!(
+            mcu.cpu.get_csr(CSRs::scause as u32).unwrap(),
+            (1 << 31) | Interrupt::STimerInterrupt as u32
+        );
+        assert_eq!(mcu.cpu.get_csr(CSRs::sepc as u32).unwrap(), 0x40);
+    }
+
+    #[test]
+    fn sfence_vma() {
+        let mut mcu = mcu();
+        mcu.mmu.ww(0x0, SFENCE_VMA).unwrap();
+        mcu.mmu.ww(0x4, SFENCE_VMA).unwrap();
+        mcu.cpu.privilege = Privilege::Supervisor;
+        mcu.tick();
+        assert_eq!(mcu.cpu.pc, 0x4);
+
+        // mstatus.TVM traps it in S-mode
+        mcu.cpu.set_csr(CSRs::mstatus as u32, MSTATUS_TVM).unwrap();
+        mcu.tick();
+        assert_eq!(mcu.cpu.pc, MTVEC);
+        assert_eq!(mcu.cpu.get_csr(CSRs::mcause as u32).unwrap(), 2);
+    }
+
+    #[test]
+    fn vectored_traps() {
+        let mut mcu = mcu();
+        mcu.cpu.set_csr(CSRs::mtvec as u32, MTVEC | 1).unwrap();
+        mcu.cpu
+            .set_csr(CSRs::mie as u32, 1 << Interrupt::MTimerInterrupt as u32)
+            .unwrap();
+        mcu.cpu
+            .set_csr(CSRs::mip as u32, 1 << Interrupt::MTimerInterrupt as u32)
+            .unwrap();
+        mcu.cpu.privilege = Privilege::User;
+
+        // interrupts jump to BASE + 4 * cause
+        mcu.tick();
+        assert_eq!(mcu.cpu.pc, MTVEC + 4 * 7);
+
+        // exceptions jump to BASE
+        mcu.cpu.set_csr(CSRs::mie as u32, 0).unwrap();
+        mcu.cpu.privilege = Privilege::User;
+        mcu.cpu.pc = 0x40;
+        mcu.mmu.ww(0x40, ECALL).unwrap();
+        mcu.tick();
+        assert_eq!(mcu.cpu.pc, MTVEC);
+
+        // reserved modes keep the current mode
+        mcu.cpu.write_csr(CSRs::mtvec as u32, 0x200 | 0b10).unwrap();
+        assert_eq!(mcu.cpu.get_csr(CSRs::mtvec as u32).unwrap(), 0x201);
+        mcu.cpu.write_csr(CSRs::stvec as u32, 0x200 | 0b11).unwrap();
+        assert_eq!(mcu.cpu.get_csr(CSRs::stvec as u32).unwrap(), 0x200);
+    }
+
+    #[test]
+    fn debug_mode() {
+        let mut mcu = mcu();
+        let cause = |mcu: &MCU| {
+            (mcu.cpu.get_csr(CSRs::dcsr as u32).unwrap() & DCSR_CAUSE) >> DCSR_CAUSE_SHIFT
+        };
+        mcu.mmu.ww(0x0, DRET).unwrap();
+        mcu.mmu.ww(0x40, EBREAK).unwrap();
+        mcu.mmu.ww(0x44, ADDI).unwrap();
+        mcu.mmu.ww(0x48, ADDI).unwrap();
+
+        mcu.tick();
+        assert_eq!(mcu.cpu.pc, MTVEC, "dret is illegal outside Debug mode");
+        assert_eq!(mcu.cpu.get_csr(CSRs::mcause as u32).unwrap(), 2);
+
+        // ebreaku makes U-mode breakpoints enter Debug mode instead of trapping
+        mcu.cpu.set_csr(CSRs::dcsr as u32, DCSR_EBREAKU).unwrap();
+        mcu.cpu.privilege = Privilege::User;
+        mcu.cpu.pc = 0x40;
+        let minstret = mcu.cpu.get_csr(CSRs::minstret as u32).unwrap();
+        mcu.tick();
+        assert!(mcu.cpu.debug_mode);
+        assert_eq!(cause(&mcu), DebugCause::Ebreak as u32);
+        assert_eq!(
+            mcu.cpu.get_csr(CSRs::minstret as u32).unwrap(),
+            minstret,
+            "the ebreak doesn't retire"
+        );
+        assert_eq!(mcu.cpu.get_csr(CSRs::dpc as u32).unwrap(), 0x40);
+        assert!(matches!(mcu.tick(), TickResult::Debug));
+        assert_eq!(mcu.cpu.pc, 0x40, "a halted hart doesn't execute");
+
+        // single step the next instruction
+        mcu.cpu.set_csr(CSRs::dpc as u32, 0x44).unwrap();
+        mcu.cpu
+            .set_csr(CSRs::dcsr as u32, DCSR_STEP | Privilege::User as u32)
+            .unwrap();
+        mcu.cpu.leave_debug_mode();
+        mcu.tick();
+        assert!(mcu.cpu.debug_mode);
+        assert_eq!(cause(&mcu), DebugCause::Step as u32);
+        assert_eq!(mcu.cpu.get_x(1), 1);
+        assert_eq!(mcu.cpu.get_csr(CSRs::dpc as u32).unwrap(), 0x48);
+
+        // dret leaves Debug mode
+        RVPrivileged::try_from(DRET)
+            .unwrap()
+            .execute(&mut mcu)
+            .unwrap();
+        assert!(!mcu.cpu.debug_mode);
+        assert_eq!(mcu.cpu.pc, 0x48);
+        assert_eq!(mcu.cpu.privilege, Privilege::User);
+    }
+}
@@ -0,0 +1,230 @@
+use super::{Exception, ExceptionInterrupt, Instruction};
+use crate::mcu::MCU;
+use riscv_isa_types::rv32a::*;
+
+use ExceptionInterrupt::*;
+
+/// Atomically reads the word at x[rs1], writes `op(word, x[rs2])` back and returns the
+/// original word in x[rd].
+fn amo(
+    mcu: &mut MCU,
+    rd: u32,
+    rs1: u32,
+    rs2: u32,
+    op: fn(u32, u32) -> u32,
+) -> Result<u32, ExceptionInterrupt> {
+    let addr = mcu.cpu.get_x(rs1);
+    if addr & 0b11 != 0 {
+        return Err(Exception(
+            mcu.fault(Exception::StoreAddressMisaligned, addr),
+        ));
+    }
+    // AMOs report access and page faults as store faults
+    let t = mcu.load(addr, 4).map_err(|e| match e {
+        Exception::LoadAccessFault => Exception(Exception::StoreAccessFault),
+        Exception::LoadPageFault => Exception(Exception::StorePageFault),
+        e => Exception(e),
+    })?;
+    mcu.store(addr, 4, op(t, mcu.cpu.get_x(rs2)))
+        .map_err(Exception)?;
+    mcu.cpu.set_x(rd, t);
+    Ok(2)
+}
+
+impl Instruction for LRW {
+    fn execute(&self, mcu: &mut MCU) -> Result<u32, ExceptionInterrupt> {
+        let addr = mcu.cpu.get_x(self.rs1);
+        if addr & 0b11 != 0 {
+            return Err(Exception(mcu.fault(Exception::LoadAddressMisaligned, addr)));
+        }
+        let v = mcu.load(addr, 4).map_err(Exception)?;
+        mcu.cpu.reservation = Some(addr);
+        mcu.cpu.set_x(self.rd, v);
+        Ok(1)
+    }
+}
+
+impl Instruction for SCW {
+    fn execute(&self, mcu: &mut MCU) -> Result<u32, ExceptionInterrupt> {
+        let addr = mcu.cpu.get_x(self.rs1);
+        if addr & 0b11 != 0 {
+            return Err(Exception(
+                mcu.fault(Exception::StoreAddressMisaligned, addr),
+            ));
+        }
+        // The reservation is consumed whether the store succeeds or not
+        let v = if mcu.cpu.reservation.take() == Some(addr) {
+            mcu.store(addr, 4, mcu.cpu.get_x(self.rs2))
+                .map_err(Exception)?;
+            0
+        } else {
+            1
+        };
+        mcu.cpu.set_x(self.rd, v);
+        Ok(1)
+    }
+}
+
+impl Instruction for AMOSWAPW {
+    fn execute(&self, mcu: &mut MCU) -> Result<u32, ExceptionInterrupt> {
+        amo(mcu, self.rd, self.rs1, self.rs2, |_, b| b)
+    }
+}
+
+impl Instruction for AMOADDW {
+    fn execute(&self, mcu: &mut MCU) -> Result<u32, ExceptionInterrupt> {
+        amo(mcu, self.rd, self.rs1, self.rs2, |a, b| a.wrapping_add(b))
+    }
+}
+
+impl Instruction for AMOXORW {
+    fn execute(&self, mcu: &mut MCU) -> Result<u32, ExceptionInterrupt> {
+        amo(mcu, self.rd, self.rs1, self.rs2, |a, b| a ^ b)
+    }
+}
+
+impl Instruction for AMOANDW {
+    fn execute(&self, mcu: &mut MCU) -> Result<u32, ExceptionInterrupt> {
+        amo(mcu, self.rd, self.rs1, self.rs2, |a, b| a & b)
+    }
+}
+
+impl Instruction for AMOORW {
+    fn execute(&self, mcu: &mut MCU) -> Result<u32, ExceptionInterrupt> {
+        amo(mcu, self.rd, self.rs1, self.rs2, |a, b| a | b)
+    }
+}
+
+impl Instruction for AMOMINW {
+    fn execute(&self, mcu: &mut MCU) -> Result<u32, ExceptionInterrupt> {
+        amo(mcu, self.rd, self.rs1, self.rs2, |a, b| {
+            (a as i32).min(b as i32) as u32
+        })
+    }
+}
+
+impl Instruction for AMOMAXW {
+    fn execute(&self, mcu: &mut MCU) -> Result<u32, ExceptionInterrupt> {
+        amo(mcu, self.rd, self.rs1, self.rs2, |a, b| {
+            (a as i32).max(b as i32) as u32
+        })
+    }
+}
+
+impl Instruction for AMOMINUW {
+    fn execute(&self, mcu: &mut MCU) -> Result<u32, ExceptionInterrupt> {
+        amo(mcu, self.rd, self.rs1, self.rs2, |a, b| a.min(b))
+    }
+}
+
+impl Instruction for AMOMAXUW {
+    fn execute(&self, mcu: &mut MCU) -> Result<u32, ExceptionInterrupt> {
+        amo(mcu, self.rd, self.rs1, self.rs2, |a, b| a.max(b))
+    }
+}
+
+impl Instruction for RV32a {
+    fn execute(&self, mcu: &mut MCU) -> Result<u32, ExceptionInterrupt> {
+        use RV32a::*;
+        match self {
+            LRW(i) => i.execute(mcu),
+            SCW(i) => i.execute(mcu),
+            AMOSWAPW(i) => i.execute(mcu),
+            AMOADDW(i) => i.execute(mcu),
+            AMOXORW(i) => i.execute(mcu),
+            AMOANDW(i) => i.execute(mcu),
+            AMOORW(i) => i.execute(mcu),
+            AMOMINW(i) => i.execute(mcu),
+            AMOMAXW(i) => i.execute(mcu),
+            AMOMINUW(i) => i.execute(mcu),
+            AMOMAXUW(i) => i.execute(mcu),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mcu::test_mcu;
+    use crate::memory::Memory;
+
+    fn mcu() -> MCU {
+        test_mcu(0x100, vec![])
+    }
+
+    // lr.w x3, (x1)
+    const LRW: u32 = 0x1000_a1af;
+    // sc.w x3, x2, (x1)
+    const SCW: u32 = 0x1820_a1af;
+    // amoadd.w x3, x2, (x1)
+    const AMOADDW: u32 = 0x0020_a1af;
+
+    fn run(mcu: &mut MCU, word: u32) -> Result<u32, ExceptionInterrupt> {
+        RV32a::try_from(word).unwrap().execute(mcu)
+    }
+
+    #[test]
+    fn lr_sc() {
+        let mut mcu = mcu();
+        mcu.cpu.set_x(1, 0x10);
+        mcu.cpu.set_x(2, 42);
+        mcu.mmu.ww(0x10, 7).unwrap();
+
+        run(&mut mcu, LRW).unwrap();
+        assert_eq!(mcu.cpu.get_x(3), 7);
+        run(&mut mcu, SCW).unwrap();
+        assert_eq!(mcu.cpu.get_x(3), 0, "sc should succeed");
+        assert_eq!(mcu.mmu.rw(0x10), Ok(42));
+
+        mcu.cpu.set_x(2, 43);
+        run(&mut mcu, SCW).unwrap();
+        assert_eq!(mcu.cpu.get_x(3), 1, "reservation was already consumed");
+        assert_eq!(mcu.mmu.rw(0x10), Ok(42));
+    }
+
+    #[test]
+    fn amo() {
+        let mut mcu = mcu();
+        mcu.cpu.set_x(1, 0x10);
+        mcu.cpu.set_x(2, 5);
+        mcu.mmu.ww(0x10, 7).unwrap();
+
+        run(&mut mcu, LRW).unwrap();
+        run(&mut mcu, AMOADDW).unwrap();
+        assert_eq!(mcu.cpu.get_x(3), 7);
+        assert_eq!(mcu.mmu.rw(0x10), Ok(12));
+        assert_eq!(mcu.cpu.reservation, None, "stores clear the reservation");
+    }
+
+    #[test]
+    fn misaligned_amo() {
+        let mut mcu = mcu();
+        mcu.cpu.set_x(1, 0x12);
+        assert!(matches!(
+            run(&mut mcu, AMOADDW),
+            Err(Exception(Exception::StoreAddressMisaligned))
+        ));
+    }
+
+    #[test]
+    fn amo_trigger() {
+        use crate::trigger::*;
+
+        let mut mcu = mcu();
+        mcu.cpu.set_x(1, 0x10);
+        mcu.cpu.set_x(2, 5);
+        mcu.mmu.ww(0x10, 7).unwrap();
+        for flags in [STORE, LOAD | STORE] {
+            mcu.cpu
+                .write_csr(TDATA1, TYPE_MCONTROL6 << TYPE_SHIFT | M | flags)
+                .unwrap();
+            mcu.cpu.write_csr(TDATA2, 0x10).unwrap();
+            assert!(matches!(
+                run(&mut mcu, AMOADDW),
+                Err(Exception(Exception::Breakpoint))
+            ));
+            assert_eq!(mcu.mmu.rw(0x10), Ok(7), "the store didn't happen");
+            assert_eq!(mcu.cpu.get_x(3), 0);
+        }
+    }
+}
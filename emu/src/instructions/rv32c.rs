@@ -0,0 +1,411 @@
+use super::{take_branch, Exception, ExceptionInterrupt, Instruction};
+use crate::mcu::MCU;
+use crate::utils::*;
+use macros::mask;
+use riscv_isa_types::rv32c::*;
+use riscv_isa_types::rv32i::*;
+
+use ExceptionInterrupt::*;
+
+// Most compressed instructions are executed through the 32-bit instruction they expand to.
+// Control transfer instructions are implemented here because they depend on the instruction
+// length (2 bytes) to compute the link address and the fall-through pc.
+
+/// Sign extends a compressed immediate to the 12 bits expected by I-type instructions
+fn imm12(imm: u32, from: u32) -> u32 {
+    sext(imm, from, 32) & mask!(12)
+}
+
+impl Instruction for CADDI4SPN {
+    fn execute(&self, mcu: &mut MCU) -> Result<u32, ExceptionInterrupt> {
+        if self.nzuimm() == 0 {
+            return Err(Exception(Exception::IllegalInstruction));
+        }
+        ADDI {
+            rd: reg(self.rd_p),
+            rs1: 2,
+            imm: self.nzuimm(),
+        }
+        .execute(mcu)
+    }
+}
+
+impl Instruction for CLW {
+    fn execute(&self, mcu: &mut MCU) -> Result<u32, ExceptionInterrupt> {
+        LW {
+            rd: reg(self.rd_p),
+            rs1: reg(self.rs1_p),
+            imm: self.offset(),
+        }
+        .execute(mcu)
+    }
+}
+
+impl Instruction for CSW {
+    fn execute(&self, mcu: &mut MCU) -> Result<u32, ExceptionInterrupt> {
+        SW {
+            rs1: reg(self.rs1_p),
+            rs2: reg(self.rs2_p),
+            offset: self.offset(),
+        }
+        .execute(mcu)
+    }
+}
+
+impl Instruction for CADDI {
+    fn execute(&self, mcu: &mut MCU) -> Result<u32, ExceptionInterrupt> {
+        ADDI {
+            rd: self.rd,
+            rs1: self.rd,
+            imm: imm12(self.imm, 6),
+        }
+        .execute(mcu)
+    }
+}
+
+impl Instruction for CJAL {
+    fn execute(&self, mcu: &mut MCU) -> Result<u32, ExceptionInterrupt> {
+        mcu.cpu.set_x(1, mcu.cpu.pc.wrapping_add(2));
+        mcu.cpu.pc = (mcu.cpu.pc as i32).wrapping_add(sext(self.offset(), 12, 32) as i32) as u32;
+        Ok(1)
+    }
+    fn update_pc(&self, _mcu: &mut MCU) {}
+}
+
+impl Instruction for CLI {
+    fn execute(&self, mcu: &mut MCU) -> Result<u32, ExceptionInterrupt> {
+        ADDI {
+            rd: self.rd,
+            rs1: 0,
+            imm: imm12(self.imm, 6),
+        }
+        .execute(mcu)
+    }
+}
+
+impl Instruction for CADDI16SP {
+    fn execute(&self, mcu: &mut MCU) -> Result<u32, ExceptionInterrupt> {
+        if self.nzimm() == 0 {
+            return Err(Exception(Exception::IllegalInstruction));
+        }
+        ADDI {
+            rd: 2,
+            rs1: 2,
+            imm: imm12(self.nzimm(), 10),
+        }
+        .execute(mcu)
+    }
+}
+
+impl Instruction for CLUI {
+    fn execute(&self, mcu: &mut MCU) -> Result<u32, ExceptionInterrupt> {
+        if self.imm == 0 {
+            return Err(Exception(Exception::IllegalInstruction));
+        }
+        LUI {
+            rd: self.rd,
+            imm: sext(self.imm, 6, 32) & mask!(20),
+        }
+        .execute(mcu)
+    }
+}
+
+impl Instruction for CSRLI {
+    fn execute(&self, mcu: &mut MCU) -> Result<u32, ExceptionInterrupt> {
+        // shamt[5] is reserved on RV32
+        if self.shamt() & 0b100000 != 0 {
+            return Err(Exception(Exception::IllegalInstruction));
+        }
+        SRLI {
+            rd: reg(self.rs1_p),
+            rs1: reg(self.rs1_p),
+            imm: self.shamt(),
+        }
+        .execute(mcu)
+    }
+}
+
+impl Instruction for CSRAI {
+    fn execute(&self, mcu: &mut MCU) -> Result<u32, ExceptionInterrupt> {
+        if self.shamt() & 0b100000 != 0 {
+            return Err(Exception(Exception::IllegalInstruction));
+        }
+        SRAI {
+            rd: reg(self.rs1_p),
+            rs1: reg(self.rs1_p),
+            imm: self.shamt(),
+        }
+        .execute(mcu)
+    }
+}
+
+impl Instruction for CANDI {
+    fn execute(&self, mcu: &mut MCU) -> Result<u32, ExceptionInterrupt> {
+        ANDI {
+            rd: reg(self.rs1_p),
+            rs1: reg(self.rs1_p),
+            imm: imm12(self.imm(), 6),
+        }
+        .execute(mcu)
+    }
+}
+
+impl Instruction for CSUB {
+    fn execute(&self, mcu: &mut MCU) -> Result<u32, ExceptionInterrupt> {
+        SUB {
+            rd: reg(self.rd_p),
+            rs1: reg(self.rd_p),
+            rs2: reg(self.rs2_p),
+        }
+        .execute(mcu)
+    }
+}
+
+impl Instruction for CXOR {
+    fn execute(&self, mcu: &mut MCU) -> Result<u32, ExceptionInterrupt> {
+        XOR {
+            rd: reg(self.rd_p),
+            rs1: reg(self.rd_p),
+            rs2: reg(self.rs2_p),
+        }
+        .execute(mcu)
+    }
+}
+
+impl Instruction for COR {
+    fn execute(&self, mcu: &mut MCU) -> Result<u32, ExceptionInterrupt> {
+        OR {
+            rd: reg(self.rd_p),
+            rs1: reg(self.rd_p),
+            rs2: reg(self.rs2_p),
+        }
+        .execute(mcu)
+    }
+}
+
+impl Instruction for CAND {
+    fn execute(&self, mcu: &mut MCU) -> Result<u32, ExceptionInterrupt> {
+        AND {
+            rd: reg(self.rd_p),
+            rs1: reg(self.rd_p),
+            rs2: reg(self.rs2_p),
+        }
+        .execute(mcu)
+    }
+}
+
+impl Instruction for CJ {
+    fn execute(&self, mcu: &mut MCU) -> Result<u32, ExceptionInterrupt> {
+        mcu.cpu.pc = (mcu.cpu.pc as i32).wrapping_add(sext(self.offset(), 12, 32) as i32) as u32;
+        Ok(1)
+    }
+    fn update_pc(&self, _mcu: &mut MCU) {}
+}
+
+impl Instruction for CBEQZ {
+    fn execute(&self, mcu: &mut MCU) -> Result<u32, ExceptionInterrupt> {
+        if mcu.cpu.get_x(reg(self.rs1_p)) == 0 {
+            take_branch(mcu, sext(self.offset(), 9, 32))?;
+        } else {
+            mcu.cpu.pc += 2;
+        }
+        Ok(1)
+    }
+    fn update_pc(&self, _mcu: &mut MCU) {}
+}
+
+impl Instruction for CBNEZ {
+    fn execute(&self, mcu: &mut MCU) -> Result<u32, ExceptionInterrupt> {
+        if mcu.cpu.get_x(reg(self.rs1_p)) != 0 {
+            take_branch(mcu, sext(self.offset(), 9, 32))?;
+        } else {
+            mcu.cpu.pc += 2;
+        }
+        Ok(1)
+    }
+    fn update_pc(&self, _mcu: &mut MCU) {}
+}
+
+impl Instruction for CSLLI {
+    fn execute(&self, mcu: &mut MCU) -> Result<u32, ExceptionInterrupt> {
+        if self.shamt & 0b100000 != 0 {
+            return Err(Exception(Exception::IllegalInstruction));
+        }
+        SLLI {
+            rd: self.rd,
+            rs1: self.rd,
+            imm: self.shamt,
+        }
+        .execute(mcu)
+    }
+}
+
+impl Instruction for CLWSP {
+    fn execute(&self, mcu: &mut MCU) -> Result<u32, ExceptionInterrupt> {
+        if self.rd == 0 {
+            return Err(Exception(Exception::IllegalInstruction));
+        }
+        LW {
+            rd: self.rd,
+            rs1: 2,
+            imm: self.offset(),
+        }
+        .execute(mcu)
+    }
+}
+
+impl Instruction for CJR {
+    fn execute(&self, mcu: &mut MCU) -> Result<u32, ExceptionInterrupt> {
+        if self.rd == 0 {
+            return Err(Exception(Exception::IllegalInstruction));
+        }
+        mcu.cpu.pc = mcu.cpu.get_x(self.rd) & !0b1;
+        Ok(1)
+    }
+    fn update_pc(&self, _mcu: &mut MCU) {}
+}
+
+impl Instruction for CMV {
+    fn execute(&self, mcu: &mut MCU) -> Result<u32, ExceptionInterrupt> {
+        ADD {
+            rd: self.rd,
+            rs1: 0,
+            rs2: self.rs2,
+        }
+        .execute(mcu)
+    }
+}
+
+impl Instruction for CEBREAK {
+    fn execute(&self, mcu: &mut MCU) -> Result<u32, ExceptionInterrupt> {
+        EBREAK { rd: 0 }.execute(mcu)
+    }
+}
+
+impl Instruction for CJALR {
+    fn execute(&self, mcu: &mut MCU) -> Result<u32, ExceptionInterrupt> {
+        let t = mcu.cpu.get_x(self.rd);
+        mcu.cpu.set_x(1, mcu.cpu.pc.wrapping_add(2));
+        mcu.cpu.pc = t & !0b1;
+        Ok(1)
+    }
+    fn update_pc(&self, _mcu: &mut MCU) {}
+}
+
+impl Instruction for CADD {
+    fn execute(&self, mcu: &mut MCU) -> Result<u32, ExceptionInterrupt> {
+        ADD {
+            rd: self.rd,
+            rs1: self.rd,
+            rs2: self.rs2,
+        }
+        .execute(mcu)
+    }
+}
+
+impl Instruction for CSWSP {
+    fn execute(&self, mcu: &mut MCU) -> Result<u32, ExceptionInterrupt> {
+        SW {
+            rs1: 2,
+            rs2: self.rs2,
+            offset: self.offset(),
+        }
+        .execute(mcu)
+    }
+}
+
+impl Instruction for RV32c {
+    fn execute(&self, mcu: &mut MCU) -> Result<u32, ExceptionInterrupt> {
+        use RV32c::*;
+        match *self {
+            CADDI4SPN(i) => i.execute(mcu),
+            CLW(i) => i.execute(mcu),
+            CSW(i) => i.execute(mcu),
+            CADDI(i) => i.execute(mcu),
+            CJAL(i) => i.execute(mcu),
+            CLI(i) => i.execute(mcu),
+            CADDI16SP(i) => i.execute(mcu),
+            CLUI(i) => i.execute(mcu),
+            CSRLI(i) => i.execute(mcu),
+            CSRAI(i) => i.execute(mcu),
+            CANDI(i) => i.execute(mcu),
+            CSUB(i) => i.execute(mcu),
+            CXOR(i) => i.execute(mcu),
+            COR(i) => i.execute(mcu),
+            CAND(i) => i.execute(mcu),
+            CJ(i) => i.execute(mcu),
+            CBEQZ(i) => i.execute(mcu),
+            CBNEZ(i) => i.execute(mcu),
+            CSLLI(i) => i.execute(mcu),
+            CLWSP(i) => i.execute(mcu),
+            CJR(i) => i.execute(mcu),
+            CMV(i) => i.execute(mcu),
+            CEBREAK(i) => i.execute(mcu),
+            CJALR(i) => i.execute(mcu),
+            CADD(i) => i.execute(mcu),
+            CSWSP(i) => i.execute(mcu),
+        }
+    }
+
+    fn update_pc(&self, mcu: &mut MCU) {
+        use RV32c::*;
+        match *self {
+            CJAL(_) => (),
+            CJ(_) => (),
+            CBEQZ(_) => (),
+            CBNEZ(_) => (),
+            CJR(_) => (),
+            CJALR(_) => (),
+            _ => mcu.cpu.pc += 2,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(mcu: &mut MCU, word: u32) {
+        let i = RV32c::try_from(word).unwrap();
+        i.execute(mcu).unwrap();
+        i.update_pc(mcu);
+    }
+
+    #[test]
+    fn expanded_instructions_advance_two_bytes() {
+        let mut mcu = MCU::new();
+        mcu.cpu.set_x(1, 40);
+        mcu.cpu.set_x(2, 2);
+        // c.add x1, x2
+        run(&mut mcu, 0x908a);
+        assert_eq!(mcu.cpu.get_x(1), 42);
+        assert_eq!(mcu.cpu.pc, 2);
+        // c.addi x1, -2
+        run(&mut mcu, 0x10f9);
+        assert_eq!(mcu.cpu.get_x(1), 40);
+        assert_eq!(mcu.cpu.pc, 4);
+    }
+
+    #[test]
+    fn jumps_link_the_compressed_length() {
+        let mut mcu = MCU::new();
+        mcu.cpu.pc = 0x102;
+        mcu.cpu.set_x(5, 0x2006);
+        // c.jalr x5
+        run(&mut mcu, 0x9282);
+        assert_eq!(mcu.cpu.get_x(1), 0x104);
+        assert_eq!(mcu.cpu.pc, 0x2006);
+        // c.j -2
+        run(&mut mcu, 0xbffd);
+        assert_eq!(mcu.cpu.pc, 0x2004);
+    }
+
+    #[test]
+    fn link_wraps_around() {
+        let mut mcu = MCU::new();
+        mcu.cpu.pc = 0xffff_fffe;
+        // c.jalr x5
+        run(&mut mcu, 0x9282);
+        assert_eq!(mcu.cpu.get_x(1), 0);
+    }
+}
@@ -1,4 +1,5 @@
 use super::{ExceptionInterrupt, Instruction};
+use crate::instructions::take_branch;
 use crate::mcu::MCU;
 use crate::utils::*;
 use riscv_isa_types::rv32i::*;
@@ -6,7 +7,7 @@ use riscv_isa_types::rv32i::*;
 impl Instruction for BEQ {
     fn execute(&self, mcu: &mut MCU) -> Result<u32, ExceptionInterrupt> {
         if mcu.cpu.get_x(self.rs1) == mcu.cpu.get_x(self.rs2) {
-            mcu.cpu.pc = (mcu.cpu.pc as i32).wrapping_add(sext(self.offset, 12, 32) as i32) as u32;
+            take_branch(mcu, sext(self.offset, 12, 32))?;
         } else {
             mcu.cpu.pc += 4;
         }
@@ -18,7 +19,7 @@ impl Instruction for BEQ {
 impl Instruction for BGE {
     fn execute(&self, mcu: &mut MCU) -> Result<u32, ExceptionInterrupt> {
         if mcu.cpu.get_x(self.rs1) as i32 >= mcu.cpu.get_x(self.rs2) as i32 {
-            mcu.cpu.pc = (mcu.cpu.pc as i32).wrapping_add(sext(self.offset, 12, 32) as i32) as u32;
+            take_branch(mcu, sext(self.offset, 12, 32))?;
         } else {
             mcu.cpu.pc += 4;
         }
@@ -30,7 +31,7 @@ impl Instruction for BGE {
 impl Instruction for BGEU {
     fn execute(&self, mcu: &mut MCU) -> Result<u32, ExceptionInterrupt> {
         if mcu.cpu.get_x(self.rs1) >= mcu.cpu.get_x(self.rs2) {
-            mcu.cpu.pc = (mcu.cpu.pc as i32).wrapping_add(sext(self.offset, 12, 32) as i32) as u32;
+            take_branch(mcu, sext(self.offset, 12, 32))?;
         } else {
             mcu.cpu.pc += 4;
         }
@@ -42,7 +43,7 @@ impl Instruction for BGEU {
 impl Instruction for BLT {
     fn execute(&self, mcu: &mut MCU) -> Result<u32, ExceptionInterrupt> {
         if (mcu.cpu.get_x(self.rs1) as i32) < (mcu.cpu.get_x(self.rs2) as i32) {
-            mcu.cpu.pc = (mcu.cpu.pc as i32).wrapping_add(sext(self.offset, 12, 32) as i32) as u32;
+            take_branch(mcu, sext(self.offset, 12, 32))?;
         } else {
             mcu.cpu.pc += 4;
         }
@@ -54,7 +55,7 @@ impl Instruction for BLT {
 impl Instruction for BLTU {
     fn execute(&self, mcu: &mut MCU) -> Result<u32, ExceptionInterrupt> {
         if mcu.cpu.get_x(self.rs1) < mcu.cpu.get_x(self.rs2) {
-            mcu.cpu.pc = (mcu.cpu.pc as i32).wrapping_add(sext(self.offset, 12, 32) as i32) as u32;
+            take_branch(mcu, sext(self.offset, 12, 32))?;
         } else {
             mcu.cpu.pc += 4;
         }
@@ -66,7 +67,7 @@ impl Instruction for BLTU {
 impl Instruction for BNE {
     fn execute(&self, mcu: &mut MCU) -> Result<u32, ExceptionInterrupt> {
         if mcu.cpu.get_x(self.rs1) != mcu.cpu.get_x(self.rs2) {
-            mcu.cpu.pc = (mcu.cpu.pc as i32).wrapping_add(sext(self.offset, 12, 32) as i32) as u32;
+            take_branch(mcu, sext(self.offset, 12, 32))?;
         } else {
             mcu.cpu.pc += 4;
         }
@@ -1,6 +1,5 @@
 use super::{Exception, ExceptionInterrupt, Instruction};
 use crate::mcu::MCU;
-use crate::memory::Memory;
 use crate::utils::*;
 use riscv_isa_types::rv32i::*;
 
@@ -8,11 +7,8 @@ impl Instruction for LB {
     fn execute(&self, mcu: &mut MCU) -> Result<u32, ExceptionInterrupt> {
         let addr =
             (mcu.cpu.get_x(self.rs1) as i32).wrapping_add(sext(self.imm, 12, 32) as i32) as u32;
-        let byte = mcu
-            .mmu
-            .rb(addr)
-            .map_err(|_| Exception(Exception::LoadAccessFault))?;
-        mcu.cpu.set_x(self.rd, sext(byte as u32, 8, 32));
+        let byte = mcu.load(addr, 1).map_err(Exception)?;
+        mcu.cpu.set_x(self.rd, sext(byte, 8, 32));
         Ok(1)
     }
     fn update_pc(&self, mcu: &mut MCU) {
@@ -24,12 +20,8 @@ impl Instruction for LBU {
     fn execute(&self, mcu: &mut MCU) -> Result<u32, ExceptionInterrupt> {
         let addr =
             (mcu.cpu.get_x(self.rs1) as i32).wrapping_add(sext(self.imm, 12, 32) as i32) as u32;
-        mcu.cpu.set_x(
-            self.rd,
-            mcu.mmu
-                .rb(addr)
-                .map_err(|_| Exception(Exception::LoadAccessFault))? as u32,
-        );
+        let v = mcu.load(addr, 1).map_err(Exception)?;
+        mcu.cpu.set_x(self.rd, v);
         Ok(1)
     }
     fn update_pc(&self, mcu: &mut MCU) {
@@ -41,16 +33,8 @@ impl Instruction for LH {
     fn execute(&self, mcu: &mut MCU) -> Result<u32, ExceptionInterrupt> {
         let addr =
             (mcu.cpu.get_x(self.rs1) as i32).wrapping_add(sext(self.imm, 12, 32) as i32) as u32;
-        mcu.cpu.set_x(
-            self.rd,
-            sext(
-                mcu.mmu
-                    .rhw(addr)
-                    .map_err(|_| Exception(Exception::LoadAccessFault))? as u32,
-                16,
-                32,
-            ),
-        );
+        let v = mcu.load(addr, 2).map_err(Exception)?;
+        mcu.cpu.set_x(self.rd, sext(v, 16, 32));
         Ok(1)
     }
     fn update_pc(&self, mcu: &mut MCU) {
@@ -62,12 +46,8 @@ impl Instruction for LHU {
     fn execute(&self, mcu: &mut MCU) -> Result<u32, ExceptionInterrupt> {
         let addr =
             (mcu.cpu.get_x(self.rs1) as i32).wrapping_add(sext(self.imm, 12, 32) as i32) as u32;
-        mcu.cpu.set_x(
-            self.rd,
-            mcu.mmu
-                .rhw(addr)
-                .map_err(|_| Exception(Exception::LoadAccessFault))? as u32,
-        );
+        let v = mcu.load(addr, 2).map_err(Exception)?;
+        mcu.cpu.set_x(self.rd, v);
         Ok(1)
     }
     fn update_pc(&self, mcu: &mut MCU) {
@@ -78,12 +58,8 @@ impl Instruction for LHU {
 impl Instruction for LW {
     fn execute(&self, mcu: &mut MCU) -> Result<u32, ExceptionInterrupt> {
         let addr = ((mcu.cpu.get_x(self.rs1) as i32) + (sext(self.imm, 12, 32) as i32)) as u32;
-        mcu.cpu.set_x(
-            self.rd,
-            mcu.mmu
-                .rw(addr)
-                .map_err(|_| Exception(Exception::LoadAccessFault))?,
-        );
+        let v = mcu.load(addr, 4).map_err(Exception)?;
+        mcu.cpu.set_x(self.rd, v);
         Ok(1)
     }
     fn update_pc(&self, mcu: &mut MCU) {
@@ -95,12 +71,8 @@ impl Instruction for LWU {
     fn execute(&self, mcu: &mut MCU) -> Result<u32, ExceptionInterrupt> {
         let addr =
             (mcu.cpu.get_x(self.rs1) as i32).wrapping_add(sext(self.imm, 12, 32) as i32) as u32;
-        mcu.cpu.set_x(
-            self.rd,
-            mcu.mmu
-                .rw(addr)
-                .map_err(|_| Exception(Exception::LoadAccessFault))? as u32,
-        );
+        let v = mcu.load(addr, 4).map_err(Exception)?;
+        mcu.cpu.set_x(self.rd, v);
         Ok(1)
     }
     fn update_pc(&self, mcu: &mut MCU) {
@@ -6,7 +6,7 @@ mod op;
 mod store;
 mod system;
 
-use super::{Exception, ExceptionInterrupt, Instruction};
+use super::{check_target, Exception, ExceptionInterrupt, Instruction};
 use crate::mcu::MCU;
 use crate::utils::*;
 use riscv_isa_types::rv32i::*;
@@ -67,8 +67,10 @@ impl Instruction for AUIPC {
 
 impl Instruction for JAL {
     fn execute(&self, mcu: &mut MCU) -> Result<u32, ExceptionInterrupt> {
+        let target = mcu.cpu.pc.wrapping_add(sext(self.offset, 20, 32));
+        check_target(mcu, target)?;
         mcu.cpu.set_x(self.rd, mcu.cpu.pc + 4);
-        mcu.cpu.pc = (mcu.cpu.pc as i32 + (sext(self.offset, 20, 32) as i32)) as u32;
+        mcu.cpu.pc = target;
         Ok(1)
     }
     fn update_pc(&self, _mcu: &mut MCU) {}
@@ -76,9 +78,10 @@ impl Instruction for JAL {
 
 impl Instruction for JALR {
     fn execute(&self, mcu: &mut MCU) -> Result<u32, ExceptionInterrupt> {
-        let t = mcu.cpu.get_x(self.rs1);
+        let target = mcu.cpu.get_x(self.rs1).wrapping_add(sext(self.imm, 12, 32)) & !1;
+        check_target(mcu, target)?;
         mcu.cpu.set_x(self.rd, mcu.cpu.pc + 4);
-        mcu.cpu.pc = (((t as i32) + (sext(self.imm, 12, 32) as i32)) & !(0b1 as i32)) as u32;
+        mcu.cpu.pc = target;
         Ok(1)
     }
     fn update_pc(&self, _mcu: &mut MCU) {}
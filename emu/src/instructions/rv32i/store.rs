@@ -1,17 +1,14 @@
 use super::{Exception, ExceptionInterrupt, Instruction};
 use crate::mcu::MCU;
-use crate::memory::Memory;
 use crate::utils::*;
 use riscv_isa_types::rv32i::*;
-use Exception::*;
 
 impl Instruction for SB {
     fn execute(&self, mcu: &mut MCU) -> Result<u32, ExceptionInterrupt> {
         let addr =
             (mcu.cpu.get_x(self.rs1) as i32).wrapping_add(sext(self.offset, 12, 32) as i32) as u32;
-        mcu.mmu
-            .wb(addr, mcu.cpu.get_x(self.rs2) as u8)
-            .map_err(|_| Exception(StoreAccessFault))?;
+        mcu.store(addr, 1, mcu.cpu.get_x(self.rs2))
+            .map_err(Exception)?;
         Ok(1)
     }
 }
@@ -20,9 +17,8 @@ impl Instruction for SH {
     fn execute(&self, mcu: &mut MCU) -> Result<u32, ExceptionInterrupt> {
         let addr =
             (mcu.cpu.get_x(self.rs1) as i32).wrapping_add(sext(self.offset, 12, 32) as i32) as u32;
-        mcu.mmu
-            .whw(addr, mcu.cpu.get_x(self.rs2) as u16)
-            .map_err(|_| Exception(StoreAccessFault))?;
+        mcu.store(addr, 2, mcu.cpu.get_x(self.rs2))
+            .map_err(Exception)?;
         Ok(1)
     }
 }
@@ -31,9 +27,7 @@ impl Instruction for SW {
     fn execute(&self, mcu: &mut MCU) -> Result<u32, ExceptionInterrupt> {
         let addr = ((mcu.cpu.get_x(self.rs1) as i32) + (sext(self.offset, 12, 32) as i32)) as u32;
         let value = mcu.cpu.get_x(self.rs2);
-        mcu.mmu
-            .ww(addr, value)
-            .map_err(|_| Exception(StoreAccessFault))?;
+        mcu.store(addr, 4, value).map_err(Exception)?;
         Ok(1)
     }
 }
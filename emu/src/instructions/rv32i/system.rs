@@ -1,118 +1,98 @@
 use super::{Exception, ExceptionInterrupt, Instruction};
+use crate::cpu::Privilege;
 use crate::mcu::MCU;
+use crate::semihosting::is_semihosting_call;
 use riscv_isa_types::rv32i::*;
 // test
 
 impl Instruction for EBREAK {
-    fn execute(&self, _mcu: &mut MCU) -> Result<u32, ExceptionInterrupt> {
+    fn execute(&self, mcu: &mut MCU) -> Result<u32, ExceptionInterrupt> {
+        if mcu.semihosting.is_some() && is_semihosting_call(mcu) {
+            let mut semihosting = mcu.semihosting.take().unwrap();
+            semihosting.call(mcu);
+            mcu.semihosting = Some(semihosting);
+            return Ok(1);
+        }
+        // dcsr.ebreakm/s/u make the exception halt the hart in Debug mode instead of trapping
         Err(ExceptionInterrupt::Exception(Exception::Breakpoint))
     }
 }
 
 impl Instruction for ECALL {
-    fn execute(&self, _mcu: &mut MCU) -> Result<u32, ExceptionInterrupt> {
-        // TODO: Environemnt calls on other privilege modes
-        Err(ExceptionInterrupt::Exception(Exception::MEnvironmentCall))
+    fn execute(&self, mcu: &mut MCU) -> Result<u32, ExceptionInterrupt> {
+        let exception = match mcu.cpu.privilege {
+            Privilege::User => Exception::UEnvironmentCall,
+            Privilege::Supervisor => Exception::SEnvironmentCall,
+            Privilege::Machine => Exception::MEnvironmentCall,
+        };
+        Err(ExceptionInterrupt::Exception(exception))
+    }
+}
+
+/// Reads the CSR into rd and, if `write` is set, writes back the value computed by `op` from
+/// the old one. CSRRS and CSRRC with x0 as the source (or a zero immediate) don't write, so
+/// they can read read-only CSRs.
+fn csr_access(
+    mcu: &mut MCU,
+    csr: u32,
+    rd: u32,
+    write: bool,
+    op: impl FnOnce(u32) -> u32,
+) -> Result<u32, ExceptionInterrupt> {
+    let t = mcu
+        .cpu
+        .read_csr(csr)
+        .map_err(ExceptionInterrupt::Exception)?;
+    if write {
+        mcu.cpu
+            .write_csr(csr, op(t))
+            .map_err(ExceptionInterrupt::Exception)?;
     }
+    mcu.cpu.set_x(rd, t);
+    Ok(1)
 }
 
+// The immediate variants encode a 5 bit unsigned immediate in the rs1 field
+
 impl Instruction for CSRRCI {
     fn execute(&self, mcu: &mut MCU) -> Result<u32, ExceptionInterrupt> {
-        let t = match mcu.cpu.get_csr(self.imm) {
-            Ok(v) => v,
-            Err(err) => return Err(ExceptionInterrupt::Exception(err)),
-        };
-        match mcu
-            .cpu
-            .set_csr(self.imm, t & !(mcu.cpu.get_x(self.rs1) & 0b11111))
-        {
-            Err(err) => return Err(ExceptionInterrupt::Exception(err)),
-            _ => {}
-        };
-        mcu.cpu.set_x(self.rd, t as u32);
-        Ok(1)
+        let uimm = self.rs1;
+        csr_access(mcu, self.imm, self.rd, uimm != 0, |t| t & !uimm)
     }
 }
 
 impl Instruction for CSRRSI {
     fn execute(&self, mcu: &mut MCU) -> Result<u32, ExceptionInterrupt> {
-        let t = match mcu.cpu.get_csr(self.imm) {
-            Ok(v) => v,
-            Err(err) => {
-                return Err(ExceptionInterrupt::Exception(err));
-            }
-        };
-        let new_value = mcu.cpu.get_x(self.rs1) | t;
-        match mcu.cpu.set_csr(self.imm, new_value) {
-            Err(err) => {
-                return Err(ExceptionInterrupt::Exception(err));
-            }
-            _ => {}
-        };
-        mcu.cpu.set_x(self.rd, t as u32);
-        Ok(1)
+        let uimm = self.rs1;
+        csr_access(mcu, self.imm, self.rd, uimm != 0, |t| t | uimm)
     }
 }
 
 impl Instruction for CSRRWI {
     fn execute(&self, mcu: &mut MCU) -> Result<u32, ExceptionInterrupt> {
-        mcu.cpu.set_x(
-            self.rd,
-            match mcu.cpu.get_csr(self.imm) {
-                Ok(v) => v,
-                Err(err) => return Err(ExceptionInterrupt::Exception(err)),
-            },
-        );
-        match mcu.cpu.set_csr(self.imm, mcu.cpu.get_x(self.rs1) & 0b11111) {
-            Err(err) => return Err(ExceptionInterrupt::Exception(err)),
-            _ => (),
-        };
-        Ok(1)
+        let uimm = self.rs1;
+        csr_access(mcu, self.imm, self.rd, true, |_| uimm)
     }
 }
 
 impl Instruction for CSRRC {
     fn execute(&self, mcu: &mut MCU) -> Result<u32, ExceptionInterrupt> {
-        let t = match mcu.cpu.get_csr(self.imm) {
-            Ok(v) => v,
-            Err(err) => return Err(ExceptionInterrupt::Exception(err)),
-        };
-        match mcu.cpu.set_csr(self.imm, t & !mcu.cpu.get_x(self.rs1)) {
-            Err(err) => return Err(ExceptionInterrupt::Exception(err)),
-            _ => {}
-        };
-        mcu.cpu.set_x(self.rd, t as u32);
-        Ok(1)
+        let x = mcu.cpu.get_x(self.rs1);
+        csr_access(mcu, self.imm, self.rd, self.rs1 != 0, |t| t & !x)
     }
 }
 
 impl Instruction for CSRRS {
     fn execute(&self, mcu: &mut MCU) -> Result<u32, ExceptionInterrupt> {
-        let t = match mcu.cpu.get_csr(self.imm) {
-            Ok(v) => v,
-            Err(err) => return Err(ExceptionInterrupt::Exception(err)),
-        };
-        match mcu.cpu.set_csr(self.imm, mcu.cpu.get_x(self.rs1) | t) {
-            Err(err) => return Err(ExceptionInterrupt::Exception(err)),
-            _ => {}
-        };
-        mcu.cpu.set_x(self.rd, t as u32);
-        Ok(1)
+        let x = mcu.cpu.get_x(self.rs1);
+        csr_access(mcu, self.imm, self.rd, self.rs1 != 0, |t| t | x)
     }
 }
 
 impl Instruction for CSRRW {
     fn execute(&self, mcu: &mut MCU) -> Result<u32, ExceptionInterrupt> {
-        let t = match mcu.cpu.get_csr(self.imm) {
-            Ok(v) => v,
-            Err(err) => return Err(ExceptionInterrupt::Exception(err)),
-        };
-        match mcu.cpu.set_csr(self.imm, mcu.cpu.get_x(self.rs1)) {
-            Err(err) => return Err(ExceptionInterrupt::Exception(err)),
-            _ => {}
-        };
-        mcu.cpu.set_x(self.rd, t as u32);
-        Ok(1)
+        let x = mcu.cpu.get_x(self.rs1);
+        csr_access(mcu, self.imm, self.rd, true, |_| x)
     }
 }
 
@@ -130,3 +110,133 @@ impl Instruction for System {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::cpu::CSRs;
+    use crate::instructions::Exception;
+    use crate::mcu::{test_mcu, MisalignedAccess, MCU};
+    use crate::memory::Memory;
+
+    const MTVEC: u32 = 0x80;
+
+    /// Loads `program` at address 0
+    fn load(program: &[u32]) -> MCU {
+        let mut mcu = test_mcu(0x100, vec![]);
+        mcu.cpu.set_csr(CSRs::mtvec as u32, MTVEC).unwrap();
+        for (i, word) in program.iter().enumerate() {
+            mcu.mmu.ww(4 * i as u32, *word).unwrap();
+        }
+        mcu
+    }
+
+    /// Runs `program` from address 0, one tick per instruction
+    fn run(program: &[u32]) -> MCU {
+        let mut mcu = load(program);
+        for _ in program {
+            mcu.tick();
+        }
+        mcu
+    }
+
+    fn mcause(mcu: &MCU) -> u32 {
+        mcu.cpu.get_csr(CSRs::mcause as u32).unwrap()
+    }
+
+    fn mtval(mcu: &MCU) -> u32 {
+        mcu.cpu.get_csr(CSRs::mtval as u32).unwrap()
+    }
+
+    fn mepc(mcu: &MCU) -> u32 {
+        mcu.cpu.get_csr(CSRs::mepc as u32).unwrap()
+    }
+
+    #[test]
+    fn read_only_csrs() {
+        // csrr t0, mhartid
+        let mcu = run(&[0xf14022f3]);
+        assert_eq!(mcu.cpu.pc, 0x4, "csrr doesn't write the CSR");
+        assert_eq!(mcu.cpu.get_x(5), 0);
+
+        // csrrs t0, mhartid, t1 is a write even if t1 is zero
+        let mcu = run(&[0xf14322f3]);
+        assert_eq!(mcu.cpu.pc, MTVEC);
+        assert_eq!(mcause(&mcu), 2);
+
+        // csrw mhartid, t0
+        let mcu = run(&[0xf1429073]);
+        assert_eq!(mcu.cpu.pc, MTVEC);
+        assert_eq!(mcause(&mcu), 2);
+    }
+
+    #[test]
+    fn immediate_operands() {
+        // csrsi mstatus, 8; csrrci t1, mstatus, 8; csrrwi t0, mscratch, 31
+        let mcu = run(&[0x30046073, 0x30047373, 0x340fd2f3]);
+        assert_eq!(mcu.cpu.get_x(6), 8, "mstatus.MIE was set");
+        assert_eq!(mcu.cpu.get_csr(CSRs::mstatus as u32).unwrap(), 0);
+        assert_eq!(mcu.cpu.get_csr(CSRs::mscratch as u32).unwrap(), 31);
+    }
+
+    #[test]
+    fn counters() {
+        let mcu = run(&[
+            0x00300293, // li t0, 3
+            0x32329073, // csrw mhpmevent3, t0
+            0xb0201073, // csrw minstret, zero
+            0x00000263, // beqz zero, 4
+            0xc0202373, // rdinstret t1
+            0xc00023f3, // rdcycle t2
+            0xc0302e73, // csrr t3, hpmcounter3
+        ]);
+        assert_eq!(mcu.cpu.get_x(6), 1, "the write to minstret isn't counted");
+        assert_eq!(mcu.cpu.get_x(7), 5);
+        assert_eq!(mcu.cpu.get_x(28), 1, "one taken branch");
+    }
+
+    #[test]
+    fn trap_values() {
+        // csrw mhartid, t0
+        let mcu = run(&[0xf1429073]);
+        assert_eq!(
+            mtval(&mcu),
+            0xf1429073,
+            "illegal instructions report their bits"
+        );
+        assert_eq!(mepc(&mcu), 0);
+
+        // lui t1, 1; lw t0, 0(t1)
+        let mcu = run(&[0x00001337, 0x00032283]);
+        assert_eq!(mcause(&mcu), Exception::LoadAccessFault as u32);
+        assert_eq!(mtval(&mcu), 0x1000, "access faults report the address");
+        assert_eq!(mepc(&mcu), 4);
+    }
+
+    #[test]
+    fn misaligned_accesses() {
+        // li t1, 0x41; sh t1, 0(t1); lh t0, 0(t1)
+        let program = [0x04100313, 0x00631023, 0x00031283];
+        let mcu = run(&program);
+        assert_eq!(mcu.cpu.get_x(5), 0x41, "emulated by default");
+
+        let mut mcu = load(&program);
+        mcu.misaligned = MisalignedAccess::Trap;
+        mcu.tick();
+        mcu.tick();
+        assert_eq!(mcause(&mcu), Exception::StoreAddressMisaligned as u32);
+        assert_eq!(mtval(&mcu), 0x41);
+        assert_eq!(mepc(&mcu), 4);
+    }
+
+    #[test]
+    fn misaligned_jump() {
+        // csrci misa, 4 disables the C extension; j 6
+        let mcu = run(&[0x30127073, 0x0060006f]);
+        assert_eq!(
+            mcause(&mcu),
+            Exception::InstructionAddressMissaligned as u32
+        );
+        assert_eq!(mtval(&mcu), 0xa);
+        assert_eq!(mepc(&mcu), 4);
+    }
+}
@@ -0,0 +1,163 @@
+use super::{ExceptionInterrupt, Instruction};
+use crate::mcu::MCU;
+use riscv_isa_types::rv32m::*;
+
+// Cycle costs, modeled after a single cycle multiplier and an iterative (radix-2) divider
+const MUL_CYCLES: u32 = 1;
+const MULH_CYCLES: u32 = 2;
+const DIV_CYCLES: u32 = 33;
+const REM_CYCLES: u32 = 34;
+
+impl Instruction for MUL {
+    fn execute(&self, mcu: &mut MCU) -> Result<u32, ExceptionInterrupt> {
+        mcu.cpu.set_x(
+            self.rd,
+            mcu.cpu
+                .get_x(self.rs1)
+                .wrapping_mul(mcu.cpu.get_x(self.rs2)),
+        );
+        Ok(MUL_CYCLES)
+    }
+}
+
+impl Instruction for MULH {
+    fn execute(&self, mcu: &mut MCU) -> Result<u32, ExceptionInterrupt> {
+        let rs1 = mcu.cpu.get_x(self.rs1) as i32 as i64;
+        let rs2 = mcu.cpu.get_x(self.rs2) as i32 as i64;
+        mcu.cpu.set_x(self.rd, ((rs1 * rs2) >> 32) as u32);
+        Ok(MULH_CYCLES)
+    }
+}
+
+impl Instruction for MULHSU {
+    fn execute(&self, mcu: &mut MCU) -> Result<u32, ExceptionInterrupt> {
+        let rs1 = mcu.cpu.get_x(self.rs1) as i32 as i64;
+        let rs2 = mcu.cpu.get_x(self.rs2) as i64;
+        mcu.cpu.set_x(self.rd, (rs1.wrapping_mul(rs2) >> 32) as u32);
+        Ok(MULH_CYCLES)
+    }
+}
+
+impl Instruction for MULHU {
+    fn execute(&self, mcu: &mut MCU) -> Result<u32, ExceptionInterrupt> {
+        let rs1 = mcu.cpu.get_x(self.rs1) as u64;
+        let rs2 = mcu.cpu.get_x(self.rs2) as u64;
+        mcu.cpu.set_x(self.rd, ((rs1 * rs2) >> 32) as u32);
+        Ok(MULH_CYCLES)
+    }
+}
+
+impl Instruction for DIV {
+    fn execute(&self, mcu: &mut MCU) -> Result<u32, ExceptionInterrupt> {
+        let rs1 = mcu.cpu.get_x(self.rs1) as i32;
+        let rs2 = mcu.cpu.get_x(self.rs2) as i32;
+        // Division by zero yields all bits set, overflow (MIN / -1) yields MIN
+        let v = if rs2 == 0 { -1 } else { rs1.wrapping_div(rs2) };
+        mcu.cpu.set_x(self.rd, v as u32);
+        Ok(DIV_CYCLES)
+    }
+}
+
+impl Instruction for DIVU {
+    fn execute(&self, mcu: &mut MCU) -> Result<u32, ExceptionInterrupt> {
+        let rs1 = mcu.cpu.get_x(self.rs1);
+        let rs2 = mcu.cpu.get_x(self.rs2);
+        let v = rs1.checked_div(rs2).unwrap_or(u32::MAX);
+        mcu.cpu.set_x(self.rd, v);
+        Ok(DIV_CYCLES)
+    }
+}
+
+impl Instruction for REM {
+    fn execute(&self, mcu: &mut MCU) -> Result<u32, ExceptionInterrupt> {
+        let rs1 = mcu.cpu.get_x(self.rs1) as i32;
+        let rs2 = mcu.cpu.get_x(self.rs2) as i32;
+        // Division by zero yields the dividend, overflow (MIN % -1) yields 0
+        let v = if rs2 == 0 { rs1 } else { rs1.wrapping_rem(rs2) };
+        mcu.cpu.set_x(self.rd, v as u32);
+        Ok(REM_CYCLES)
+    }
+}
+
+impl Instruction for REMU {
+    fn execute(&self, mcu: &mut MCU) -> Result<u32, ExceptionInterrupt> {
+        let rs1 = mcu.cpu.get_x(self.rs1);
+        let rs2 = mcu.cpu.get_x(self.rs2);
+        let v = rs1.checked_rem(rs2).unwrap_or(rs1);
+        mcu.cpu.set_x(self.rd, v);
+        Ok(REM_CYCLES)
+    }
+}
+
+impl Instruction for RV32m {
+    fn execute(&self, mcu: &mut MCU) -> Result<u32, ExceptionInterrupt> {
+        use RV32m::*;
+        match self {
+            MUL(i) => i.execute(mcu),
+            MULH(i) => i.execute(mcu),
+            MULHSU(i) => i.execute(mcu),
+            MULHU(i) => i.execute(mcu),
+            DIV(i) => i.execute(mcu),
+            DIVU(i) => i.execute(mcu),
+            REM(i) => i.execute(mcu),
+            REMU(i) => i.execute(mcu),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(word: u32, rs1: u32, rs2: u32) -> u32 {
+        let mut mcu = MCU::new();
+        mcu.cpu.set_x(1, rs1);
+        mcu.cpu.set_x(2, rs2);
+        let i = RV32m::try_from(word).unwrap();
+        i.execute(&mut mcu).unwrap();
+        i.update_pc(&mut mcu);
+        assert_eq!(mcu.cpu.pc, 4);
+        mcu.cpu.get_x(3)
+    }
+
+    // <op> x3, x1, x2
+    const MUL: u32 = 0x0220_81b3;
+    const MULH: u32 = 0x0220_91b3;
+    const MULHSU: u32 = 0x0220_a1b3;
+    const MULHU: u32 = 0x0220_b1b3;
+    const DIV: u32 = 0x0220_c1b3;
+    const DIVU: u32 = 0x0220_d1b3;
+    const REM: u32 = 0x0220_e1b3;
+    const REMU: u32 = 0x0220_f1b3;
+
+    #[test]
+    fn multiplication() {
+        assert_eq!(run(MUL, 7, (-3i32) as u32), (-21i32) as u32);
+        assert_eq!(run(MULH, (-1i32) as u32, (-1i32) as u32), 0);
+        assert_eq!(run(MULH, 0x8000_0000, 0x8000_0000), 0x4000_0000);
+        assert_eq!(run(MULHSU, (-1i32) as u32, u32::MAX), u32::MAX);
+        assert_eq!(run(MULHU, u32::MAX, u32::MAX), 0xffff_fffe);
+    }
+
+    #[test]
+    fn division() {
+        assert_eq!(run(DIV, (-7i32) as u32, 2), (-3i32) as u32);
+        assert_eq!(run(DIVU, 7, 2), 3);
+        assert_eq!(run(REM, (-7i32) as u32, 2), (-1i32) as u32);
+        assert_eq!(run(REMU, 7, 2), 1);
+    }
+
+    #[test]
+    fn division_by_zero() {
+        assert_eq!(run(DIV, 7, 0), u32::MAX);
+        assert_eq!(run(DIVU, 7, 0), u32::MAX);
+        assert_eq!(run(REM, (-7i32) as u32, 0), (-7i32) as u32);
+        assert_eq!(run(REMU, 7, 0), 7);
+    }
+
+    #[test]
+    fn signed_overflow() {
+        assert_eq!(run(DIV, 0x8000_0000, u32::MAX), 0x8000_0000);
+        assert_eq!(run(REM, 0x8000_0000, u32::MAX), 0);
+    }
+}
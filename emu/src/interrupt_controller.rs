@@ -1,10 +1,17 @@
-use crate::cpu::{CSRs, CPU};
+use crate::cpu::CPU;
 use crate::instructions::Interrupt;
 use crate::memory::DeviceMap;
+use crate::peripherals::plic::PLIC;
 
+/// Drives the interrupt pending bits of the hart from the interrupt lines of the devices. Lines
+/// are level-sensitive: a device asserts its line while the interrupt condition holds and
+/// deasserts it once it's cleared, so mip always reflects the current state of the devices.
 pub struct InterruptController {
     peripherals: DeviceMap,
-    interrupts: Vec<Interrupt>,
+    // asserted lines, one bit per mip bit
+    lines: u32,
+    // edge-triggered interrupts raised since the last notification
+    edges: u32,
 }
 
 impl std::fmt::Debug for InterruptController {
@@ -17,99 +24,140 @@ impl InterruptController {
     pub fn new(peripherals: DeviceMap) -> Self {
         Self {
             peripherals,
-            interrupts: vec![],
+            lines: 0,
+            edges: 0,
         }
     }
 
-    /// Register a new interrupt. The interrupts will be accumulated until the end of the tick and
-    /// disposed afterwards.
-    pub fn interrupt(&mut self, interrupt: Interrupt, id: u32) {
-        if interrupt == Interrupt::MExternalInterrupt {
-            let peripherals = self.peripherals.borrow();
-            let peripheral = peripherals
-                .get("PLIC")
-                .ok_or(())
-                .and_then(|p| p.try_borrow_mut().map_err(|_| ()));
-
-            if let Ok(mut peripheral) = peripheral {
-                if let Some(plic) = peripheral.as_plic() {
-                    if plic.h0mie & id as u64 != 0 {
-                        let mut pending = plic.pending.borrow_mut();
-                        *pending |= id as u64;
-                        self.interrupts.push(interrupt);
-                        return;
-                    }
-                }
-            }
+    /// Runs `f` on the PLIC, if the MCU has one
+    fn with_plic<T>(&self, f: impl FnOnce(&mut PLIC) -> T) -> Option<T> {
+        let peripherals = self.peripherals.borrow();
+        let mut peripheral = peripherals.get("PLIC")?.try_borrow_mut().ok()?;
+        peripheral.as_plic().map(f)
+    }
+
+    /// Asserts or deasserts the line of `interrupt`, for devices wired directly to the hart
+    pub fn set_interrupt(&mut self, interrupt: Interrupt, level: bool) {
+        let bit = 1 << interrupt as u32;
+        if level {
+            self.lines |= bit;
+        } else {
+            self.lines &= !bit;
         }
+    }
 
-        self.interrupts.push(interrupt)
+    /// Sets the pending bit of `interrupt` once, software clears it in mip
+    pub fn trigger(&mut self, interrupt: Interrupt) {
+        self.edges |= 1 << interrupt as u32;
     }
 
-    fn highest_priority_interrupt(&self) -> Option<Interrupt> {
-        let mut highest_priority = None;
-        for &interrupt in self.interrupts.iter() {
-            match interrupt {
-                // Interrupt::NMI => highest_priority = Some(Interrupt::NMI),
-                Interrupt::MExternalInterrupt => return Some(interrupt),
-                Interrupt::SExternalInterrupt => highest_priority = Some(interrupt),
-                Interrupt::MSoftInterrupt => {
-                    if highest_priority != Some(Interrupt::SExternalInterrupt) {
-                        highest_priority = Some(interrupt);
-                    }
-                }
-                Interrupt::SSoftInterrupt => {
-                    if highest_priority != Some(Interrupt::SExternalInterrupt)
-                        && highest_priority != Some(Interrupt::MSoftInterrupt)
-                    {
-                        highest_priority = Some(interrupt);
-                    }
-                }
-                Interrupt::MTimerInterrupt => {
-                    if highest_priority != Some(Interrupt::SExternalInterrupt)
-                        && highest_priority != Some(Interrupt::MSoftInterrupt)
-                        && highest_priority != Some(Interrupt::SSoftInterrupt)
-                    {
-                        highest_priority = Some(interrupt);
-                    }
-                }
-                Interrupt::STimerInterrupt => {
-                    if highest_priority != Some(Interrupt::SExternalInterrupt)
-                        && highest_priority != Some(Interrupt::MSoftInterrupt)
-                        && highest_priority != Some(Interrupt::SSoftInterrupt)
-                        && highest_priority != Some(Interrupt::MTimerInterrupt)
-                    {
-                        highest_priority = Some(interrupt);
-                    }
-                }
-            }
+    /// Asserts or deasserts the line of the PLIC interrupt source `source`. Without a PLIC the
+    /// line drives the M-mode external interrupt directly.
+    pub fn set_external_interrupt(&mut self, source: u32, level: bool) {
+        if self
+            .with_plic(|plic| plic.set_level(source as usize, level))
+            .is_none()
+        {
+            self.set_interrupt(Interrupt::MExternalInterrupt, level);
         }
-        highest_priority
     }
 
+    /// Updates the interrupt pending bits of the hart, the PLIC drives the external interrupts
     pub fn notify_cpu(&mut self, cpu: &mut CPU) {
-        if let Some(interrupt) = self.highest_priority_interrupt() {
-            let mip = cpu.get_csr(CSRs::mip as u32).unwrap();
-            let mip_mei = mip | (1 << interrupt as u32);
-            cpu.set_csr(CSRs::mip as u32, mip_mei).unwrap();
+        let mut lines = self.lines;
+        let external = self.with_plic(|plic| {
+            (
+                plic.interrupt_pending(PLIC::context(0, false)),
+                plic.interrupt_pending(PLIC::context(0, true)),
+            )
+        });
+        if let Some((machine, supervisor)) = external {
+            lines |= (machine as u32) << Interrupt::MExternalInterrupt as u32;
+            lines |= (supervisor as u32) << Interrupt::SExternalInterrupt as u32;
         }
+        cpu.set_interrupt_lines(lines);
+        cpu.set_pending(self.edges);
+        self.edges = 0;
     }
+}
 
-    pub fn reset(&mut self, cpu: &mut CPU) {
-        self.interrupts = vec![];
+#[cfg(test)]
+mod tests {
+    use crate::cpu::CSRs;
+    use crate::instructions::Interrupt;
+    use crate::mcu::{test_mcu, DeviceDef, MCU};
+    use crate::peripherals::{clint::CLINT, plic::PLIC};
 
-        let peripherals = self.peripherals.borrow();
-        let peripheral = peripherals
-            .get("PLIC")
-            .ok_or(())
-            .and_then(|p| p.try_borrow_mut().map_err(|_| ()));
-        if let Ok(mut peripheral) = peripheral {
-            if let Some(plic) = peripheral.as_plic() {
-                let mut pending = plic.pending.borrow_mut();
-                *pending = 0;
-            }
-        }
+    const CLINT_BASE: u32 = 0x0200_0000;
+    const PLIC_BASE: u32 = 0x0c00_0000;
+    const PLIC_CLAIM: u32 = PLIC_BASE + 0x20_0004;
+    const NOP: u32 = 0x13;
+
+    fn mcu() -> MCU {
+        let mut mcu = test_mcu(
+            0x100,
+            vec![
+                DeviceDef {
+                    identifier: "CLINT".to_string(),
+                    memory_start: CLINT_BASE,
+                    memory_end: 0x0200_ffff,
+                    device: Box::new(CLINT::new()),
+                },
+                DeviceDef {
+                    identifier: "PLIC".to_string(),
+                    memory_start: PLIC_BASE,
+                    memory_end: 0x0fff_ffff,
+                    device: Box::new(PLIC::new()),
+                },
+            ],
+        );
+        mcu.flash(NOP.to_le_bytes().repeat(0x40));
+        mcu
+    }
+
+    fn pending(mcu: &MCU, interrupt: Interrupt) -> bool {
+        mcu.cpu.get_csr(CSRs::mip as u32).unwrap() & (1 << interrupt as u32) != 0
+    }
+
+    #[test]
+    fn clint_lines() {
+        let mut mcu = mcu();
+        mcu.store(CLINT_BASE, 4, 1).unwrap();
+        mcu.tick();
+        mcu.tick();
+        assert!(pending(&mcu, Interrupt::MSoftInterrupt));
+
+        // mip follows msip, software can't clear the bit through mip
+        mcu.cpu.write_csr(CSRs::mip as u32, 0).unwrap();
+        assert!(pending(&mcu, Interrupt::MSoftInterrupt));
+        mcu.store(CLINT_BASE, 4, 0).unwrap();
+        mcu.tick();
+        assert!(!pending(&mcu, Interrupt::MSoftInterrupt));
+    }
+
+    #[test]
+    fn plic_lines() {
+        let mut mcu = mcu();
+        // source 3 with priority 1, enabled for hart 0 in M-mode
+        mcu.store(PLIC_BASE + 0xc, 4, 1).unwrap();
+        mcu.store(PLIC_BASE + 0x2000, 4, 1 << 3).unwrap();
+
+        mcu.int_ctrl.set_external_interrupt(3, true);
+        mcu.tick();
+        assert!(pending(&mcu, Interrupt::MExternalInterrupt));
+        assert_eq!(mcu.load(PLIC_CLAIM, 4), Ok(3));
+        mcu.tick();
+        assert!(!pending(&mcu, Interrupt::MExternalInterrupt));
+
+        // the line is still asserted, completing the interrupt makes a new request
+        mcu.store(PLIC_CLAIM, 4, 3).unwrap();
+        mcu.tick();
+        assert!(pending(&mcu, Interrupt::MExternalInterrupt));
+        assert_eq!(mcu.load(PLIC_CLAIM, 4), Ok(3));
 
-        cpu.set_csr(CSRs::mip as u32, 0).unwrap();
+        mcu.int_ctrl.set_external_interrupt(3, false);
+        mcu.store(PLIC_CLAIM, 4, 3).unwrap();
+        mcu.tick();
+        assert!(!pending(&mcu, Interrupt::MExternalInterrupt));
     }
 }
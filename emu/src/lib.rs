@@ -1,11 +1,18 @@
 pub mod cpu;
+pub mod debug;
+pub mod dump;
+pub mod elf;
 pub mod emulator;
+pub mod gdb;
 pub mod instructions;
 pub mod interrupt_controller;
 pub mod mcu;
 pub mod memory;
 pub mod peripherals;
+pub mod pmp;
+pub mod semihosting;
 pub mod terminal;
+pub mod trigger;
 pub mod utils;
 
 #[cfg(test)]
@@ -1,11 +1,23 @@
-use crate::cpu::{CSRs, CPU};
+use crate::cpu::{
+    CSRs, DebugCause, HpmEvent, Privilege, CPU, MSTATUS_MIE, MSTATUS_MPIE, MSTATUS_MPP,
+    MSTATUS_MPP_SHIFT, MSTATUS_MPRV, MSTATUS_MXR, MSTATUS_SIE, MSTATUS_SPIE, MSTATUS_SPP,
+    MSTATUS_SUM, TVEC_MODE, TVEC_VECTORED,
+};
+use crate::elf::{Elf, ElfError, SymbolTable};
 use crate::instructions::Instruction;
 use crate::instructions::{Exception, ExceptionInterrupt};
 use crate::interrupt_controller::InterruptController;
+use crate::memory::sv32::{Context, Tlb};
 use crate::memory::DeviceMap;
-use crate::memory::{DeviceMeta, Memory, MMU};
+use crate::memory::{Access, DeviceMeta, Memory, MMU};
+use crate::peripherals::htif::HtifPort;
 use crate::peripherals::Peripheral;
-use riscv_isa_types::{privileged::RVPrivileged, rv32i::RV32i};
+use crate::semihosting::Semihosting;
+use crate::trigger::TriggerAction;
+use riscv_isa_types::format::is_compressed;
+use riscv_isa_types::{
+    privileged::RVPrivileged, rv32a::RV32a, rv32c::RV32c, rv32i::RV32i, rv32m::RV32m,
+};
 use std::collections::BTreeMap;
 
 pub struct DeviceDef {
@@ -15,12 +27,69 @@ pub struct DeviceDef {
     pub device: Box<dyn Peripheral>,
 }
 
+/// The kind of data access a watchpoint triggers on
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WatchKind {
+    Write,
+    Read,
+    Access,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Watchpoint {
+    pub kind: WatchKind,
+    pub addr: u32,
+    pub len: u32,
+}
+
+impl Watchpoint {
+    fn matches(&self, write: bool, addr: u32, size: u32) -> bool {
+        let kind = match self.kind {
+            WatchKind::Write => write,
+            WatchKind::Read => !write,
+            WatchKind::Access => true,
+        };
+        kind && addr < self.addr.wrapping_add(self.len) && self.addr < addr.wrapping_add(size)
+    }
+}
+
+/// What loads and stores to addresses that aren't a multiple of their size do
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MisalignedAccess {
+    /// Raise a LoadAddressMisaligned or StoreAddressMisaligned exception
+    Trap,
+    /// Split the access in single byte accesses
+    Emulate,
+}
+
 // Micro controller unit
 pub struct MCU {
     pub cpu: CPU,
     pub int_ctrl: InterruptController,
     pub mmu: MMU,
+    // cached Sv32 translations
+    pub tlb: Tlb,
     pub devices: DeviceMap,
+    // symbols of the loaded ELF, if any
+    pub symbols: SymbolTable,
+    // data watchpoints set by a debugger
+    pub watchpoints: Vec<Watchpoint>,
+    // last watchpoint triggered and the address that triggered it
+    pub watchpoint_hit: Option<(Watchpoint, u32)>,
+    // host services for the guest, if enabled
+    pub semihosting: Option<Semihosting>,
+    // exit code requested by the guest outside of an ecall, returned as a HALT by the next tick
+    pub halt: Option<u32>,
+    // tohost/fromhost addresses, if the program talks to the host through HTIF
+    pub htif: Option<HtifPort>,
+    // how misaligned loads and stores are handled
+    pub misaligned: MisalignedAccess,
+    // value for mtval/stval of the exception being raised, set where the fault is detected
+    tval: u32,
+    // action of the trigger that aborted the running instruction
+    trigger: Option<TriggerAction>,
+    // cycles taken by the last tick, timers advance mtime by them on the next one
+    elapsed: u32,
 }
 
 impl MCU {
@@ -30,16 +99,33 @@ impl MCU {
             cpu: CPU::new(),
             int_ctrl: InterruptController::new(std::rc::Rc::clone(&devices)),
             mmu: MMU::new(std::rc::Rc::clone(&devices)),
+            tlb: Tlb::new(),
             devices,
+            symbols: SymbolTable::default(),
+            watchpoints: Vec::new(),
+            watchpoint_hit: None,
+            semihosting: None,
+            halt: None,
+            htif: None,
+            misaligned: MisalignedAccess::Emulate,
+            tval: 0,
+            trigger: None,
+            elapsed: 0,
         }
     }
 
-    pub fn add_device(&mut self, device: DeviceDef) -> Result<&mut Self, ()> {
+    pub fn add_device(&mut self, mut device: DeviceDef) -> Result<&mut Self, ()> {
         self.mmu.insert_device(DeviceMeta::new(
             device.identifier.clone(),
             device.memory_start,
             device.memory_end,
         ))?;
+        if device.device.as_htif().is_some() {
+            self.htif = Some(HtifPort {
+                tohost: device.memory_start,
+                fromhost: Some(device.memory_start + 8),
+            });
+        }
         self.devices
             .borrow_mut()
             .insert(device.identifier, std::cell::RefCell::new(device.device));
@@ -48,10 +134,181 @@ impl MCU {
 
     pub fn flash(&mut self, mem: Vec<u8>) {
         for i in 0..mem.len() {
-            self.mmu.wb(i as u32, mem[i]).unwrap();
+            self.mmu.program(i as u32, mem[i]).unwrap();
+        }
+    }
+
+    /// Places every loadable segment at its physical address, zeroing the memory not backed by
+    /// file data, and jumps to the entry point.
+    pub fn load_elf(&mut self, elf: Elf) -> Result<(), ElfError> {
+        for segment in elf.segments.iter() {
+            let start = segment.paddr;
+            let end = start.wrapping_add(segment.mem_size - 1);
+            if !self.mmu.is_mapped(start, end) {
+                return Err(ElfError::UnmappedSegment { start, end });
+            }
+        }
+
+        for segment in elf.segments.iter() {
+            for offset in 0..segment.mem_size {
+                let addr = segment.paddr + offset;
+                let byte = segment.data.get(offset as usize).copied().unwrap_or(0);
+                self.mmu
+                    .program(addr, byte)
+                    .map_err(|_| ElfError::WriteFault(addr))?;
+            }
+        }
+
+        self.cpu.pc = elf.entry;
+        self.symbols = elf.symbols;
+        if let (None, Some(tohost)) = (self.htif, self.symbols.get("tohost")) {
+            self.htif = Some(HtifPort {
+                tohost: tohost.addr,
+                fromhost: self.symbols.get("fromhost").map(|s| s.addr),
+            });
+        }
+        Ok(())
+    }
+
+    fn check_watchpoints(&mut self, write: bool, addr: u32, size: u32) {
+        if let Some(w) = self
+            .watchpoints
+            .iter()
+            .find(|w| w.matches(write, addr, size))
+        {
+            self.watchpoint_hit = Some((*w, addr));
+        }
+    }
+
+    /// The privilege level loads and stores are performed with. When mstatus.MPRV is set they
+    /// use the privilege level in mstatus.MPP instead of the current one.
+    pub fn data_privilege(&self) -> Privilege {
+        let mstatus = self.cpu.get_csr(CSRs::mstatus as u32).unwrap();
+        if mstatus & MSTATUS_MPRV != 0 {
+            Privilege::from_bits((mstatus & MSTATUS_MPP) >> MSTATUS_MPP_SHIFT)
+        } else {
+            self.cpu.privilege
+        }
+    }
+
+    /// Translates a virtual address through the Sv32 page tables and checks the access of
+    /// `size` bytes against the PMP. Instruction fetches use the current privilege level and
+    /// data accesses the one given by [`MCU::data_privilege`].
+    pub fn translate(&mut self, vaddr: u32, size: u32, access: Access) -> Result<u32, Exception> {
+        let mstatus = self.cpu.get_csr(CSRs::mstatus as u32).unwrap();
+        let ctx = Context {
+            satp: self.cpu.get_csr(CSRs::satp as u32).unwrap(),
+            privilege: match access {
+                Access::Fetch => self.cpu.privilege,
+                _ => self.data_privilege(),
+            },
+            sum: mstatus & MSTATUS_SUM != 0,
+            mxr: mstatus & MSTATUS_MXR != 0,
+        };
+        let addr = self
+            .tlb
+            .translate(&mut self.mmu, &self.cpu.pmp, &ctx, vaddr, access)
+            .map_err(|e| self.fault(e, vaddr))?;
+        if !self.cpu.pmp.check(addr, size, access, ctx.privilege) {
+            return Err(self.fault(access.access_fault(), vaddr));
+        }
+        Ok(addr)
+    }
+
+    /// Records `tval` as the value mtval or stval take when the exception `e` is handled
+    pub fn fault(&mut self, e: Exception, tval: u32) -> Exception {
+        self.tval = tval;
+        e
+    }
+
+    /// Evaluates the triggers for an access by the running instruction. A trigger firing aborts
+    /// the instruction with a breakpoint exception, which enters Debug mode instead of trapping
+    /// if it was set by the debugger.
+    fn check_triggers(
+        &mut self,
+        access: Access,
+        addr: u32,
+        size: u32,
+        value: u32,
+    ) -> Result<(), Exception> {
+        let privilege = self.cpu.privilege;
+        self.trigger = self
+            .cpu
+            .triggers
+            .check(access, privilege, addr, size, value);
+        match self.trigger {
+            Some(_) => Err(self.fault(Exception::Breakpoint, addr)),
+            None => Ok(()),
         }
     }
 
+    /// Reads `size` (1, 2 or 4) bytes of data at `addr` on behalf of the running program.
+    /// Misaligned loads are trapped or split in byte loads depending on [`MCU::misaligned`].
+    pub fn load(&mut self, addr: u32, size: u32) -> Result<u32, Exception> {
+        self.check_watchpoints(false, addr, size);
+        let v = if addr.is_multiple_of(size) {
+            self.read(addr, size)?
+        } else if self.misaligned == MisalignedAccess::Emulate {
+            let mut v = 0;
+            for i in 0..size {
+                v |= self.read(addr.wrapping_add(i), 1)? << (8 * i);
+            }
+            v
+        } else {
+            return Err(self.fault(Exception::LoadAddressMisaligned, addr));
+        };
+        self.check_triggers(Access::Load, addr, size, v)?;
+        self.cpu.count_event(HpmEvent::Load);
+        Ok(v)
+    }
+
+    /// Writes the lower `size` (1, 2 or 4) bytes of `value` at `addr` on behalf of the running
+    /// program. Any store invalidates the load reservation. Misaligned stores are trapped or
+    /// split in byte stores depending on [`MCU::misaligned`].
+    pub fn store(&mut self, addr: u32, size: u32, value: u32) -> Result<(), Exception> {
+        self.check_watchpoints(true, addr, size);
+        let mask = u32::MAX >> (32 - 8 * size);
+        self.check_triggers(Access::Store, addr, size, value & mask)?;
+        if addr.is_multiple_of(size) {
+            self.write(addr, size, value)?;
+        } else if self.misaligned == MisalignedAccess::Emulate {
+            for i in 0..size {
+                self.write(addr.wrapping_add(i), 1, value >> (8 * i))?;
+            }
+        } else {
+            return Err(self.fault(Exception::StoreAddressMisaligned, addr));
+        }
+        self.cpu.count_event(HpmEvent::Store);
+        self.cpu.reservation = None;
+        Ok(())
+    }
+
+    /// Translates and reads an aligned `size` bytes at `vaddr`
+    fn read(&mut self, vaddr: u32, size: u32) -> Result<u32, Exception> {
+        let addr = self.translate(vaddr, size, Access::Load)?;
+        let v = match size {
+            1 => self.mmu.rb(addr).map(|v| v as u32),
+            2 => self.mmu.rhw(addr).map(|v| v as u32),
+            _ => self.mmu.rw(addr),
+        };
+        v.map_err(|_| self.fault(Exception::LoadAccessFault, vaddr))
+    }
+
+    /// Translates and writes the lower `size` bytes of `value` at the aligned `vaddr`
+    fn write(&mut self, vaddr: u32, size: u32, value: u32) -> Result<(), Exception> {
+        let addr = self.translate(vaddr, size, Access::Store)?;
+        let r = match size {
+            1 => self.mmu.wb(addr, value as u8),
+            2 => self.mmu.whw(addr, value as u16),
+            _ => self.mmu.ww(addr, value),
+        };
+        r.map_err(|_| self.fault(Exception::StoreAccessFault, vaddr))?;
+        if let Some(htif) = self.htif.filter(|htif| htif.is_command(addr, size)) {
+            htif.process(self);
+        }
+        Ok(())
+    }
+
     fn run_instruction(&mut self, word: u32) -> Result<u32, ExceptionInterrupt> {
         let v = if let Ok(v) = RVPrivileged::try_from(word) {
             log::trace!("instruction: {:?}", v);
@@ -63,6 +320,21 @@ impl MCU {
             let cost = v.execute(self)?;
             v.update_pc(self);
             cost
+        } else if let Ok(v) = RV32m::try_from(word) {
+            log::trace!("instruction: {:?}", v);
+            let cost = v.execute(self)?;
+            v.update_pc(self);
+            cost
+        } else if let Ok(v) = RV32a::try_from(word) {
+            log::trace!("instruction: {:?}", v);
+            let cost = v.execute(self)?;
+            v.update_pc(self);
+            cost
+        } else if let Ok(v) = RV32c::try_from(word) {
+            log::trace!("instruction: {:?}", v);
+            let cost = v.execute(self)?;
+            v.update_pc(self);
+            cost
         } else {
             log::error!("error decoding instruction: {word:b} at {:x}", self.cpu.pc);
             return Err(ExceptionInterrupt::Exception(Exception::IllegalInstruction));
@@ -71,100 +343,229 @@ impl MCU {
         Ok(v)
     }
 
+    /// Fetches the instruction at `addr`. Instructions are 16-bit aligned, the upper half is only
+    /// read when the lower half doesn't belong to a compressed instruction. Each half is
+    /// translated on its own, as an instruction may cross a page boundary.
+    fn fetch(&mut self, addr: u32) -> Result<u32, Exception> {
+        let compressed = self.cpu.ialign() == 2;
+        let mut read_half = |vaddr: u32| {
+            let addr = self.translate(vaddr, 2, Access::Fetch)?;
+            let half = self.mmu.rhw(addr).map(|v| v as u32);
+            half.map_err(|_| self.fault(Exception::InstructionAccessFault, vaddr))
+        };
+        let low = read_half(addr)?;
+        if compressed && is_compressed(low) {
+            Ok(low)
+        } else {
+            let high = read_half(addr.wrapping_add(2))?;
+            Ok(low | high << 16)
+        }
+    }
+
     pub fn tick(&mut self) -> TickResult {
         {
             let devices = std::rc::Rc::clone(&self.devices);
             for (_k, device) in devices.borrow().iter() {
                 let deviceref = &mut *device.borrow_mut();
+                if let Some(mtime) = deviceref.as_mtime() {
+                    mtime.advance(self.elapsed);
+                    self.cpu.set_time(mtime.get());
+                }
                 deviceref.tick(&mut self.int_ctrl);
             }
             self.int_ctrl.notify_cpu(&mut self.cpu);
         };
-        let pc = self.cpu.pc;
-        let word = self.mmu.rw(pc);
-        let mstatus = self.cpu.get_csr(CSRs::mstatus as u32).unwrap();
-        let mstatus_mie = (mstatus & (1 << 3)) != 0;
 
-        let mut interrupt = None;
-        if mstatus_mie {
-            interrupt = self.cpu.get_interrupt();
+        // Halt requests are taken between instructions
+        if self.cpu.halt_request && !self.cpu.debug_mode {
+            self.cpu.enter_debug_mode(DebugCause::HaltRequest);
+        }
+        if self.cpu.debug_mode {
+            self.elapsed = 1;
+            return TickResult::Debug;
         }
+        let stepping = self.cpu.stepping();
+        let result = self.execute();
+        self.elapsed = match result {
+            TickResult::Cycles(cycles) => cycles,
+            _ => 1,
+        };
+        // A step executes one instruction or takes one trap, halting at the next pc
+        if stepping && !self.cpu.debug_mode {
+            self.cpu.enter_debug_mode(DebugCause::Step);
+        }
+        result
+    }
+
+    /// Takes the highest priority pending interrupt or executes the next instruction
+    fn execute(&mut self) -> TickResult {
+        let pc = self.cpu.pc;
+        let interrupt = self.cpu.get_interrupt();
 
         if let Some(exc) = interrupt {
-            log::trace!("interrupt - mstatus: {mstatus}, exc: {exc:?}, pc: {pc:x}");
+            log::trace!("interrupt - exc: {exc:?}, pc: {pc:x}");
             self.handle_exception(ExceptionInterrupt::Interrupt(exc))
         } else if self.cpu.wfi {
+            self.cpu.count_event(HpmEvent::WfiCycle);
+            self.cpu.retire(1, false);
             TickResult::WFI
-        } else if let Ok(0) = word {
-            self.handle_exception(ExceptionInterrupt::Exception(Exception::IllegalInstruction))
-        } else if let Ok(addr) = word {
-            self.cpu.log_registers();
-            match self.run_instruction(addr) {
-                Ok(v) => TickResult::Cycles(v),
-                Err(err) => self.handle_exception(err),
-            }
         } else {
-            self.handle_exception(ExceptionInterrupt::Exception(
-                Exception::InstructionAccessFault,
-            ))
+            let fetched = self.fetch(pc).and_then(|word| {
+                self.check_triggers(Access::Fetch, pc, 1, word)?;
+                Ok(word)
+            });
+            match fetched {
+                // compressed instructions are illegal while the C extension is disabled
+                Ok(word) if word == 0 || (is_compressed(word) && self.cpu.ialign() == 4) => {
+                    let e = self.fault(Exception::IllegalInstruction, word);
+                    self.handle_exception(ExceptionInterrupt::Exception(e))
+                }
+                Ok(word) => {
+                    self.cpu.log_registers();
+                    match self.run_instruction(word) {
+                        Ok(v) => {
+                            self.cpu.retire(v, true);
+                            match self.halt.take() {
+                                Some(code) => TickResult::HALT(code),
+                                None => TickResult::Cycles(v),
+                            }
+                        }
+                        Err(ExceptionInterrupt::Exception(Exception::IllegalInstruction)) => {
+                            let e = self.fault(Exception::IllegalInstruction, word);
+                            self.handle_exception(ExceptionInterrupt::Exception(e))
+                        }
+                        Err(err) => self.handle_exception(err),
+                    }
+                }
+                Err(e) => self.handle_exception(ExceptionInterrupt::Exception(e)),
+            }
         }
     }
 
     // Handles interrupts and exceptions
     fn handle_exception(&mut self, exc: ExceptionInterrupt) -> TickResult {
         log::trace!("excp: {:?}", exc);
-        let mstatus = self.cpu.get_csr(CSRs::mstatus as u32).unwrap();
-        let mstatus_mie = mstatus & (1 << 3);
-        let mie = self.cpu.get_csr(CSRs::mie as u32).unwrap();
-
-        let _mcause = match exc {
+        let (cause, tval, deleg) = match exc {
             ExceptionInterrupt::Interrupt(i) => {
                 self.cpu.wfi = false;
-                let cause = 1 << i as u32;
-                if mstatus_mie != 0 && (mie & cause != 0) {
-                    self.cpu
-                        .set_csr(CSRs::mcause as u32, cause | (1 << 31))
-                        .unwrap();
-                } else {
-                    return TickResult::Cycles(4);
-                };
-                cause
+                let mideleg = self.cpu.get_csr(CSRs::mideleg as u32).unwrap();
+                (i as u32 | (1 << 31), 0, mideleg & (1 << i as u32))
             }
             ExceptionInterrupt::Exception(e) => {
                 match e {
                     Exception::MEnvironmentCall if self.cpu.get_x(10) == 255 => {
-                        return TickResult::HALT
+                        return TickResult::HALT(self.cpu.get_x(11));
                     }
                     Exception::MEnvironmentCall if self.cpu.get_x(10) == 254 => {
                         let start = self.cpu.get_x(11);
                         let end = self.cpu.get_x(12);
                         let range = std::ops::RangeInclusive::new(start, end);
+                        // The dump is handled by the emulator, the program resumes after the ecall
+                        self.cpu.pc = self.cpu.pc.wrapping_add(4);
                         return TickResult::Dump(range);
                     }
-                    _ => {
-                        // XXX: Exceptions add the pc to the mtval, which may not be the correct
-                        // way to pass the pc to the handler
-                        self.cpu.set_csr(CSRs::mcause as u32, e as u32).unwrap();
-                        self.cpu.set_csr(CSRs::mtval as u32, self.cpu.pc).unwrap();
+                    _ => {}
+                };
+                // The instruction stopped at a trigger set by the debugger, or at an ebreak that
+                // dcsr makes halt the hart. Neither retires the instruction.
+                let trigger = self.trigger.take();
+                let debug_cause = match (trigger, e) {
+                    (Some(TriggerAction::DebugMode), _) => Some(DebugCause::Trigger),
+                    (None, Exception::Breakpoint) if self.cpu.ebreak_enters_debug_mode() => {
+                        Some(DebugCause::Ebreak)
                     }
+                    _ => None,
+                };
+                if let Some(cause) = debug_cause {
+                    self.tval = 0;
+                    self.cpu.enter_debug_mode(cause);
+                    return TickResult::Debug;
+                }
+                let medeleg = self.cpu.get_csr(CSRs::medeleg as u32).unwrap();
+                // Faults carry the faulting address and illegal instructions their bits,
+                // breakpoints report the pc, or the address matched by a trigger
+                let tval = match e {
+                    Exception::Breakpoint if trigger.is_none() => self.cpu.pc,
+                    Exception::UEnvironmentCall
+                    | Exception::SEnvironmentCall
+                    | Exception::MEnvironmentCall => 0,
+                    _ => self.tval,
                 };
-                e as u32
+                (e as u32, tval, medeleg & (1 << e as u32))
             }
         };
 
-        let mstatus = (mstatus & !(1 << 3)) | mstatus_mie << 4; // move mie to mpie and set mie to 0 (disable interrupts)
-        self.cpu.set_csr(CSRs::mstatus as u32, mstatus).unwrap();
-        self.cpu.set_csr(CSRs::mepc as u32, self.cpu.pc).unwrap();
-        // TODO: Set privilege mode on msatus.MPP
-        let mtvec = self.cpu.get_csr(CSRs::mtvec as u32).unwrap();
-        self.cpu.pc = mtvec;
+        // Traps invalidate any outstanding load reservation
+        self.cpu.reservation = None;
+
+        // Traps taken in U or S-mode are handled in S-mode when delegated
+        let mstatus = self.cpu.get_csr(CSRs::mstatus as u32).unwrap();
+        let (tvec, epc, xcause, xtval) = if deleg != 0 && self.cpu.privilege < Privilege::Machine {
+            // move sie to spie, disable interrupts and save the privilege level in spp
+            let mut status = mstatus & !(MSTATUS_SIE | MSTATUS_SPIE | MSTATUS_SPP);
+            if mstatus & MSTATUS_SIE != 0 {
+                status |= MSTATUS_SPIE;
+            }
+            if self.cpu.privilege == Privilege::Supervisor {
+                status |= MSTATUS_SPP;
+            }
+            self.cpu.privilege = Privilege::Supervisor;
+            self.cpu.set_csr(CSRs::mstatus as u32, status).unwrap();
+            (CSRs::stvec, CSRs::sepc, CSRs::scause, CSRs::stval)
+        } else {
+            // move mie to mpie, disable interrupts and save the privilege level in mpp
+            let mut status = mstatus & !(MSTATUS_MIE | MSTATUS_MPIE | MSTATUS_MPP);
+            if mstatus & MSTATUS_MIE != 0 {
+                status |= MSTATUS_MPIE;
+            }
+            status |= (self.cpu.privilege as u32) << MSTATUS_MPP_SHIFT;
+            self.cpu.privilege = Privilege::Machine;
+            self.cpu.set_csr(CSRs::mstatus as u32, status).unwrap();
+            (CSRs::mtvec, CSRs::mepc, CSRs::mcause, CSRs::mtval)
+        };
+
+        self.cpu.set_csr(xcause as u32, cause).unwrap();
+        self.cpu.set_csr(xtval as u32, tval).unwrap();
+        self.tval = 0;
+        self.cpu.set_csr(epc as u32, self.cpu.pc).unwrap();
+        // In vectored mode interrupts jump to BASE + 4 * cause, exceptions to BASE
+        let tvec = self.cpu.get_csr(tvec as u32).unwrap();
+        let base = tvec & !TVEC_MODE;
+        self.cpu.pc = match exc {
+            ExceptionInterrupt::Interrupt(i) if tvec & TVEC_MODE == TVEC_VECTORED => {
+                base.wrapping_add(4 * i as u32)
+            }
+            _ => base,
+        };
+        self.cpu.count_event(HpmEvent::Trap);
+        self.cpu.retire(4, false);
         TickResult::Cycles(4)
     }
 }
 
 pub enum TickResult {
     WFI,
-    HALT,
+    // The hart is halted in Debug mode
+    Debug,
+    // The program requested to stop, carries its exit code
+    HALT(u32),
     Dump(std::ops::RangeInclusive<u32>),
     Cycles(u32),
 }
+
+/// An MCU for tests, with `flash_size` bytes of flash at address 0 and `devices`
+#[cfg(test)]
+pub(crate) fn test_mcu(flash_size: u32, devices: Vec<DeviceDef>) -> MCU {
+    let mut mcu = MCU::new();
+    mcu.add_device(DeviceDef {
+        identifier: "FLASH".to_string(),
+        memory_start: 0,
+        memory_end: flash_size - 1,
+        device: Box::new(crate::peripherals::flash::Flash::new(flash_size)),
+    })
+    .unwrap();
+    for device in devices {
+        mcu.add_device(device).unwrap();
+    }
+    mcu
+}
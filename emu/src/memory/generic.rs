@@ -13,7 +13,7 @@ pub struct GenericMemory {
 impl GenericMemory {
     pub fn new(size: u32) -> Self {
         let layout = Layout::from_size_align(size as usize, 4).unwrap();
-        let addr = unsafe { alloc::alloc(layout) };
+        let addr = unsafe { alloc::alloc_zeroed(layout) };
         Self { size, addr, layout, read_only: false }
     }
 
@@ -30,6 +30,13 @@ impl GenericMemory {
         self.read_only = read_only;
     }
 
+    /// Writes a byte even if the memory is read-only, as a loader or a flash programmer does
+    pub fn program(&mut self, addr: u32, value: u8) -> Result<(), MemoryError> {
+        self.validate(addr, 1)?;
+        unsafe { write_volatile((self.addr as usize + addr as usize) as *mut u8, value) };
+        Ok(())
+    }
+
     fn validate(&self, addr: u32, len: u32) -> Result<(), MemoryError> {
         if addr > self.size - len {
             return Err(MemoryError::AccessFault);
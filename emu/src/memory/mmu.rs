@@ -53,6 +53,25 @@ impl MMU {
         }
     }
 
+    /// Returns true if the whole address range is backed by a single device
+    pub fn is_mapped(&self, start: u32, end: u32) -> bool {
+        self.find_device_meta(start)
+            .map(|meta| end >= start && end <= meta.mem_end)
+            .unwrap_or(false)
+    }
+
+    /// Writes a byte through [`crate::peripherals::Peripheral::program`], bypassing the write
+    /// protection of read-only memories
+    pub fn program(&mut self, addr: u32, value: u8) -> Result<(), MemoryError> {
+        if let Some(meta) = self.find_device_meta(addr) {
+            let devices = self.devices.borrow();
+            let mut device = devices.get(&meta.identifier).unwrap().borrow_mut();
+            device.program(addr - meta.mem_start, value)
+        } else {
+            Err(MemoryError::AccessFault)
+        }
+    }
+
     /// Returns an error if the device overlaps memory with another
     pub fn insert_device(&mut self, meta: DeviceMeta) -> Result<(), ()> {
         //let search_result = self.find_device_index(meta.mem_start);
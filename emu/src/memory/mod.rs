@@ -1,6 +1,8 @@
 mod generic;
 mod mapped_memory;
 mod mmu;
+pub mod sv32;
+use crate::instructions::Exception;
 use crate::interrupt_controller::InterruptController;
 use crate::peripherals::Peripheral;
 pub use generic::GenericMemory;
@@ -23,6 +25,33 @@ impl std::fmt::Display for MemoryError {
 
 impl std::error::Error for MemoryError {}
 
+/// The kind of access performed by the running program, it selects the permissions checked
+/// and the exception raised on failure
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Access {
+    Fetch,
+    Load,
+    Store,
+}
+
+impl Access {
+    pub fn page_fault(self) -> Exception {
+        match self {
+            Access::Fetch => Exception::InstructionPageFault,
+            Access::Load => Exception::LoadPageFault,
+            Access::Store => Exception::StorePageFault,
+        }
+    }
+
+    pub fn access_fault(self) -> Exception {
+        match self {
+            Access::Fetch => Exception::InstructionAccessFault,
+            Access::Load => Exception::LoadAccessFault,
+            Access::Store => Exception::StoreAccessFault,
+        }
+    }
+}
+
 pub trait Memory {
     fn rb(&self, addr: u32) -> Result<u8, MemoryError>;
 
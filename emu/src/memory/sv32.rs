@@ -0,0 +1,425 @@
+//! Sv32 virtual memory: two-level page-table walks driven by satp and a small TLB in front of
+//! them. The walker reads and updates the page tables through the physical bus, these accesses
+//! are checked by the PMP as S-mode accesses.
+use super::{Access, Memory, MMU};
+use crate::cpu::Privilege;
+use crate::instructions::Exception;
+use crate::pmp::Pmp;
+
+// satp fields
+pub const SATP_MODE: u32 = 1 << 31;
+const SATP_ASID_SHIFT: u32 = 22;
+const SATP_ASID: u32 = 0x1ff;
+const SATP_PPN: u32 = 0x3f_ffff;
+
+// page table entry fields
+const PTE_V: u32 = 1 << 0;
+const PTE_R: u32 = 1 << 1;
+const PTE_W: u32 = 1 << 2;
+const PTE_X: u32 = 1 << 3;
+const PTE_U: u32 = 1 << 4;
+const PTE_G: u32 = 1 << 5;
+const PTE_A: u32 = 1 << 6;
+const PTE_D: u32 = 1 << 7;
+const PTE_PPN_SHIFT: u32 = 10;
+
+const PAGE_SHIFT: u32 = 12;
+const MEGAPAGE_SHIFT: u32 = 22;
+const TLB_ENTRIES: usize = 32;
+
+/// The state translations depend on, taken from satp, mstatus and the effective privilege level
+#[derive(Debug, Clone, Copy)]
+pub struct Context {
+    pub satp: u32,
+    pub privilege: Privilege,
+    /// mstatus.SUM, S-mode loads and stores may access user pages
+    pub sum: bool,
+    /// mstatus.MXR, loads from executable pages are allowed
+    pub mxr: bool,
+}
+
+impl Context {
+    fn asid(&self) -> u32 {
+        (self.satp >> SATP_ASID_SHIFT) & SATP_ASID
+    }
+
+    /// M-mode accesses and a satp in Bare mode are not translated
+    fn is_bare(&self) -> bool {
+        self.satp & SATP_MODE == 0 || self.privilege == Privilege::Machine
+    }
+
+    /// Checks the permissions of a leaf PTE for the access
+    fn allows(&self, pte: u32, access: Access) -> bool {
+        let user_page = pte & PTE_U != 0;
+        match self.privilege {
+            Privilege::User if !user_page => return false,
+            Privilege::Supervisor if user_page && (access == Access::Fetch || !self.sum) => {
+                return false
+            }
+            _ => {}
+        }
+        match access {
+            Access::Fetch => pte & PTE_X != 0,
+            Access::Load => pte & PTE_R != 0 || (self.mxr && pte & PTE_X != 0),
+            Access::Store => pte & PTE_W != 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct TlbEntry {
+    // virtual page number, for megapages only the upper 10 bits are significant
+    vpn: u32,
+    asid: u32,
+    pte: u32,
+    megapage: bool,
+}
+
+impl TlbEntry {
+    fn matches(&self, vaddr: u32, asid: Option<u32>) -> bool {
+        let shift = if self.megapage {
+            MEGAPAGE_SHIFT
+        } else {
+            PAGE_SHIFT
+        };
+        let asid_matches = match asid {
+            Some(asid) => self.asid == asid || self.pte & PTE_G != 0,
+            None => true,
+        };
+        (self.vpn << PAGE_SHIFT) >> shift == vaddr >> shift && asid_matches
+    }
+
+    fn physical_address(&self, vaddr: u32) -> u32 {
+        let ppn = self.pte >> PTE_PPN_SHIFT;
+        if self.megapage {
+            (ppn << PAGE_SHIFT) & !((1 << MEGAPAGE_SHIFT) - 1) | vaddr & ((1 << MEGAPAGE_SHIFT) - 1)
+        } else {
+            ppn << PAGE_SHIFT | vaddr & ((1 << PAGE_SHIFT) - 1)
+        }
+    }
+}
+
+/// Caches the leaf PTEs of recent translations. Permissions are checked again on every hit, so
+/// changes to the privilege level, SUM or MXR don't need a flush; changes to the page tables do
+/// through SFENCE.VMA.
+pub struct Tlb {
+    entries: Vec<TlbEntry>,
+    // the entry replaced next once the TLB is full
+    next: usize,
+}
+
+impl Tlb {
+    pub fn new() -> Self {
+        Self {
+            entries: Vec::with_capacity(TLB_ENTRIES),
+            next: 0,
+        }
+    }
+
+    /// Flushes the entries matching `vaddr` and `asid`, `None` matches every address or
+    /// address space. Global mappings are kept when flushing a single address space.
+    pub fn flush(&mut self, vaddr: Option<u32>, asid: Option<u32>) {
+        self.entries.retain(|e| {
+            let vaddr_matches = vaddr.map(|v| e.matches(v, None)).unwrap_or(true);
+            let asid_matches = asid
+                .map(|a| e.asid == a && e.pte & PTE_G == 0)
+                .unwrap_or(true);
+            !(vaddr_matches && asid_matches)
+        });
+        self.next = 0;
+    }
+
+    fn insert(&mut self, entry: TlbEntry) {
+        if self.entries.len() < TLB_ENTRIES {
+            self.entries.push(entry);
+        } else {
+            self.entries[self.next] = entry;
+            self.next = (self.next + 1) % TLB_ENTRIES;
+        }
+    }
+
+    /// Translates `vaddr` to a physical address, walking the page tables on a TLB miss
+    pub fn translate(
+        &mut self,
+        bus: &mut MMU,
+        pmp: &Pmp,
+        ctx: &Context,
+        vaddr: u32,
+        access: Access,
+    ) -> Result<u32, Exception> {
+        if ctx.is_bare() {
+            return Ok(vaddr);
+        }
+        let asid = ctx.asid();
+        let hit = self
+            .entries
+            .iter()
+            .position(|e| e.matches(vaddr, Some(asid)));
+        // Stores to pages that aren't dirty yet go through the walker to set D
+        let entry = match hit {
+            Some(i) if access != Access::Store || self.entries[i].pte & PTE_D != 0 => {
+                self.entries[i]
+            }
+            hit => {
+                if let Some(i) = hit {
+                    self.entries.remove(i);
+                }
+                self.walk(bus, pmp, ctx, vaddr, access)?
+            }
+        };
+        if !ctx.allows(entry.pte, access) {
+            return Err(access.page_fault());
+        }
+        Ok(entry.physical_address(vaddr))
+    }
+
+    /// Walks the page tables, updating the A and D bits of the leaf PTE and caching it
+    fn walk(
+        &mut self,
+        bus: &mut MMU,
+        pmp: &Pmp,
+        ctx: &Context,
+        vaddr: u32,
+        access: Access,
+    ) -> Result<TlbEntry, Exception> {
+        let vpn = [(vaddr >> PAGE_SHIFT) & 0x3ff, vaddr >> MEGAPAGE_SHIFT];
+        // Physical addresses are 34 bits wide, the bus only decodes 32 of them
+        let table_address = |ppn: u32| {
+            ppn.checked_shl(PAGE_SHIFT)
+                .filter(|_| ppn >> (32 - PAGE_SHIFT) == 0)
+                .ok_or(access.access_fault())
+        };
+
+        let mut table = table_address(ctx.satp & SATP_PPN)?;
+        for level in (0..2).rev() {
+            let pte_addr = table + vpn[level] * 4;
+            if !pmp.check(pte_addr, 4, Access::Load, Privilege::Supervisor) {
+                return Err(access.access_fault());
+            }
+            let pte = bus.rw(pte_addr).map_err(|_| access.access_fault())?;
+            if pte & PTE_V == 0 || (pte & PTE_R == 0 && pte & PTE_W != 0) {
+                return Err(access.page_fault());
+            }
+            let ppn = pte >> PTE_PPN_SHIFT;
+            if pte & (PTE_R | PTE_X) == 0 {
+                // pointer to the next level, the last level can't have pointers
+                if level == 0 {
+                    return Err(access.page_fault());
+                }
+                table = table_address(ppn)?;
+                continue;
+            }
+
+            // Leaf PTE, megapages must be aligned to 4 MiB
+            let megapage = level == 1;
+            if megapage && ppn & 0x3ff != 0 {
+                return Err(access.page_fault());
+            }
+            if !ctx.allows(pte, access) {
+                return Err(access.page_fault());
+            }
+            table_address(ppn)?;
+            let mut updated = pte | PTE_A;
+            if access == Access::Store {
+                updated |= PTE_D;
+            }
+            if updated != pte {
+                if !pmp.check(pte_addr, 4, Access::Store, Privilege::Supervisor) {
+                    return Err(access.access_fault());
+                }
+                bus.ww(pte_addr, updated)
+                    .map_err(|_| access.access_fault())?;
+            }
+
+            let entry = TlbEntry {
+                vpn: vaddr >> PAGE_SHIFT,
+                asid: ctx.asid(),
+                pte: updated,
+                megapage,
+            };
+            self.insert(entry);
+            return Ok(entry);
+        }
+        unreachable!()
+    }
+}
+
+impl Default for Tlb {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mcu::{test_mcu, MCU};
+    use crate::pmp::{PMPADDR0, PMPCFG0};
+
+    const ROOT: u32 = 0x1000;
+    const TABLE: u32 = 0x2000;
+    const PAGE: u32 = 0x3000;
+
+    fn pte(addr: u32, flags: u32) -> u32 {
+        (addr >> PAGE_SHIFT) << PTE_PPN_SHIFT | flags | PTE_V
+    }
+
+    /// Maps 0x4000_5000 to PAGE through a two level table with the given leaf flags
+    fn mcu(flags: u32) -> MCU {
+        let mut mcu = test_mcu(0x10000, vec![]);
+        mcu.cpu.set_csr(PMPADDR0, u32::MAX).unwrap();
+        mcu.cpu.set_csr(PMPCFG0, 0x1f).unwrap();
+        mcu.mmu.ww(ROOT + 0x100 * 4, pte(TABLE, 0)).unwrap();
+        mcu.mmu.ww(TABLE + 5 * 4, pte(PAGE, flags)).unwrap();
+        mcu
+    }
+
+    fn ctx(privilege: Privilege) -> Context {
+        Context {
+            satp: SATP_MODE | (7 << SATP_ASID_SHIFT) | ROOT >> PAGE_SHIFT,
+            privilege,
+            sum: false,
+            mxr: false,
+        }
+    }
+
+    fn translate(
+        mcu: &mut MCU,
+        ctx: &Context,
+        vaddr: u32,
+        access: Access,
+    ) -> Result<u32, Exception> {
+        mcu.tlb
+            .translate(&mut mcu.mmu, &mcu.cpu.pmp, ctx, vaddr, access)
+    }
+
+    #[test]
+    fn walk_sets_accessed_and_dirty() {
+        let mut mcu = mcu(PTE_R | PTE_W);
+        let ctx = ctx(Privilege::Supervisor);
+        assert_eq!(
+            translate(&mut mcu, &ctx, 0x4000_5123, Access::Load),
+            Ok(0x3123)
+        );
+        assert_eq!(
+            mcu.mmu.rw(TABLE + 5 * 4),
+            Ok(pte(PAGE, PTE_R | PTE_W | PTE_A))
+        );
+        assert_eq!(
+            translate(&mut mcu, &ctx, 0x4000_5ffc, Access::Store),
+            Ok(0x3ffc)
+        );
+        assert_eq!(
+            mcu.mmu.rw(TABLE + 5 * 4),
+            Ok(pte(PAGE, PTE_R | PTE_W | PTE_A | PTE_D))
+        );
+
+        assert_eq!(
+            translate(&mut mcu, &ctx, 0x4000_5000, Access::Fetch),
+            Err(Exception::InstructionPageFault)
+        );
+        assert_eq!(
+            translate(&mut mcu, &ctx, 0x4000_6000, Access::Load),
+            Err(Exception::LoadPageFault)
+        );
+        // M-mode isn't translated
+        assert_eq!(
+            translate(
+                &mut mcu,
+                &self::ctx(Privilege::Machine),
+                0x4000_5000,
+                Access::Load
+            ),
+            Ok(0x4000_5000)
+        );
+    }
+
+    #[test]
+    fn user_pages() {
+        let mut mcu = mcu(PTE_R | PTE_X | PTE_U);
+        let mut ctx = ctx(Privilege::Supervisor);
+        assert_eq!(
+            translate(&mut mcu, &ctx, 0x4000_5000, Access::Load),
+            Err(Exception::LoadPageFault)
+        );
+        ctx.sum = true;
+        assert_eq!(
+            translate(&mut mcu, &ctx, 0x4000_5000, Access::Load),
+            Ok(PAGE)
+        );
+        assert!(
+            translate(&mut mcu, &ctx, 0x4000_5000, Access::Fetch).is_err(),
+            "S-mode can never execute user pages"
+        );
+        assert_eq!(
+            translate(&mut mcu, &ctx, 0x4000_5000, Access::Store),
+            Err(Exception::StorePageFault)
+        );
+
+        let ctx = self::ctx(Privilege::User);
+        assert_eq!(
+            translate(&mut mcu, &ctx, 0x4000_5000, Access::Fetch),
+            Ok(PAGE)
+        );
+    }
+
+    #[test]
+    fn make_executable_readable() {
+        let mut mcu = mcu(PTE_X);
+        let mut ctx = ctx(Privilege::Supervisor);
+        assert!(translate(&mut mcu, &ctx, 0x4000_5000, Access::Load).is_err());
+        ctx.mxr = true;
+        assert_eq!(
+            translate(&mut mcu, &ctx, 0x4000_5000, Access::Load),
+            Ok(PAGE)
+        );
+    }
+
+    #[test]
+    fn megapages() {
+        let mut mcu = mcu(0);
+        let ctx = ctx(Privilege::Supervisor);
+        mcu.mmu
+            .ww(ROOT + 0x200 * 4, pte(0x40_0000, PTE_R | PTE_A))
+            .unwrap();
+        assert_eq!(
+            translate(&mut mcu, &ctx, 0x8012_3456, Access::Load),
+            Ok(0x0052_3456)
+        );
+        // megapages must be aligned
+        mcu.mmu.ww(ROOT + 0x201 * 4, pte(PAGE, PTE_R)).unwrap();
+        assert!(translate(&mut mcu, &ctx, 0x8040_0000, Access::Load).is_err());
+    }
+
+    #[test]
+    fn tlb_flush() {
+        let mut mcu = mcu(PTE_R);
+        let ctx = ctx(Privilege::Supervisor);
+        assert_eq!(
+            translate(&mut mcu, &ctx, 0x4000_5000, Access::Load),
+            Ok(PAGE)
+        );
+        mcu.mmu.ww(TABLE + 5 * 4, pte(0x8000, PTE_R)).unwrap();
+        assert_eq!(
+            translate(&mut mcu, &ctx, 0x4000_5000, Access::Load),
+            Ok(PAGE),
+            "the stale translation is cached"
+        );
+
+        mcu.tlb.flush(Some(0x4000_6000), None);
+        assert_eq!(
+            translate(&mut mcu, &ctx, 0x4000_5000, Access::Load),
+            Ok(PAGE)
+        );
+        mcu.tlb.flush(None, Some(3));
+        assert_eq!(
+            translate(&mut mcu, &ctx, 0x4000_5000, Access::Load),
+            Ok(PAGE)
+        );
+        mcu.tlb.flush(Some(0x4000_5abc), Some(7));
+        assert_eq!(
+            translate(&mut mcu, &ctx, 0x4000_5000, Access::Load),
+            Ok(0x8000)
+        );
+    }
+}
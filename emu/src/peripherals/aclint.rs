@@ -0,0 +1,313 @@
+//! The devices of the RISC-V Advanced Core Local Interruptor: MSWI, MTIMER and SSWI. Each has a
+//! register per hart for `harts` harts and is added to the MCU on its own with a `DeviceDef`.
+//!
+//! The MCU has a single hart, the interrupt lines of the other harts aren't connected.
+use crate::instructions::Interrupt;
+use crate::interrupt_controller::InterruptController;
+use crate::memory::Clocked;
+use crate::memory::{Memory, MemoryError};
+use crate::peripherals::clint::{set_half, Mtime, Timebase};
+use crate::peripherals::Peripheral;
+
+// offset of the mtime register in the MTIMER, after the mtimecmp registers of 4095 harts
+const MTIME: u32 = 0x7ff8;
+
+/// The index of the 32-bit register at `addr`, if it's below `len`
+fn register(addr: u32, len: usize) -> Result<usize, MemoryError> {
+    let index = (addr / 4) as usize;
+    if !addr.is_multiple_of(4) || index >= len {
+        return Err(MemoryError::AccessFault);
+    }
+    Ok(index)
+}
+
+/// Machine-level software interrupt device, an msip register per hart drives its MSIP
+pub struct MSWI {
+    pub msip: Vec<u32>,
+}
+
+impl MSWI {
+    pub fn new(harts: usize) -> Self {
+        Self {
+            msip: vec![0; harts],
+        }
+    }
+}
+
+impl Peripheral for MSWI {}
+
+impl Clocked for MSWI {
+    fn tick(&mut self, int_ctrl: &mut InterruptController) {
+        int_ctrl.set_interrupt(Interrupt::MSoftInterrupt, self.msip[0] != 0);
+    }
+}
+
+impl Memory for MSWI {
+    fn rb(&self, _addr: u32) -> Result<u8, MemoryError> {
+        Err(MemoryError::AccessFault)
+    }
+
+    fn wb(&mut self, _addr: u32, _value: u8) -> Result<(), MemoryError> {
+        Err(MemoryError::AccessFault)
+    }
+
+    fn rhw(&self, _addr: u32) -> Result<u16, MemoryError> {
+        Err(MemoryError::AccessFault)
+    }
+
+    fn whw(&mut self, _addr: u32, _value: u16) -> Result<(), MemoryError> {
+        Err(MemoryError::AccessFault)
+    }
+
+    fn rw(&self, addr: u32) -> Result<u32, MemoryError> {
+        Ok(self.msip[register(addr, self.msip.len())?])
+    }
+
+    fn ww(&mut self, addr: u32, value: u32) -> Result<(), MemoryError> {
+        let hart = register(addr, self.msip.len())?;
+        // only the lowest bit of msip is writable
+        self.msip[hart] = value & 1;
+        Ok(())
+    }
+}
+
+/// Machine-level timer device, an mtimecmp register per hart at `8 * hart` and the mtime they
+/// share at 0x7ff8. MTIP is asserted while `mtime >= mtimecmp`.
+pub struct MTIMER {
+    pub mtimecmp: Vec<u64>,
+    pub mtime: Mtime,
+}
+
+impl MTIMER {
+    /// An MTIMER whose mtime advances once per cycle
+    pub fn new(harts: usize) -> Self {
+        Self::with_timebase(harts, Timebase::Cycles { hz: 1, cpu_hz: 1 })
+    }
+
+    pub fn with_timebase(harts: usize, timebase: Timebase) -> Self {
+        Self {
+            mtimecmp: vec![0; harts],
+            mtime: Mtime::new(timebase),
+        }
+    }
+}
+
+impl Peripheral for MTIMER {
+    fn as_mtime(&mut self) -> Option<&mut Mtime> {
+        Some(&mut self.mtime)
+    }
+}
+
+impl Clocked for MTIMER {
+    fn tick(&mut self, int_ctrl: &mut InterruptController) {
+        int_ctrl.set_interrupt(
+            Interrupt::MTimerInterrupt,
+            self.mtime.get() >= self.mtimecmp[0],
+        );
+    }
+}
+
+impl Memory for MTIMER {
+    fn rb(&self, _addr: u32) -> Result<u8, MemoryError> {
+        Err(MemoryError::AccessFault)
+    }
+
+    fn wb(&mut self, _addr: u32, _value: u8) -> Result<(), MemoryError> {
+        Err(MemoryError::AccessFault)
+    }
+
+    fn rhw(&self, _addr: u32) -> Result<u16, MemoryError> {
+        Err(MemoryError::AccessFault)
+    }
+
+    fn whw(&mut self, _addr: u32, _value: u16) -> Result<(), MemoryError> {
+        Err(MemoryError::AccessFault)
+    }
+
+    fn rw(&self, addr: u32) -> Result<u32, MemoryError> {
+        if addr >= MTIME {
+            register(addr - MTIME, 2)?;
+            return Ok(self.mtime.read_half(addr - MTIME));
+        }
+        let index = register(addr, 2 * self.mtimecmp.len())?;
+        Ok((self.mtimecmp[index / 2] >> (32 * (index % 2))) as u32)
+    }
+
+    fn ww(&mut self, addr: u32, value: u32) -> Result<(), MemoryError> {
+        if addr >= MTIME {
+            register(addr - MTIME, 2)?;
+            self.mtime.write_half(addr - MTIME, value);
+            return Ok(());
+        }
+        let index = register(addr, 2 * self.mtimecmp.len())?;
+        let mtimecmp = &mut self.mtimecmp[index / 2];
+        *mtimecmp = set_half(*mtimecmp, 4 * (index as u32 % 2), value);
+        Ok(())
+    }
+}
+
+/// Supervisor-level software interrupt device, writing 1 to the setssip register of a hart sets
+/// its SSIP bit, which the supervisor clears in sip. setssip always reads as zero.
+pub struct SSWI {
+    // harts whose setssip was written since the last tick
+    setssip: Vec<bool>,
+}
+
+impl SSWI {
+    pub fn new(harts: usize) -> Self {
+        Self {
+            setssip: vec![false; harts],
+        }
+    }
+}
+
+impl Peripheral for SSWI {}
+
+impl Clocked for SSWI {
+    fn tick(&mut self, int_ctrl: &mut InterruptController) {
+        if self.setssip[0] {
+            int_ctrl.trigger(Interrupt::SSoftInterrupt);
+        }
+        self.setssip.fill(false);
+    }
+}
+
+impl Memory for SSWI {
+    fn rb(&self, _addr: u32) -> Result<u8, MemoryError> {
+        Err(MemoryError::AccessFault)
+    }
+
+    fn wb(&mut self, _addr: u32, _value: u8) -> Result<(), MemoryError> {
+        Err(MemoryError::AccessFault)
+    }
+
+    fn rhw(&self, _addr: u32) -> Result<u16, MemoryError> {
+        Err(MemoryError::AccessFault)
+    }
+
+    fn whw(&mut self, _addr: u32, _value: u16) -> Result<(), MemoryError> {
+        Err(MemoryError::AccessFault)
+    }
+
+    fn rw(&self, addr: u32) -> Result<u32, MemoryError> {
+        register(addr, self.setssip.len())?;
+        Ok(0)
+    }
+
+    fn ww(&mut self, addr: u32, value: u32) -> Result<(), MemoryError> {
+        let hart = register(addr, self.setssip.len())?;
+        if value & 1 != 0 {
+            self.setssip[hart] = true;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::CSRs;
+    use crate::mcu::{test_mcu, DeviceDef, MCU};
+
+    const MSWI_BASE: u32 = 0x0200_0000;
+    const MTIMER_BASE: u32 = 0x0200_4000;
+    const SSWI_BASE: u32 = 0x02f0_0000;
+    const NOP: u32 = 0x13;
+
+    /// An MCU with the ACLINT devices for 2 harts at the addresses of the QEMU virt machine
+    fn mcu() -> MCU {
+        let mut mcu = test_mcu(
+            0x100,
+            vec![
+                DeviceDef {
+                    identifier: "MSWI".to_string(),
+                    memory_start: MSWI_BASE,
+                    memory_end: 0x0200_3fff,
+                    device: Box::new(MSWI::new(2)),
+                },
+                DeviceDef {
+                    identifier: "MTIMER".to_string(),
+                    memory_start: MTIMER_BASE,
+                    memory_end: 0x0200_bfff,
+                    device: Box::new(MTIMER::new(2)),
+                },
+                DeviceDef {
+                    identifier: "SSWI".to_string(),
+                    memory_start: SSWI_BASE,
+                    memory_end: 0x02f0_3fff,
+                    device: Box::new(SSWI::new(2)),
+                },
+            ],
+        );
+        mcu.flash(NOP.to_le_bytes().repeat(0x40));
+        // a timer interrupt is pending while mtimecmp is 0
+        mcu.store(MTIMER_BASE + 4, 4, u32::MAX).unwrap();
+        mcu
+    }
+
+    fn pending(mcu: &MCU, interrupt: Interrupt) -> bool {
+        mcu.cpu.get_csr(CSRs::mip as u32).unwrap() & (1 << interrupt as u32) != 0
+    }
+
+    #[test]
+    fn mswi() {
+        let mut mcu = mcu();
+        mcu.store(MSWI_BASE + 4, 4, u32::MAX).unwrap();
+        assert_eq!(mcu.load(MSWI_BASE + 4, 4), Ok(1));
+        mcu.tick();
+        assert!(
+            !pending(&mcu, Interrupt::MSoftInterrupt),
+            "hart 1 isn't connected"
+        );
+        mcu.store(MSWI_BASE, 4, 1).unwrap();
+        mcu.tick();
+        assert!(pending(&mcu, Interrupt::MSoftInterrupt));
+        mcu.store(MSWI_BASE, 4, 0).unwrap();
+        mcu.tick();
+        assert!(!pending(&mcu, Interrupt::MSoftInterrupt));
+        assert!(mcu.load(MSWI_BASE + 8, 4).is_err(), "there are 2 harts");
+    }
+
+    #[test]
+    fn mtimer() {
+        let mut mcu = mcu();
+        mcu.store(MTIMER_BASE + 8, 4, 3).unwrap();
+        assert_eq!(mcu.load(MTIMER_BASE + 8, 4), Ok(3));
+        assert!(
+            mcu.load(MTIMER_BASE + 0x10, 4).is_err(),
+            "there are 2 harts"
+        );
+        for _ in 0..4 {
+            mcu.tick();
+        }
+        assert!(!pending(&mcu, Interrupt::MTimerInterrupt));
+
+        mcu.store(MTIMER_BASE + 4, 4, 0).unwrap();
+        mcu.store(MTIMER_BASE, 4, 2).unwrap();
+        mcu.tick();
+        assert!(mcu.load(MTIMER_BASE + MTIME, 4).unwrap() >= 2);
+        assert!(pending(&mcu, Interrupt::MTimerInterrupt));
+        assert_eq!(
+            mcu.cpu.get_csr(CSRs::time as u32),
+            mcu.load(MTIMER_BASE + MTIME, 4)
+        );
+
+        mcu.store(MTIMER_BASE + MTIME, 4, 0).unwrap();
+        mcu.tick();
+        assert!(!pending(&mcu, Interrupt::MTimerInterrupt));
+    }
+
+    #[test]
+    fn sswi() {
+        let mut mcu = mcu();
+        mcu.store(SSWI_BASE, 4, 1).unwrap();
+        assert_eq!(mcu.load(SSWI_BASE, 4), Ok(0));
+        mcu.tick();
+        assert!(pending(&mcu, Interrupt::SSoftInterrupt));
+
+        // the supervisor clears SSIP, the SSWI doesn't raise it again
+        mcu.cpu.write_csr(CSRs::mip as u32, 0).unwrap();
+        mcu.tick();
+        assert!(!pending(&mcu, Interrupt::SSoftInterrupt));
+    }
+}
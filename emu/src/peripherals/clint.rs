@@ -3,37 +3,129 @@ use crate::interrupt_controller::InterruptController;
 use crate::memory::Clocked;
 use crate::memory::{Memory, MemoryError};
 use crate::peripherals::Peripheral;
+use std::time::Instant;
+
+/// How mtime advances
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Timebase {
+    /// `hz` ticks per second of emulated time, following the cycles of a hart clocked at
+    /// `cpu_hz`
+    Cycles { hz: u64, cpu_hz: u64 },
+    /// `hz` ticks per second of the host wall clock, so tick rates match real time
+    WallClock { hz: u64 },
+}
+
+/// The mtime counter of a timer device. The MCU advances it with the cycles of the hart and
+/// mirrors it in the time CSR.
+pub struct Mtime {
+    value: u64,
+    timebase: Timebase,
+    // cycles times the timebase frequency that didn't make a whole mtime tick yet
+    fraction: u64,
+    // host time of the last write to mtime and the value written, for the wall clock timebase
+    epoch: (Instant, u64),
+}
+
+impl Mtime {
+    pub fn new(timebase: Timebase) -> Self {
+        Self {
+            value: 0,
+            timebase,
+            fraction: 0,
+            epoch: (Instant::now(), 0),
+        }
+    }
+
+    pub fn get(&self) -> u64 {
+        self.value
+    }
+
+    pub fn set(&mut self, value: u64) {
+        self.value = value;
+        self.fraction = 0;
+        self.epoch = (Instant::now(), value);
+    }
+
+    /// Advances mtime by the time `cycles` cycles of the hart take, or up to the current time of
+    /// the host with the wall clock timebase
+    pub fn advance(&mut self, cycles: u32) {
+        match self.timebase {
+            Timebase::Cycles { hz, cpu_hz } => {
+                self.fraction += cycles as u64 * hz;
+                self.value = self.value.wrapping_add(self.fraction / cpu_hz);
+                self.fraction %= cpu_hz;
+            }
+            Timebase::WallClock { hz } => {
+                let (instant, value) = self.epoch;
+                let ticks = instant.elapsed().as_nanos() * hz as u128 / 1_000_000_000;
+                self.value = value.wrapping_add(ticks as u64);
+            }
+        }
+    }
+
+    /// Reads the low (offset 0) or high (offset 4) half
+    pub fn read_half(&self, offset: u32) -> u32 {
+        (self.value >> (8 * offset)) as u32
+    }
+
+    /// Writes the low (offset 0) or high (offset 4) half
+    pub fn write_half(&mut self, offset: u32, value: u32) {
+        self.set(set_half(self.value, offset, value));
+    }
+}
+
+/// Replaces the low (offset 0) or high (offset 4) half of `register` with `value`
+pub(crate) fn set_half(register: u64, offset: u32, value: u32) -> u64 {
+    let shift = 8 * offset;
+    (register & !(0xffff_ffff << shift)) | ((value as u64) << shift)
+}
 
 pub struct CLINT {
     pub msip0: u32,    // addr 0
-    pub mtimecmp: u64, // addr 4
-    pub mtime: u64,    // addr 12
+    pub mtimecmp: u64, // addr 0x4000
+    pub mtime: Mtime,  // addr 0xbff8
 }
 
 impl CLINT {
+    /// A CLINT whose mtime advances once per cycle
     pub fn new() -> Self {
+        Self::with_timebase(Timebase::Cycles { hz: 1, cpu_hz: 1 })
+    }
+
+    pub fn with_timebase(timebase: Timebase) -> Self {
         Self {
             msip0: 0,
             mtimecmp: 0,
-            mtime: 0,
+            mtime: Mtime::new(timebase),
         }
     }
 }
 
-impl Peripheral for CLINT {}
+impl Default for CLINT {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
-impl Clocked for CLINT {
-    /// Increases time, generates timer & software interrupts
-    fn tick(&mut self, int_ctrl: &mut InterruptController) -> () {
-        self.mtime += 1;
+impl Peripheral for CLINT {
+    fn as_clint(&mut self) -> Option<&mut Self> {
+        Some(self)
+    }
 
-        if self.mtimecmp != 0 && self.mtime >= self.mtimecmp {
-            int_ctrl.interrupt(Interrupt::MTimerInterrupt, 0)
-        }
+    fn as_mtime(&mut self) -> Option<&mut Mtime> {
+        Some(&mut self.mtime)
+    }
+}
 
-        if self.msip0 > 0 {
-            int_ctrl.interrupt(Interrupt::MSoftInterrupt, self.msip0)
-        }
+impl Clocked for CLINT {
+    /// Generates timer & software interrupts, time is advanced by the MCU through
+    /// [`Mtime::advance`]
+    fn tick(&mut self, int_ctrl: &mut InterruptController) -> () {
+        int_ctrl.set_interrupt(
+            Interrupt::MTimerInterrupt,
+            self.mtime.get() >= self.mtimecmp,
+        );
+        int_ctrl.set_interrupt(Interrupt::MSoftInterrupt, self.msip0 & 1 != 0);
     }
 }
 
@@ -59,8 +151,7 @@ impl Memory for CLINT {
             0 => Ok(self.msip0),
             0x4000 => Ok(self.mtimecmp as u32),
             0x4004 => Ok((self.mtimecmp >> 32) as u32),
-            0xbff8 => Ok(self.mtime as u32),
-            0xbffc => Ok((self.mtime >> 32) as u32),
+            0xbff8 | 0xbffc => Ok(self.mtime.read_half(addr - 0xbff8)),
             _ => Err(MemoryError::AccessFault),
         }
     }
@@ -68,21 +159,92 @@ impl Memory for CLINT {
     fn ww(&mut self, addr: u32, value: u32) -> Result<(), MemoryError> {
         match addr {
             0 => {
-                self.msip0 = value;
+                // only the lowest bit of msip is writable
+                self.msip0 = value & 1;
                 Ok(())
             }
-            0x4000 => {
-                self.mtimecmp = (self.mtimecmp & !0xffff_ffff) | value as u64;
+            0x4000 | 0x4004 => {
+                self.mtimecmp = set_half(self.mtimecmp, addr - 0x4000, value);
                 Ok(())
             }
-            0x4004 => {
-                self.mtimecmp = (self.mtimecmp & !(0xffff_ffff << 32)) | ((value as u64) << 32);
+            0xbff8 | 0xbffc => {
+                self.mtime.write_half(addr - 0xbff8, value);
                 Ok(())
             }
-            0xbff8 => Ok(()),
-            0xbffc => Ok(()),
-            v if v == 0xbff8 || v == (0xbff8 + 4) => Ok(()),
             _ => Err(MemoryError::AccessFault),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::CSRs;
+    use crate::mcu::{test_mcu, DeviceDef, MCU};
+
+    const NOP: u32 = 0x13;
+
+    fn mcu(clint: CLINT) -> MCU {
+        let mut mcu = test_mcu(
+            0x100,
+            vec![DeviceDef {
+                identifier: "CLINT".to_string(),
+                memory_start: 0x0200_0000,
+                memory_end: 0x0200_ffff,
+                device: Box::new(clint),
+            }],
+        );
+        mcu.flash(NOP.to_le_bytes().repeat(0x40));
+        mcu
+    }
+
+    fn timer_pending(mcu: &MCU) -> bool {
+        mcu.cpu.get_csr(CSRs::mip as u32).unwrap() & (1 << Interrupt::MTimerInterrupt as u32) != 0
+    }
+
+    #[test]
+    fn timer() {
+        let mut mcu = mcu(CLINT::new());
+        mcu.tick();
+        assert!(timer_pending(&mcu), "a zero mtimecmp fires");
+
+        // moving mtimecmp forward deasserts MTIP
+        mcu.store(0x0200_4000, 4, 10).unwrap();
+        mcu.tick();
+        assert!(!timer_pending(&mcu));
+
+        // mtime is writable
+        mcu.store(0x0200_bffc, 4, 1).unwrap();
+        mcu.store(0x0200_bff8, 4, 5).unwrap();
+        assert_eq!(mcu.load(0x0200_bffc, 4), Ok(1));
+        assert_eq!(mcu.load(0x0200_bff8, 4), Ok(5));
+        mcu.tick();
+        assert!(timer_pending(&mcu));
+        assert_eq!(mcu.cpu.get_csr(CSRs::timeh as u32), Ok(1));
+        mcu.store(0x0200_bffc, 4, 0).unwrap();
+        mcu.store(0x0200_bff8, 4, 0).unwrap();
+        for _ in 0..9 {
+            mcu.tick();
+        }
+        assert!(!timer_pending(&mcu));
+        mcu.tick();
+        assert!(timer_pending(&mcu));
+    }
+
+    #[test]
+    fn timebase() {
+        let mut clint = CLINT::with_timebase(Timebase::Cycles { hz: 3, cpu_hz: 4 });
+        clint.mtime.advance(1);
+        assert_eq!(clint.mtime.get(), 0);
+        clint.mtime.advance(2);
+        assert_eq!(clint.mtime.get(), 2);
+        clint.mtime.advance(5);
+        assert_eq!(clint.mtime.get(), 6);
+
+        let mut clint = CLINT::with_timebase(Timebase::WallClock { hz: 1000 });
+        clint.ww(0xbff8, 100).unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        clint.mtime.advance(0);
+        assert!(clint.mtime.get() >= 105);
+    }
+}
@@ -8,6 +8,12 @@ impl Flash {
     pub fn new(size: u32) -> Self {
         Self(GenericMemory::new(size))
     }
+
+    /// Makes stores from the program fail, as in execute-in-place flash. The loader can still
+    /// write it.
+    pub fn set_read_only(&mut self, read_only: bool) {
+        self.0.set_read_only(read_only);
+    }
 }
 
 impl From<Vec<u8>> for Flash {
@@ -25,7 +31,11 @@ impl From<GenericMemory> for Flash {
     }
 }
 
-impl Peripheral for Flash {}
+impl Peripheral for Flash {
+    fn program(&mut self, addr: u32, value: u8) -> Result<(), MemoryError> {
+        self.0.program(addr, value)
+    }
+}
 
 impl Clocked for Flash {
     fn tick(&mut self, _: &mut InterruptController) {}
@@ -55,4 +65,3 @@ impl Memory for Flash {
         self.0.ww(addr, value)
     }
 }
-
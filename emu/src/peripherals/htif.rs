@@ -0,0 +1,299 @@
+//! Host-target interface (HTIF), the `tohost`/`fromhost` protocol used by riscv-tests and many
+//! bare-metal test suites to report results and print to the console.
+//!
+//! The guest writes a 64-bit command to `tohost`: the device in bits 63:56, the command in bits
+//! 55:48 and the payload in bits 47:0. The command is processed when the upper word is written.
+use crate::interrupt_controller::InterruptController;
+use crate::mcu::MCU;
+use crate::memory::{Clocked, Memory, MemoryError};
+use crate::peripherals::Peripheral;
+use std::io::Write;
+
+const DEVICE_SYSCALL: u32 = 0;
+const DEVICE_CONSOLE: u32 = 1;
+const CONSOLE_PUTCHAR: u32 = 1;
+
+const SYS_WRITE: u32 = 64;
+const SYS_EXIT: u32 = 93;
+const EFAULT: u32 = 14;
+const ENOSYS: u32 = 38;
+// SYS_WRITE copies the guest's buffer in chunks of at most this many bytes
+const WRITE_CHUNK: u32 = 4096;
+
+/// `tohost`/`fromhost` registers, to be mapped through a [`crate::mcu::DeviceDef`]. `tohost`
+/// is at offset 0 and `fromhost` at offset 8.
+pub struct HTIF {
+    registers: [u32; 4],
+}
+
+impl HTIF {
+    pub fn new() -> Self {
+        Self { registers: [0; 4] }
+    }
+}
+
+impl Default for HTIF {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Peripheral for HTIF {
+    fn as_htif(&mut self) -> Option<&mut Self> {
+        Some(self)
+    }
+}
+
+impl Clocked for HTIF {
+    fn tick(&mut self, _: &mut InterruptController) {}
+}
+
+impl Memory for HTIF {
+    fn rb(&self, addr: u32) -> Result<u8, MemoryError> {
+        let word = self.rw(addr & !0b11)?;
+        Ok((word >> (8 * (addr & 0b11))) as u8)
+    }
+
+    fn wb(&mut self, addr: u32, value: u8) -> Result<(), MemoryError> {
+        let shift = 8 * (addr & 0b11);
+        let word = self.rw(addr & !0b11)? & !(0xff << shift);
+        self.ww(addr & !0b11, word | (value as u32) << shift)
+    }
+
+    fn rhw(&self, addr: u32) -> Result<u16, MemoryError> {
+        let word = self.rw(addr & !0b11)?;
+        Ok((word >> (8 * (addr & 0b10))) as u16)
+    }
+
+    fn whw(&mut self, addr: u32, value: u16) -> Result<(), MemoryError> {
+        let shift = 8 * (addr & 0b10);
+        let word = self.rw(addr & !0b11)? & !(0xffff << shift);
+        self.ww(addr & !0b11, word | (value as u32) << shift)
+    }
+
+    fn rw(&self, addr: u32) -> Result<u32, MemoryError> {
+        self.registers
+            .get(addr as usize / 4)
+            .copied()
+            .ok_or(MemoryError::AccessFault)
+    }
+
+    fn ww(&mut self, addr: u32, value: u32) -> Result<(), MemoryError> {
+        let reg = self
+            .registers
+            .get_mut(addr as usize / 4)
+            .ok_or(MemoryError::AccessFault)?;
+        *reg = value;
+        Ok(())
+    }
+}
+
+/// The addresses of `tohost` and `fromhost`, either in an [`HTIF`] device or in memory when
+/// resolved from the ELF symbols
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HtifPort {
+    pub tohost: u32,
+    pub fromhost: Option<u32>,
+}
+
+impl HtifPort {
+    /// Returns true if a store of `size` bytes at `addr` completes a command, that is, it
+    /// writes the upper word of `tohost`
+    pub fn is_command(&self, addr: u32, size: u32) -> bool {
+        let upper = self.tohost.wrapping_add(4);
+        addr < upper.wrapping_add(4) && upper < addr.wrapping_add(size)
+    }
+
+    /// Executes the command in `tohost`, acknowledging it in `fromhost`. Exit commands are
+    /// reported through [`MCU::halt`].
+    pub fn process(&self, mcu: &mut MCU) {
+        let (Ok(low), Ok(high)) = (
+            mcu.mmu.rw(self.tohost),
+            mcu.mmu.rw(self.tohost.wrapping_add(4)),
+        ) else {
+            return;
+        };
+        if low == 0 && high == 0 {
+            return;
+        }
+        let device = high >> 24;
+        let cmd = (high >> 16) & 0xff;
+        // Payloads are addresses or small values, the upper bits are never used on rv32
+        let payload = low;
+
+        match (device, cmd) {
+            (DEVICE_SYSCALL, 0) if payload & 1 == 1 => {
+                let code = payload >> 1;
+                if code == 0 {
+                    log::info!("HTIF: test passed");
+                } else {
+                    log::info!("HTIF: test failed, test case {code}");
+                }
+                mcu.halt = Some(code);
+            }
+            (DEVICE_SYSCALL, 0) => self.syscall(mcu, payload),
+            (DEVICE_CONSOLE, CONSOLE_PUTCHAR) => {
+                let mut stdout = std::io::stdout();
+                let _ = stdout
+                    .write_all(&[payload as u8])
+                    .and_then(|_| stdout.flush());
+            }
+            _ => log::warn!("HTIF: unsupported command {cmd} for device {device}"),
+        }
+
+        let _ = mcu.mmu.ww(self.tohost, 0);
+        let _ = mcu.mmu.ww(self.tohost.wrapping_add(4), 0);
+        if let Some(fromhost) = self.fromhost {
+            let _ = mcu.mmu.ww(fromhost, 1);
+            let _ = mcu.mmu.ww(fromhost.wrapping_add(4), high & 0xffff_0000);
+        }
+    }
+
+    /// Proxies a syscall described by the 8 dwords at `magic_mem`, the result is written back
+    /// to the first dword
+    fn syscall(&self, mcu: &mut MCU, magic_mem: u32) {
+        let arg = |mcu: &MCU, i: u32| mcu.mmu.rw(magic_mem.wrapping_add(8 * i)).unwrap_or(0);
+        let which = arg(mcu, 0);
+        let result = match which {
+            SYS_WRITE => {
+                let (fd, buf, len) = (arg(mcu, 1), arg(mcu, 2), arg(mcu, 3));
+                Self::write(mcu, fd, buf, len)
+            }
+            SYS_EXIT => {
+                mcu.halt = Some(arg(mcu, 1));
+                0
+            }
+            _ => {
+                log::warn!("HTIF: unsupported syscall {which}");
+                ENOSYS.wrapping_neg()
+            }
+        };
+        let _ = mcu.mmu.ww(magic_mem, result);
+        let _ = mcu.mmu.ww(magic_mem.wrapping_add(4), 0);
+    }
+
+    /// Writes `len` bytes at `buf` to stdout or stderr, returning the number of bytes written
+    /// or a negated errno
+    fn write(mcu: &MCU, fd: u32, buf: u32, len: u32) -> u32 {
+        let mut out: Box<dyn Write> = match fd {
+            1 => Box::new(std::io::stdout()),
+            2 => Box::new(std::io::stderr()),
+            _ => return u32::MAX,
+        };
+        let mut done = 0;
+        while done < len {
+            let chunk = (len - done).min(WRITE_CHUNK);
+            let data: Option<Vec<u8>> = (0..chunk)
+                .map(|i| mcu.mmu.rb(buf.wrapping_add(done + i)).ok())
+                .collect();
+            let Some(data) = data else {
+                return EFAULT.wrapping_neg();
+            };
+            if out.write_all(&data).is_err() {
+                return u32::MAX;
+            }
+            done += chunk;
+        }
+        match out.flush() {
+            Ok(()) => len,
+            Err(_) => u32::MAX,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mcu::{test_mcu, DeviceDef};
+
+    fn mcu() -> MCU {
+        test_mcu(0x1000, vec![])
+    }
+
+    fn write_tohost(mcu: &mut MCU, tohost: u32, value: u64) {
+        mcu.store(tohost, 4, value as u32).unwrap();
+        assert_eq!(
+            mcu.halt, None,
+            "commands run when the upper word is written"
+        );
+        mcu.store(tohost + 4, 4, (value >> 32) as u32).unwrap();
+    }
+
+    #[test]
+    fn device_exit_codes() {
+        let mut mcu = mcu();
+        mcu.add_device(DeviceDef {
+            identifier: "HTIF".to_string(),
+            memory_start: 0x1000,
+            memory_end: 0x100f,
+            device: Box::new(HTIF::new()),
+        })
+        .unwrap();
+        assert_eq!(
+            mcu.htif,
+            Some(HtifPort {
+                tohost: 0x1000,
+                fromhost: Some(0x1008)
+            })
+        );
+
+        write_tohost(&mut mcu, 0x1000, 1);
+        assert_eq!(mcu.halt.take(), Some(0));
+        assert_eq!(mcu.mmu.rw(0x1000), Ok(0), "tohost is cleared");
+        assert_eq!(
+            mcu.mmu.rw(0x1008),
+            Ok(1),
+            "fromhost acknowledges the command"
+        );
+
+        write_tohost(&mut mcu, 0x1000, (5 << 1) | 1);
+        assert_eq!(mcu.halt.take(), Some(5));
+    }
+
+    #[test]
+    fn syscalls() {
+        let mut mcu = mcu();
+        mcu.htif = Some(HtifPort {
+            tohost: 0x100,
+            fromhost: None,
+        });
+        // sys_write(1, 0x300, 0) then sys_exit(3)
+        let magic_mem = 0x200;
+        for (i, v) in [SYS_WRITE, 1, 0x300, 0].iter().enumerate() {
+            mcu.mmu.ww(magic_mem + 8 * i as u32, *v).unwrap();
+        }
+        write_tohost(&mut mcu, 0x100, magic_mem as u64);
+        assert_eq!(mcu.halt, None);
+        assert_eq!(mcu.mmu.rw(magic_mem), Ok(0), "wrote 0 bytes");
+
+        // the buffer is copied as it's written, unmapped memory fails with EFAULT
+        for (i, v) in [SYS_WRITE, 1, 0x8000_0000, u32::MAX].iter().enumerate() {
+            mcu.mmu.ww(magic_mem + 8 * i as u32, *v).unwrap();
+        }
+        write_tohost(&mut mcu, 0x100, magic_mem as u64);
+        assert_eq!(mcu.mmu.rw(magic_mem), Ok(EFAULT.wrapping_neg()));
+
+        mcu.mmu.ww(magic_mem, SYS_EXIT).unwrap();
+        mcu.mmu.ww(magic_mem + 8, 3).unwrap();
+        write_tohost(&mut mcu, 0x100, magic_mem as u64);
+        assert_eq!(mcu.halt, Some(3));
+    }
+
+    #[test]
+    fn elf_symbols() {
+        use crate::elf::tests::build_elf;
+        use crate::elf::Elf;
+
+        let mut mcu = mcu();
+        let symbols = [("tohost", 0x100, 8), ("fromhost", 0x140, 8)];
+        let elf = build_elf(0, 0, &[0x13, 0, 0, 0], 4, &symbols);
+        mcu.load_elf(Elf::parse(&elf).unwrap()).unwrap();
+        assert_eq!(
+            mcu.htif,
+            Some(HtifPort {
+                tohost: 0x100,
+                fromhost: Some(0x140)
+            })
+        );
+    }
+}
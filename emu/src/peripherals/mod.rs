@@ -1,8 +1,11 @@
 use crate::memory::Clocked;
-use crate::memory::Memory;
+use crate::memory::{Memory, MemoryError};
+pub mod aclint;
 pub mod clint;
 pub mod flash;
+pub mod htif;
 pub mod plic;
+pub mod ram;
 pub mod rom;
 pub mod uart;
 
@@ -10,4 +13,19 @@ pub trait Peripheral: Clocked + Memory {
     fn as_plic(&mut self) -> Option<&mut plic::PLIC> {
         None
     }
+    fn as_htif(&mut self) -> Option<&mut htif::HTIF> {
+        None
+    }
+    fn as_clint(&mut self) -> Option<&mut clint::CLINT> {
+        None
+    }
+    /// The mtime counter of timer devices, advanced by the MCU every tick
+    fn as_mtime(&mut self) -> Option<&mut clint::Mtime> {
+        None
+    }
+    /// Writes a byte on behalf of the program loader or a debugger, which can also write
+    /// read-only memories
+    fn program(&mut self, addr: u32, value: u8) -> Result<(), MemoryError> {
+        self.wb(addr, value)
+    }
 }
@@ -1,55 +1,161 @@
+//! Platform-Level Interrupt Controller, with the register layout of the RISC-V PLIC spec: 1023
+//! interrupt sources and an M-mode and an S-mode context per hart, context `2 * hart` for M-mode
+//! and `2 * hart + 1` for S-mode.
+//!
+//! Every source goes through a gateway that forwards one request at a time: once a request is
+//! pending the source can't raise another until the claimed interrupt is completed.
 use crate::interrupt_controller::InterruptController;
 use crate::memory::Clocked;
 use crate::memory::{Memory, MemoryError};
 use crate::peripherals::Peripheral;
 use std::cell::RefCell;
 
+// interrupt source 0 doesn't exist
+pub const SOURCES: usize = 1024;
+const WORDS: usize = SOURCES / 32;
+// 7 priority levels, like the FE310
+const PRIORITY_MASK: u32 = 0b111;
+
+// register layout
+const PRIORITY: u32 = 0x0;
+const PENDING: u32 = 0x1000;
+const ENABLE: u32 = 0x2000;
+const ENABLE_STRIDE: u32 = 0x80;
+const CONTEXT: u32 = 0x20_0000;
+const CONTEXT_STRIDE: u32 = 0x1000;
+const CLAIM: u32 = 0x4;
+
+/// One bit per interrupt source
+type Bitmap = [u32; WORDS];
+
+/// Returns true if the bit of `source` is set
+fn bit(bitmap: &Bitmap, source: usize) -> bool {
+    bitmap[source / 32] & (1 << (source % 32)) != 0
+}
+
+fn set_bit(bitmap: &mut Bitmap, source: usize, value: bool) {
+    if value {
+        bitmap[source / 32] |= 1 << (source % 32);
+    } else {
+        bitmap[source / 32] &= !(1 << (source % 32));
+    }
+}
+
 pub struct PLIC {
-    pub source_priority: [u32; 52], // addr 0x0
-    pub pending: RefCell<u64>,      // addr 0xd0
-    // TODO: This should be an array with a touple per hart so this would work with multiple cores
-    pub h0mie: u64, // hart0 M-Mode interrupt enables - addr 216
-    pub h0mpt: u32, // hart0 M-Mode priority threshold - addr 224
-                    // hart0 M-Mode claim/complete - addr 228
+    priority: Box<[u32; SOURCES]>,
+    pending: RefCell<Bitmap>,
+    // sources whose gateway forwarded a request that wasn't completed yet
+    in_flight: Bitmap,
+    // sources whose interrupt line is asserted
+    level: Bitmap,
+    // enable bits and priority threshold of every context
+    enable: Vec<Bitmap>,
+    threshold: Vec<u32>,
 }
 
 impl PLIC {
+    /// A PLIC for a single hart
     pub fn new() -> Self {
+        Self::with_harts(1)
+    }
+
+    /// A PLIC with M and S-mode contexts for `harts` harts
+    pub fn with_harts(harts: usize) -> Self {
         Self {
-            source_priority: [0; 52],
-            pending: RefCell::new(0),
-            h0mie: 0,
-            h0mpt: 0,
+            priority: Box::new([0; SOURCES]),
+            pending: RefCell::new([0; WORDS]),
+            in_flight: [0; WORDS],
+            level: [0; WORDS],
+            enable: vec![[0; WORDS]; 2 * harts],
+            threshold: vec![0; 2 * harts],
+        }
+    }
+
+    /// The context of `hart` in M-mode, or in S-mode if `supervisor` is set
+    pub fn context(hart: usize, supervisor: bool) -> usize {
+        2 * hart + supervisor as usize
+    }
+
+    /// A request from interrupt source `source`. The gateway ignores it while a previous request
+    /// of the source is pending or being serviced.
+    pub fn raise(&mut self, source: usize) {
+        if source == 0 || source >= SOURCES || bit(&self.in_flight, source) {
+            return;
         }
+        set_bit(&mut self.in_flight, source, true);
+        set_bit(&mut self.pending.borrow_mut(), source, true);
     }
 
-    pub fn claim_interrupt(&self) -> u32 {
-        let mut interrupts = self.get_interrupts();
-        let interrupt = interrupts.pop().unwrap_or(0);
-        let mut pending = self.pending.borrow_mut();
-        *pending = *pending & !(1 << interrupt);
-        interrupt
-    }
-
-    // Returns the enabled and pending interrupts ordered by priority
-    pub fn get_interrupts(&self) -> Vec<u32> {
-        let mut interrupts = Vec::new();
-        for i in 0..52 {
-            let code = 1 << i;
-            let priority = self.source_priority[i as usize];
-            if self.h0mie & code != 0
-                && *self.pending.borrow() & code != 0
-                && priority >= self.h0mpt
-            {
-                interrupts.push(i);
+    /// Asserts or deasserts the line of the level-triggered source `source`. The gateway forwards
+    /// a request while the line is asserted and no previous request is in flight.
+    pub fn set_level(&mut self, source: usize, level: bool) {
+        if source == 0 || source >= SOURCES {
+            return;
+        }
+        set_bit(&mut self.level, source, level);
+        if level {
+            self.raise(source);
+        }
+    }
+
+    /// The highest priority interrupt pending and enabled for `context` with a priority over
+    /// its threshold, ties go to the lowest ID
+    pub fn highest_pending(&self, context: usize) -> Option<usize> {
+        let pending = self.pending.borrow();
+        let mut highest = None;
+        let mut max_priority = self.threshold[context];
+        for (word, enabled) in self.enable[context].iter().enumerate() {
+            let mut candidates = pending[word] & enabled;
+            while candidates != 0 {
+                let source = 32 * word + candidates.trailing_zeros() as usize;
+                candidates &= candidates - 1;
+                if self.priority[source] > max_priority {
+                    highest = Some(source);
+                    max_priority = self.priority[source];
+                }
+            }
+        }
+        highest
+    }
+
+    /// Returns true if `context` has an interrupt to notify to its hart
+    pub fn interrupt_pending(&self, context: usize) -> bool {
+        self.highest_pending(context).is_some()
+    }
+
+    /// Claims the highest priority interrupt for `context`, clearing its pending bit. Returns 0
+    /// if there's none.
+    pub fn claim(&self, context: usize) -> u32 {
+        let Some(source) = self.highest_pending(context) else {
+            return 0;
+        };
+        set_bit(&mut self.pending.borrow_mut(), source, false);
+        source as u32
+    }
+
+    /// Completes the interrupt `source`, letting its gateway forward new requests. Completions of
+    /// sources that aren't enabled for the context are ignored.
+    pub fn complete(&mut self, context: usize, source: u32) {
+        let source = source as usize;
+        if source < SOURCES && bit(&self.enable[context], source) {
+            set_bit(&mut self.in_flight, source, false);
+            // a line that is still asserted makes a new request
+            if bit(&self.level, source) {
+                self.raise(source);
             }
         }
-        interrupts.sort_by(|a, b| {
-            let pa = self.source_priority[*a as usize];
-            let pb = self.source_priority[*b as usize];
-            pa.cmp(&pb)
-        });
-        interrupts
+    }
+
+    /// The context and the offset in its enable bits or its threshold and claim registers
+    fn decode(&self, addr: u32, base: u32, stride: u32) -> Option<(usize, u32)> {
+        let context = ((addr - base) / stride) as usize;
+        (context < self.threshold.len()).then_some((context, (addr - base) % stride))
+    }
+}
+
+impl Default for PLIC {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
@@ -81,49 +187,160 @@ impl Memory for PLIC {
     }
 
     fn rw(&self, addr: u32) -> Result<u32, MemoryError> {
+        if !addr.is_multiple_of(4) {
+            return Err(MemoryError::AccessFault);
+        }
+        let word = |base: u32| ((addr - base) / 4) as usize;
         match addr {
-            v if v >= 0x4 && v < 0xd4 => Ok(self.source_priority[((v - 4) / 4) as usize]),
-            0x1000 => Ok(*self.pending.borrow() as u32),
-            0x1004 => Ok((*self.pending.borrow() >> 32) as u32),
-            0x2000 => Ok(self.h0mie as u32),
-            0x2004 => Ok((self.h0mie >> 32) as u32),
-            0x20_0000 => Ok(self.h0mpt),
-            0x20_0004 => {
-                // XXX: Should we keep a list of claimed interrupts?
-                let interrupt = self.claim_interrupt();
-                Ok(interrupt)
-            }
+            a if a < PENDING => Ok(self.priority[word(PRIORITY)]),
+            a if a < PENDING + 4 * WORDS as u32 => Ok(self.pending.borrow()[word(PENDING)]),
+            a if (ENABLE..CONTEXT).contains(&a) => match self.decode(a, ENABLE, ENABLE_STRIDE) {
+                Some((context, offset)) if offset < 4 * WORDS as u32 => {
+                    Ok(self.enable[context][offset as usize / 4])
+                }
+                _ => Err(MemoryError::AccessFault),
+            },
+            a if a >= CONTEXT => match self.decode(a, CONTEXT, CONTEXT_STRIDE) {
+                Some((context, 0)) => Ok(self.threshold[context]),
+                Some((context, CLAIM)) => Ok(self.claim(context)),
+                _ => Err(MemoryError::AccessFault),
+            },
             _ => Err(MemoryError::AccessFault),
         }
     }
 
     fn ww(&mut self, addr: u32, value: u32) -> Result<(), MemoryError> {
+        if !addr.is_multiple_of(4) {
+            return Err(MemoryError::AccessFault);
+        }
         match addr {
-            v if v >= 0x4 && v < 0xd4 => {
-                self.source_priority[((v - 4) / 4) as usize] = value;
-                Ok(())
-            }
-            v if v == 0x1000 || v == 0x1004 => {
-                // pending is not writable
+            // source 0 doesn't exist
+            0 => Ok(()),
+            a if a < PENDING => {
+                self.priority[(a / 4) as usize] = value & PRIORITY_MASK;
                 Ok(())
             }
-            0x2000 => {
-                self.h0mie = (self.h0mie & !0xffff_ffff) | value as u64;
-                Ok(())
-            }
-            0x2004 => {
-                self.h0mie = (self.h0mie & !(0xffff_ffff << 32)) | (value as u64) << 32;
-                Ok(())
-            }
-            0x20_0000 => {
-                self.h0mpt = value;
-                Ok(())
-            }
-            0x20_0004 => {
-                // XXX: Not sure what to do here. Should we keep a list of the claimed interrupts?
-                Ok(())
+            // pending is not writable
+            a if a < PENDING + 4 * WORDS as u32 => Ok(()),
+            a if (ENABLE..CONTEXT).contains(&a) => {
+                match self.decode(a, ENABLE, ENABLE_STRIDE) {
+                    Some((context, offset)) if offset < 4 * WORDS as u32 => {
+                        // source 0 can't be enabled
+                        let mask = if offset == 0 { !1 } else { u32::MAX };
+                        self.enable[context][offset as usize / 4] = value & mask;
+                        Ok(())
+                    }
+                    _ => Err(MemoryError::AccessFault),
+                }
             }
+            a if a >= CONTEXT => match self.decode(a, CONTEXT, CONTEXT_STRIDE) {
+                Some((context, 0)) => {
+                    self.threshold[context] = value & PRIORITY_MASK;
+                    Ok(())
+                }
+                Some((context, CLAIM)) => {
+                    self.complete(context, value);
+                    Ok(())
+                }
+                _ => Err(MemoryError::AccessFault),
+            },
             _ => Err(MemoryError::AccessFault),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const M_CONTEXT: u32 = CONTEXT;
+    const M_CLAIM: u32 = CONTEXT + CLAIM;
+
+    /// A PLIC with the sources enabled for hart 0 in M-mode with the given priorities
+    fn plic(priorities: &[(usize, u32)]) -> PLIC {
+        let mut plic = PLIC::new();
+        for &(source, priority) in priorities {
+            plic.ww(4 * source as u32, priority).unwrap();
+            let enable = ENABLE + 4 * (source as u32 / 32);
+            let bits = plic.rw(enable).unwrap();
+            plic.ww(enable, bits | 1 << (source % 32)).unwrap();
+        }
+        plic
+    }
+
+    #[test]
+    fn register_layout() {
+        let mut plic = PLIC::with_harts(2);
+        plic.ww(0xffc, 0xff).unwrap();
+        assert_eq!(plic.rw(0xffc), Ok(7), "source 1023 has 7 priority levels");
+        plic.ww(ENABLE, u32::MAX).unwrap();
+        assert_eq!(plic.rw(ENABLE), Ok(!1), "source 0 doesn't exist");
+        // hart 1 S-mode
+        let context = PLIC::context(1, true) as u32;
+        plic.ww(ENABLE + context * ENABLE_STRIDE + 0x7c, 1 << 31)
+            .unwrap();
+        plic.ww(CONTEXT + context * CONTEXT_STRIDE, 3).unwrap();
+        assert_eq!(plic.rw(CONTEXT + context * CONTEXT_STRIDE), Ok(3));
+        plic.ww(0xffc, 3).unwrap();
+        plic.raise(1023);
+        assert_eq!(plic.rw(PENDING + 0x7c), Ok(1 << 31));
+        assert_eq!(
+            plic.rw(CONTEXT + context * CONTEXT_STRIDE + CLAIM),
+            Ok(0),
+            "the priority is under the threshold"
+        );
+        plic.ww(0xffc, 4).unwrap();
+        assert!(plic.interrupt_pending(context as usize));
+        assert!(!plic.interrupt_pending(0));
+        assert_eq!(
+            plic.rw(CONTEXT + context * CONTEXT_STRIDE + CLAIM),
+            Ok(1023)
+        );
+        assert_eq!(
+            plic.rw(CONTEXT + 4 * CONTEXT_STRIDE),
+            Err(MemoryError::AccessFault),
+            "there are 4 contexts"
+        );
+    }
+
+    #[test]
+    fn priorities() {
+        let mut plic = plic(&[(3, 2), (5, 2), (8, 1)]);
+        plic.raise(8);
+        plic.raise(5);
+        plic.raise(3);
+        plic.ww(M_CONTEXT, 1).unwrap();
+        assert_eq!(plic.rw(M_CLAIM), Ok(3), "ties go to the lowest ID");
+        assert_eq!(plic.rw(M_CLAIM), Ok(5));
+        assert_eq!(
+            plic.rw(M_CLAIM),
+            Ok(0),
+            "the priority must be over the threshold"
+        );
+        plic.ww(M_CONTEXT, 0).unwrap();
+        assert_eq!(plic.rw(M_CLAIM), Ok(8));
+    }
+
+    #[test]
+    fn gateways() {
+        let mut plic = plic(&[(4, 1)]);
+        plic.raise(4);
+        assert_eq!(plic.rw(M_CLAIM), Ok(4));
+        plic.raise(4);
+        assert_eq!(plic.rw(PENDING), Ok(0), "the gateway waits for completion");
+        assert_eq!(plic.rw(M_CLAIM), Ok(0));
+
+        // completions of disabled sources are ignored
+        plic.ww(M_CLAIM, 7).unwrap();
+        plic.ww(ENABLE, 0).unwrap();
+        plic.ww(M_CLAIM, 4).unwrap();
+        plic.raise(4);
+        assert_eq!(plic.rw(PENDING), Ok(0));
+
+        plic.ww(ENABLE, 1 << 4).unwrap();
+        plic.ww(M_CLAIM, 4).unwrap();
+        plic.raise(4);
+        assert_eq!(plic.rw(PENDING), Ok(1 << 4));
+        assert_eq!(plic.rw(M_CLAIM), Ok(4));
+    }
+}
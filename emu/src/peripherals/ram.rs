@@ -0,0 +1,99 @@
+use crate::interrupt_controller::InterruptController;
+use crate::memory::{Clocked, GenericMemory, Memory, MemoryError};
+use crate::peripherals::Peripheral;
+
+/// On-chip data RAM, like the DTIM of the FE310
+pub struct RAM(GenericMemory);
+
+impl RAM {
+    /// A zero initialized RAM of `size` bytes
+    pub fn new(size: u32) -> Self {
+        Self(GenericMemory::new(size))
+    }
+
+    /// A RAM of `size` bytes filled with the little endian `pattern`, so reads of memory the
+    /// program never wrote stand out
+    pub fn with_pattern(size: u32, pattern: u32) -> Self {
+        let mut mem = GenericMemory::new(size);
+        for addr in 0..size {
+            mem.wb(addr, pattern.to_le_bytes()[addr as usize % 4])
+                .unwrap();
+        }
+        Self(mem)
+    }
+}
+
+impl Peripheral for RAM {}
+
+impl Clocked for RAM {
+    fn tick(&mut self, _: &mut InterruptController) {}
+}
+impl Memory for RAM {
+    fn rb(&self, addr: u32) -> Result<u8, MemoryError> {
+        self.0.rb(addr)
+    }
+
+    fn wb(&mut self, addr: u32, value: u8) -> Result<(), MemoryError> {
+        self.0.wb(addr, value)
+    }
+
+    fn rhw(&self, addr: u32) -> Result<u16, MemoryError> {
+        self.0.rhw(addr)
+    }
+
+    fn whw(&mut self, addr: u32, value: u16) -> Result<(), MemoryError> {
+        self.0.whw(addr, value)
+    }
+
+    fn rw(&self, addr: u32) -> Result<u32, MemoryError> {
+        self.0.rw(addr)
+    }
+
+    fn ww(&mut self, addr: u32, value: u32) -> Result<(), MemoryError> {
+        self.0.ww(addr, value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instructions::Exception;
+    use crate::mcu::{DeviceDef, MCU};
+    use crate::peripherals::flash::Flash;
+
+    #[test]
+    fn pattern() {
+        let ram = RAM::with_pattern(0x10, 0xdead_beef);
+        assert_eq!(ram.rw(0xc), Ok(0xdead_beef));
+        assert_eq!(ram.rhw(0x2), Ok(0xdead));
+        assert_eq!(RAM::new(0x10).rw(0x4), Ok(0));
+    }
+
+    #[test]
+    fn read_only_flash() {
+        let mut flash = Flash::new(0x100);
+        flash.set_read_only(true);
+        let mut mcu = MCU::new();
+        mcu.add_device(DeviceDef {
+            identifier: "FLASH".to_string(),
+            memory_start: 0,
+            memory_end: 0xff,
+            device: Box::new(flash),
+        })
+        .unwrap();
+        mcu.add_device(DeviceDef {
+            identifier: "RAM".to_string(),
+            memory_start: 0x8000_0000,
+            memory_end: 0x8000_3fff,
+            device: Box::new(RAM::new(0x4000)),
+        })
+        .unwrap();
+
+        // the loader can write the flash, the program can't
+        mcu.flash(vec![0x13, 0, 0, 0]);
+        assert_eq!(mcu.load(0x0, 4), Ok(0x13));
+        assert_eq!(mcu.store(0x0, 4, 0), Err(Exception::StoreAccessFault));
+        mcu.store(0x8000_3ffc, 4, 0x1234).unwrap();
+        assert_eq!(mcu.load(0x8000_3ffc, 4), Ok(0x1234));
+    }
+}
@@ -27,7 +27,11 @@ impl From<GenericMemory> for ROM {
     }
 }
 
-impl Peripheral for ROM {}
+impl Peripheral for ROM {
+    fn program(&mut self, addr: u32, value: u8) -> Result<(), MemoryError> {
+        self.0.program(addr, value)
+    }
+}
 
 impl Clocked for ROM {
     fn tick(&mut self, _: &mut InterruptController) {}
@@ -57,4 +61,3 @@ impl Memory for ROM {
         self.0.ww(addr, value)
     }
 }
-
@@ -1,4 +1,3 @@
-use crate::instructions::Interrupt;
 use crate::interrupt_controller::InterruptController;
 use crate::memory::Clocked;
 use crate::memory::{Memory, MemoryError};
@@ -38,6 +37,7 @@ impl UART {
         }
     }
 
+    /// Sets the PLIC interrupt source the UART raises
     pub fn set_interrupt_id(&mut self, id: u32) {
         self.interrupt_id = id;
     }
@@ -93,11 +93,7 @@ impl Clocked for UART {
             self.ip = self.ip & !0b01;
         }
 
-        let uart_ip = self.get_ip();
-
-        if uart_ip != 0 {
-            int_ctrl.interrupt(Interrupt::MExternalInterrupt, self.interrupt_id)
-        }
+        int_ctrl.set_external_interrupt(self.interrupt_id, self.get_ip() != 0);
     }
 }
 
@@ -0,0 +1,267 @@
+//! Physical Memory Protection: 16 regions configured through pmpcfg0-3 and pmpaddr0-15 that
+//! restrict the physical addresses less privileged modes (and M-mode, when locked) can access.
+use crate::cpu::Privilege;
+use crate::memory::Access;
+
+pub const PMP_ENTRIES: usize = 16;
+pub const PMPCFG0: u32 = 0x3a0;
+pub const PMPADDR0: u32 = 0x3b0;
+
+// pmpcfg fields
+const PMP_R: u8 = 1 << 0;
+const PMP_W: u8 = 1 << 1;
+const PMP_X: u8 = 1 << 2;
+const PMP_A_SHIFT: u8 = 3;
+const PMP_A: u8 = 0b11 << PMP_A_SHIFT;
+const PMP_L: u8 = 1 << 7;
+
+// address matching modes
+const OFF: u8 = 0;
+const TOR: u8 = 1;
+const NA4: u8 = 2;
+const NAPOT: u8 = 3;
+
+pub struct Pmp {
+    cfg: [u8; PMP_ENTRIES],
+    // bits 33:2 of the addresses
+    addr: [u32; PMP_ENTRIES],
+}
+
+impl Pmp {
+    pub fn new() -> Self {
+        Self {
+            cfg: [0; PMP_ENTRIES],
+            addr: [0; PMP_ENTRIES],
+        }
+    }
+
+    /// Returns true if `csr` is one of the PMP CSRs
+    pub fn is_pmp_csr(csr: u32) -> bool {
+        (PMPCFG0..PMPCFG0 + PMP_ENTRIES as u32 / 4).contains(&csr)
+            || (PMPADDR0..PMPADDR0 + PMP_ENTRIES as u32).contains(&csr)
+    }
+
+    pub fn get_csr(&self, csr: u32) -> u32 {
+        if csr >= PMPADDR0 {
+            self.addr[(csr - PMPADDR0) as usize]
+        } else {
+            let first = (csr - PMPCFG0) as usize * 4;
+            u32::from_le_bytes(self.cfg[first..first + 4].try_into().unwrap())
+        }
+    }
+
+    /// Writes a PMP CSR, locked entries ignore the write
+    pub fn set_csr(&mut self, csr: u32, v: u32) {
+        if csr >= PMPADDR0 {
+            let i = (csr - PMPADDR0) as usize;
+            if !self.is_addr_locked(i) {
+                self.addr[i] = v;
+            }
+        } else {
+            let first = (csr - PMPCFG0) as usize * 4;
+            for (i, cfg) in v.to_le_bytes().into_iter().enumerate() {
+                let i = first + i;
+                if self.cfg[i] & PMP_L != 0 {
+                    continue;
+                }
+                // W without R is reserved, bits 6:5 are hardwired to zero
+                let mut cfg = cfg & (PMP_L | PMP_A | PMP_X | PMP_W | PMP_R);
+                if cfg & PMP_R == 0 {
+                    cfg &= !PMP_W;
+                }
+                self.cfg[i] = cfg;
+            }
+        }
+    }
+
+    /// pmpaddr is locked by its own entry and by the next one when it's the top of a TOR range
+    fn is_addr_locked(&self, i: usize) -> bool {
+        let next_is_locked_tor = self
+            .cfg
+            .get(i + 1)
+            .map(|cfg| cfg & PMP_L != 0 && (cfg & PMP_A) >> PMP_A_SHIFT == TOR)
+            .unwrap_or(false);
+        self.cfg[i] & PMP_L != 0 || next_is_locked_tor
+    }
+
+    /// The byte range matched by entry `i`, if it's enabled
+    fn range(&self, i: usize) -> Option<std::ops::Range<u64>> {
+        let addr = (self.addr[i] as u64) << 2;
+        match (self.cfg[i] & PMP_A) >> PMP_A_SHIFT {
+            TOR => {
+                let start = i
+                    .checked_sub(1)
+                    .map(|i| (self.addr[i] as u64) << 2)
+                    .unwrap_or(0);
+                Some(start..addr)
+            }
+            NA4 => Some(addr..addr + 4),
+            NAPOT => {
+                // the trailing ones encode the size, starting at 8 bytes
+                let ones = self.addr[i].trailing_ones();
+                let size = 1u64 << (ones + 3);
+                let start = addr & !(size - 1);
+                Some(start..start + size)
+            }
+            OFF => None,
+            _ => unreachable!(),
+        }
+    }
+
+    /// Checks an access of `size` bytes at the physical address `addr`. The lowest numbered
+    /// entry matching any of the bytes decides, and it must match all of them.
+    pub fn check(&self, addr: u32, size: u32, access: Access, privilege: Privilege) -> bool {
+        let start = addr as u64;
+        let end = start + size as u64;
+        for i in 0..PMP_ENTRIES {
+            let Some(range) = self.range(i) else {
+                continue;
+            };
+            if end <= range.start || start >= range.end {
+                continue;
+            }
+            if start < range.start || end > range.end {
+                return false;
+            }
+            let cfg = self.cfg[i];
+            if privilege == Privilege::Machine && cfg & PMP_L == 0 {
+                return true;
+            }
+            let permission = match access {
+                Access::Fetch => PMP_X,
+                Access::Load => PMP_R,
+                Access::Store => PMP_W,
+            };
+            return cfg & permission != 0;
+        }
+        // S and U-mode accesses fail when no entry matches, as there are entries implemented
+        privilege == Privilege::Machine
+    }
+}
+
+impl Default for Pmp {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RWX: u32 = (PMP_R | PMP_W | PMP_X) as u32;
+
+    fn cfg(mode: u8, flags: u32) -> u32 {
+        (mode << PMP_A_SHIFT) as u32 | flags
+    }
+
+    #[test]
+    fn no_entries() {
+        let pmp = Pmp::new();
+        assert!(pmp.check(0x1000, 4, Access::Store, Privilege::Machine));
+        assert!(!pmp.check(0x1000, 4, Access::Load, Privilege::User));
+        assert!(!pmp.check(0x1000, 4, Access::Fetch, Privilege::Supervisor));
+    }
+
+    #[test]
+    fn matching_modes() {
+        let mut pmp = Pmp::new();
+        // entry 0: NA4 at 0x100, read only
+        pmp.set_csr(PMPADDR0, 0x100 >> 2);
+        // entry 1: TOR from 0x100 to 0x200, read and execute
+        pmp.set_csr(PMPADDR0 + 1, 0x200 >> 2);
+        // entry 2: NAPOT 0x1000-0x1fff, read and write
+        pmp.set_csr(PMPADDR0 + 2, (0x1000 >> 2) | 0x1ff);
+        pmp.set_csr(
+            PMPCFG0,
+            cfg(NA4, PMP_R as u32)
+                | cfg(TOR, (PMP_R | PMP_X) as u32) << 8
+                | cfg(NAPOT, (PMP_R | PMP_W) as u32) << 16,
+        );
+
+        let user = Privilege::User;
+        assert!(pmp.check(0x100, 4, Access::Load, user));
+        assert!(
+            !pmp.check(0x100, 4, Access::Fetch, user),
+            "the lowest entry has priority"
+        );
+        assert!(pmp.check(0x104, 4, Access::Fetch, user));
+        assert!(!pmp.check(0x1fc, 4, Access::Store, user));
+        assert!(
+            !pmp.check(0x1fe, 4, Access::Load, user),
+            "accesses must be fully contained"
+        );
+        assert!(pmp.check(0x1ffc, 4, Access::Store, user));
+        assert!(!pmp.check(0x2000, 1, Access::Load, user));
+        assert!(
+            pmp.check(0x1fc, 4, Access::Store, Privilege::Machine),
+            "unlocked entries don't apply to M-mode"
+        );
+    }
+
+    #[test]
+    fn lock() {
+        let mut pmp = Pmp::new();
+        pmp.set_csr(PMPADDR0, 0x100 >> 2);
+        pmp.set_csr(PMPADDR0 + 1, 0x200 >> 2);
+        pmp.set_csr(PMPCFG0, cfg(TOR, PMP_L as u32 | PMP_R as u32) << 8);
+        assert!(!pmp.check(0x100, 4, Access::Store, Privilege::Machine));
+        assert!(pmp.check(0x100, 4, Access::Load, Privilege::Machine));
+
+        pmp.set_csr(PMPCFG0, cfg(NAPOT, RWX) << 8);
+        pmp.set_csr(PMPADDR0 + 1, 0);
+        pmp.set_csr(PMPADDR0, 0);
+        assert_eq!(
+            pmp.get_csr(PMPCFG0) >> 8,
+            cfg(TOR, PMP_L as u32 | PMP_R as u32)
+        );
+        assert_eq!(pmp.get_csr(PMPADDR0 + 1), 0x200 >> 2);
+        assert_eq!(
+            pmp.get_csr(PMPADDR0),
+            0x100 >> 2,
+            "the bottom of a locked TOR range is locked"
+        );
+    }
+
+    #[test]
+    fn reserved_permissions() {
+        let mut pmp = Pmp::new();
+        pmp.set_csr(PMPCFG0 + 3, 0xff);
+        assert_eq!(pmp.get_csr(PMPCFG0 + 3), 0x9f);
+        pmp.set_csr(PMPCFG0, PMP_W as u32 | 0x60);
+        assert_eq!(pmp.get_csr(PMPCFG0), 0, "W without R is reserved");
+    }
+
+    #[test]
+    fn mcu_accesses() {
+        use crate::cpu::{CSRs, MSTATUS_MPRV};
+        use crate::instructions::Exception;
+        use crate::mcu::test_mcu;
+
+        let mut mcu = test_mcu(0x1000, vec![]);
+        // 0x000-0x7ff executable, 0x800-0xfff readable
+        mcu.cpu.set_csr(PMPADDR0, 0xff).unwrap();
+        mcu.cpu.set_csr(PMPADDR0 + 1, 0x1000 >> 2).unwrap();
+        mcu.cpu
+            .set_csr(
+                PMPCFG0,
+                cfg(NAPOT, PMP_X as u32) | cfg(TOR, PMP_R as u32) << 8,
+            )
+            .unwrap();
+
+        assert!(mcu.load(0x100, 4).is_ok(), "M-mode isn't restricted");
+        // loads with mstatus.MPRV are checked with the privilege in MPP, U-mode here
+        mcu.cpu.set_csr(CSRs::mstatus as u32, MSTATUS_MPRV).unwrap();
+        assert_eq!(mcu.load(0x100, 4), Err(Exception::LoadAccessFault));
+        assert!(mcu.load(0x900, 4).is_ok());
+        assert_eq!(mcu.store(0x900, 4, 1), Err(Exception::StoreAccessFault));
+
+        mcu.cpu.privilege = Privilege::User;
+        mcu.cpu.pc = 0x800;
+        mcu.tick();
+        assert_eq!(
+            mcu.cpu.get_csr(CSRs::mcause as u32).unwrap(),
+            Exception::InstructionAccessFault as u32
+        );
+    }
+}
@@ -0,0 +1,589 @@
+//! RISC-V semihosting: lets the guest use the host's console, filesystem and clock.
+//!
+//! A semihosting call is an `ebreak` surrounded by `slli x0, x0, 0x1f` and `srai x0, x0, 7`.
+//! The operation is passed in `a0`, its parameter (usually a pointer to a block of words) in
+//! `a1`, and the result is returned in `a0`. Files are sandboxed to a root directory.
+use crate::mcu::MCU;
+use crate::memory::{Access, Memory};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+
+// slli x0, x0, 0x1f
+const ENTRY: u32 = 0x01f0_1013;
+// srai x0, x0, 7
+const EXIT: u32 = 0x4070_5013;
+
+const SYS_OPEN: u32 = 0x01;
+const SYS_CLOSE: u32 = 0x02;
+const SYS_WRITEC: u32 = 0x03;
+const SYS_WRITE0: u32 = 0x04;
+const SYS_WRITE: u32 = 0x05;
+const SYS_READ: u32 = 0x06;
+const SYS_READC: u32 = 0x07;
+const SYS_ISTTY: u32 = 0x09;
+const SYS_SEEK: u32 = 0x0a;
+const SYS_FLEN: u32 = 0x0c;
+const SYS_CLOCK: u32 = 0x10;
+const SYS_TIME: u32 = 0x11;
+const SYS_ERRNO: u32 = 0x13;
+const SYS_GET_CMDLINE: u32 = 0x15;
+const SYS_HEAPINFO: u32 = 0x16;
+const SYS_EXIT: u32 = 0x18;
+const SYS_EXIT_EXTENDED: u32 = 0x20;
+
+const ADP_STOPPED_APPLICATION_EXIT: u32 = 0x20026;
+// Longest string read by SYS_WRITE0
+const MAX_STRING: u32 = 4096;
+// SYS_READ fills the guest's buffer in chunks of at most this many bytes
+const READ_CHUNK: u32 = 4096;
+// errno values returned by SYS_ERRNO for errors without an OS error code
+const EBADF: u32 = 9;
+const EACCES: u32 = 13;
+const EINVAL: u32 = 22;
+
+const FAILURE: u32 = u32::MAX;
+
+enum Handle {
+    Stdin,
+    Stdout,
+    Stderr,
+    File(std::fs::File),
+}
+
+/// Memory layout reported to SYS_HEAPINFO, zero means unknown
+#[derive(Debug, Default, Clone, Copy)]
+pub struct HeapInfo {
+    pub heap_base: u32,
+    pub heap_limit: u32,
+    pub stack_base: u32,
+    pub stack_limit: u32,
+}
+
+pub struct Semihosting {
+    root: PathBuf,
+    cmdline: String,
+    pub heap_info: HeapInfo,
+    handles: Vec<Option<Handle>>,
+    errno: u32,
+    start: std::time::Instant,
+}
+
+/// Returns true if the `ebreak` at the pc is part of a semihosting call sequence
+pub fn is_semihosting_call(mcu: &mut MCU) -> bool {
+    let pc = mcu.cpu.pc;
+    let mut fetch = |addr: u32| {
+        let addr = mcu.translate(addr, 4, Access::Fetch).ok()?;
+        mcu.mmu.rw(addr).ok()
+    };
+    pc >= 4 && fetch(pc - 4) == Some(ENTRY) && fetch(pc.wrapping_add(4)) == Some(EXIT)
+}
+
+// The guest's memory is accessed like the caller's loads and stores, through the Sv32 page
+// tables and the PMP with the privilege level of the call
+
+fn read_byte(mcu: &mut MCU, addr: u32) -> Option<u8> {
+    let addr = mcu.translate(addr, 1, Access::Load).ok()?;
+    mcu.mmu.rb(addr).ok()
+}
+
+fn write_byte(mcu: &mut MCU, addr: u32, b: u8) -> Option<()> {
+    let addr = mcu.translate(addr, 1, Access::Store).ok()?;
+    mcu.mmu.wb(addr, b).ok()
+}
+
+fn read_bytes(mcu: &mut MCU, addr: u32, len: u32) -> Option<Vec<u8>> {
+    (0..len)
+        .map(|i| read_byte(mcu, addr.wrapping_add(i)))
+        .collect()
+}
+
+fn write_bytes(mcu: &mut MCU, addr: u32, data: &[u8]) -> Option<()> {
+    for (i, b) in data.iter().enumerate() {
+        write_byte(mcu, addr.wrapping_add(i as u32), *b)?;
+    }
+    Some(())
+}
+
+fn read_word(mcu: &mut MCU, addr: u32) -> Option<u32> {
+    let bytes = read_bytes(mcu, addr, 4)?;
+    Some(u32::from_le_bytes(bytes.try_into().ok()?))
+}
+
+fn write_word(mcu: &mut MCU, addr: u32, v: u32) -> Option<()> {
+    write_bytes(mcu, addr, &v.to_le_bytes())
+}
+
+fn read_args<const N: usize>(mcu: &mut MCU, addr: u32) -> Option<[u32; N]> {
+    let mut args = [0; N];
+    for (i, arg) in args.iter_mut().enumerate() {
+        *arg = read_word(mcu, addr.wrapping_add(4 * i as u32))?;
+    }
+    Some(args)
+}
+
+fn read_cstr(mcu: &mut MCU, addr: u32) -> Option<Vec<u8>> {
+    let mut s = Vec::new();
+    for i in 0..MAX_STRING {
+        match read_byte(mcu, addr.wrapping_add(i))? {
+            0 => return Some(s),
+            b => s.push(b),
+        }
+    }
+    Some(s)
+}
+
+impl Semihosting {
+    /// Files opened by the guest are resolved relative to `root`, `cmdline` is returned by
+    /// SYS_GET_CMDLINE
+    pub fn new(root: PathBuf, cmdline: String) -> Self {
+        Self {
+            root,
+            cmdline,
+            heap_info: HeapInfo::default(),
+            handles: Vec::new(),
+            errno: 0,
+            start: std::time::Instant::now(),
+        }
+    }
+
+    /// Resolves a guest path inside the root directory, rejecting absolute paths and paths
+    /// escaping it
+    fn resolve(&self, name: &str) -> Option<PathBuf> {
+        let path = Path::new(name);
+        let escapes = path
+            .components()
+            .any(|c| !matches!(c, Component::Normal(_) | Component::CurDir));
+        if escapes {
+            None
+        } else {
+            Some(self.root.join(path))
+        }
+    }
+
+    fn add_handle(&mut self, handle: Handle) -> u32 {
+        match self.handles.iter().position(|h| h.is_none()) {
+            Some(idx) => {
+                self.handles[idx] = Some(handle);
+                idx as u32 + 1
+            }
+            None => {
+                self.handles.push(Some(handle));
+                self.handles.len() as u32
+            }
+        }
+    }
+
+    fn handle(&mut self, fd: u32) -> Option<&mut Handle> {
+        let handle = self
+            .handles
+            .get_mut((fd as usize).wrapping_sub(1))
+            .and_then(|h| h.as_mut());
+        if handle.is_none() {
+            self.errno = EBADF;
+        }
+        handle
+    }
+
+    fn io_error(&mut self, err: std::io::Error) -> u32 {
+        self.errno = err.raw_os_error().map(|e| e as u32).unwrap_or(EINVAL);
+        FAILURE
+    }
+
+    fn open(&mut self, mcu: &mut MCU, name: u32, mode: u32, len: u32) -> Option<u32> {
+        let name = String::from_utf8(read_bytes(mcu, name, len)?).ok()?;
+        if name == ":tt" {
+            let handle = match mode {
+                0..=3 => Handle::Stdin,
+                4..=7 => Handle::Stdout,
+                _ => Handle::Stderr,
+            };
+            return Some(self.add_handle(handle));
+        }
+        let Some(path) = self.resolve(&name) else {
+            log::warn!("semihosting: refusing to open {name:?} outside of the sandbox");
+            self.errno = EACCES;
+            return Some(FAILURE);
+        };
+        // modes follow fopen: r, rb, r+, r+b, w, wb, w+, w+b, a, ab, a+, a+b
+        let mut options = std::fs::OpenOptions::new();
+        match mode / 4 {
+            0 => options.read(true).write(mode & 0b10 != 0),
+            1 => options
+                .write(true)
+                .read(mode & 0b10 != 0)
+                .create(true)
+                .truncate(true),
+            2 => options.append(true).read(mode & 0b10 != 0).create(true),
+            _ => {
+                self.errno = EINVAL;
+                return Some(FAILURE);
+            }
+        };
+        Some(match options.open(path) {
+            Ok(file) => self.add_handle(Handle::File(file)),
+            Err(err) => self.io_error(err),
+        })
+    }
+
+    fn write(&mut self, fd: u32, data: &[u8]) -> u32 {
+        let r = match self.handle(fd) {
+            Some(Handle::Stdout) => std::io::stdout()
+                .write_all(data)
+                .and_then(|_| std::io::stdout().flush()),
+            Some(Handle::Stderr) => std::io::stderr().write_all(data),
+            Some(Handle::File(f)) => f.write_all(data),
+            Some(Handle::Stdin) => {
+                self.errno = EBADF;
+                return data.len() as u32;
+            }
+            None => return data.len() as u32,
+        };
+        match r {
+            Ok(()) => 0,
+            Err(err) => {
+                self.io_error(err);
+                data.len() as u32
+            }
+        }
+    }
+
+    fn read(&mut self, fd: u32, len: u32) -> Result<Vec<u8>, u32> {
+        let mut buf = vec![0; len as usize];
+        let r = match self.handle(fd) {
+            Some(Handle::Stdin) => std::io::stdin().read(&mut buf),
+            Some(Handle::File(f)) => f.read(&mut buf),
+            Some(_) => {
+                self.errno = EBADF;
+                return Err(FAILURE);
+            }
+            None => return Err(FAILURE),
+        };
+        match r {
+            Ok(n) => {
+                buf.truncate(n);
+                Ok(buf)
+            }
+            Err(err) => Err(self.io_error(err)),
+        }
+    }
+
+    /// Executes the semihosting operation in a0, returning the value for a0 or `None` if the
+    /// guest passed an invalid pointer
+    fn operation(&mut self, mcu: &mut MCU, op: u32, arg: u32) -> Option<u32> {
+        let r = match op {
+            SYS_OPEN => {
+                let [name, mode, len] = read_args(mcu, arg)?;
+                self.open(mcu, name, mode, len)?
+            }
+            SYS_CLOSE => {
+                let [fd] = read_args(mcu, arg)?;
+                match self.handle(fd) {
+                    Some(_) => {
+                        self.handles[fd as usize - 1] = None;
+                        0
+                    }
+                    None => FAILURE,
+                }
+            }
+            SYS_WRITEC => {
+                let c = read_byte(mcu, arg)?;
+                std::io::stdout().write_all(&[c]).ok()?;
+                std::io::stdout().flush().ok()?;
+                0
+            }
+            SYS_WRITE0 => {
+                let s = read_cstr(mcu, arg)?;
+                std::io::stdout().write_all(&s).ok()?;
+                std::io::stdout().flush().ok()?;
+                0
+            }
+            SYS_WRITE => {
+                let [fd, buf, len] = read_args(mcu, arg)?;
+                let data = read_bytes(mcu, buf, len)?;
+                self.write(fd, &data)
+            }
+            SYS_READ => {
+                let [fd, buf, len] = read_args(mcu, arg)?;
+                let mut done = 0;
+                while done < len {
+                    let chunk = (len - done).min(READ_CHUNK);
+                    let Ok(data) = self.read(fd, chunk) else {
+                        break;
+                    };
+                    write_bytes(mcu, buf.wrapping_add(done), &data)?;
+                    done += data.len() as u32;
+                    // a short read is the end of the file or of the available input
+                    if (data.len() as u32) < chunk {
+                        break;
+                    }
+                }
+                len - done
+            }
+            SYS_READC => {
+                let mut c = [0u8];
+                match std::io::stdin().read_exact(&mut c) {
+                    Ok(()) => c[0] as u32,
+                    Err(err) => self.io_error(err),
+                }
+            }
+            SYS_ISTTY => {
+                let [fd] = read_args(mcu, arg)?;
+                match self.handle(fd) {
+                    Some(Handle::File(_)) => 0,
+                    Some(_) => 1,
+                    None => FAILURE,
+                }
+            }
+            SYS_SEEK => {
+                let [fd, pos] = read_args(mcu, arg)?;
+                let r = match self.handle(fd) {
+                    Some(Handle::File(f)) => f.seek(SeekFrom::Start(pos as u64)),
+                    _ => return Some(FAILURE),
+                };
+                match r {
+                    Ok(_) => 0,
+                    Err(err) => self.io_error(err),
+                }
+            }
+            SYS_FLEN => {
+                let [fd] = read_args(mcu, arg)?;
+                let r = match self.handle(fd) {
+                    Some(Handle::File(f)) => f.metadata(),
+                    _ => return Some(FAILURE),
+                };
+                match r {
+                    Ok(m) => m.len() as u32,
+                    Err(err) => self.io_error(err),
+                }
+            }
+            SYS_CLOCK => (self.start.elapsed().as_millis() / 10) as u32,
+            SYS_TIME => std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|d| d.as_secs() as u32)
+                .unwrap_or(0),
+            SYS_ERRNO => self.errno,
+            SYS_GET_CMDLINE => {
+                let [buf, len] = read_args::<2>(mcu, arg)?;
+                let cmdline = self.cmdline.as_bytes();
+                if cmdline.len() as u32 >= len {
+                    return Some(FAILURE);
+                }
+                // the buffer, with the terminator, can't wrap around the address space
+                let end = buf.checked_add(cmdline.len() as u32)?;
+                write_bytes(mcu, buf, cmdline)?;
+                write_byte(mcu, end, 0)?;
+                write_word(mcu, arg.wrapping_add(4), cmdline.len() as u32)?;
+                0
+            }
+            SYS_HEAPINFO => {
+                let [block] = read_args(mcu, arg)?;
+                let info = self.heap_info;
+                for (i, v) in [
+                    info.heap_base,
+                    info.heap_limit,
+                    info.stack_base,
+                    info.stack_limit,
+                ]
+                .iter()
+                .enumerate()
+                {
+                    write_word(mcu, block.wrapping_add(4 * i as u32), *v)?;
+                }
+                0
+            }
+            SYS_EXIT => {
+                // On 32-bit targets the parameter is the reason code itself
+                mcu.halt = Some((arg != ADP_STOPPED_APPLICATION_EXIT) as u32);
+                0
+            }
+            SYS_EXIT_EXTENDED => {
+                let [reason, code] = read_args(mcu, arg)?;
+                mcu.halt = Some(if reason == ADP_STOPPED_APPLICATION_EXIT {
+                    code
+                } else {
+                    1
+                });
+                0
+            }
+            _ => {
+                log::warn!("semihosting: unsupported operation {op:#x}");
+                FAILURE
+            }
+        };
+        Some(r)
+    }
+
+    /// Executes the semihosting call described by a0 and a1, leaving the result in a0
+    pub fn call(&mut self, mcu: &mut MCU) {
+        let op = mcu.cpu.get_x(10);
+        let arg = mcu.cpu.get_x(11);
+        log::trace!("semihosting: operation {op:#x}, parameter {arg:#x}");
+        let r = self.operation(mcu, op, arg).unwrap_or_else(|| {
+            log::warn!("semihosting: operation {op:#x} accessed invalid memory");
+            self.errno = EINVAL;
+            FAILURE
+        });
+        mcu.cpu.set_x(10, r);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mcu::{test_mcu, DeviceDef, TickResult};
+
+    const EBREAK: u32 = 0x0010_0073;
+    const NAME: u32 = 0x200;
+    const BLOCK: u32 = 0x300;
+    const BUF: u32 = 0x400;
+
+    fn sandbox(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("riscv-emu-semihosting-{name}"));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn mcu(root: PathBuf) -> MCU {
+        let mut mcu = test_mcu(0x1000, vec![]);
+        mcu.semihosting = Some(Semihosting::new(root, "prog --flag".to_string()));
+        mcu
+    }
+
+    /// Runs a semihosting call sequence at address 0 and returns a0
+    fn call(mcu: &mut MCU, op: u32, arg: u32) -> u32 {
+        mcu.mmu.ww(0x0, ENTRY).unwrap();
+        mcu.mmu.ww(0x4, EBREAK).unwrap();
+        mcu.mmu.ww(0x8, EXIT).unwrap();
+        mcu.cpu.pc = 0;
+        mcu.cpu.set_x(10, op);
+        mcu.cpu.set_x(11, arg);
+        for _ in 0..3 {
+            if let TickResult::HALT(_) = mcu.tick() {
+                break;
+            }
+        }
+        mcu.cpu.get_x(10)
+    }
+
+    fn set_block(mcu: &mut MCU, args: &[u32]) {
+        for (i, v) in args.iter().enumerate() {
+            mcu.mmu.ww(BLOCK + 4 * i as u32, *v).unwrap();
+        }
+    }
+
+    fn open(mcu: &mut MCU, name: &str, mode: u32) -> u32 {
+        write_bytes(mcu, NAME, name.as_bytes()).unwrap();
+        set_block(mcu, &[NAME, mode, name.len() as u32]);
+        call(mcu, SYS_OPEN, BLOCK)
+    }
+
+    #[test]
+    fn files() {
+        let root = sandbox("files");
+        let mut mcu = mcu(root.clone());
+
+        let fd = open(&mut mcu, "out.txt", 4);
+        assert_ne!(fd, FAILURE);
+        write_bytes(&mut mcu, BUF, b"hello").unwrap();
+        set_block(&mut mcu, &[fd, BUF, 5]);
+        assert_eq!(call(&mut mcu, SYS_WRITE, BLOCK), 0);
+        set_block(&mut mcu, &[fd]);
+        assert_eq!(call(&mut mcu, SYS_CLOSE, BLOCK), 0);
+        assert_eq!(std::fs::read(root.join("out.txt")).unwrap(), b"hello");
+
+        let fd = open(&mut mcu, "out.txt", 0);
+        set_block(&mut mcu, &[fd]);
+        assert_eq!(call(&mut mcu, SYS_FLEN, BLOCK), 5);
+        set_block(&mut mcu, &[fd, BUF + 0x10, 8]);
+        // returns the number of bytes not read
+        assert_eq!(call(&mut mcu, SYS_READ, BLOCK), 3);
+        assert_eq!(read_bytes(&mut mcu, BUF + 0x10, 5).unwrap(), b"hello");
+        // the length asked by the guest isn't allocated up front
+        set_block(&mut mcu, &[fd, 1]);
+        assert_eq!(call(&mut mcu, SYS_SEEK, BLOCK), 0);
+        set_block(&mut mcu, &[fd, BUF + 0x20, u32::MAX]);
+        assert_eq!(call(&mut mcu, SYS_READ, BLOCK), u32::MAX - 4);
+        assert_eq!(read_bytes(&mut mcu, BUF + 0x20, 4).unwrap(), b"ello");
+        set_block(&mut mcu, &[fd]);
+        assert_eq!(call(&mut mcu, SYS_CLOSE, BLOCK), 0);
+        assert_eq!(call(&mut mcu, SYS_CLOSE, BLOCK), FAILURE);
+        assert_eq!(mcu.cpu.pc, 0xc, "execution continues after the call sequence");
+    }
+
+    #[test]
+    fn sandboxed() {
+        let mut mcu = mcu(sandbox("sandboxed"));
+        assert_eq!(open(&mut mcu, "../escape.txt", 4), FAILURE);
+        assert_eq!(open(&mut mcu, "/etc/passwd", 0), FAILURE);
+        assert_eq!(call(&mut mcu, SYS_ERRNO, 0), EACCES);
+        assert_ne!(open(&mut mcu, ":tt", 4), FAILURE);
+    }
+
+    #[test]
+    fn caller_privilege() {
+        use crate::cpu::{CSRs, MSTATUS_MPRV};
+        use crate::pmp::{PMPADDR0, PMPCFG0};
+
+        let mut mcu = mcu(sandbox("privilege"));
+        // with mstatus.MPRV the parameter block is read as U-mode, which the PMP denies
+        mcu.cpu.set_csr(CSRs::mstatus as u32, MSTATUS_MPRV).unwrap();
+        set_block(&mut mcu, &[BUF, 64]);
+        assert_eq!(call(&mut mcu, SYS_GET_CMDLINE, BLOCK), FAILURE);
+        assert_eq!(call(&mut mcu, SYS_ERRNO, 0), EINVAL);
+
+        // a TOR entry granting U-mode reads and writes below 0x1000
+        mcu.cpu.set_csr(PMPADDR0, 0x1000 >> 2).unwrap();
+        mcu.cpu.set_csr(PMPCFG0, 0x0b).unwrap();
+        assert_eq!(call(&mut mcu, SYS_GET_CMDLINE, BLOCK), 0);
+        assert_eq!(read_cstr(&mut mcu, BUF).unwrap(), b"prog --flag");
+    }
+
+    #[test]
+    fn cmdline_and_heapinfo() {
+        let mut mcu = mcu(sandbox("cmdline"));
+        set_block(&mut mcu, &[BUF, 64]);
+        assert_eq!(call(&mut mcu, SYS_GET_CMDLINE, BLOCK), 0);
+        assert_eq!(read_cstr(&mut mcu, BUF).unwrap(), b"prog --flag");
+        assert_eq!(mcu.mmu.rw(BLOCK + 4), Ok(11));
+        mcu.add_device(DeviceDef {
+            identifier: "RAM".to_string(),
+            memory_start: 0xffff_ff00,
+            memory_end: u32::MAX,
+            device: Box::new(crate::peripherals::ram::RAM::new(0x100)),
+        })
+        .unwrap();
+        set_block(&mut mcu, &[0xffff_fff5, 64]);
+        assert_eq!(call(&mut mcu, SYS_GET_CMDLINE, BLOCK), FAILURE);
+
+        mcu.semihosting.as_mut().unwrap().heap_info.heap_limit = 0x8000;
+        mcu.mmu.ww(BLOCK, BUF).unwrap();
+        assert_eq!(call(&mut mcu, SYS_HEAPINFO, BLOCK), 0);
+        assert_eq!(mcu.mmu.rw(BUF + 4), Ok(0x8000));
+    }
+
+    #[test]
+    fn exit() {
+        let mut mcu = mcu(sandbox("exit"));
+        mcu.mmu.ww(0x0, ENTRY).unwrap();
+        mcu.mmu.ww(0x4, EBREAK).unwrap();
+        mcu.mmu.ww(0x8, EXIT).unwrap();
+        mcu.cpu.set_x(10, SYS_EXIT_EXTENDED);
+        mcu.cpu.set_x(11, BLOCK);
+        set_block(&mut mcu, &[ADP_STOPPED_APPLICATION_EXIT, 42]);
+        assert!(matches!(mcu.tick(), TickResult::Cycles(_)));
+        assert!(matches!(mcu.tick(), TickResult::HALT(42)));
+    }
+
+    #[test]
+    fn plain_ebreak() {
+        let mut mcu = mcu(sandbox("ebreak"));
+        mcu.mmu.ww(0x4, EBREAK).unwrap();
+        mcu.cpu.pc = 4;
+        mcu.cpu
+            .set_csr(crate::cpu::CSRs::mtvec as u32, 0x100)
+            .unwrap();
+        mcu.tick();
+        assert_eq!(mcu.cpu.pc, 0x100, "a lone ebreak still traps");
+    }
+}
@@ -0,0 +1,410 @@
+//! The Sdtrig trigger module: 4 triggers configured through tselect and tdata1-3 that match
+//! instruction fetches and data accesses, by address or by value, and either raise a breakpoint
+//! exception or halt the hart in Debug mode before the instruction executes.
+//!
+//! Triggers can be of the mcontrol (type 2) and mcontrol6 (type 6) kinds. There is no tcontrol,
+//! so M-mode triggers raising breakpoint exceptions also fire inside trap handlers.
+use crate::cpu::Privilege;
+use crate::memory::Access;
+
+pub const TRIGGERS: usize = 4;
+pub const TSELECT: u32 = 0x7a0;
+pub const TDATA1: u32 = 0x7a1;
+pub const TDATA2: u32 = 0x7a2;
+pub const TDATA3: u32 = 0x7a3;
+pub const TINFO: u32 = 0x7a4;
+
+// tdata1 fields common to every type
+pub const TYPE_SHIFT: u32 = 28;
+const DMODE: u32 = 1 << 27;
+
+// trigger types
+pub const TYPE_MCONTROL: u32 = 2;
+pub const TYPE_MCONTROL6: u32 = 6;
+const TYPE_DISABLED: u32 = 15;
+
+// mcontrol and mcontrol6 fields, except the ones at different positions
+const MCONTROL_MASKMAX: u32 = 31 << 21;
+const MCONTROL_HIT: u32 = 1 << 20;
+const MCONTROL_SELECT: u32 = 1 << 19;
+const MCONTROL6_HIT0: u32 = 1 << 22;
+const MCONTROL6_SELECT: u32 = 1 << 21;
+const ACTION_SHIFT: u32 = 12;
+const ACTION: u32 = 0b1111 << ACTION_SHIFT;
+pub const CHAIN: u32 = 1 << 11;
+pub const MATCH_SHIFT: u32 = 7;
+const MATCH: u32 = 0b1111 << MATCH_SHIFT;
+pub const M: u32 = 1 << 6;
+pub const S: u32 = 1 << 4;
+pub const U: u32 = 1 << 3;
+pub const EXECUTE: u32 = 1 << 2;
+pub const STORE: u32 = 1 << 1;
+pub const LOAD: u32 = 1 << 0;
+const WRITABLE: u32 = DMODE | ACTION | CHAIN | MATCH | M | S | U | EXECUTE | STORE | LOAD;
+
+// tdata2 comparisons
+const MATCH_EQUAL: u32 = 0;
+const MATCH_NAPOT: u32 = 1;
+const MATCH_GE: u32 = 2;
+const MATCH_LT: u32 = 3;
+const MATCH_MASK_LOW: u32 = 4;
+const MATCH_MASK_HIGH: u32 = 5;
+
+// Sdtrig version 1.0, supporting mcontrol, mcontrol6 and disabled triggers
+const TINFO_VALUE: u32 = 1 << 24 | 1 << TYPE_MCONTROL | 1 << TYPE_MCONTROL6 | 1 << TYPE_DISABLED;
+
+/// What a trigger does when it fires
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TriggerAction {
+    Breakpoint = 0,
+    DebugMode = 1,
+}
+
+pub struct Triggers {
+    select: usize,
+    tdata1: [u32; TRIGGERS],
+    tdata2: [u32; TRIGGERS],
+}
+
+impl Triggers {
+    pub fn new() -> Self {
+        Self {
+            select: 0,
+            tdata1: [TYPE_DISABLED << TYPE_SHIFT; TRIGGERS],
+            tdata2: [0; TRIGGERS],
+        }
+    }
+
+    /// Returns true if `csr` is one of the trigger CSRs
+    pub fn is_trigger_csr(csr: u32) -> bool {
+        (TSELECT..=TINFO).contains(&csr)
+    }
+
+    pub fn get_csr(&self, csr: u32) -> u32 {
+        match csr {
+            TSELECT => self.select as u32,
+            TDATA1 => self.tdata1[self.select],
+            TDATA2 => self.tdata2[self.select],
+            TINFO => TINFO_VALUE,
+            // there are no textra fields
+            _ => 0,
+        }
+    }
+
+    /// Writes a trigger CSR. Triggers owned by the debugger (dmode set) can only be written in
+    /// Debug mode, and only the debugger can hand them over.
+    pub fn set_csr(&mut self, csr: u32, v: u32, debug_mode: bool) {
+        let i = self.select;
+        let locked = self.tdata1[i] & DMODE != 0 && !debug_mode;
+        match csr {
+            // selecting a trigger that doesn't exist keeps the current one
+            TSELECT if (v as usize) < TRIGGERS => self.select = v as usize,
+            TDATA1 if !locked => self.tdata1[i] = Self::legalize(v, debug_mode),
+            TDATA2 if !locked => self.tdata2[i] = v,
+            _ => {}
+        }
+    }
+
+    /// The value tdata1 takes when written with `v`, unsupported types disable the trigger
+    fn legalize(v: u32, debug_mode: bool) -> u32 {
+        let (ty, raw) = (v >> TYPE_SHIFT, v);
+        let mut v = v & WRITABLE;
+        if !debug_mode {
+            v &= !DMODE;
+        }
+        // only the debugger can ask to enter Debug mode, the other actions aren't supported
+        let action = (v & ACTION) >> ACTION_SHIFT;
+        if action > TriggerAction::DebugMode as u32
+            || (action == TriggerAction::DebugMode as u32 && v & DMODE == 0)
+        {
+            v &= !ACTION;
+        }
+        if (v & MATCH) >> MATCH_SHIFT > MATCH_MASK_HIGH {
+            v &= !MATCH;
+        }
+        // hit and select move between types, timing and size always read as zero
+        let (hit, select) = match ty {
+            TYPE_MCONTROL => (MCONTROL_HIT, MCONTROL_SELECT),
+            TYPE_MCONTROL6 => (MCONTROL6_HIT0, MCONTROL6_SELECT),
+            _ => return TYPE_DISABLED << TYPE_SHIFT | v & DMODE,
+        };
+        let v = ty << TYPE_SHIFT | v | raw & (hit | select);
+        if ty == TYPE_MCONTROL {
+            v | MCONTROL_MASKMAX
+        } else {
+            v
+        }
+    }
+
+    /// The hit and select bits of trigger `i`, depending on its type
+    fn fields(&self, i: usize) -> Option<(u32, u32)> {
+        match self.tdata1[i] >> TYPE_SHIFT {
+            TYPE_MCONTROL => Some((MCONTROL_HIT, MCONTROL_SELECT)),
+            TYPE_MCONTROL6 => Some((MCONTROL6_HIT0, MCONTROL6_SELECT)),
+            _ => None,
+        }
+    }
+
+    /// Returns true if trigger `i` matches an access of `size` bytes at `addr`, or the `value`
+    /// accessed when it selects data
+    fn matches(
+        &self,
+        i: usize,
+        access: Access,
+        privilege: Privilege,
+        addr: u32,
+        size: u32,
+        value: u32,
+    ) -> bool {
+        let Some((_, select)) = self.fields(i) else {
+            return false;
+        };
+        let tdata1 = self.tdata1[i];
+        let kind = match access {
+            Access::Fetch => EXECUTE,
+            Access::Load => LOAD,
+            Access::Store => STORE,
+        };
+        let mode = match privilege {
+            Privilege::Machine => M,
+            Privilege::Supervisor => S,
+            Privilege::User => U,
+        };
+        if tdata1 & kind == 0 || tdata1 & mode == 0 {
+            return false;
+        }
+        let (start, size) = if tdata1 & select != 0 {
+            (value, 1)
+        } else {
+            (addr, size)
+        };
+        let end = start as u64 + size as u64;
+        let tdata2 = self.tdata2[i];
+        match (tdata1 & MATCH) >> MATCH_SHIFT {
+            // any of the accessed bytes matches
+            MATCH_EQUAL => (start as u64..end).contains(&(tdata2 as u64)),
+            MATCH_NAPOT => {
+                // the trailing ones of tdata2 and the next bit are ignored
+                let ignored = (tdata2.trailing_ones() + 1).min(32);
+                let mask = !((1u64 << ignored) - 1);
+                (start as u64 & mask) <= (tdata2 as u64 & mask) && (tdata2 as u64 & mask) < end
+            }
+            MATCH_GE => start >= tdata2,
+            MATCH_LT => start < tdata2,
+            MATCH_MASK_LOW => start & (tdata2 >> 16) == tdata2 & 0xffff,
+            MATCH_MASK_HIGH => (start >> 16) & (tdata2 >> 16) == tdata2 & 0xffff,
+            _ => false,
+        }
+    }
+
+    /// Evaluates the triggers for an access of `size` bytes at `addr` with `value` (the
+    /// instruction bits for fetches), setting the hit bits of the triggers that fire. A chain
+    /// of triggers fires when all of them match, with the action of the last one.
+    pub fn check(
+        &mut self,
+        access: Access,
+        privilege: Privilege,
+        addr: u32,
+        size: u32,
+        value: u32,
+    ) -> Option<TriggerAction> {
+        let mut chain_start = 0;
+        let mut chain_matches = true;
+        for i in 0..TRIGGERS {
+            chain_matches &= self.matches(i, access, privilege, addr, size, value);
+            if self.tdata1[i] & CHAIN != 0 && i + 1 < TRIGGERS {
+                continue;
+            }
+            if chain_matches {
+                for j in chain_start..=i {
+                    if let Some((hit, _)) = self.fields(j) {
+                        self.tdata1[j] |= hit;
+                    }
+                }
+                return Some(match (self.tdata1[i] & ACTION) >> ACTION_SHIFT {
+                    0 => TriggerAction::Breakpoint,
+                    _ => TriggerAction::DebugMode,
+                });
+            }
+            chain_start = i + 1;
+            chain_matches = true;
+        }
+        None
+    }
+}
+
+impl Default for Triggers {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mcontrol6(flags: u32, r#match: u32) -> u32 {
+        TYPE_MCONTROL6 << TYPE_SHIFT | r#match << MATCH_SHIFT | flags
+    }
+
+    fn set(triggers: &mut Triggers, i: u32, tdata1: u32, tdata2: u32) {
+        triggers.set_csr(TSELECT, i, false);
+        triggers.set_csr(TDATA1, tdata1, false);
+        triggers.set_csr(TDATA2, tdata2, false);
+    }
+
+    #[test]
+    fn legal_values() {
+        let mut triggers = Triggers::new();
+        triggers.set_csr(TSELECT, TRIGGERS as u32, false);
+        assert_eq!(triggers.get_csr(TSELECT), 0, "there are only 4 triggers");
+        assert_eq!(triggers.get_csr(TINFO) & 0xffff, 0x8044);
+
+        triggers.set_csr(TDATA1, 3 << TYPE_SHIFT | M | LOAD, false);
+        assert_eq!(
+            triggers.get_csr(TDATA1),
+            TYPE_DISABLED << TYPE_SHIFT,
+            "icount isn't supported"
+        );
+        triggers.set_csr(TDATA1, TYPE_MCONTROL << TYPE_SHIFT | u32::MAX >> 4, false);
+        assert_eq!(
+            triggers.get_csr(TDATA1),
+            TYPE_MCONTROL << TYPE_SHIFT
+                | MCONTROL_MASKMAX
+                | MCONTROL_HIT
+                | MCONTROL_SELECT
+                | CHAIN
+                | M
+                | S
+                | U
+                | EXECUTE
+                | STORE
+                | LOAD,
+            "dmode, the action and the match field keep legal values"
+        );
+    }
+
+    #[test]
+    fn debugger_triggers() {
+        let mut triggers = Triggers::new();
+        let action = (TriggerAction::DebugMode as u32) << ACTION_SHIFT;
+        triggers.set_csr(TDATA1, mcontrol6(DMODE | action | M | EXECUTE, 0), true);
+        triggers.set_csr(TDATA2, 0x100, true);
+        triggers.set_csr(TDATA1, 0, false);
+        triggers.set_csr(TDATA2, 0, false);
+        assert_eq!(
+            triggers.get_csr(TDATA2),
+            0x100,
+            "only the debugger can write it"
+        );
+        assert_eq!(
+            triggers.check(Access::Fetch, Privilege::Machine, 0x100, 1, 0),
+            Some(TriggerAction::DebugMode)
+        );
+        assert_ne!(triggers.get_csr(TDATA1) & MCONTROL6_HIT0, 0);
+    }
+
+    #[test]
+    fn matching() {
+        let mut triggers = Triggers::new();
+        // 0x1000-0x10ff written from U-mode
+        set(
+            &mut triggers,
+            0,
+            mcontrol6(U | STORE, MATCH_NAPOT),
+            0x1000 | 0x7f,
+        );
+        // loads of the value 0x1234
+        set(
+            &mut triggers,
+            1,
+            mcontrol6(MCONTROL6_SELECT | M | LOAD, MATCH_EQUAL),
+            0x1234,
+        );
+        let user = Privilege::User;
+        assert_eq!(
+            triggers.check(Access::Store, user, 0x10fe, 4, 0),
+            Some(TriggerAction::Breakpoint)
+        );
+        assert_eq!(triggers.check(Access::Store, user, 0xffe, 2, 0), None);
+        assert_eq!(
+            triggers.check(Access::Store, user, 0xffe, 4, 0),
+            Some(TriggerAction::Breakpoint),
+            "any accessed byte matches"
+        );
+        assert_eq!(triggers.check(Access::Load, user, 0x1000, 4, 0), None);
+        assert_eq!(
+            triggers.check(Access::Store, Privilege::Machine, 0x1000, 4, 0),
+            None
+        );
+        assert_eq!(
+            triggers.check(Access::Load, Privilege::Machine, 0x1000, 4, 0x1234),
+            Some(TriggerAction::Breakpoint)
+        );
+
+        // chained triggers: loads of addresses in 0x2000-0x2fff
+        set(
+            &mut triggers,
+            2,
+            mcontrol6(CHAIN | M | LOAD, MATCH_GE),
+            0x2000,
+        );
+        set(&mut triggers, 3, mcontrol6(M | LOAD, MATCH_LT), 0x3000);
+        let machine = Privilege::Machine;
+        assert_eq!(triggers.check(Access::Load, machine, 0x1ffc, 4, 0), None);
+        assert_eq!(triggers.check(Access::Load, machine, 0x3000, 4, 0), None);
+        assert_eq!(
+            triggers.check(Access::Load, machine, 0x2800, 4, 0),
+            Some(TriggerAction::Breakpoint)
+        );
+    }
+
+    #[test]
+    fn mcu_triggers() {
+        use crate::cpu::{CSRs, DebugCause, DCSR_CAUSE, DCSR_CAUSE_SHIFT};
+        use crate::instructions::Exception;
+        use crate::mcu::test_mcu;
+        use crate::memory::Memory;
+
+        let mut mcu = test_mcu(0x1000, vec![]);
+        mcu.cpu.set_csr(CSRs::mtvec as u32, 0x800).unwrap();
+        // li t0, 0x104; sw t0, 0(t0); addi ra, ra, 1
+        mcu.mmu.ww(0x0, 0x1040_0293).unwrap();
+        mcu.mmu.ww(0x4, 0x0052_a023).unwrap();
+        mcu.mmu.ww(0x8, 0x0010_8093).unwrap();
+        // the store raises a breakpoint exception with the address in mtval
+        mcu.cpu
+            .write_csr(TDATA1, mcontrol6(M | STORE, MATCH_EQUAL))
+            .unwrap();
+        mcu.cpu.write_csr(TDATA2, 0x104).unwrap();
+        mcu.tick();
+        mcu.tick();
+        assert_eq!(mcu.cpu.pc, 0x800);
+        assert_eq!(
+            mcu.cpu.get_csr(CSRs::mcause as u32),
+            Ok(Exception::Breakpoint as u32)
+        );
+        assert_eq!(mcu.cpu.get_csr(CSRs::mtval as u32), Ok(0x104));
+        assert_eq!(mcu.cpu.get_csr(CSRs::mepc as u32), Ok(0x4));
+        assert_eq!(mcu.mmu.rw(0x104), Ok(0), "the store didn't happen");
+
+        // a debugger breakpoint halts before the instruction executes
+        let action = (TriggerAction::DebugMode as u32) << ACTION_SHIFT;
+        mcu.cpu.debug_mode = true;
+        mcu.cpu
+            .write_csr(TDATA1, mcontrol6(DMODE | action | M | EXECUTE, 0))
+            .unwrap();
+        mcu.cpu.write_csr(TDATA2, 0x8).unwrap();
+        mcu.cpu.debug_mode = false;
+        mcu.cpu.pc = 0x8;
+        mcu.tick();
+        assert!(mcu.cpu.debug_mode);
+        assert_eq!(mcu.cpu.get_x(1), 0);
+        let dcsr = mcu.cpu.get_csr(CSRs::dcsr as u32).unwrap();
+        assert_eq!(
+            (dcsr & DCSR_CAUSE) >> DCSR_CAUSE_SHIFT,
+            DebugCause::Trigger as u32
+        );
+        assert_eq!(mcu.cpu.get_csr(CSRs::dpc as u32), Ok(0x8));
+    }
+}
@@ -326,12 +326,16 @@ fn struct_instruction_format(input: TokenStream) -> Result<TokenStream> {
             }
         });
 
+    // Structs whose encoding is fully defined by the checks have no fields of their own
+    let strut_format_fields = strut_format_fields.map(|fields| quote!(#fields,));
+
     let into_format = quote!(
     impl From<#struct_ident> for #format_ident {
+        #[allow(unused_variables)]
         fn from(i: #struct_ident) -> #format_ident {
             #format_ident {
                 #args_format_fields,
-                #strut_format_fields,
+                #strut_format_fields
                 ..#format_ident::default()
             }
         }
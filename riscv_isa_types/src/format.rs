@@ -40,6 +40,47 @@ impl From<RFormat> for u32 {
     }
 }
 
+/// R-type variant used by the atomic (A) extension, funct7 is split into the funct5 and the
+/// acquire/release ordering bits
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AFormat {
+    pub op: u32,
+    pub rd: u32,
+    pub funct3: u32,
+    pub rs1: u32,
+    pub rs2: u32,
+    pub rl: u32,
+    pub aq: u32,
+    pub funct5: u32,
+}
+
+impl From<u32> for AFormat {
+    fn from(v: u32) -> AFormat {
+        AFormat {
+            op: v & OPCODE_MASK,
+            rd: (v & RD_MASK) >> 7,
+            funct3: (v & FUNCT3_MASK) >> 12,
+            rs1: (v & RS1_MASK) >> 15,
+            rs2: (v & RS2_MASK) >> 20,
+            rl: (v & (1 << 25)) >> 25,
+            aq: (v & (1 << 26)) >> 26,
+            funct5: (v & (mask!(5) << 27)) >> 27,
+        }
+    }
+}
+
+impl From<AFormat> for u32 {
+    fn from(v: AFormat) -> u32 {
+        v.op | v.rd << 7
+            | v.funct3 << 12
+            | v.rs1 << 15
+            | v.rs2 << 20
+            | v.rl << 25
+            | v.aq << 26
+            | v.funct5 << 27
+    }
+}
+
 #[derive(Debug, Clone, Copy, Default)]
 pub struct IFormat {
     pub op: u32,
@@ -191,6 +232,256 @@ impl From<JFormat> for u32 {
     }
 }
 
+// Compressed (16-bit) instruction formats. Registers in the CIW, CL, CS, CA and CB formats are
+// 3-bit fields (rd', rs1', rs2') that address x8 to x15.
+
+pub const C_OPCODE_MASK: u32 = mask!(2);
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CRFormat {
+    pub op: u32,
+    pub rs2: u32,
+    pub rd: u32,
+    pub funct4: u32,
+}
+
+impl From<u32> for CRFormat {
+    fn from(v: u32) -> CRFormat {
+        CRFormat {
+            op: v & C_OPCODE_MASK,
+            rs2: (v & (mask!(5) << 2)) >> 2,
+            rd: (v & (mask!(5) << 7)) >> 7,
+            funct4: (v & (mask!(4) << 12)) >> 12,
+        }
+    }
+}
+
+impl From<CRFormat> for u32 {
+    fn from(v: CRFormat) -> u32 {
+        v.op | v.rs2 << 2 | v.rd << 7 | v.funct4 << 12
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CIFormat {
+    pub op: u32,
+    pub imm0: u32,
+    pub rd: u32,
+    pub imm1: u32,
+    pub funct3: u32,
+}
+
+impl From<u32> for CIFormat {
+    fn from(v: u32) -> CIFormat {
+        CIFormat {
+            op: v & C_OPCODE_MASK,
+            imm0: (v & (mask!(5) << 2)) >> 2,
+            rd: (v & (mask!(5) << 7)) >> 7,
+            imm1: (v & (1 << 12)) >> 12,
+            funct3: (v & (mask!(3) << 13)) >> 13,
+        }
+    }
+}
+
+impl From<CIFormat> for u32 {
+    fn from(v: CIFormat) -> u32 {
+        v.op | v.imm0 << 2 | v.rd << 7 | v.imm1 << 12 | v.funct3 << 13
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CSSFormat {
+    pub op: u32,
+    pub rs2: u32,
+    pub imm: u32,
+    pub funct3: u32,
+}
+
+impl From<u32> for CSSFormat {
+    fn from(v: u32) -> CSSFormat {
+        CSSFormat {
+            op: v & C_OPCODE_MASK,
+            rs2: (v & (mask!(5) << 2)) >> 2,
+            imm: (v & (mask!(6) << 7)) >> 7,
+            funct3: (v & (mask!(3) << 13)) >> 13,
+        }
+    }
+}
+
+impl From<CSSFormat> for u32 {
+    fn from(v: CSSFormat) -> u32 {
+        v.op | v.rs2 << 2 | v.imm << 7 | v.funct3 << 13
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CIWFormat {
+    pub op: u32,
+    pub rd_p: u32,
+    pub imm: u32,
+    pub funct3: u32,
+}
+
+impl From<u32> for CIWFormat {
+    fn from(v: u32) -> CIWFormat {
+        CIWFormat {
+            op: v & C_OPCODE_MASK,
+            rd_p: (v & (mask!(3) << 2)) >> 2,
+            imm: (v & (mask!(8) << 5)) >> 5,
+            funct3: (v & (mask!(3) << 13)) >> 13,
+        }
+    }
+}
+
+impl From<CIWFormat> for u32 {
+    fn from(v: CIWFormat) -> u32 {
+        v.op | v.rd_p << 2 | v.imm << 5 | v.funct3 << 13
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CLFormat {
+    pub op: u32,
+    pub rd_p: u32,
+    pub imm0: u32,
+    pub rs1_p: u32,
+    pub imm1: u32,
+    pub funct3: u32,
+}
+
+impl From<u32> for CLFormat {
+    fn from(v: u32) -> CLFormat {
+        CLFormat {
+            op: v & C_OPCODE_MASK,
+            rd_p: (v & (mask!(3) << 2)) >> 2,
+            imm0: (v & (mask!(2) << 5)) >> 5,
+            rs1_p: (v & (mask!(3) << 7)) >> 7,
+            imm1: (v & (mask!(3) << 10)) >> 10,
+            funct3: (v & (mask!(3) << 13)) >> 13,
+        }
+    }
+}
+
+impl From<CLFormat> for u32 {
+    fn from(v: CLFormat) -> u32 {
+        v.op | v.rd_p << 2 | v.imm0 << 5 | v.rs1_p << 7 | v.imm1 << 10 | v.funct3 << 13
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CSFormat {
+    pub op: u32,
+    pub rs2_p: u32,
+    pub imm0: u32,
+    pub rs1_p: u32,
+    pub imm1: u32,
+    pub funct3: u32,
+}
+
+impl From<u32> for CSFormat {
+    fn from(v: u32) -> CSFormat {
+        CSFormat {
+            op: v & C_OPCODE_MASK,
+            rs2_p: (v & (mask!(3) << 2)) >> 2,
+            imm0: (v & (mask!(2) << 5)) >> 5,
+            rs1_p: (v & (mask!(3) << 7)) >> 7,
+            imm1: (v & (mask!(3) << 10)) >> 10,
+            funct3: (v & (mask!(3) << 13)) >> 13,
+        }
+    }
+}
+
+impl From<CSFormat> for u32 {
+    fn from(v: CSFormat) -> u32 {
+        v.op | v.rs2_p << 2 | v.imm0 << 5 | v.rs1_p << 7 | v.imm1 << 10 | v.funct3 << 13
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CAFormat {
+    pub op: u32,
+    pub rs2_p: u32,
+    pub funct2: u32,
+    pub rd_p: u32,
+    pub funct6: u32,
+}
+
+impl From<u32> for CAFormat {
+    fn from(v: u32) -> CAFormat {
+        CAFormat {
+            op: v & C_OPCODE_MASK,
+            rs2_p: (v & (mask!(3) << 2)) >> 2,
+            funct2: (v & (mask!(2) << 5)) >> 5,
+            rd_p: (v & (mask!(3) << 7)) >> 7,
+            funct6: (v & (mask!(6) << 10)) >> 10,
+        }
+    }
+}
+
+impl From<CAFormat> for u32 {
+    fn from(v: CAFormat) -> u32 {
+        v.op | v.rs2_p << 2 | v.funct2 << 5 | v.rd_p << 7 | v.funct6 << 10
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CBFormat {
+    pub op: u32,
+    pub imm0: u32,
+    pub rs1_p: u32,
+    pub imm1: u32,
+    pub funct3: u32,
+    pub funct2: u32,
+}
+
+impl From<u32> for CBFormat {
+    fn from(v: u32) -> CBFormat {
+        CBFormat {
+            op: v & C_OPCODE_MASK,
+            imm0: (v & (mask!(5) << 2)) >> 2,
+            rs1_p: (v & (mask!(3) << 7)) >> 7,
+            imm1: (v & (mask!(3) << 10)) >> 10,
+            funct3: (v & (mask!(3) << 13)) >> 13,
+            // Not part of the official docs, but helpful to parse c.srli, c.srai and c.andi
+            funct2: (v & (mask!(2) << 10)) >> 10,
+        }
+    }
+}
+
+impl From<CBFormat> for u32 {
+    fn from(v: CBFormat) -> u32 {
+        v.op | v.imm0 << 2 | v.rs1_p << 7 | v.imm1 << 10 | v.funct3 << 13
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CJFormat {
+    pub op: u32,
+    pub imm: u32,
+    pub funct3: u32,
+}
+
+impl From<u32> for CJFormat {
+    fn from(v: u32) -> CJFormat {
+        CJFormat {
+            op: v & C_OPCODE_MASK,
+            imm: (v & (mask!(11) << 2)) >> 2,
+            funct3: (v & (mask!(3) << 13)) >> 13,
+        }
+    }
+}
+
+impl From<CJFormat> for u32 {
+    fn from(v: CJFormat) -> u32 {
+        v.op | v.imm << 2 | v.funct3 << 13
+    }
+}
+
+/// Returns true if the lower half of an instruction belongs to a 16-bit (compressed) encoding
+pub fn is_compressed(v: u32) -> bool {
+    v & C_OPCODE_MASK != 0b11
+}
+
 #[cfg(test)]
 mod tests {
 
@@ -210,6 +501,23 @@ mod tests {
         assert_eq!(inst, parsed.into());
     }
 
+    #[test]
+    fn parse_a_type() {
+        // amoswap.w.aq x1, x3, (x2)
+        // 00001_1_0_00011_00010_010_00001_0101111
+        let inst = 0x0c31_20af;
+
+        let parsed = AFormat::from(inst);
+
+        assert_eq!(parsed.rd, 1, "rd");
+        assert_eq!(parsed.rs1, 2, "rs1");
+        assert_eq!(parsed.rs2, 3, "rs2");
+        assert_eq!(parsed.aq, 1, "aq");
+        assert_eq!(parsed.rl, 0, "rl");
+        assert_eq!(parsed.funct5, 0b00001, "funct5");
+        assert_eq!(inst, parsed.into());
+    }
+
     #[test]
     fn parse_i_type() {
         // add x1, x2, 0x80
@@ -286,4 +594,37 @@ mod tests {
         assert_eq!(parsed.imm3, 0, "imm3");
         assert_eq!(inst, parsed.into());
     }
+
+    #[test]
+    fn parse_cl_type() {
+        // c.lw x9, 4(x10)
+        // 010_001_010_10_001_00
+        let inst = 0x4144;
+
+        let parsed = CLFormat::from(inst);
+
+        assert!(is_compressed(inst));
+        assert_eq!(parsed.op, 0b00, "op");
+        assert_eq!(parsed.rd_p, 1, "rd'");
+        assert_eq!(parsed.rs1_p, 2, "rs1'");
+        assert_eq!(parsed.imm0, 0b10, "imm0");
+        assert_eq!(parsed.imm1, 0, "imm1");
+        assert_eq!(parsed.funct3, 0b010, "funct3");
+        assert_eq!(inst, parsed.into());
+    }
+
+    #[test]
+    fn parse_cr_type() {
+        // c.add x1, x2
+        // 1001_00001_00010_10
+        let inst = 0x908a;
+
+        let parsed = CRFormat::from(inst);
+
+        assert!(is_compressed(inst));
+        assert_eq!(parsed.rd, 1, "rd");
+        assert_eq!(parsed.rs2, 2, "rs2");
+        assert_eq!(parsed.funct4, 0b1001, "funct4");
+        assert_eq!(inst, parsed.into());
+    }
 }
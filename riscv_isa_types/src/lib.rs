@@ -1,3 +1,6 @@
 pub mod format;
 pub mod privileged;
+pub mod rv32a;
+pub mod rv32c;
 pub mod rv32i;
+pub mod rv32m;
@@ -4,7 +4,7 @@ use macros::instruction;
 #[derive(Debug, Clone, Copy)]
 #[instruction]
 #[format(RFormat)]
-#[checks(op = 0b1110011, funct7 = 0b11000, rs2 = 0b10)]
+#[checks(op = 0b1110011, funct3 = 0b000, funct7 = 0b11000, rs2 = 0b10)]
 pub struct MRET {
     rd: u32,
 }
@@ -12,7 +12,32 @@ pub struct MRET {
 #[derive(Debug, Clone, Copy)]
 #[instruction]
 #[format(RFormat)]
-#[checks(op = 0b1110011, funct7 = 0b1000, rs2 = 0b101)]
+#[checks(op = 0b1110011, funct3 = 0b000, funct7 = 0b1000, rs2 = 0b10)]
+pub struct SRET {
+    rd: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+#[instruction]
+#[format(RFormat)]
+#[checks(op = 0b1110011, funct3 = 0b000, funct7 = 0b111101, rs2 = 0b10010)]
+pub struct DRET {
+    rd: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+#[instruction]
+#[format(RFormat)]
+#[checks(op = 0b1110011, funct3 = 0b000, funct7 = 0b1001)]
+pub struct SFENCEVMA {
+    pub rs1: u32,
+    pub rs2: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+#[instruction]
+#[format(RFormat)]
+#[checks(op = 0b1110011, funct3 = 0b000, funct7 = 0b1000, rs2 = 0b101)]
 pub struct WFI {
     rd: u32,
 }
@@ -20,10 +45,38 @@ pub struct WFI {
 #[derive(Debug, Clone, Copy)]
 #[instruction]
 #[format(RFormat)]
-#[checks(op = 0b1110011)]
+#[checks(op = 0b1110011, funct3 = 0b000)]
 pub enum RVPrivileged {
     #[checks(funct7 = 0b11000, rs2 = 0b10)]
     MRET(MRET),
+    #[checks(funct7 = 0b1000, rs2 = 0b10)]
+    SRET(SRET),
+    #[checks(funct7 = 0b111101, rs2 = 0b10010)]
+    DRET(DRET),
+    #[checks(funct7 = 0b1001)]
+    SFENCEVMA(SFENCEVMA),
     #[checks(funct7 = 0b1000, rs2 = 0b101)]
     WFI(WFI),
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn csr_instructions_are_not_privileged() {
+        assert!(matches!(
+            RVPrivileged::try_from(0x3020_0073),
+            Ok(RVPrivileged::MRET(_))
+        ));
+        // csrw medeleg, t0 and csrw stvec, t0 share funct7 and rs2 with mret and wfi
+        assert!(RVPrivileged::try_from(0x3022_9073).is_err());
+        assert!(RVPrivileged::try_from(0x1052_9073).is_err());
+        assert!(matches!(
+            RVPrivileged::try_from(0x7b20_0073),
+            Ok(RVPrivileged::DRET(_))
+        ));
+        // csrw dscratch0, t0
+        assert!(RVPrivileged::try_from(0x7b22_9073).is_err());
+    }
+}
@@ -0,0 +1,179 @@
+use crate::format::AFormat;
+use macros::instruction;
+
+#[derive(Debug, Clone, Copy)]
+#[instruction]
+#[format(AFormat)]
+#[checks(op = 0b0101111, funct3 = 0b010, funct5 = 0b00010, rs2 = 0)]
+pub struct LRW {
+    pub rd: u32,
+    pub rs1: u32,
+    pub aq: u32,
+    pub rl: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+#[instruction]
+#[format(AFormat)]
+#[checks(op = 0b0101111, funct3 = 0b010, funct5 = 0b00011)]
+pub struct SCW {
+    pub rd: u32,
+    pub rs1: u32,
+    pub rs2: u32,
+    pub aq: u32,
+    pub rl: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+#[instruction]
+#[format(AFormat)]
+#[checks(op = 0b0101111, funct3 = 0b010, funct5 = 0b00001)]
+pub struct AMOSWAPW {
+    pub rd: u32,
+    pub rs1: u32,
+    pub rs2: u32,
+    pub aq: u32,
+    pub rl: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+#[instruction]
+#[format(AFormat)]
+#[checks(op = 0b0101111, funct3 = 0b010, funct5 = 0b00000)]
+pub struct AMOADDW {
+    pub rd: u32,
+    pub rs1: u32,
+    pub rs2: u32,
+    pub aq: u32,
+    pub rl: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+#[instruction]
+#[format(AFormat)]
+#[checks(op = 0b0101111, funct3 = 0b010, funct5 = 0b00100)]
+pub struct AMOXORW {
+    pub rd: u32,
+    pub rs1: u32,
+    pub rs2: u32,
+    pub aq: u32,
+    pub rl: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+#[instruction]
+#[format(AFormat)]
+#[checks(op = 0b0101111, funct3 = 0b010, funct5 = 0b01100)]
+pub struct AMOANDW {
+    pub rd: u32,
+    pub rs1: u32,
+    pub rs2: u32,
+    pub aq: u32,
+    pub rl: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+#[instruction]
+#[format(AFormat)]
+#[checks(op = 0b0101111, funct3 = 0b010, funct5 = 0b01000)]
+pub struct AMOORW {
+    pub rd: u32,
+    pub rs1: u32,
+    pub rs2: u32,
+    pub aq: u32,
+    pub rl: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+#[instruction]
+#[format(AFormat)]
+#[checks(op = 0b0101111, funct3 = 0b010, funct5 = 0b10000)]
+pub struct AMOMINW {
+    pub rd: u32,
+    pub rs1: u32,
+    pub rs2: u32,
+    pub aq: u32,
+    pub rl: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+#[instruction]
+#[format(AFormat)]
+#[checks(op = 0b0101111, funct3 = 0b010, funct5 = 0b10100)]
+pub struct AMOMAXW {
+    pub rd: u32,
+    pub rs1: u32,
+    pub rs2: u32,
+    pub aq: u32,
+    pub rl: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+#[instruction]
+#[format(AFormat)]
+#[checks(op = 0b0101111, funct3 = 0b010, funct5 = 0b11000)]
+pub struct AMOMINUW {
+    pub rd: u32,
+    pub rs1: u32,
+    pub rs2: u32,
+    pub aq: u32,
+    pub rl: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+#[instruction]
+#[format(AFormat)]
+#[checks(op = 0b0101111, funct3 = 0b010, funct5 = 0b11100)]
+pub struct AMOMAXUW {
+    pub rd: u32,
+    pub rs1: u32,
+    pub rs2: u32,
+    pub aq: u32,
+    pub rl: u32,
+}
+
+/// Atomic instructions extension
+/// AMOs atomically load the word at x[rs1] into x[rd], apply the operation to the loaded value
+/// and x[rs2], and store the result back at x[rs1]
+#[derive(Debug, Clone, Copy)]
+#[instruction]
+#[format(AFormat)]
+#[checks(op = 0b0101111, funct3 = 0b010)]
+pub enum RV32a {
+    /// Load Reserved Word
+    /// Loads the word at x[rs1] into x[rd] and registers a reservation on that address
+    #[checks(funct5 = 0b00010, rs2 = 0)]
+    LRW(LRW),
+    /// Store Conditional Word
+    /// Stores x[rs2] at x[rs1] if a reservation on that address exists, writes 0 to x[rd] on
+    /// success and 1 otherwise
+    #[checks(funct5 = 0b00011)]
+    SCW(SCW),
+    /// Atomic Swap Word
+    #[checks(funct5 = 0b00001)]
+    AMOSWAPW(AMOSWAPW),
+    /// Atomic Add Word
+    #[checks(funct5 = 0b00000)]
+    AMOADDW(AMOADDW),
+    /// Atomic Xor Word
+    #[checks(funct5 = 0b00100)]
+    AMOXORW(AMOXORW),
+    /// Atomic And Word
+    #[checks(funct5 = 0b01100)]
+    AMOANDW(AMOANDW),
+    /// Atomic Or Word
+    #[checks(funct5 = 0b01000)]
+    AMOORW(AMOORW),
+    /// Atomic Minimum Word
+    #[checks(funct5 = 0b10000)]
+    AMOMINW(AMOMINW),
+    /// Atomic Maximum Word
+    #[checks(funct5 = 0b10100)]
+    AMOMAXW(AMOMAXW),
+    /// Atomic Unsigned Minimum Word
+    #[checks(funct5 = 0b11000)]
+    AMOMINUW(AMOMINUW),
+    /// Atomic Unsigned Maximum Word
+    #[checks(funct5 = 0b11100)]
+    AMOMAXUW(AMOMAXUW),
+}
@@ -0,0 +1,494 @@
+use crate::format::{
+    CAFormat, CBFormat, CIFormat, CIWFormat, CJFormat, CLFormat, CRFormat, CSFormat, CSSFormat,
+};
+use macros::instruction;
+
+// Compressed instructions keep the raw immediate fields of their format, the accessors on each
+// instruction reassemble the (unsigned, not yet sign-extended) immediate they encode.
+
+/// Maps a 3-bit compressed register field (rd', rs1', rs2') to the x register it addresses
+pub fn reg(r: u32) -> u32 {
+    r + 8
+}
+
+/// Reassembles the CB branch offset[8|4:3] / offset[7:6|2:1|5]
+fn cb_offset(imm0: u32, imm1: u32) -> u32 {
+    ((imm1 & 0b100) << 6)
+        | ((imm1 & 0b011) << 3)
+        | ((imm0 & 0b11000) << 3)
+        | (imm0 & 0b00110)
+        | ((imm0 & 0b00001) << 5)
+}
+
+/// Reassembles the CJ jump offset[11|4|9:8|10|6|7|3:1|5]
+fn cj_offset(imm: u32) -> u32 {
+    ((imm & (1 << 10)) << 1)
+        | ((imm & (1 << 9)) >> 5)
+        | ((imm & (0b11 << 7)) << 1)
+        | ((imm & (1 << 6)) << 4)
+        | ((imm & (1 << 5)) << 1)
+        | ((imm & (1 << 4)) << 3)
+        | (imm & 0b1110)
+        | ((imm & 1) << 5)
+}
+
+// Quadrant 0
+
+#[derive(Debug, Clone, Copy)]
+#[instruction]
+#[format(CIWFormat)]
+#[checks(op = 0b00, funct3 = 0b000)]
+pub struct CADDI4SPN {
+    pub rd_p: u32,
+    pub imm: u32,
+}
+
+impl CADDI4SPN {
+    /// nzuimm[5:4|9:6|2|3]
+    pub fn nzuimm(&self) -> u32 {
+        ((self.imm & 0b1100_0000) >> 2)
+            | ((self.imm & 0b0011_1100) << 4)
+            | ((self.imm & 0b10) << 1)
+            | ((self.imm & 0b01) << 3)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+#[instruction]
+#[format(CLFormat)]
+#[checks(op = 0b00, funct3 = 0b010)]
+pub struct CLW {
+    pub rd_p: u32,
+    pub rs1_p: u32,
+    pub imm0: u32,
+    pub imm1: u32,
+}
+
+impl CLW {
+    /// offset[5:3] / offset[2|6]
+    pub fn offset(&self) -> u32 {
+        (self.imm1 << 3) | ((self.imm0 & 0b10) << 1) | ((self.imm0 & 0b01) << 6)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+#[instruction]
+#[format(CSFormat)]
+#[checks(op = 0b00, funct3 = 0b110)]
+pub struct CSW {
+    pub rs1_p: u32,
+    pub rs2_p: u32,
+    pub imm0: u32,
+    pub imm1: u32,
+}
+
+impl CSW {
+    /// offset[5:3] / offset[2|6]
+    pub fn offset(&self) -> u32 {
+        (self.imm1 << 3) | ((self.imm0 & 0b10) << 1) | ((self.imm0 & 0b01) << 6)
+    }
+}
+
+// Quadrant 1
+
+#[derive(Debug, Clone, Copy)]
+#[instruction]
+#[format(CIFormat)]
+#[checks(op = 0b01, funct3 = 0b000)]
+pub struct CADDI {
+    pub rd: u32,
+    #[format_mapping(imm0 = 0, imm1 = 5)]
+    pub imm: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+#[instruction]
+#[format(CJFormat)]
+#[checks(op = 0b01, funct3 = 0b001)]
+pub struct CJAL {
+    pub imm: u32,
+}
+
+impl CJAL {
+    pub fn offset(&self) -> u32 {
+        cj_offset(self.imm)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+#[instruction]
+#[format(CIFormat)]
+#[checks(op = 0b01, funct3 = 0b010)]
+pub struct CLI {
+    pub rd: u32,
+    #[format_mapping(imm0 = 0, imm1 = 5)]
+    pub imm: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+#[instruction]
+#[format(CIFormat)]
+#[checks(op = 0b01, funct3 = 0b011, rd = 2)]
+pub struct CADDI16SP {
+    pub imm0: u32,
+    pub imm1: u32,
+}
+
+impl CADDI16SP {
+    /// nzimm[9] / nzimm[4|6|8:7|5]
+    pub fn nzimm(&self) -> u32 {
+        (self.imm1 << 9)
+            | (self.imm0 & 0b10000)
+            | ((self.imm0 & 0b01000) << 3)
+            | ((self.imm0 & 0b00110) << 6)
+            | ((self.imm0 & 0b00001) << 5)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+#[instruction]
+#[format(CIFormat)]
+#[checks(op = 0b01, funct3 = 0b011)]
+pub struct CLUI {
+    pub rd: u32,
+    /// nzimm[17:12]
+    #[format_mapping(imm0 = 0, imm1 = 5)]
+    pub imm: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+#[instruction]
+#[format(CBFormat)]
+#[checks(op = 0b01, funct3 = 0b100, funct2 = 0b00)]
+pub struct CSRLI {
+    pub rs1_p: u32,
+    pub imm0: u32,
+    pub imm1: u32,
+}
+
+impl CSRLI {
+    /// shamt[5] / shamt[4:0]
+    pub fn shamt(&self) -> u32 {
+        ((self.imm1 & 0b100) << 3) | self.imm0
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+#[instruction]
+#[format(CBFormat)]
+#[checks(op = 0b01, funct3 = 0b100, funct2 = 0b01)]
+pub struct CSRAI {
+    pub rs1_p: u32,
+    pub imm0: u32,
+    pub imm1: u32,
+}
+
+impl CSRAI {
+    /// shamt[5] / shamt[4:0]
+    pub fn shamt(&self) -> u32 {
+        ((self.imm1 & 0b100) << 3) | self.imm0
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+#[instruction]
+#[format(CBFormat)]
+#[checks(op = 0b01, funct3 = 0b100, funct2 = 0b10)]
+pub struct CANDI {
+    pub rs1_p: u32,
+    pub imm0: u32,
+    pub imm1: u32,
+}
+
+impl CANDI {
+    /// imm[5] / imm[4:0]
+    pub fn imm(&self) -> u32 {
+        ((self.imm1 & 0b100) << 3) | self.imm0
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+#[instruction]
+#[format(CAFormat)]
+#[checks(op = 0b01, funct6 = 0b100011, funct2 = 0b00)]
+pub struct CSUB {
+    pub rd_p: u32,
+    pub rs2_p: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+#[instruction]
+#[format(CAFormat)]
+#[checks(op = 0b01, funct6 = 0b100011, funct2 = 0b01)]
+pub struct CXOR {
+    pub rd_p: u32,
+    pub rs2_p: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+#[instruction]
+#[format(CAFormat)]
+#[checks(op = 0b01, funct6 = 0b100011, funct2 = 0b10)]
+pub struct COR {
+    pub rd_p: u32,
+    pub rs2_p: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+#[instruction]
+#[format(CAFormat)]
+#[checks(op = 0b01, funct6 = 0b100011, funct2 = 0b11)]
+pub struct CAND {
+    pub rd_p: u32,
+    pub rs2_p: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+#[instruction]
+#[format(CJFormat)]
+#[checks(op = 0b01, funct3 = 0b101)]
+pub struct CJ {
+    pub imm: u32,
+}
+
+impl CJ {
+    pub fn offset(&self) -> u32 {
+        cj_offset(self.imm)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+#[instruction]
+#[format(CBFormat)]
+#[checks(op = 0b01, funct3 = 0b110)]
+pub struct CBEQZ {
+    pub rs1_p: u32,
+    pub imm0: u32,
+    pub imm1: u32,
+}
+
+impl CBEQZ {
+    pub fn offset(&self) -> u32 {
+        cb_offset(self.imm0, self.imm1)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+#[instruction]
+#[format(CBFormat)]
+#[checks(op = 0b01, funct3 = 0b111)]
+pub struct CBNEZ {
+    pub rs1_p: u32,
+    pub imm0: u32,
+    pub imm1: u32,
+}
+
+impl CBNEZ {
+    pub fn offset(&self) -> u32 {
+        cb_offset(self.imm0, self.imm1)
+    }
+}
+
+// Quadrant 2
+
+#[derive(Debug, Clone, Copy)]
+#[instruction]
+#[format(CIFormat)]
+#[checks(op = 0b10, funct3 = 0b000)]
+pub struct CSLLI {
+    pub rd: u32,
+    /// shamt[5] / shamt[4:0]
+    #[format_mapping(imm0 = 0, imm1 = 5)]
+    pub shamt: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+#[instruction]
+#[format(CIFormat)]
+#[checks(op = 0b10, funct3 = 0b010)]
+pub struct CLWSP {
+    pub rd: u32,
+    pub imm0: u32,
+    pub imm1: u32,
+}
+
+impl CLWSP {
+    /// offset[5] / offset[4:2|7:6]
+    pub fn offset(&self) -> u32 {
+        (self.imm1 << 5) | (self.imm0 & 0b11100) | ((self.imm0 & 0b00011) << 6)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+#[instruction]
+#[format(CRFormat)]
+#[checks(op = 0b10, funct4 = 0b1000, rs2 = 0)]
+pub struct CJR {
+    pub rd: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+#[instruction]
+#[format(CRFormat)]
+#[checks(op = 0b10, funct4 = 0b1000)]
+pub struct CMV {
+    pub rd: u32,
+    pub rs2: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+#[instruction]
+#[format(CRFormat)]
+#[checks(op = 0b10, funct4 = 0b1001, rd = 0, rs2 = 0)]
+pub struct CEBREAK {}
+
+#[derive(Debug, Clone, Copy)]
+#[instruction]
+#[format(CRFormat)]
+#[checks(op = 0b10, funct4 = 0b1001, rs2 = 0)]
+pub struct CJALR {
+    pub rd: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+#[instruction]
+#[format(CRFormat)]
+#[checks(op = 0b10, funct4 = 0b1001)]
+pub struct CADD {
+    pub rd: u32,
+    pub rs2: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+#[instruction]
+#[format(CSSFormat)]
+#[checks(op = 0b10, funct3 = 0b110)]
+pub struct CSWSP {
+    pub rs2: u32,
+    pub imm: u32,
+}
+
+impl CSWSP {
+    /// offset[5:2|7:6]
+    pub fn offset(&self) -> u32 {
+        (self.imm & 0b111100) | ((self.imm & 0b000011) << 6)
+    }
+}
+
+/// Compressed instructions extension
+/// Every variant is a 16-bit encoding of an existing 32-bit instruction. Variants sharing an
+/// encoding space are ordered so the most specific one is tried first.
+#[derive(Debug, Clone, Copy)]
+#[instruction]
+pub enum RV32c {
+    /// addi rd', x2, nzuimm
+    CADDI4SPN(CADDI4SPN),
+    /// lw rd', offset(rs1')
+    CLW(CLW),
+    /// sw rs2', offset(rs1')
+    CSW(CSW),
+    /// addi rd, rd, imm (c.nop when rd is x0)
+    CADDI(CADDI),
+    /// jal x1, offset
+    CJAL(CJAL),
+    /// addi rd, x0, imm
+    CLI(CLI),
+    /// addi x2, x2, nzimm
+    CADDI16SP(CADDI16SP),
+    /// lui rd, nzimm
+    CLUI(CLUI),
+    /// srli rd', rd', shamt
+    CSRLI(CSRLI),
+    /// srai rd', rd', shamt
+    CSRAI(CSRAI),
+    /// andi rd', rd', imm
+    CANDI(CANDI),
+    /// sub rd', rd', rs2'
+    CSUB(CSUB),
+    /// xor rd', rd', rs2'
+    CXOR(CXOR),
+    /// or rd', rd', rs2'
+    COR(COR),
+    /// and rd', rd', rs2'
+    CAND(CAND),
+    /// jal x0, offset
+    CJ(CJ),
+    /// beq rs1', x0, offset
+    CBEQZ(CBEQZ),
+    /// bne rs1', x0, offset
+    CBNEZ(CBNEZ),
+    /// slli rd, rd, shamt
+    CSLLI(CSLLI),
+    /// lw rd, offset(x2)
+    CLWSP(CLWSP),
+    /// jalr x0, 0(rs1)
+    CJR(CJR),
+    /// add rd, x0, rs2
+    CMV(CMV),
+    /// ebreak
+    CEBREAK(CEBREAK),
+    /// jalr x1, 0(rs1)
+    CJALR(CJALR),
+    /// add rd, rd, rs2
+    CADD(CADD),
+    /// sw rs2, offset(x2)
+    CSWSP(CSWSP),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_offsets() {
+        // c.j -2
+        // 101_11111111111_01
+        match RV32c::try_from(0xbffd) {
+            Ok(RV32c::CJ(i)) => assert_eq!(i.offset(), 0xffe),
+            v => panic!("{v:?}"),
+        }
+
+        // c.beqz x8, -4
+        // 110_111_000_11101_01
+        match RV32c::try_from(0xdc75) {
+            Ok(RV32c::CBEQZ(i)) => {
+                assert_eq!(reg(i.rs1_p), 8);
+                assert_eq!(i.offset(), 0x1fc);
+            }
+            v => panic!("{v:?}"),
+        }
+
+        // c.lwsp x1, 12(x2)
+        // 010_0_00001_01100_10
+        match RV32c::try_from(0x40b2) {
+            Ok(RV32c::CLWSP(i)) => {
+                assert_eq!(i.rd, 1);
+                assert_eq!(i.offset(), 12);
+            }
+            v => panic!("{v:?}"),
+        }
+
+        // c.addi16sp -64
+        // 011_1_00010_01110_01
+        match RV32c::try_from(0x7139) {
+            Ok(RV32c::CADDI16SP(i)) => assert_eq!(i.nzimm(), 0x3c0),
+            v => panic!("{v:?}"),
+        }
+    }
+
+    #[test]
+    fn decode_shared_encodings() {
+        // c.ebreak
+        assert!(matches!(RV32c::try_from(0x9002), Ok(RV32c::CEBREAK(_))));
+        // c.jalr x1
+        assert!(matches!(RV32c::try_from(0x9082), Ok(RV32c::CJALR(_))));
+        // c.add x1, x2
+        assert!(matches!(RV32c::try_from(0x908a), Ok(RV32c::CADD(_))));
+        // c.jr x1
+        assert!(matches!(RV32c::try_from(0x8082), Ok(RV32c::CJR(_))));
+        // c.lui x1, 1
+        assert!(matches!(RV32c::try_from(0x6085), Ok(RV32c::CLUI(_))));
+        // 32-bit instructions are never compressed
+        assert!(RV32c::try_from(0x0031_00b3).is_err());
+    }
+}
@@ -106,21 +106,21 @@ pub struct ADD {
 #[format(RFormat)]
 #[checks(op = 0b0110011)]
 pub enum Op {
-    #[checks(funct3 = 0b111)]
+    #[checks(funct3 = 0b111, funct7 = 0b0000000)]
     AND(AND),
-    #[checks(funct3 = 0b110)]
+    #[checks(funct3 = 0b110, funct7 = 0b0000000)]
     OR(OR),
     #[checks(funct3 = 0b101, funct7 = 0b0000000)]
     SRL(SRL),
     #[checks(funct3 = 0b101, funct7 = 0b0100000)]
     SRA(SRA),
-    #[checks(funct3 = 0b100)]
+    #[checks(funct3 = 0b100, funct7 = 0b0000000)]
     XOR(XOR),
-    #[checks(funct3 = 0b011)]
+    #[checks(funct3 = 0b011, funct7 = 0b0000000)]
     SLTU(SLTU),
-    #[checks(funct3 = 0b010)]
+    #[checks(funct3 = 0b010, funct7 = 0b0000000)]
     SLT(SLT),
-    #[checks(funct3 = 0b001)]
+    #[checks(funct3 = 0b001, funct7 = 0b0000000)]
     SLL(SLL),
     #[checks(funct3 = 0b000, funct7 = 0b0000000)]
     ADD(ADD),
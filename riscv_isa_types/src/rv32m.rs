@@ -0,0 +1,120 @@
+use crate::format::RFormat;
+use macros::instruction;
+
+#[derive(Debug, Clone, Copy)]
+#[instruction]
+#[format(RFormat)]
+#[checks(op = 0b0110011, funct3 = 0b000, funct7 = 0b0000001)]
+pub struct MUL {
+    pub rd: u32,
+    pub rs1: u32,
+    pub rs2: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+#[instruction]
+#[format(RFormat)]
+#[checks(op = 0b0110011, funct3 = 0b001, funct7 = 0b0000001)]
+pub struct MULH {
+    pub rd: u32,
+    pub rs1: u32,
+    pub rs2: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+#[instruction]
+#[format(RFormat)]
+#[checks(op = 0b0110011, funct3 = 0b010, funct7 = 0b0000001)]
+pub struct MULHSU {
+    pub rd: u32,
+    pub rs1: u32,
+    pub rs2: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+#[instruction]
+#[format(RFormat)]
+#[checks(op = 0b0110011, funct3 = 0b011, funct7 = 0b0000001)]
+pub struct MULHU {
+    pub rd: u32,
+    pub rs1: u32,
+    pub rs2: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+#[instruction]
+#[format(RFormat)]
+#[checks(op = 0b0110011, funct3 = 0b100, funct7 = 0b0000001)]
+pub struct DIV {
+    pub rd: u32,
+    pub rs1: u32,
+    pub rs2: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+#[instruction]
+#[format(RFormat)]
+#[checks(op = 0b0110011, funct3 = 0b101, funct7 = 0b0000001)]
+pub struct DIVU {
+    pub rd: u32,
+    pub rs1: u32,
+    pub rs2: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+#[instruction]
+#[format(RFormat)]
+#[checks(op = 0b0110011, funct3 = 0b110, funct7 = 0b0000001)]
+pub struct REM {
+    pub rd: u32,
+    pub rs1: u32,
+    pub rs2: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+#[instruction]
+#[format(RFormat)]
+#[checks(op = 0b0110011, funct3 = 0b111, funct7 = 0b0000001)]
+pub struct REMU {
+    pub rd: u32,
+    pub rs1: u32,
+    pub rs2: u32,
+}
+
+/// Integer multiplication and division extension
+#[derive(Debug, Clone, Copy)]
+#[instruction]
+#[format(RFormat)]
+#[checks(op = 0b0110011, funct7 = 0b0000001)]
+pub enum RV32m {
+    /// Multiply
+    /// Writes the lower 32 bits of x[rs1] * x[rs2] to x[rd]
+    #[checks(funct3 = 0b000)]
+    MUL(MUL),
+    /// Multiply High
+    /// Writes the upper 32 bits of the signed product x[rs1] * x[rs2] to x[rd]
+    #[checks(funct3 = 0b001)]
+    MULH(MULH),
+    /// Multiply High Signed-Unsigned
+    /// Writes the upper 32 bits of the product of signed x[rs1] and unsigned x[rs2] to x[rd]
+    #[checks(funct3 = 0b010)]
+    MULHSU(MULHSU),
+    /// Multiply High Unsigned
+    /// Writes the upper 32 bits of the unsigned product x[rs1] * x[rs2] to x[rd]
+    #[checks(funct3 = 0b011)]
+    MULHU(MULHU),
+    /// Divide
+    /// Signed division of x[rs1] by x[rs2], rounding towards zero
+    #[checks(funct3 = 0b100)]
+    DIV(DIV),
+    /// Divide Unsigned
+    #[checks(funct3 = 0b101)]
+    DIVU(DIVU),
+    /// Remainder
+    /// Remainder of the signed division of x[rs1] by x[rs2]
+    #[checks(funct3 = 0b110)]
+    REM(REM),
+    /// Remainder Unsigned
+    #[checks(funct3 = 0b111)]
+    REMU(REMU),
+}